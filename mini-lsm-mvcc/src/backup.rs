@@ -0,0 +1,338 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::fs::{FileSystem, copy_file, default_fs};
+use crate::lsm_storage::MiniLsm;
+
+/// Stores incremental backups of a database in a local directory.
+///
+/// SSTs are immutable, so they are stored once in `shared/` and referenced by every backup that contains them. The
+/// other files of a backup are stored in `private/<backup_id>/`, and the backup is described by `meta/<backup_id>`,
+/// which is written last. A backup without metadata is incomplete and is removed when the engine is opened.
+pub struct BackupEngine {
+    fs: Arc<dyn FileSystem>,
+    dir: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupInfo {
+    pub backup_id: u64,
+    /// Seconds since the Unix epoch when the backup was created.
+    pub timestamp: u64,
+    /// The latest commit timestamp included in the backup.
+    pub commit_ts: u64,
+    pub files: Vec<BackupFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupFile {
+    /// The file name in the database directory.
+    pub name: String,
+    /// The path relative to the backup directory.
+    pub path: String,
+    pub size: u64,
+    pub checksum: u32,
+}
+
+fn file_checksum(fs: &dyn FileSystem, path: &Path) -> Result<(u64, u32)> {
+    let file = fs.open(path)?;
+    let size = file.size();
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; 1 << 16];
+    let mut offset = 0;
+    while offset < size {
+        let len = (size - offset).min(buf.len() as u64) as usize;
+        file.read_at(&mut buf[..len], offset)?;
+        hasher.update(&buf[..len]);
+        offset += len as u64;
+    }
+    Ok((size, hasher.finalize()))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap().to_string_lossy().into_owned()
+}
+
+impl BackupEngine {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_fs(default_fs(), dir)
+    }
+
+    /// Open the backup directory on the given file system, which may differ from the file system of the databases.
+    pub fn open_with_fs(fs: Arc<dyn FileSystem>, dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        for sub_dir in ["shared", "private", "meta"] {
+            fs.create_dir_all(&dir.join(sub_dir))?;
+        }
+        let engine = Self {
+            fs,
+            dir: dir.to_path_buf(),
+        };
+        engine.delete_unreferenced_files()?;
+        Ok(engine)
+    }
+
+    fn path_of_meta(&self, backup_id: u64) -> PathBuf {
+        self.dir.join("meta").join(backup_id.to_string())
+    }
+
+    fn path_of_private(&self, backup_id: u64) -> PathBuf {
+        self.dir.join("private").join(backup_id.to_string())
+    }
+
+    /// List the backups from the oldest to the latest.
+    pub fn list_backups(&self) -> Result<Vec<BackupInfo>> {
+        let mut backups = Vec::new();
+        for path in self.fs.list_dir(&self.dir.join("meta"))? {
+            if file_name(&path).parse::<u64>().is_err() {
+                continue;
+            }
+            let meta = self.fs.read(&path)?;
+            backups.push(
+                serde_json::from_slice::<BackupInfo>(&meta)
+                    .with_context(|| format!("failed to parse {}", path.display()))?,
+            );
+        }
+        backups.sort_by_key(|x| x.backup_id);
+        Ok(backups)
+    }
+
+    pub fn get_backup(&self, backup_id: u64) -> Result<BackupInfo> {
+        let meta = self
+            .fs
+            .read(&self.path_of_meta(backup_id))
+            .with_context(|| format!("backup {} not found", backup_id))?;
+        Ok(serde_json::from_slice(&meta)?)
+    }
+
+    /// Back up the database. Only SSTs that are not in the backup directory yet are copied.
+    pub fn create_backup(&self, storage: &MiniLsm) -> Result<BackupInfo> {
+        let backups = self.list_backups()?;
+        let backup_id = backups.last().map(|x| x.backup_id + 1).unwrap_or(1);
+        // SSTs are immutable, so an SST with the name and the size of a backed up one is taken as the same file, and
+        // is neither read nor copied again.
+        let shared_files = backups
+            .into_iter()
+            .flat_map(|x| x.files)
+            .filter(|x| x.path.starts_with("shared/"))
+            .map(|x| ((x.name.clone(), x.size), x))
+            .collect::<HashMap<_, _>>();
+        let private_dir = self.path_of_private(backup_id);
+        let mut files = Vec::new();
+        let mut copied = 0;
+        let commit_ts =
+            storage
+                .inner
+                .create_checkpoint_with(&self.fs, &private_dir, |_, path| {
+                    let (file, is_new) = self.add_shared_file(
+                        storage.inner.fs(),
+                        path,
+                        &shared_files,
+                        |src, dst| copy_file(storage.inner.fs(), src, self.fs.as_ref(), dst),
+                    )?;
+                    copied += is_new as usize;
+                    files.push(file);
+                    Ok(())
+                })?;
+
+        // The checkpoint contains the manifest, the options and the SSTs flushed from memtables.
+        for path in self.fs.list_dir(&private_dir)? {
+            if path.extension().is_some_and(|ext| ext == "sst") {
+                let (file, is_new) =
+                    self.add_shared_file(self.fs.as_ref(), &path, &shared_files, |src, dst| {
+                        self.fs.rename(src, dst)
+                    })?;
+                copied += is_new as usize;
+                files.push(file);
+            } else {
+                let (size, checksum) = file_checksum(self.fs.as_ref(), &path)?;
+                files.push(BackupFile {
+                    name: file_name(&path),
+                    path: format!("private/{}/{}", backup_id, file_name(&path)),
+                    size,
+                    checksum,
+                });
+            }
+        }
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        self.fs.sync_dir(&self.dir.join("shared"))?;
+
+        let info = BackupInfo {
+            backup_id,
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_secs(),
+            commit_ts,
+            files,
+        };
+        let tmp_path = self.dir.join("meta").join(format!("{}.tmp", backup_id));
+        self.fs
+            .write(&tmp_path, &serde_json::to_vec_pretty(&info)?)?;
+        self.fs.rename(&tmp_path, &self.path_of_meta(backup_id))?;
+        self.fs.sync_dir(&self.dir.join("meta"))?;
+        println!(
+            "created backup {} with {} files, {} new SSTs copied",
+            backup_id,
+            info.files.len(),
+            copied
+        );
+        Ok(info)
+    }
+
+    /// Add an SST on the file system `src_fs` to the shared directory unless it is one of `shared_files`, keyed by
+    /// name and size, or an identical file is already there. Returns the backup file and whether the file is added.
+    fn add_shared_file(
+        &self,
+        src_fs: &dyn FileSystem,
+        path: &Path,
+        shared_files: &HashMap<(String, u64), BackupFile>,
+        add: impl FnOnce(&Path, &Path) -> Result<()>,
+    ) -> Result<(BackupFile, bool)> {
+        let name = file_name(path);
+        let size = src_fs.open(path)?.size();
+        if let Some(file) = shared_files.get(&(name.clone(), size))
+            && self.fs.exists(&self.dir.join(&file.path))
+        {
+            return Ok((file.clone(), false));
+        }
+        let (size, checksum) = file_checksum(src_fs, path)?;
+        let stem = name.strip_suffix(".sst").unwrap();
+        let shared_path = format!("shared/{}_{:08x}_{}.sst", stem, checksum, size);
+        let dst = self.dir.join(&shared_path);
+        let is_new = !self.fs.exists(&dst);
+        if is_new {
+            // Write to a temporary file first, so that a partial file is never taken as a complete one.
+            let tmp_dst = self.dir.join(format!("{}.tmp", shared_path));
+            add(path, &tmp_dst)?;
+            self.fs.rename(&tmp_dst, &dst)?;
+        }
+        Ok((
+            BackupFile {
+                name,
+                path: shared_path,
+                size,
+                checksum,
+            },
+            is_new,
+        ))
+    }
+
+    /// Check that all files of the backup exist and match their sizes and checksums.
+    pub fn verify_backup(&self, backup_id: u64) -> Result<()> {
+        let info = self.get_backup(backup_id)?;
+        for file in &info.files {
+            let path = self.dir.join(&file.path);
+            let (size, checksum) = file_checksum(self.fs.as_ref(), &path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            if size != file.size || checksum != file.checksum {
+                bail!(
+                    "backup {}: {} is corrupted, expected size={} checksum={:08x}, found size={} checksum={:08x}",
+                    backup_id,
+                    file.path,
+                    file.size,
+                    file.checksum,
+                    size,
+                    checksum
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Restore the backup into `target_dir`, which must be empty or not exist. The restored database can be opened
+    /// with `MiniLsm::open`.
+    pub fn restore(&self, backup_id: u64, target_dir: impl AsRef<Path>) -> Result<()> {
+        self.restore_with_fs(backup_id, default_fs().as_ref(), target_dir)
+    }
+
+    /// Restore the backup into `target_dir` on the file system `fs`.
+    pub fn restore_with_fs(
+        &self,
+        backup_id: u64,
+        fs: &dyn FileSystem,
+        target_dir: impl AsRef<Path>,
+    ) -> Result<()> {
+        let target_dir = target_dir.as_ref();
+        if fs.exists(target_dir) && !fs.list_dir(target_dir)?.is_empty() {
+            bail!("restore directory {} is not empty", target_dir.display());
+        }
+        self.verify_backup(backup_id)?;
+        fs.create_dir_all(target_dir)?;
+        let info = self.get_backup(backup_id)?;
+        for file in &info.files {
+            copy_file(
+                self.fs.as_ref(),
+                &self.dir.join(&file.path),
+                fs,
+                &target_dir.join(&file.name),
+            )?;
+        }
+        fs.sync_dir(target_dir)?;
+        Ok(())
+    }
+
+    pub fn delete_backup(&self, backup_id: u64) -> Result<()> {
+        self.fs
+            .remove_file(&self.path_of_meta(backup_id))
+            .with_context(|| format!("backup {} not found", backup_id))?;
+        self.delete_unreferenced_files()
+    }
+
+    /// Delete the oldest backups, keeping the latest `num_backups_to_keep` ones.
+    pub fn purge_old_backups(&self, num_backups_to_keep: usize) -> Result<()> {
+        let backups = self.list_backups()?;
+        let num_to_delete = backups.len().saturating_sub(num_backups_to_keep);
+        for backup in &backups[..num_to_delete] {
+            self.fs.remove_file(&self.path_of_meta(backup.backup_id))?;
+        }
+        self.delete_unreferenced_files()
+    }
+
+    /// Delete the files that are not referenced by any backup, including the files of incomplete backups.
+    fn delete_unreferenced_files(&self) -> Result<()> {
+        let backups = self.list_backups()?;
+        let referenced = backups
+            .iter()
+            .flat_map(|x| x.files.iter().map(|file| file.path.clone()))
+            .collect::<HashSet<_>>();
+        let backup_ids = backups
+            .iter()
+            .map(|x| x.backup_id.to_string())
+            .collect::<HashSet<_>>();
+        for path in self.fs.list_dir(&self.dir.join("shared"))? {
+            if !referenced.contains(&format!("shared/{}", file_name(&path))) {
+                self.fs.remove_file(&path)?;
+            }
+        }
+        for path in self.fs.list_dir(&self.dir.join("private"))? {
+            if !backup_ids.contains(&file_name(&path)) {
+                self.fs.remove_dir_all(&path)?;
+            }
+        }
+        for path in self.fs.list_dir(&self.dir.join("meta"))? {
+            if path.extension().is_some_and(|ext| ext == "tmp") {
+                self.fs.remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The parts of the compaction simulator that depend on the compaction controllers of the crate it is built with.
+
+use std::collections::HashSet;
+
+use clap::{Subcommand, ValueEnum};
+
+use crate::MockStorage;
+use crate::mini_lsm_wrapper::clock::default_clock;
+use crate::mini_lsm_wrapper::compact::{
+    CompactionPri, CompactionTriggers, HybridCompactionController, HybridCompactionOptions,
+    LeveledCompactionController, LeveledCompactionOptions, LeveledCompactionTask,
+    SimpleLeveledCompactionController, SimpleLeveledCompactionTask, TieredCompactionController,
+    TieredCompactionOptions, TieredCompactionTask,
+};
+use crate::mini_lsm_wrapper::lsm_storage::LsmStorageState;
+
+#[derive(clap::Args, Debug)]
+pub struct LeveledArgs {
+    /// How to pick the SST to compact from a level. Run the simulator with each of them to compare the write
+    /// amplification.
+    #[clap(long, value_enum, default_value = "oldest-file")]
+    compaction_pri: CompactionPriArg,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum CompactionPriArg {
+    OldestFile,
+    MinOverlappingRatio,
+    OldestSmallestSeq,
+    RoundRobin,
+}
+
+impl From<CompactionPriArg> for CompactionPri {
+    fn from(arg: CompactionPriArg) -> Self {
+        match arg {
+            CompactionPriArg::OldestFile => CompactionPri::OldestFile,
+            CompactionPriArg::MinOverlappingRatio => CompactionPri::MinOverlappingRatio,
+            CompactionPriArg::OldestSmallestSeq => CompactionPri::OldestSmallestSeq,
+            CompactionPriArg::RoundRobin => CompactionPri::RoundRobin,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    Hybrid {
+        /// Dump the generated ID instead of where the original data comes from.
+        /// For example, if SST 1, 2, 3 is compacted to another level, it should have
+        /// a new SST ID 4, 5, 6 as SSTs are immutable and write-once. With this flag
+        /// enabled, you will see the new level has SST 1, 2, 3 because the data of
+        /// 4, 5, 6 are originated from 1, 2, 3.
+        #[clap(long)]
+        dump_real_id: bool,
+        /// Only dump size information instead of the layer files. if this is enabled,
+        /// it will print one row per compaction iteration.
+        #[clap(long)]
+        size_only: bool,
+        #[clap(long, default_value = "4")]
+        num_levels: usize,
+        #[clap(long, default_value = "3")]
+        max_runs_per_level: usize,
+        #[clap(long, default_value = "50")]
+        iterations: usize,
+        #[clap(long, default_value = "32")]
+        sst_size_mb: usize,
+    },
+}
+
+pub fn run(command: Command) {
+    match command {
+        Command::Hybrid {
+            dump_real_id,
+            size_only,
+            num_levels,
+            max_runs_per_level,
+            iterations,
+            sst_size_mb,
+        } => {
+            // A flushed run is one SST, so the runs of L1 are about the size of an SST.
+            let controller = HybridCompactionController::new(HybridCompactionOptions {
+                num_levels,
+                max_runs_per_level,
+                base_run_size_mb: sst_size_mb,
+            });
+            let mut storage = MockStorage::new(sst_size_mb);
+            let mut max_space = 0;
+            for i in 0..iterations {
+                println!("=== Iteration {i} ===");
+                storage.flush_sst_to_new_tier();
+                println!("--- After Flush ---");
+                if size_only {
+                    storage.dump_size_only();
+                } else if dump_real_id {
+                    storage.dump_real_id(false, false);
+                } else {
+                    storage.dump_original_id(false, false);
+                }
+                let mut num_compactions = 0;
+                while let Some(task) = {
+                    if !size_only {
+                        println!("--- Compaction Task ---");
+                    }
+                    controller.generate_compaction_task(&storage.snapshot, &HashSet::new())
+                } {
+                    let mut input = Vec::new();
+                    for (tier_id, files) in &task.tiers {
+                        input.extend(files);
+                        print!("L{} {:?} ", tier_id, files);
+                    }
+                    let sst_ids = storage.write_compaction_output(&input);
+                    println!("-> {:?}", sst_ids);
+                    max_space = max_space.max(storage.space_used());
+                    let (snapshot, del) =
+                        controller.apply_compaction_result(&storage.snapshot, &task, &sst_ids);
+                    storage.snapshot = snapshot;
+                    storage.remove(&del, &sst_ids);
+                    println!("--- After Compaction ---");
+                    if size_only {
+                        storage.dump_size_only();
+                    } else if dump_real_id {
+                        storage.dump_real_id(false, false);
+                    } else {
+                        storage.dump_original_id(false, false);
+                    }
+                    num_compactions += 1;
+                    if num_compactions >= num_levels * 3 {
+                        panic!("compaction does not converge?");
+                    }
+                }
+                if num_compactions == 0 {
+                    println!("no compaction triggered");
+                } else {
+                    println!("{num_compactions} compaction triggered in this iteration");
+                }
+                max_space = max_space.max(storage.space_used());
+                storage.dump_statistics(max_space);
+            }
+        }
+    }
+}
+
+pub fn simple_task(
+    controller: &SimpleLeveledCompactionController,
+    snapshot: &LsmStorageState,
+) -> Option<SimpleLeveledCompactionTask> {
+    controller.generate_compaction_task(snapshot, &HashSet::new())
+}
+
+pub fn simple_trivial_move(
+    task: &SimpleLeveledCompactionTask,
+    snapshot: &LsmStorageState,
+) -> Option<Vec<usize>> {
+    task.trivial_move(snapshot)
+}
+
+pub fn tiered_controller(options: TieredCompactionOptions) -> TieredCompactionController {
+    TieredCompactionController::new(options, CompactionTriggers::default(), default_clock())
+}
+
+pub fn tiered_task(
+    controller: &TieredCompactionController,
+    snapshot: &LsmStorageState,
+) -> Option<TieredCompactionTask> {
+    controller.generate_compaction_task(snapshot, &HashSet::new(), u64::MAX)
+}
+
+pub fn leveled_controller(
+    level0_file_num_compaction_trigger: usize,
+    level_size_multiplier: usize,
+    max_levels: usize,
+    base_level_size_mb: usize,
+    args: LeveledArgs,
+) -> LeveledCompactionController {
+    LeveledCompactionController::new(
+        LeveledCompactionOptions {
+            level0_file_num_compaction_trigger,
+            level_size_multiplier,
+            max_levels,
+            base_level_size_mb,
+            compaction_pri: args.compaction_pri.into(),
+        },
+        CompactionTriggers::default(),
+        default_clock(),
+    )
+}
+
+pub fn leveled_task(
+    controller: &LeveledCompactionController,
+    snapshot: &LsmStorageState,
+) -> Option<LeveledCompactionTask> {
+    controller.generate_compaction_task(snapshot, &HashSet::new(), u64::MAX)
+}
+
+pub fn leveled_trivial_move(
+    task: &LeveledCompactionTask,
+    snapshot: &LsmStorageState,
+) -> Option<Vec<usize>> {
+    task.trivial_move(snapshot)
+}
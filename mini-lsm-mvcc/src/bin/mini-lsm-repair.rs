@@ -0,0 +1,51 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod wrapper;
+
+use anyhow::{Result, bail};
+use clap::Parser;
+use std::path::PathBuf;
+use wrapper::mini_lsm_wrapper;
+
+use mini_lsm_wrapper::fs::default_fs;
+use mini_lsm_wrapper::lsm_storage::LsmStorageOptions;
+use mini_lsm_wrapper::repair::repair_db;
+
+/// Rebuild the manifest of a database from its SSTs and WALs. The database must not be open.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(long, default_value = "lsm.db")]
+    path: PathBuf,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    // The options decide where the SSTs are placed, so they must come from the database.
+    let Some(options) = LsmStorageOptions::load(default_fs().as_ref(), &args.path)? else {
+        bail!(
+            "no OPTIONS file in {}, cannot decide the compaction strategy",
+            args.path.display()
+        );
+    };
+    let report = repair_db(&args.path, &options)?;
+    println!("recovered SSTs: {:?}", report.recovered_ssts);
+    println!("SSTs built from WALs: {:?}", report.wal_ssts);
+    for file in &report.lost_files {
+        println!("moved to lost: {}", file.display());
+    }
+    println!("max ts: {}", report.max_ts);
+    Ok(())
+}
@@ -0,0 +1,110 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Result, bail};
+
+use crate::fs::{FileSystem, copy_file};
+use crate::lsm_storage::LsmStorageInner;
+use crate::manifest::{Manifest, VersionEdit};
+
+impl LsmStorageInner {
+    /// Create a checkpoint of the database in `dir`, which can be opened with `MiniLsm::open` as a copy of the
+    /// database at the returned commit timestamp. SSTs are hard linked if possible, and memtables are flushed into
+    /// new SSTs in the checkpoint, so the checkpoint does not contain any WAL.
+    pub fn create_checkpoint(&self, dir: impl AsRef<Path>) -> Result<u64> {
+        let dir = dir.as_ref();
+        let commit_ts = self.create_checkpoint_with(&self.options.fs, dir, |sst_id, path| {
+            let dst = Self::path_of_sst_static(dir, sst_id);
+            // Hard links do not work across file systems.
+            if self.fs().hard_link(path, &dst).is_err() {
+                copy_file(self.fs(), path, self.fs(), &dst)?;
+            }
+            Ok(())
+        })?;
+        println!(
+            "created checkpoint at {} with commit ts {}",
+            dir.display(),
+            commit_ts
+        );
+        Ok(commit_ts)
+    }
+
+    /// Write the manifest, the options and the flushed memtables of a checkpoint to `dir` on the file system `fs`, and
+    /// call `add_sst` with the id and the path of every existing SST in the checkpoint. The SSTs are not deleted before
+    /// `add_sst` returns.
+    pub(crate) fn create_checkpoint_with(
+        &self,
+        fs: &Arc<dyn FileSystem>,
+        dir: &Path,
+        mut add_sst: impl FnMut(usize, &Path) -> Result<()>,
+    ) -> Result<u64> {
+        if fs.exists(dir) && !fs.list_dir(dir)?.is_empty() {
+            bail!("checkpoint directory {} is not empty", dir.display());
+        }
+        fs.create_dir_all(dir)?;
+
+        // Keep the SSTs of the snapshot until they are linked, even if they are compacted in the meantime.
+        let _file_deletion_pause = self.pause_file_deletion();
+        let (snapshot, commit_ts) = {
+            // Block commits so that all data up to `commit_ts` is in the frozen memtables.
+            let _write_lock = self.mvcc().write_lock.lock();
+            let state_lock = self.state_lock.lock();
+            if !self.state.read().memtable.is_empty() {
+                self.force_freeze_memtable(&state_lock)?;
+            }
+            (self.state.read().clone(), self.mvcc().latest_commit_ts())
+        };
+
+        let mut state = snapshot.as_ref().clone();
+        for memtable in snapshot.imm_memtables.iter().rev() {
+            if memtable.is_empty() {
+                continue;
+            }
+            let mut builder = self.new_sst_builder();
+            memtable.flush(&mut builder)?;
+            let sst_id = memtable.id();
+            let sst = builder.build_with_fs(
+                fs.as_ref(),
+                sst_id,
+                None,
+                Self::path_of_sst_static(dir, sst_id),
+            )?;
+            if self.compaction_controller.flush_to_l0() {
+                state.l0_sstables.insert(0, sst_id);
+            } else {
+                state.levels.insert(0, (sst_id, vec![sst_id]));
+            }
+            state.sstables.insert(sst_id, Arc::new(sst));
+        }
+        for sst_id in snapshot
+            .l0_sstables
+            .iter()
+            .chain(snapshot.levels.iter().flat_map(|(_, files)| files))
+        {
+            add_sst(*sst_id, &self.path_of_sst(*sst_id))?;
+        }
+
+        let manifest = Manifest::create(fs.clone(), dir, self.options.max_manifest_file_size)?;
+        let mut edit = VersionEdit::snapshot(&state);
+        // All memtables are flushed, so there is nothing to recover from WALs.
+        edit.new_memtables.clear();
+        manifest.add_record_when_init(edit)?;
+        Self::write_options(fs.as_ref(), dir, &self.options)?;
+        fs.sync_dir(dir)?;
+        Ok(commit_ts)
+    }
+}
@@ -12,49 +12,213 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod fifo;
+mod filter;
+mod hybrid;
 mod leveled;
+mod manual;
+mod merge;
+mod scheduler;
 mod simple_leveled;
 mod tiered;
 
 use std::collections::HashSet;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, UNIX_EPOCH};
 
 use anyhow::Result;
-pub use leveled::{LeveledCompactionController, LeveledCompactionOptions, LeveledCompactionTask};
+use bytes::Bytes;
+pub use fifo::{FifoCompactionController, FifoCompactionOptions, FifoCompactionTask};
+use filter::CompactionFilterRunner;
+pub use filter::{CompactionDecision, CompactionFilter, PrefixCompactionFilter};
+pub use hybrid::{HybridCompactionController, HybridCompactionOptions};
+use leveled::apply_leveled_compaction_result;
+pub use leveled::{
+    CompactionPri, LeveledCompactionController, LeveledCompactionOptions, LeveledCompactionTask,
+};
+pub(crate) use scheduler::{CompactionEvent, RunningCompactions};
 use serde::{Deserialize, Serialize};
 pub use simple_leveled::{
     SimpleLeveledCompactionController, SimpleLeveledCompactionOptions, SimpleLeveledCompactionTask,
 };
+use tiered::apply_tiered_compaction_result;
 pub use tiered::{TieredCompactionController, TieredCompactionOptions, TieredCompactionTask};
 
 use crate::iterators::StorageIterator;
 use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
-use crate::key::KeySlice;
-use crate::lsm_storage::{CompactionFilter, LsmStorageInner, LsmStorageState};
-use crate::manifest::ManifestRecord;
+use crate::key::{KeySlice, TS_RANGE_BEGIN};
+use crate::lsm_storage::{LsmStorageInner, LsmStorageOptions, LsmStorageState};
+use crate::manifest::VersionEdit;
+use crate::rate_limiter::IoPriority;
 use crate::table::{SsTable, SsTableBuilder, SsTableIterator};
+use crate::value;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum CompactionTask {
     Leveled(LeveledCompactionTask),
     Tiered(TieredCompactionTask),
     Simple(SimpleLeveledCompactionTask),
+    Fifo(FifoCompactionTask),
     ForceFullCompaction {
         l0_sstables: Vec<usize>,
         l1_sstables: Vec<usize>,
     },
 }
 
+/// Check if the SSTs of a compaction can be moved to an empty key range of the lower level as they are, i.e., they
+/// come from a sorted run, or they are L0 SSTs that do not overlap with each other. Returns the SSTs in key order.
+pub(crate) fn trivially_movable_ssts(
+    snapshot: &LsmStorageState,
+    upper_level: Option<usize>,
+    sst_ids: &[usize],
+) -> Option<Vec<usize>> {
+    if upper_level.is_some() || sst_ids.len() == 1 {
+        return Some(sst_ids.to_vec());
+    }
+    let mut ssts = Vec::with_capacity(sst_ids.len());
+    for id in sst_ids {
+        ssts.push(snapshot.sstables.get(id)?);
+    }
+    ssts.sort_by(|x, y| x.first_key().cmp(y.first_key()));
+    if ssts.windows(2).any(|x| x[0].last_key() >= x[1].first_key()) {
+        return None;
+    }
+    Some(ssts.iter().map(|x| x.sst_id()).collect())
+}
+
+/// The time since an SST was written, from the creation time in its stats in milliseconds since the UNIX epoch. SSTs
+/// written before the creation time was recorded fall back to their file modification time.
+pub(crate) fn sst_age(sst: &SsTable, now: u64) -> Duration {
+    let created_at = match sst.stats() {
+        Some(stats) if stats.created_at != 0 => stats.created_at,
+        _ => sst
+            .file
+            .modified()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_millis() as u64)
+            .unwrap_or(0),
+    };
+    Duration::from_millis(now.saturating_sub(created_at))
+}
+
+/// Why an SST is compacted even though its level does not exceed the size limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CompactionMark {
+    /// Too many of its entries are deletes.
+    Tombstones,
+    /// It was written too long ago.
+    Age,
+}
+
+/// The triggers that compact an SST even though its level or tier is within the size limits. They are used by leveled
+/// and tiered compaction.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CompactionTriggers {
+    /// Compact an SST once this percentage of its entries are deletes. `None` ignores the deletes.
+    pub tombstone_ratio_trigger_percent: Option<usize>,
+    /// Compact an SST once it was written longer than this ago. `None` ignores the age of SSTs.
+    pub periodic_compaction_age: Option<Duration>,
+}
+
+impl CompactionTriggers {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.tombstone_ratio_trigger_percent.is_some() || self.periodic_compaction_age.is_some()
+    }
+
+    /// Check if an SST should be compacted for its deletes or its age. The age comes first, as compacting an old SST
+    /// is never deferred. SSTs written without stats are never marked for their deletes.
+    pub(crate) fn mark(&self, sst: &SsTable, now: u64) -> Option<CompactionMark> {
+        if let Some(max_age) = self.periodic_compaction_age
+            && sst_age(sst, now) >= max_age
+        {
+            return Some(CompactionMark::Age);
+        }
+        if let (Some(percent), Some(stats)) = (self.tombstone_ratio_trigger_percent, sst.stats())
+            && stats.num_deletes > 0
+            && stats.tombstone_ratio() * 100.0 >= percent as f64
+        {
+            return Some(CompactionMark::Tombstones);
+        }
+        None
+    }
+}
+
+/// The statistics of the compactions since the storage engine was opened.
+#[derive(Debug, Clone, Default)]
+pub struct CompactionStats {
+    /// The number of compactions that rewrote their input SSTs.
+    pub compactions: u64,
+    /// The number of compactions that moved SSTs to the lower level without rewriting them.
+    pub trivial_moves: u64,
+    /// The bytes written by compactions.
+    pub bytes_written: u64,
+    /// The bytes of the SSTs moved by trivial moves.
+    pub bytes_moved: u64,
+    /// The number of key ranges compacted in parallel by compactions that were split.
+    pub subcompactions: u64,
+}
+
 impl CompactionTask {
+    /// If the task moves SSTs to the lower level without merging them with anything, return the SSTs in key order.
+    /// Such a task is done by changing the LSM structure without rewriting the SSTs.
+    pub fn trivial_move(&self, snapshot: &LsmStorageState) -> Option<Vec<usize>> {
+        match self {
+            CompactionTask::Leveled(task) => task.trivial_move(snapshot),
+            CompactionTask::Simple(task) => task.trivial_move(snapshot),
+            CompactionTask::Tiered(_)
+            | CompactionTask::Fifo(_)
+            | CompactionTask::ForceFullCompaction { .. } => None,
+        }
+    }
+
+    /// The SSTs read by the task.
+    pub fn input_sst_ids(&self) -> Vec<usize> {
+        match self {
+            CompactionTask::Leveled(LeveledCompactionTask {
+                upper_level_sst_ids,
+                lower_level_sst_ids,
+                ..
+            })
+            | CompactionTask::Simple(SimpleLeveledCompactionTask {
+                upper_level_sst_ids,
+                lower_level_sst_ids,
+                ..
+            }) => upper_level_sst_ids
+                .iter()
+                .chain(lower_level_sst_ids)
+                .copied()
+                .collect(),
+            CompactionTask::ForceFullCompaction {
+                l0_sstables,
+                l1_sstables,
+            } => l0_sstables.iter().chain(l1_sstables).copied().collect(),
+            CompactionTask::Tiered(TieredCompactionTask { tiers, .. }) => {
+                tiers.iter().flat_map(|(_, ssts)| ssts).copied().collect()
+            }
+            CompactionTask::Fifo(task) => task.sst_ids().to_vec(),
+        }
+    }
+
+    /// The level that the task writes to, or 0 in tiered and FIFO compaction.
+    fn output_level(&self) -> usize {
+        match self {
+            CompactionTask::ForceFullCompaction { .. } => 1,
+            CompactionTask::Leveled(task) => task.lower_level,
+            CompactionTask::Simple(task) => task.lower_level,
+            CompactionTask::Tiered(_) | CompactionTask::Fifo(_) => 0,
+        }
+    }
+
     fn compact_to_bottom_level(&self) -> bool {
         match self {
             CompactionTask::ForceFullCompaction { .. } => true,
             CompactionTask::Leveled(task) => task.is_lower_level_bottom_level,
             CompactionTask::Simple(task) => task.is_lower_level_bottom_level,
             CompactionTask::Tiered(task) => task.bottom_tier_included,
+            // The merged SSTs are the latest ones, and older SSTs might have older versions of their keys.
+            CompactionTask::Fifo(_) => false,
         }
     }
 }
@@ -63,20 +227,35 @@ pub(crate) enum CompactionController {
     Leveled(LeveledCompactionController),
     Tiered(TieredCompactionController),
     Simple(SimpleLeveledCompactionController),
+    Fifo(FifoCompactionController),
+    Hybrid(HybridCompactionController),
     NoCompaction,
 }
 
 impl CompactionController {
-    pub fn generate_compaction_task(&self, snapshot: &LsmStorageState) -> Option<CompactionTask> {
+    /// Generates a task that reads no SST in `in_progress`. `watermark` is the lowest timestamp that readers may still
+    /// read at, which tells whether compacting an SST can drop its deletes.
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+        in_progress: &HashSet<usize>,
+        watermark: u64,
+    ) -> Option<CompactionTask> {
         match self {
             CompactionController::Leveled(ctrl) => ctrl
-                .generate_compaction_task(snapshot)
+                .generate_compaction_task(snapshot, in_progress, watermark)
                 .map(CompactionTask::Leveled),
             CompactionController::Simple(ctrl) => ctrl
-                .generate_compaction_task(snapshot)
+                .generate_compaction_task(snapshot, in_progress)
                 .map(CompactionTask::Simple),
             CompactionController::Tiered(ctrl) => ctrl
-                .generate_compaction_task(snapshot)
+                .generate_compaction_task(snapshot, in_progress, watermark)
+                .map(CompactionTask::Tiered),
+            CompactionController::Fifo(ctrl) => ctrl
+                .generate_compaction_task(snapshot, in_progress)
+                .map(CompactionTask::Fifo),
+            CompactionController::Hybrid(ctrl) => ctrl
+                .generate_compaction_task(snapshot, in_progress)
                 .map(CompactionTask::Tiered),
             CompactionController::NoCompaction => unreachable!(),
         }
@@ -96,9 +275,21 @@ impl CompactionController {
             (CompactionController::Simple(ctrl), CompactionTask::Simple(task)) => {
                 ctrl.apply_compaction_result(snapshot, task, output)
             }
+            // `compact_range` compacts part of a level with leveled compaction tasks.
+            (
+                CompactionController::Simple(_) | CompactionController::NoCompaction,
+                CompactionTask::Leveled(task),
+            ) => apply_leveled_compaction_result(snapshot, task, output, in_recovery),
             (CompactionController::Tiered(ctrl), CompactionTask::Tiered(task)) => {
                 ctrl.apply_compaction_result(snapshot, task, output)
             }
+            // Hybrid compaction merges its runs with tiered compaction tasks.
+            (CompactionController::Hybrid(_), CompactionTask::Tiered(task)) => {
+                apply_tiered_compaction_result(snapshot, task, output)
+            }
+            (CompactionController::Fifo(ctrl), CompactionTask::Fifo(task)) => {
+                ctrl.apply_compaction_result(snapshot, task, output)
+            }
             _ => unreachable!(),
         }
     }
@@ -108,12 +299,12 @@ impl CompactionController {
     pub fn flush_to_l0(&self) -> bool {
         matches!(
             self,
-            Self::Leveled(_) | Self::Simple(_) | Self::NoCompaction
+            Self::Leveled(_) | Self::Simple(_) | Self::Fifo(_) | Self::NoCompaction
         )
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CompactionOptions {
     /// Leveled compaction with partial compaction + dynamic level support (= RocksDB's Leveled
     /// Compaction)
@@ -122,25 +313,88 @@ pub enum CompactionOptions {
     Tiered(TieredCompactionOptions),
     /// Simple leveled compaction
     Simple(SimpleLeveledCompactionOptions),
+    /// FIFO compaction, which deletes the oldest SSTs (= RocksDB's FIFO compaction)
+    Fifo(FifoCompactionOptions),
+    /// Hybrid compaction with tiered upper levels and a single sorted run in the last level (= lazy leveling)
+    Hybrid(HybridCompactionOptions),
     /// In no compaction mode (week 1), always flush to L0
     NoCompaction,
 }
 
+/// Tracks how much of the grandparent level, i.e., the level below the output level, the compaction output SST being
+/// built overlaps, and decides where to cut the output SSTs so that each of them overlaps a bounded part of that level.
+struct GrandparentOverlap {
+    /// The SSTs of the grandparent level sorted by key, or empty if the limit is disabled.
+    ssts: Vec<Arc<SsTable>>,
+    max_overlap_bytes: u64,
+    target_sst_size: usize,
+    /// The first grandparent SST that does not end before the last key added to the output.
+    index: usize,
+    /// The bytes of the grandparent SSTs that the output SST being built overlaps.
+    overlapped_bytes: u64,
+}
+
+impl GrandparentOverlap {
+    /// Move past the grandparent SSTs that end before `key`, and tell whether the output SST should be cut before
+    /// `key`. It is cut when its overlap passes the limit, or when it is at least half full and `key` starts after a
+    /// grandparent SST, which aligns the cut with an SST boundary of the grandparent level.
+    fn should_cut_before(&mut self, key: &[u8], builder: &SsTableBuilder) -> bool {
+        let mut crossed_boundary = false;
+        while self.index < self.ssts.len() && key > self.ssts[self.index].last_key().key_ref() {
+            // The grandparent SSTs before the first key of the output do not overlap it.
+            if !builder.is_empty() {
+                self.overlapped_bytes += self.ssts[self.index].table_size();
+                crossed_boundary = true;
+            }
+            self.index += 1;
+        }
+        if builder.is_empty() {
+            return false;
+        }
+        self.overlapped_bytes > self.max_overlap_bytes
+            || (crossed_boundary && builder.estimated_size() >= self.target_sst_size / 2)
+    }
+
+    fn start_new_output(&mut self) {
+        self.overlapped_bytes = 0;
+    }
+}
+
 impl LsmStorageInner {
     fn compact_generate_sst_from_iter(
         &self,
         mut iter: impl for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>,
-        compact_to_bottom_level: bool,
+        task: &CompactionTask,
+        upper_bound: Option<&[u8]>,
+        mut grandparents: GrandparentOverlap,
     ) -> Result<Vec<Arc<SsTable>>> {
+        let compact_to_bottom_level = task.compact_to_bottom_level();
         let mut builder = None;
         let mut new_sst = Vec::new();
         let watermark = self.mvcc().watermark();
+        let now = self.options.clock.now_millis();
         let mut last_key = Vec::<u8>::new();
         let mut first_key_below_watermark = false;
-        let compaction_filters = self.compaction_filters.lock().clone();
-        'outer: while iter.is_valid() {
+        let mut compaction_filters = CompactionFilterRunner::new(
+            self.compaction_filters.lock().clone(),
+            task.output_level(),
+        );
+        // The bytes read from the input that are not yet charged to the rate limiter.
+        let mut read_bytes = 0;
+        while iter.is_valid() {
+            if let Some(upper) = upper_bound
+                && iter.key().key_ref() >= upper
+            {
+                break;
+            }
             if builder.is_none() {
-                builder = Some(SsTableBuilder::new(self.options.block_size));
+                builder = Some(self.new_sst_builder());
+            }
+
+            read_bytes += iter.key().raw_len() + iter.value().len();
+            if read_bytes >= self.options.block_size {
+                self.rate_limiter.request(read_bytes, IoPriority::Low);
+                read_bytes = 0;
             }
 
             let same_as_last_key = iter.key().key_ref() == last_key;
@@ -160,6 +414,7 @@ impl LsmStorageInner {
                 continue;
             }
 
+            let mut decision = CompactionDecision::Keep;
             if iter.key().ts() <= watermark {
                 if !first_key_below_watermark {
                     iter.next()?;
@@ -168,36 +423,79 @@ impl LsmStorageInner {
 
                 first_key_below_watermark = false;
 
-                if !compaction_filters.is_empty() {
-                    for filter in &compaction_filters {
-                        match filter {
-                            CompactionFilter::Prefix(x) => {
-                                if iter.key().key_ref().starts_with(x) {
-                                    iter.next()?;
-                                    continue 'outer;
-                                }
-                            }
+                if value::is_merge_operand(iter.value()) {
+                    let key = iter.key().key_ref().to_vec();
+                    let mut versions = Vec::new();
+                    loop {
+                        versions.push((iter.key().ts(), Bytes::copy_from_slice(iter.value())));
+                        iter.next()?;
+                        if !value::is_merge_operand(&versions.last().unwrap().1)
+                            || !iter.is_valid()
+                            || iter.key().key_ref() != key
+                        {
+                            break;
                         }
+                        read_bytes += iter.key().raw_len() + iter.value().len();
+                    }
+                    let mut same_as_last_key = same_as_last_key;
+                    for (ts, value) in merge::merge_operands(
+                        self.options.merge_operator.as_deref(),
+                        &key,
+                        versions,
+                        compact_to_bottom_level,
+                        now,
+                    ) {
+                        self.add_to_compaction_output(
+                            &mut builder,
+                            &mut new_sst,
+                            &mut grandparents,
+                            KeySlice::from_slice(&key, ts),
+                            &value,
+                            same_as_last_key,
+                        )?;
+                        same_as_last_key = true;
                     }
+                    // The older versions of the key are skipped as they are below the watermark.
+                    last_key = key;
+                    continue;
                 }
-            }
 
-            let builder_inner = builder.as_mut().unwrap();
-
-            if builder_inner.estimated_size() >= self.options.target_sst_size && !same_as_last_key {
-                let sst_id = self.next_sst_id();
-                let old_builder = builder.take().unwrap();
-                let sst = Arc::new(old_builder.build(
-                    sst_id,
-                    Some(self.block_cache.clone()),
-                    self.path_of_sst(sst_id),
-                )?);
-                new_sst.push(sst);
-                builder = Some(SsTableBuilder::new(self.options.block_size));
+                if value::is_expired(iter.value(), now) {
+                    // Every read from now on treats an expired version as a delete.
+                    decision = CompactionDecision::Remove;
+                } else if !compaction_filters.is_empty() && !iter.value().is_empty() {
+                    decision = compaction_filters.filter(
+                        iter.key().key_ref(),
+                        iter.key().ts(),
+                        value::decode(iter.value()),
+                    );
+                }
             }
+            let value = match decision {
+                CompactionDecision::Keep => None,
+                CompactionDecision::ChangeValue(value) => {
+                    Some(value::encode_like(iter.value(), &value))
+                }
+                CompactionDecision::Remove | CompactionDecision::RemoveAndSkipUntil(_) => {
+                    if compact_to_bottom_level {
+                        last_key.clear();
+                        last_key.extend(iter.key().key_ref());
+                        iter.next()?;
+                        continue;
+                    }
+                    // Turn it into a delete, which hides the older versions in the lower levels.
+                    Some(Bytes::new())
+                }
+            };
 
-            let builder_inner = builder.as_mut().unwrap();
-            builder_inner.add(iter.key(), iter.value());
+            self.add_to_compaction_output(
+                &mut builder,
+                &mut new_sst,
+                &mut grandparents,
+                iter.key(),
+                value.as_deref().unwrap_or(iter.value()),
+                same_as_last_key,
+            )?;
 
             if !same_as_last_key {
                 last_key.clear();
@@ -206,43 +504,217 @@ impl LsmStorageInner {
 
             iter.next()?;
         }
-        if let Some(builder) = builder {
+        // All remaining keys might have been dropped, e.g., tombstones in the bottom level.
+        if let Some(builder) = builder
+            && !builder.is_empty()
+        {
             let sst_id = self.next_sst_id(); // lock dropped here
-            let sst = Arc::new(builder.build(
+            let sst = Arc::new(builder.build_with_fs(
+                self.fs(),
                 sst_id,
                 Some(self.block_cache.clone()),
                 self.path_of_sst(sst_id),
             )?);
+            self.rate_limiter
+                .request(sst.table_size() as usize, IoPriority::Low);
             new_sst.push(sst);
         }
         Ok(new_sst)
     }
 
+    /// Add a version to the SST being built, and start a new SST first if it is full or overlaps too much of the
+    /// grandparent level. The versions of a key are never split across SSTs.
+    fn add_to_compaction_output(
+        &self,
+        builder: &mut Option<SsTableBuilder>,
+        new_sst: &mut Vec<Arc<SsTable>>,
+        grandparents: &mut GrandparentOverlap,
+        key: KeySlice,
+        value: &[u8],
+        same_as_last_key: bool,
+    ) -> Result<()> {
+        let builder_inner = builder.as_mut().unwrap();
+
+        if !same_as_last_key
+            && (grandparents.should_cut_before(key.key_ref(), builder_inner)
+                || builder_inner.estimated_size() >= self.options.target_sst_size)
+        {
+            grandparents.start_new_output();
+            let sst_id = self.next_sst_id();
+            let old_builder = builder.take().unwrap();
+            let sst = Arc::new(old_builder.build_with_fs(
+                self.fs(),
+                sst_id,
+                Some(self.block_cache.clone()),
+                self.path_of_sst(sst_id),
+            )?);
+            self.rate_limiter
+                .request(sst.table_size() as usize, IoPriority::Low);
+            new_sst.push(sst);
+            *builder = Some(self.new_sst_builder());
+        }
+
+        builder.as_mut().unwrap().add(key, value);
+        Ok(())
+    }
+
     fn compact(&self, task: &CompactionTask) -> Result<Vec<Arc<SsTable>>> {
+        if let CompactionTask::Fifo(FifoCompactionTask::Delete { .. }) = task {
+            return Ok(Vec::new());
+        }
         let snapshot = {
             let state = self.state.read();
             state.clone()
         };
+        let boundaries = self.subcompaction_boundaries(&snapshot, task);
+        if boundaries.is_empty() {
+            return self.compact_subrange(&snapshot, task, None, None);
+        }
+        println!(
+            "split compaction into {} subcompactions",
+            boundaries.len() + 1
+        );
+        self.compaction_stats.lock().subcompactions += boundaries.len() as u64 + 1;
+        // Each subcompaction covers the keys from its lower boundary (inclusive) to its upper boundary (exclusive).
+        let lower_bounds = std::iter::once(None).chain(boundaries.iter().map(|x| Some(x.as_ref())));
+        let upper_bounds = boundaries
+            .iter()
+            .map(|x| Some(x.as_ref()))
+            .chain(std::iter::once(None));
+        std::thread::scope(|scope| {
+            let handles = lower_bounds
+                .zip(upper_bounds)
+                .map(|(lower, upper)| {
+                    let snapshot = &snapshot;
+                    scope.spawn(move || self.compact_subrange(snapshot, task, lower, upper))
+                })
+                .collect::<Vec<_>>();
+            let mut output = Vec::new();
+            for handle in handles {
+                let ssts = handle
+                    .join()
+                    .map_err(|e| anyhow::anyhow!("subcompaction panicked: {:?}", e))??;
+                output.extend(ssts);
+            }
+            Ok(output)
+        })
+    }
+
+    /// Choose the user keys that split a compaction into subcompactions of disjoint key ranges. The boundaries are
+    /// taken from the first keys of the input SSTs, and each subcompaction gets about one output SST worth of input
+    /// or more.
+    fn subcompaction_boundaries(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &CompactionTask,
+    ) -> Vec<Bytes> {
+        let input_ssts = task.input_sst_ids();
+        let input_size = input_ssts
+            .iter()
+            .map(|id| snapshot.sstables[id].table_size() as usize)
+            .sum::<usize>();
+        let mut first_keys = input_ssts
+            .iter()
+            .map(|id| Bytes::copy_from_slice(snapshot.sstables[id].first_key().key_ref()))
+            .collect::<Vec<_>>();
+        first_keys.sort();
+        first_keys.dedup();
+        // The smallest key does not split anything.
+        if !first_keys.is_empty() {
+            first_keys.remove(0);
+        }
+        let num_subcompactions = self
+            .options
+            .max_subcompactions
+            .min(input_size / self.options.target_sst_size.max(1))
+            .min(first_keys.len() + 1);
+        if num_subcompactions <= 1 {
+            return Vec::new();
+        }
+        let mut boundaries = (1..num_subcompactions)
+            .map(|i| {
+                first_keys[(i * first_keys.len() / num_subcompactions).min(first_keys.len() - 1)]
+                    .clone()
+            })
+            .collect::<Vec<_>>();
+        boundaries.dedup();
+        boundaries
+    }
+
+    /// The SSTs of the level below the output level of the task, which the next compaction of an output SST will
+    /// merge with. Tiered and FIFO compactions have no such level.
+    fn grandparent_overlap(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &CompactionTask,
+    ) -> GrandparentOverlap {
+        let output_level = match task {
+            CompactionTask::ForceFullCompaction { .. } => Some(1),
+            CompactionTask::Leveled(task) => Some(task.lower_level),
+            CompactionTask::Simple(task) => Some(task.lower_level),
+            CompactionTask::Tiered(_) | CompactionTask::Fifo(_) => None,
+        };
+        let ssts = match output_level {
+            // `levels[output_level]` is the level below the output level, as the levels start from L1.
+            Some(output_level) if self.options.max_grandparent_overlap_bytes > 0 => snapshot
+                .levels
+                .get(output_level)
+                .map(|(_, ids)| ids.iter().map(|id| snapshot.sstables[id].clone()).collect())
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        };
+        GrandparentOverlap {
+            ssts,
+            max_overlap_bytes: self.options.max_grandparent_overlap_bytes as u64,
+            target_sst_size: self.options.target_sst_size,
+            index: 0,
+            overlapped_bytes: 0,
+        }
+    }
+
+    /// Compact the keys of the task in `[lower, upper)`, where `None` is unbounded.
+    fn compact_subrange(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &CompactionTask,
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+    ) -> Result<Vec<Arc<SsTable>>> {
+        let seek_table = |id: &usize| -> Result<Box<SsTableIterator>> {
+            let table = snapshot.sstables.get(id).unwrap().clone();
+            Ok(Box::new(match lower {
+                Some(lower) => SsTableIterator::create_and_seek_to_key(
+                    table,
+                    KeySlice::from_slice(lower, TS_RANGE_BEGIN),
+                )?,
+                None => SsTableIterator::create_and_seek_to_first(table)?,
+            }))
+        };
+        let seek_concat = |ids: &[usize]| -> Result<SstConcatIterator> {
+            let tables = ids
+                .iter()
+                .map(|id| snapshot.sstables.get(id).unwrap().clone())
+                .collect();
+            match lower {
+                Some(lower) => SstConcatIterator::create_and_seek_to_key(
+                    tables,
+                    KeySlice::from_slice(lower, TS_RANGE_BEGIN),
+                ),
+                None => SstConcatIterator::create_and_seek_to_first(tables),
+            }
+        };
+        let grandparents = self.grandparent_overlap(snapshot, task);
         match task {
             CompactionTask::ForceFullCompaction {
                 l0_sstables,
                 l1_sstables,
             } => {
-                let mut l0_iters = Vec::with_capacity(l0_sstables.len());
-                for id in l0_sstables.iter() {
-                    l0_iters.push(Box::new(SsTableIterator::create_and_seek_to_first(
-                        snapshot.sstables.get(id).unwrap().clone(),
-                    )?));
-                }
-                let mut l1_iters = Vec::with_capacity(l1_sstables.len());
-                for id in l1_sstables.iter() {
-                    l1_iters.push(snapshot.sstables.get(id).unwrap().clone());
-                }
+                let l0_iters = l0_sstables.iter().map(seek_table).collect::<Result<_>>()?;
                 let iter = TwoMergeIterator::create(
                     MergeIterator::create(l0_iters),
-                    SstConcatIterator::create_and_seek_to_first(l1_iters)?,
+                    seek_concat(l1_sstables)?,
                 )?;
-                self.compact_generate_sst_from_iter(iter, task.compact_to_bottom_level())
+                self.compact_generate_sst_from_iter(iter, task, upper, grandparents)
             }
             CompactionTask::Simple(SimpleLeveledCompactionTask {
                 upper_level,
@@ -259,52 +731,53 @@ impl LsmStorageInner {
                 ..
             }) => match upper_level {
                 Some(_) => {
-                    let mut upper_ssts = Vec::with_capacity(upper_level_sst_ids.len());
-                    for id in upper_level_sst_ids.iter() {
-                        upper_ssts.push(snapshot.sstables.get(id).unwrap().clone());
-                    }
-                    let upper_iter = SstConcatIterator::create_and_seek_to_first(upper_ssts)?;
-                    let mut lower_ssts = Vec::with_capacity(lower_level_sst_ids.len());
-                    for id in lower_level_sst_ids.iter() {
-                        lower_ssts.push(snapshot.sstables.get(id).unwrap().clone());
-                    }
-                    let lower_iter = SstConcatIterator::create_and_seek_to_first(lower_ssts)?;
+                    let upper_iter = seek_concat(upper_level_sst_ids)?;
+                    let lower_iter = seek_concat(lower_level_sst_ids)?;
                     self.compact_generate_sst_from_iter(
                         TwoMergeIterator::create(upper_iter, lower_iter)?,
-                        task.compact_to_bottom_level(),
+                        task,
+                        upper,
+                        grandparents,
                     )
                 }
                 None => {
-                    let mut upper_iters = Vec::with_capacity(upper_level_sst_ids.len());
-                    for id in upper_level_sst_ids.iter() {
-                        upper_iters.push(Box::new(SsTableIterator::create_and_seek_to_first(
-                            snapshot.sstables.get(id).unwrap().clone(),
-                        )?));
-                    }
+                    let upper_iters = upper_level_sst_ids
+                        .iter()
+                        .map(seek_table)
+                        .collect::<Result<_>>()?;
                     let upper_iter = MergeIterator::create(upper_iters);
-                    let mut lower_ssts = Vec::with_capacity(lower_level_sst_ids.len());
-                    for id in lower_level_sst_ids.iter() {
-                        lower_ssts.push(snapshot.sstables.get(id).unwrap().clone());
-                    }
-                    let lower_iter = SstConcatIterator::create_and_seek_to_first(lower_ssts)?;
+                    let lower_iter = seek_concat(lower_level_sst_ids)?;
                     self.compact_generate_sst_from_iter(
                         TwoMergeIterator::create(upper_iter, lower_iter)?,
-                        task.compact_to_bottom_level(),
+                        task,
+                        upper,
+                        grandparents,
                     )
                 }
             },
             CompactionTask::Tiered(TieredCompactionTask { tiers, .. }) => {
                 let mut iters = Vec::with_capacity(tiers.len());
                 for (_, tier_sst_ids) in tiers {
-                    let mut ssts = Vec::with_capacity(tier_sst_ids.len());
-                    for id in tier_sst_ids.iter() {
-                        ssts.push(snapshot.sstables.get(id).unwrap().clone());
-                    }
-                    iters.push(Box::new(SstConcatIterator::create_and_seek_to_first(ssts)?));
+                    iters.push(Box::new(seek_concat(tier_sst_ids)?));
                 }
                 self.compact_generate_sst_from_iter(
                     MergeIterator::create(iters),
-                    task.compact_to_bottom_level(),
+                    task,
+                    upper,
+                    grandparents,
+                )
+            }
+            CompactionTask::Fifo(fifo_task) => {
+                let iters = fifo_task
+                    .sst_ids()
+                    .iter()
+                    .map(seek_table)
+                    .collect::<Result<_>>()?;
+                self.compact_generate_sst_from_iter(
+                    MergeIterator::create(iters),
+                    task,
+                    upper,
+                    grandparents,
                 )
             }
         }
@@ -314,6 +787,7 @@ impl LsmStorageInner {
         let CompactionOptions::NoCompaction = self.options.compaction_options else {
             panic!("full compaction can only be called with compaction is not enabled")
         };
+        self.check_background_error()?;
 
         let snapshot = {
             let state = self.state.read();
@@ -329,12 +803,14 @@ impl LsmStorageInner {
 
         println!("force full compaction: {:?}", compaction_task);
 
-        let sstables = self.compact(&compaction_task)?;
+        let _pending_outputs = self.register_pending_outputs();
+        let sstables = self.latch_background_error(self.compact(&compaction_task))?;
         let mut ids = Vec::with_capacity(sstables.len());
 
         {
             let state_lock = self.state_lock.lock();
-            let mut state = self.state.read().as_ref().clone();
+            let old_state = self.state.read().clone();
+            let mut state = old_state.as_ref().clone();
             for sst in l0_sstables.iter().chain(l1_sstables.iter()) {
                 let result = state.sstables.remove(sst);
                 assert!(result.is_some());
@@ -354,15 +830,11 @@ impl LsmStorageInner {
                 .copied()
                 .collect::<Vec<_>>();
             assert!(l0_sstables_map.is_empty());
-            *self.state.write() = Arc::new(state);
-            self.sync_dir()?;
-            self.manifest.as_ref().unwrap().add_record(
-                &state_lock,
-                ManifestRecord::Compaction(compaction_task, ids.clone()),
-            )?;
+            let edit = VersionEdit::diff(&old_state, &state);
+            self.install_state(&state_lock, state, edit)?;
         }
         for sst in l0_sstables.iter().chain(l1_sstables.iter()) {
-            std::fs::remove_file(self.path_of_sst(*sst))?;
+            self.remove_obsolete_file(self.path_of_sst(*sst))?;
         }
 
         println!("force full compaction done, new SSTs: {:?}", ids);
@@ -370,33 +842,133 @@ impl LsmStorageInner {
         Ok(())
     }
 
-    fn trigger_compaction(&self) -> Result<()> {
+    /// Flush all memtables and compact all SSTs into a single sorted run at the bottom of the layout used by the
+    /// compaction strategy in `options`.
+    pub(crate) fn migrate_compaction_strategy(&self, options: &LsmStorageOptions) -> Result<()> {
+        self.check_background_error()?;
+        {
+            let state_lock = self.state_lock.lock();
+            if !self.state.read().memtable.is_empty() {
+                self.force_freeze_memtable(&state_lock)?;
+            }
+        }
+        while !self.state.read().imm_memtables.is_empty() {
+            self.force_flush_next_imm_memtable()?;
+        }
+
+        let snapshot = self.state.read().clone();
+        let sst_ids = snapshot
+            .l0_sstables
+            .iter()
+            .chain(snapshot.levels.iter().flat_map(|(_, files)| files))
+            .copied()
+            .collect::<Vec<_>>();
+        let _pending_outputs = self.register_pending_outputs();
+        let sstables = if sst_ids.is_empty() {
+            Vec::new()
+        } else {
+            // Every level is a sorted run, so merging all SSTs from latest to earliest yields the same result as
+            // reading the LSM tree.
+            self.latch_background_error(self.compact(&CompactionTask::ForceFullCompaction {
+                l0_sstables: sst_ids.clone(),
+                l1_sstables: Vec::new(),
+            }))?
+        };
+        let output = sstables.iter().map(|x| x.sst_id()).collect::<Vec<_>>();
+
+        {
+            let state_lock = self.state_lock.lock();
+            let mut state = self.state.read().as_ref().clone();
+            for sst in &sst_ids {
+                let result = state.sstables.remove(sst);
+                assert!(result.is_some());
+            }
+            for new_sst in sstables {
+                let result = state.sstables.insert(new_sst.sst_id(), new_sst);
+                assert!(result.is_none());
+            }
+            state.l0_sstables.clear();
+            state.levels = LsmStorageState::create(options).levels;
+            if !output.is_empty() {
+                match &options.compaction_options {
+                    CompactionOptions::Tiered(_) | CompactionOptions::Hybrid(_) => {
+                        state.levels.push((output[0], output.clone()))
+                    }
+                    CompactionOptions::Fifo(_) => state.l0_sstables.clone_from(&output),
+                    _ => state.levels.last_mut().unwrap().1.clone_from(&output),
+                }
+            }
+            self.latch_background_error(self.sync_dir())?;
+            self.latch_background_error(
+                self.manifest()
+                    .roll_over(&state_lock, VersionEdit::snapshot(&state)),
+            )?;
+            *self.state.write() = Arc::new(state);
+        }
+        for sst in &sst_ids {
+            self.remove_obsolete_file(self.path_of_sst(*sst))?;
+        }
+        self.sync_dir()?;
+
+        println!("compaction strategy migrated, new SSTs: {:?}", output);
+
+        Ok(())
+    }
+
+    /// Run a compaction task if there is one that does not conflict with the running compactions. Tests use it to
+    /// compact without waiting for the scheduler.
+    #[cfg(test)]
+    pub(crate) fn trigger_compaction(&self) -> Result<()> {
+        let Some(job) = self.pick_compaction_job() else {
+            return Ok(());
+        };
+        self.run_compaction_job(job)
+    }
+
+    fn run_compaction(&self, task: &CompactionTask, allow_trivial_move: bool) -> Result<()> {
+        self.check_background_error()?;
         let snapshot = {
             let state = self.state.read();
             state.clone()
         };
-        let task = self
-            .compaction_controller
-            .generate_compaction_task(&snapshot);
-        let Some(task) = task else {
-            return Ok(());
-        };
         self.dump_structure();
         println!("running compaction task: {:?}", task);
-        let sstables = self.compact(&task)?;
-        let output = sstables.iter().map(|x| x.sst_id()).collect::<Vec<_>>();
+        let _pending_outputs = self.register_pending_outputs();
+        let trivial_move = task.trivial_move(&snapshot).filter(|_| allow_trivial_move);
+        let (sstables, output) = if let Some(moved_ssts) = trivial_move.clone() {
+            println!("trivial move: {:?}", moved_ssts);
+            (Vec::new(), moved_ssts)
+        } else {
+            let sstables = self.latch_background_error(self.compact(task))?;
+            let output = sstables.iter().map(|x| x.sst_id()).collect::<Vec<_>>();
+            (sstables, output)
+        };
+        {
+            let mut stats = self.compaction_stats.lock();
+            if trivial_move.is_some() {
+                stats.trivial_moves += 1;
+                stats.bytes_moved += output
+                    .iter()
+                    .map(|id| snapshot.sstables[id].table_size())
+                    .sum::<u64>();
+            } else {
+                stats.compactions += 1;
+                stats.bytes_written += sstables.iter().map(|x| x.table_size()).sum::<u64>();
+            }
+        }
         let ssts_to_remove = {
             let state_lock = self.state_lock.lock();
-            let mut snapshot = self.state.read().as_ref().clone();
-            let mut new_sst_ids = Vec::new();
+            let old_state = self.state.read().clone();
+            let mut snapshot = old_state.as_ref().clone();
             for file_to_add in sstables {
-                new_sst_ids.push(file_to_add.sst_id());
                 let result = snapshot.sstables.insert(file_to_add.sst_id(), file_to_add);
                 assert!(result.is_none());
             }
-            let (mut snapshot, files_to_remove) = self
+            let (mut snapshot, mut files_to_remove) = self
                 .compaction_controller
-                .apply_compaction_result(&snapshot, &task, &output, false);
+                .apply_compaction_result(&snapshot, task, &output, false);
+            // SSTs that are moved are both removed from the upper level and added to the lower level.
+            files_to_remove.retain(|x| !output.contains(x));
 
             let mut ssts_to_remove = Vec::with_capacity(files_to_remove.len());
             for file_to_remove in &files_to_remove {
@@ -404,12 +976,8 @@ impl LsmStorageInner {
                 assert!(result.is_some(), "cannot remove {}.sst", file_to_remove);
                 ssts_to_remove.push(result.unwrap());
             }
-            let mut state = self.state.write();
-            *state = Arc::new(snapshot);
-            drop(state);
-            self.sync_dir()?;
-            self.manifest()
-                .add_record(&state_lock, ManifestRecord::Compaction(task, new_sst_ids))?;
+            let edit = VersionEdit::diff(&old_state, &snapshot);
+            self.install_state(&state_lock, snapshot, edit)?;
             ssts_to_remove
         };
         println!(
@@ -419,38 +987,13 @@ impl LsmStorageInner {
             output
         );
         for sst in ssts_to_remove {
-            std::fs::remove_file(self.path_of_sst(sst.sst_id()))?;
+            self.remove_obsolete_file(self.path_of_sst(sst.sst_id()))?;
         }
         self.sync_dir()?;
 
         Ok(())
     }
 
-    pub(crate) fn spawn_compaction_thread(
-        self: &Arc<Self>,
-        rx: crossbeam_channel::Receiver<()>,
-    ) -> Result<Option<std::thread::JoinHandle<()>>> {
-        if let CompactionOptions::Leveled(_)
-        | CompactionOptions::Simple(_)
-        | CompactionOptions::Tiered(_) = self.options.compaction_options
-        {
-            let this = self.clone();
-            let handle = std::thread::spawn(move || {
-                let ticker = crossbeam_channel::tick(Duration::from_millis(50));
-                loop {
-                    crossbeam_channel::select! {
-                        recv(ticker) -> _ => if let Err(e) = this.trigger_compaction() {
-                            eprintln!("compaction failed: {}", e);
-                        },
-                        recv(rx) -> _ => return
-                    }
-                }
-            });
-            return Ok(Some(handle));
-        }
-        Ok(None)
-    }
-
     fn trigger_flush(&self) -> Result<()> {
         let res = {
             let state = self.state.read();
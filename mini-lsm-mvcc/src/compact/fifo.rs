@@ -0,0 +1,147 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::sst_age;
+use crate::clock::Clock;
+use crate::lsm_storage::LsmStorageState;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FifoCompactionOptions {
+    /// Delete the oldest SSTs once the total size of the SSTs exceeds this many bytes.
+    pub max_table_files_size: u64,
+    /// Delete the SSTs written longer than this ago. `None` keeps SSTs regardless of their age.
+    pub ttl: Option<Duration>,
+    /// Merge the latest L0 SSTs once there are more than this many SSTs. `None` never merges SSTs. The merged SSTs
+    /// count their age from the merge, so the latest ones are merged as they are small and young.
+    pub max_l0_files: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FifoCompactionTask {
+    /// Delete the oldest SSTs without reading them.
+    Delete { sst_ids: Vec<usize> },
+    /// Merge SSTs, from latest to earliest, into new SSTs that take their place in L0. No SST between them overlaps
+    /// with them.
+    Merge { sst_ids: Vec<usize> },
+}
+
+impl FifoCompactionTask {
+    pub fn sst_ids(&self) -> &[usize] {
+        match self {
+            FifoCompactionTask::Delete { sst_ids } | FifoCompactionTask::Merge { sst_ids } => {
+                sst_ids
+            }
+        }
+    }
+}
+
+/// Keeps all SSTs in L0 and drops the oldest ones, for data that is only written once and read for a limited time,
+/// such as logs and metrics.
+pub struct FifoCompactionController {
+    options: FifoCompactionOptions,
+    clock: Arc<dyn Clock>,
+}
+
+impl FifoCompactionController {
+    pub fn new(options: FifoCompactionOptions, clock: Arc<dyn Clock>) -> Self {
+        Self { options, clock }
+    }
+
+    /// Generates a task that deletes the oldest SSTs that exceed the size limit or the TTL, or else merges the latest
+    /// SSTs if there are too many. SSTs in `in_progress` are never picked, and the oldest SSTs are only deleted up to
+    /// the first one being compacted.
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+        in_progress: &HashSet<usize>,
+    ) -> Option<FifoCompactionTask> {
+        assert!(
+            snapshot.levels.is_empty(),
+            "should not add levels in FIFO compaction"
+        );
+        let now = self.clock.now_millis();
+        let mut total_size = snapshot
+            .l0_sstables
+            .iter()
+            .map(|id| snapshot.sstables[id].table_size())
+            .sum::<u64>();
+        let mut sst_ids = Vec::new();
+        for id in snapshot.l0_sstables.iter().rev() {
+            if in_progress.contains(id) {
+                break;
+            }
+            let sst = &snapshot.sstables[id];
+            let age = sst_age(sst, now);
+            let expired = self.options.ttl.is_some_and(|ttl| age >= ttl);
+            if total_size <= self.options.max_table_files_size && !expired {
+                break;
+            }
+            println!(
+                "compaction triggered to delete SST {} with {} bytes in total and age {:?}",
+                id, total_size, age
+            );
+            total_size -= sst.table_size();
+            sst_ids.push(*id);
+        }
+        if !sst_ids.is_empty() {
+            sst_ids.reverse();
+            return Some(FifoCompactionTask::Delete { sst_ids });
+        }
+
+        let max_l0_files = self.options.max_l0_files?;
+        if snapshot.l0_sstables.len() <= max_l0_files {
+            return None;
+        }
+        let num_ssts = (snapshot.l0_sstables.len() - max_l0_files + 1).max(2);
+        let sst_ids = snapshot.l0_sstables[..num_ssts].to_vec();
+        if sst_ids.iter().any(|id| in_progress.contains(id)) {
+            return None;
+        }
+        println!(
+            "compaction triggered to merge {} SSTs because L0 has {} SSTs > {}",
+            num_ssts,
+            snapshot.l0_sstables.len(),
+            max_l0_files
+        );
+        Some(FifoCompactionTask::Merge { sst_ids })
+    }
+
+    /// Remove the SSTs of the task from L0, and put the output where the latest one was.
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &FifoCompactionTask,
+        output: &[usize],
+    ) -> (LsmStorageState, Vec<usize>) {
+        let mut snapshot = snapshot.clone();
+        let mut ssts_to_remove = task.sst_ids().iter().copied().collect::<HashSet<_>>();
+        let output_position = snapshot
+            .l0_sstables
+            .iter()
+            .take_while(|x| !ssts_to_remove.contains(x))
+            .count();
+        snapshot.l0_sstables.retain(|x| !ssts_to_remove.remove(x));
+        assert!(ssts_to_remove.is_empty(), "sst mismatched");
+        snapshot
+            .l0_sstables
+            .splice(output_position..output_position, output.iter().copied());
+        (snapshot, task.sst_ids().to_vec())
+    }
+}
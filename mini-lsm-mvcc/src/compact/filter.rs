@@ -0,0 +1,110 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+/// What a compaction filter does with a version of a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompactionDecision {
+    /// Keep the version as it is.
+    Keep,
+    /// Remove the version, together with the older versions of the key.
+    Remove,
+    /// Replace the value of the version.
+    ChangeValue(Bytes),
+    /// Remove the version, and the versions below the watermark of all keys before the given key (exclusive), without
+    /// calling the filters on them.
+    ///
+    /// It saves the filter calls, but not the I/O: the compaction still reads every key in the range, as their versions
+    /// above the watermark are kept, and the removed ones are turned into deletes outside the bottom level.
+    RemoveAndSkipUntil(Bytes),
+}
+
+/// Decides what compactions do with the data of the application, e.g., to drop expired keys or rewrite old value
+/// formats.
+///
+/// A filter is only called on the latest version of a key below the MVCC watermark, which no reader can tell apart from
+/// the older versions, and never on deletes. A removed version is dropped in the bottom level, and turned into a delete
+/// in the other levels, so that the older versions of the key in lower levels do not show up again.
+pub trait CompactionFilter: Send + Sync {
+    /// Decide what to do with a version of `key` at `ts`. `level` is the level that the compaction writes to, or 0 in
+    /// tiered compaction.
+    fn filter(&self, key: &[u8], ts: u64, value: &[u8], level: usize) -> CompactionDecision;
+}
+
+/// Removes all keys with a prefix.
+#[derive(Debug, Clone)]
+pub struct PrefixCompactionFilter(pub Bytes);
+
+impl CompactionFilter for PrefixCompactionFilter {
+    fn filter(&self, key: &[u8], _ts: u64, _value: &[u8], _level: usize) -> CompactionDecision {
+        if key.starts_with(&self.0) {
+            CompactionDecision::Remove
+        } else {
+            CompactionDecision::Keep
+        }
+    }
+}
+
+/// Runs the compaction filters on the versions of a compaction in key order.
+pub(crate) struct CompactionFilterRunner {
+    filters: Vec<Arc<dyn CompactionFilter>>,
+    level: usize,
+    skip_until: Option<Bytes>,
+}
+
+impl CompactionFilterRunner {
+    pub(crate) fn new(filters: Vec<Arc<dyn CompactionFilter>>, level: usize) -> Self {
+        Self {
+            filters,
+            level,
+            skip_until: None,
+        }
+    }
+
+    /// Run the filters in the order they are added on a version, each seeing the value changed by the previous ones.
+    /// Returns `Keep`, `Remove` or `ChangeValue`.
+    pub(crate) fn filter(&mut self, key: &[u8], ts: u64, value: &[u8]) -> CompactionDecision {
+        if let Some(skip_until) = &self.skip_until {
+            if key < skip_until.as_ref() {
+                return CompactionDecision::Remove;
+            }
+            self.skip_until = None;
+        }
+        let mut decision = CompactionDecision::Keep;
+        for filter in &self.filters {
+            let value = match &decision {
+                CompactionDecision::ChangeValue(value) => value.as_ref(),
+                _ => value,
+            };
+            match filter.filter(key, ts, value, self.level) {
+                CompactionDecision::Keep => {}
+                CompactionDecision::ChangeValue(value) => {
+                    decision = CompactionDecision::ChangeValue(value)
+                }
+                CompactionDecision::Remove => return CompactionDecision::Remove,
+                CompactionDecision::RemoveAndSkipUntil(skip_until) => {
+                    self.skip_until = Some(skip_until);
+                    return CompactionDecision::Remove;
+                }
+            }
+        }
+        decision
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+}
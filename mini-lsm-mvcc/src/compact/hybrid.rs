@@ -0,0 +1,131 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use super::TieredCompactionTask;
+use super::tiered::apply_tiered_compaction_result;
+use crate::lsm_storage::LsmStorageState;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HybridCompactionOptions {
+    /// The number of levels, which is at least 2. The upper levels hold several sorted runs each, and the last level
+    /// is a single sorted run.
+    pub num_levels: usize,
+    /// Merge the runs of an upper level once it has more than this many runs.
+    pub max_runs_per_level: usize,
+    /// The size of the runs in L1 in MB, i.e., about the size of a flushed memtable. The runs of each level are
+    /// `max_runs_per_level + 1` times larger than the ones of the level above.
+    pub base_run_size_mb: usize,
+}
+
+/// Hybrid compaction (= lazy leveling) keeps the sorted runs in the tier layout of `LsmStorageState::levels`, from the
+/// latest to the oldest. A flush adds a run to L1. Once an upper level has too many runs, they are merged into one run
+/// of the level below, and the runs of the last upper level are merged with the last level. The runs are not tagged
+/// with their levels, so the level of a run is derived from its size.
+pub struct HybridCompactionController {
+    options: HybridCompactionOptions,
+}
+
+impl HybridCompactionController {
+    pub fn new(options: HybridCompactionOptions) -> Self {
+        assert!(
+            options.num_levels >= 2,
+            "hybrid compaction needs at least 2 levels"
+        );
+        Self { options }
+    }
+
+    /// The level of each run in `LsmStorageState::levels`. The oldest run is the last level. The level of an upper run
+    /// is the one whose run size is the closest to its size, but never above the level of a newer run, so that the
+    /// runs of a level are next to each other.
+    pub fn run_levels(&self, snapshot: &LsmStorageState) -> Vec<usize> {
+        let base_size = (self.options.base_run_size_mb << 20).max(1) as f64;
+        let fanout = (self.options.max_runs_per_level + 1) as f64;
+        let mut level = 1;
+        let mut run_levels = snapshot
+            .levels
+            .iter()
+            .map(|(_, ssts)| {
+                let size = ssts
+                    .iter()
+                    .map(|x| snapshot.sstables[x].table_size())
+                    .sum::<u64>();
+                let size_level =
+                    1 + (size as f64 / base_size).log(fanout).round().max(0.0) as usize;
+                level = level.max(size_level).min(self.options.num_levels - 1);
+                level
+            })
+            .collect::<Vec<_>>();
+        if let Some(last) = run_levels.last_mut() {
+            *last = self.options.num_levels;
+        }
+        run_levels
+    }
+
+    /// Generates a compaction task that does not read any SST in `in_progress`. The upper levels are checked from L1,
+    /// as more runs in them make reads slower.
+    pub fn generate_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+        in_progress: &HashSet<usize>,
+    ) -> Option<TieredCompactionTask> {
+        assert!(
+            snapshot.l0_sstables.is_empty(),
+            "should not add l0 ssts in hybrid compaction"
+        );
+        let run_levels = self.run_levels(snapshot);
+        for level in 1..self.options.num_levels {
+            let Some(first) = run_levels.iter().position(|x| *x == level) else {
+                continue;
+            };
+            let num_runs = run_levels.iter().filter(|x| **x == level).count();
+            if num_runs <= self.options.max_runs_per_level {
+                continue;
+            }
+            let bottom_tier_included = level + 1 == self.options.num_levels;
+            let last = if bottom_tier_included {
+                snapshot.levels.len() - 1
+            } else {
+                first + num_runs - 1
+            };
+            let tiers = snapshot.levels[first..=last].to_vec();
+            if tiers
+                .iter()
+                .any(|(_, ssts)| ssts.iter().any(|x| in_progress.contains(x)))
+            {
+                continue;
+            }
+            println!(
+                "compaction triggered by {} runs in L{} > {}",
+                num_runs, level, self.options.max_runs_per_level
+            );
+            return Some(TieredCompactionTask {
+                tiers,
+                bottom_tier_included,
+            });
+        }
+        None
+    }
+
+    pub fn apply_compaction_result(
+        &self,
+        snapshot: &LsmStorageState,
+        task: &TieredCompactionTask,
+        output: &[usize],
+    ) -> (LsmStorageState, Vec<usize>) {
+        apply_tiered_compaction_result(snapshot, task, output)
+    }
+}
@@ -12,10 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 
+use super::{CompactionMark, CompactionTriggers, trivially_movable_ssts};
+use crate::clock::Clock;
+use crate::key::KeyBytes;
 use crate::lsm_storage::LsmStorageState;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -28,21 +33,77 @@ pub struct LeveledCompactionTask {
     pub is_lower_level_bottom_level: bool,
 }
 
-#[derive(Debug, Clone)]
+impl LeveledCompactionTask {
+    /// If the lower level has no SSTs in the key range of the upper level SSTs, the upper level SSTs can be moved to the
+    /// lower level without rewriting them. Returns the SSTs to move in key order.
+    pub fn trivial_move(&self, snapshot: &LsmStorageState) -> Option<Vec<usize>> {
+        if !self.lower_level_sst_ids.is_empty() {
+            return None;
+        }
+        trivially_movable_ssts(snapshot, self.upper_level, &self.upper_level_sst_ids)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LeveledCompactionOptions {
     pub level_size_multiplier: usize,
     pub level0_file_num_compaction_trigger: usize,
     pub max_levels: usize,
     pub base_level_size_mb: usize,
+    /// How to pick the SST to compact from a level that exceeds its target size.
+    #[serde(default)]
+    pub compaction_pri: CompactionPri,
+}
+
+impl Default for LeveledCompactionOptions {
+    /// The options of the CLI.
+    fn default() -> Self {
+        Self {
+            level_size_multiplier: 2,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 4,
+            base_level_size_mb: 128,
+            compaction_pri: CompactionPri::default(),
+        }
+    }
+}
+
+/// How leveled compaction picks the SST to compact from a level that exceeds its target size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompactionPri {
+    /// The SST with the lowest ID, which was written first.
+    #[default]
+    OldestFile,
+    /// The SST with the least bytes in the lower level for each of its own bytes, which rewrites the least data.
+    MinOverlappingRatio,
+    /// The SST with the oldest version, whose data has stayed in the level for the longest time.
+    OldestSmallestSeq,
+    /// The SST after the last compacted one in key order, so that compactions sweep through the key space of the level.
+    RoundRobin,
 }
 
 pub struct LeveledCompactionController {
     options: LeveledCompactionOptions,
+    triggers: CompactionTriggers,
+    clock: Arc<dyn Clock>,
+    /// The last key of the last SST compacted from each level, for `CompactionPri::RoundRobin`. It only moves when the
+    /// result of a compaction is applied, so that a task that is never run does not skip SSTs. It starts over from the
+    /// first SST after a restart.
+    round_robin_cursors: Mutex<HashMap<usize, KeyBytes>>,
 }
 
 impl LeveledCompactionController {
-    pub fn new(options: LeveledCompactionOptions) -> Self {
-        Self { options }
+    pub fn new(
+        options: LeveledCompactionOptions,
+        triggers: CompactionTriggers,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            options,
+            triggers,
+            clock,
+            round_robin_cursors: Mutex::new(HashMap::new()),
+        }
     }
 
     fn find_overlapping_ssts(
@@ -75,9 +136,61 @@ impl LeveledCompactionController {
         overlap_ssts
     }
 
+    /// Order the SSTs of a level by how they should be picked for compaction under the compaction priority.
+    fn order_candidates(
+        &self,
+        snapshot: &LsmStorageState,
+        level: usize,
+        mut candidates: Vec<usize>,
+    ) -> Vec<usize> {
+        match self.options.compaction_pri {
+            CompactionPri::OldestFile => candidates.sort(),
+            CompactionPri::MinOverlappingRatio => {
+                let mut ratios = candidates
+                    .into_iter()
+                    .map(|id| {
+                        let overlapping_bytes = self
+                            .find_overlapping_ssts(snapshot, &[id], level + 1)
+                            .iter()
+                            .map(|x| snapshot.sstables[x].table_size())
+                            .sum::<u64>();
+                        let size = snapshot.sstables[&id].table_size().max(1);
+                        (overlapping_bytes as f64 / size as f64, id)
+                    })
+                    .collect::<Vec<_>>();
+                ratios.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                candidates = ratios.into_iter().map(|(_, id)| id).collect();
+            }
+            CompactionPri::OldestSmallestSeq => {
+                // SSTs written without stats are taken as the oldest.
+                candidates.sort_by_key(|id| {
+                    let min_ts = snapshot.sstables[id].stats().map_or(0, |x| x.min_ts);
+                    (min_ts, *id)
+                });
+            }
+            CompactionPri::RoundRobin => {
+                candidates.sort_by(|x, y| {
+                    snapshot.sstables[x]
+                        .first_key()
+                        .cmp(snapshot.sstables[y].first_key())
+                });
+                if let Some(cursor) = self.round_robin_cursors.lock().get(&level) {
+                    let start = candidates
+                        .partition_point(|id| snapshot.sstables[id].first_key() <= cursor);
+                    candidates.rotate_left(start);
+                }
+            }
+        }
+        candidates
+    }
+
+    /// Generates a compaction task that does not read any SST in `in_progress`, which are being compacted by other
+    /// tasks. If no level is too large, SSTs with too many deletes or too old are compacted.
     pub fn generate_compaction_task(
         &self,
         snapshot: &LsmStorageState,
+        in_progress: &HashSet<usize>,
+        watermark: u64,
     ) -> Option<LeveledCompactionTask> {
         // step 1: compute target level size
         let mut target_level_size = (0..self.options.max_levels).map(|_| 0).collect::<Vec<_>>(); // exclude level 0
@@ -110,18 +223,23 @@ impl LeveledCompactionController {
 
         // Flush L0 SST is the top priority
         if snapshot.l0_sstables.len() >= self.options.level0_file_num_compaction_trigger {
-            println!("flush L0 SST to base level {}", base_level);
-            return Some(LeveledCompactionTask {
-                upper_level: None,
-                upper_level_sst_ids: snapshot.l0_sstables.clone(),
-                lower_level: base_level,
-                lower_level_sst_ids: self.find_overlapping_ssts(
-                    snapshot,
-                    &snapshot.l0_sstables,
-                    base_level,
-                ),
-                is_lower_level_bottom_level: base_level == self.options.max_levels,
-            });
+            let lower_level_sst_ids =
+                self.find_overlapping_ssts(snapshot, &snapshot.l0_sstables, base_level);
+            if snapshot
+                .l0_sstables
+                .iter()
+                .chain(&lower_level_sst_ids)
+                .all(|x| !in_progress.contains(x))
+            {
+                println!("flush L0 SST to base level {}", base_level);
+                return Some(LeveledCompactionTask {
+                    upper_level: None,
+                    upper_level_sst_ids: snapshot.l0_sstables.clone(),
+                    lower_level: base_level,
+                    lower_level_sst_ids,
+                    is_lower_level_bottom_level: base_level == self.options.max_levels,
+                });
+            }
         }
 
         let mut priorities = Vec::with_capacity(self.options.max_levels);
@@ -133,8 +251,25 @@ impl LeveledCompactionController {
         }
         priorities.sort_by(|a, b| a.partial_cmp(b).unwrap().reverse());
 
-        let priority = priorities.first();
-        if let Some((_, level)) = priority {
+        for (_, level) in &priorities {
+            let level = *level;
+            // select the first sst by the compaction priority that does not overlap with any running compaction
+            let candidates = snapshot.levels[level - 1]
+                .1
+                .iter()
+                .filter(|x| !in_progress.contains(x))
+                .copied()
+                .collect::<Vec<_>>();
+            let candidates = self.order_candidates(snapshot, level, candidates);
+            let Some((selected_sst, lower_level_sst_ids)) = candidates.into_iter().find_map(|x| {
+                let lower_level_sst_ids = self.find_overlapping_ssts(snapshot, &[x], level + 1);
+                lower_level_sst_ids
+                    .iter()
+                    .all(|x| !in_progress.contains(x))
+                    .then_some((x, lower_level_sst_ids))
+            }) else {
+                continue;
+            };
             println!(
                 "target level sizes: {:?}, real level sizes: {:?}, base_level: {}",
                 target_level_size
@@ -148,24 +283,78 @@ impl LeveledCompactionController {
                 base_level,
             );
 
-            let level = *level;
-            let selected_sst = snapshot.levels[level - 1].1.iter().min().copied().unwrap(); // select the oldest sst to compact
             println!(
-                "compaction triggered by priority: {level} out of {:?}, select {selected_sst} for compaction",
-                priorities
+                "compaction triggered by priority: {level} out of {:?}, select {selected_sst} for compaction by {:?}",
+                priorities, self.options.compaction_pri
             );
             return Some(LeveledCompactionTask {
                 upper_level: Some(level),
                 upper_level_sst_ids: vec![selected_sst],
                 lower_level: level + 1,
-                lower_level_sst_ids: self.find_overlapping_ssts(
-                    snapshot,
-                    &[selected_sst],
-                    level + 1,
-                ),
+                lower_level_sst_ids,
                 is_lower_level_bottom_level: level + 1 == self.options.max_levels,
             });
         }
+        self.generate_marked_compaction_task(snapshot, in_progress, watermark)
+    }
+
+    /// Compacts the oldest SST with too many deletes or too old in the upper-most level with the overlapping SSTs of the
+    /// lower level. In the bottom level, the SST is compacted by itself, and an SST with too many deletes waits until
+    /// all of its versions are below `watermark`, so that compacting it drops the deletes instead of rewriting them.
+    fn generate_marked_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+        in_progress: &HashSet<usize>,
+        watermark: u64,
+    ) -> Option<LeveledCompactionTask> {
+        if !self.triggers.is_enabled() {
+            return None;
+        }
+        let now = self.clock.now_millis();
+        for level in 1..=self.options.max_levels {
+            let is_bottom_level = level == self.options.max_levels;
+            let mut candidates = snapshot.levels[level - 1]
+                .1
+                .iter()
+                .filter(|x| !in_progress.contains(x))
+                .copied()
+                .collect::<Vec<_>>();
+            candidates.sort();
+            for selected_sst in candidates {
+                let sst = &snapshot.sstables[&selected_sst];
+                let Some(mark) = self.triggers.mark(sst, now) else {
+                    continue;
+                };
+                if is_bottom_level {
+                    if mark == CompactionMark::Tombstones && sst.max_ts() > watermark {
+                        continue;
+                    }
+                    println!(
+                        "compaction triggered by {mark:?} of SST {selected_sst} in the bottom level {level}"
+                    );
+                    return Some(LeveledCompactionTask {
+                        upper_level: (level > 1).then_some(level - 1),
+                        upper_level_sst_ids: Vec::new(),
+                        lower_level: level,
+                        lower_level_sst_ids: vec![selected_sst],
+                        is_lower_level_bottom_level: true,
+                    });
+                }
+                let lower_level_sst_ids =
+                    self.find_overlapping_ssts(snapshot, &[selected_sst], level + 1);
+                if lower_level_sst_ids.iter().any(|x| in_progress.contains(x)) {
+                    continue;
+                }
+                println!("compaction triggered by {mark:?} of SST {selected_sst} in level {level}");
+                return Some(LeveledCompactionTask {
+                    upper_level: Some(level),
+                    upper_level_sst_ids: vec![selected_sst],
+                    lower_level: level + 1,
+                    lower_level_sst_ids,
+                    is_lower_level_bottom_level: level + 1 == self.options.max_levels,
+                });
+            }
+        }
         None
     }
 
@@ -176,73 +365,98 @@ impl LeveledCompactionController {
         output: &[usize],
         in_recovery: bool,
     ) -> (LsmStorageState, Vec<usize>) {
-        let mut snapshot = snapshot.clone();
-        let mut files_to_remove = Vec::new();
-        let mut upper_level_sst_ids_set = task
-            .upper_level_sst_ids
-            .iter()
-            .copied()
-            .collect::<HashSet<_>>();
-        let mut lower_level_sst_ids_set = task
-            .lower_level_sst_ids
-            .iter()
-            .copied()
-            .collect::<HashSet<_>>();
-        if let Some(upper_level) = task.upper_level {
-            let new_upper_level_ssts = snapshot.levels[upper_level - 1]
-                .1
+        if self.options.compaction_pri == CompactionPri::RoundRobin
+            && !in_recovery
+            && let Some(level) = task.upper_level
+            && let Some(last_key) = task
+                .upper_level_sst_ids
                 .iter()
-                .filter_map(|x| {
-                    if upper_level_sst_ids_set.remove(x) {
-                        return None;
-                    }
-                    Some(*x)
-                })
-                .collect::<Vec<_>>();
-            assert!(upper_level_sst_ids_set.is_empty());
-            snapshot.levels[upper_level - 1].1 = new_upper_level_ssts;
-        } else {
-            let new_l0_ssts = snapshot
-                .l0_sstables
-                .iter()
-                .filter_map(|x| {
-                    if upper_level_sst_ids_set.remove(x) {
-                        return None;
-                    }
-                    Some(*x)
-                })
-                .collect::<Vec<_>>();
-            assert!(upper_level_sst_ids_set.is_empty());
-            snapshot.l0_sstables = new_l0_ssts;
+                .filter_map(|id| snapshot.sstables.get(id))
+                .map(|sst| sst.last_key())
+                .max()
+        {
+            self.round_robin_cursors
+                .lock()
+                .insert(level, last_key.clone());
         }
+        apply_leveled_compaction_result(snapshot, task, output, in_recovery)
+    }
+}
 
-        files_to_remove.extend(&task.upper_level_sst_ids);
-        files_to_remove.extend(&task.lower_level_sst_ids);
-
-        let mut new_lower_level_ssts = snapshot.levels[task.lower_level - 1]
+/// Replace the SSTs of a leveled compaction task with its output. The other SSTs in both levels are kept, so it also
+/// applies tasks that compact part of a level in other compaction strategies.
+pub(crate) fn apply_leveled_compaction_result(
+    snapshot: &LsmStorageState,
+    task: &LeveledCompactionTask,
+    output: &[usize],
+    in_recovery: bool,
+) -> (LsmStorageState, Vec<usize>) {
+    let mut snapshot = snapshot.clone();
+    let mut files_to_remove = Vec::new();
+    let mut upper_level_sst_ids_set = task
+        .upper_level_sst_ids
+        .iter()
+        .copied()
+        .collect::<HashSet<_>>();
+    let mut lower_level_sst_ids_set = task
+        .lower_level_sst_ids
+        .iter()
+        .copied()
+        .collect::<HashSet<_>>();
+    if let Some(upper_level) = task.upper_level {
+        let new_upper_level_ssts = snapshot.levels[upper_level - 1]
             .1
             .iter()
             .filter_map(|x| {
-                if lower_level_sst_ids_set.remove(x) {
+                if upper_level_sst_ids_set.remove(x) {
                     return None;
                 }
                 Some(*x)
             })
             .collect::<Vec<_>>();
-        assert!(lower_level_sst_ids_set.is_empty());
-        new_lower_level_ssts.extend(output);
-        // Don't sort the SST IDs during recovery because actual SSTs are not loaded at that point
-        if !in_recovery {
-            new_lower_level_ssts.sort_by(|x, y| {
-                snapshot
-                    .sstables
-                    .get(x)
-                    .unwrap()
-                    .first_key()
-                    .cmp(snapshot.sstables.get(y).unwrap().first_key())
-            });
-        }
-        snapshot.levels[task.lower_level - 1].1 = new_lower_level_ssts;
-        (snapshot, files_to_remove)
+        assert!(upper_level_sst_ids_set.is_empty());
+        snapshot.levels[upper_level - 1].1 = new_upper_level_ssts;
+    } else {
+        let new_l0_ssts = snapshot
+            .l0_sstables
+            .iter()
+            .filter_map(|x| {
+                if upper_level_sst_ids_set.remove(x) {
+                    return None;
+                }
+                Some(*x)
+            })
+            .collect::<Vec<_>>();
+        assert!(upper_level_sst_ids_set.is_empty());
+        snapshot.l0_sstables = new_l0_ssts;
+    }
+
+    files_to_remove.extend(&task.upper_level_sst_ids);
+    files_to_remove.extend(&task.lower_level_sst_ids);
+
+    let mut new_lower_level_ssts = snapshot.levels[task.lower_level - 1]
+        .1
+        .iter()
+        .filter_map(|x| {
+            if lower_level_sst_ids_set.remove(x) {
+                return None;
+            }
+            Some(*x)
+        })
+        .collect::<Vec<_>>();
+    assert!(lower_level_sst_ids_set.is_empty());
+    new_lower_level_ssts.extend(output);
+    // Don't sort the SST IDs during recovery because actual SSTs are not loaded at that point
+    if !in_recovery {
+        new_lower_level_ssts.sort_by(|x, y| {
+            snapshot
+                .sstables
+                .get(x)
+                .unwrap()
+                .first_key()
+                .cmp(snapshot.sstables.get(y).unwrap().first_key())
+        });
     }
+    snapshot.levels[task.lower_level - 1].1 = new_lower_level_ssts;
+    (snapshot, files_to_remove)
 }
@@ -0,0 +1,258 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::HashSet;
+use std::ops::Bound;
+
+use anyhow::{Result, bail};
+
+use super::{
+    CompactionOptions, CompactionTask, FifoCompactionTask, LeveledCompactionTask,
+    TieredCompactionTask,
+};
+use crate::lsm_storage::{LsmStorageInner, LsmStorageState, range_overlap};
+
+fn sst_overlaps(
+    snapshot: &LsmStorageState,
+    sst_id: usize,
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+) -> bool {
+    let sst = &snapshot.sstables[&sst_id];
+    range_overlap(
+        lower,
+        upper,
+        sst.first_key().as_key_slice(),
+        sst.last_key().as_key_slice(),
+    )
+}
+
+/// The task that compacts the SSTs of `level` (0 for L0) in the key range to the next level. If `compact_lower_level`
+/// is set, the SSTs of the next level in the key range are rewritten even if no SST of `level` overlaps with them.
+/// The L0 SSTs that overlap with the key range, and the ones that overlap with those, from latest to earliest. The
+/// other L0 SSTs do not overlap with the key range of the picked ones.
+fn overlapping_l0_ssts(
+    snapshot: &LsmStorageState,
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+) -> Vec<usize> {
+    let mut picked = HashSet::new();
+    let (mut lower, mut upper) = (lower, upper);
+    loop {
+        let overlapping = snapshot
+            .l0_sstables
+            .iter()
+            .filter(|id| sst_overlaps(snapshot, **id, lower, upper))
+            .copied()
+            .collect::<HashSet<_>>();
+        if overlapping.len() == picked.len() {
+            break;
+        }
+        picked = overlapping;
+        let first_key = picked
+            .iter()
+            .map(|id| snapshot.sstables[id].first_key().key_ref())
+            .min()
+            .unwrap();
+        let last_key = picked
+            .iter()
+            .map(|id| snapshot.sstables[id].last_key().key_ref())
+            .max()
+            .unwrap();
+        lower = Bound::Included(first_key);
+        upper = Bound::Included(last_key);
+    }
+    // Keep the order of L0 SSTs, which decides the latest version of a key.
+    snapshot
+        .l0_sstables
+        .iter()
+        .filter(|id| picked.contains(id))
+        .copied()
+        .collect::<Vec<_>>()
+}
+
+fn leveled_task(
+    snapshot: &LsmStorageState,
+    level: usize,
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+    compact_lower_level: bool,
+) -> Option<CompactionTask> {
+    let upper_level_sst_ids = if level == 0 {
+        // Older L0 SSTs that overlap with the picked ones must be compacted together, so that they do not end up
+        // above the newer data.
+        overlapping_l0_ssts(snapshot, lower, upper)
+    } else {
+        snapshot.levels[level - 1]
+            .1
+            .iter()
+            .filter(|id| sst_overlaps(snapshot, **id, lower, upper))
+            .copied()
+            .collect::<Vec<_>>()
+    };
+    let lower_ssts = &snapshot.levels[level].1;
+    let upper_range = (
+        upper_level_sst_ids
+            .iter()
+            .map(|id| snapshot.sstables[id].first_key().key_ref())
+            .min(),
+        upper_level_sst_ids
+            .iter()
+            .map(|id| snapshot.sstables[id].last_key().key_ref())
+            .max(),
+    );
+    let overlapping = lower_ssts
+        .iter()
+        .enumerate()
+        .filter(|(_, id)| {
+            if let (Some(first_key), Some(last_key)) = upper_range
+                && sst_overlaps(
+                    snapshot,
+                    **id,
+                    Bound::Included(first_key),
+                    Bound::Included(last_key),
+                )
+            {
+                return true;
+            }
+            compact_lower_level && sst_overlaps(snapshot, **id, lower, upper)
+        })
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    // The SSTs in between are compacted as well, so that the output does not overlap with the other SSTs of the level.
+    let lower_level_sst_ids = match (overlapping.first(), overlapping.last()) {
+        (Some(first), Some(last)) => lower_ssts[*first..=*last].to_vec(),
+        _ => Vec::new(),
+    };
+    if upper_level_sst_ids.is_empty() && lower_level_sst_ids.is_empty() {
+        return None;
+    }
+    Some(CompactionTask::Leveled(LeveledCompactionTask {
+        upper_level: (level > 0).then_some(level),
+        upper_level_sst_ids,
+        lower_level: level + 1,
+        lower_level_sst_ids,
+        is_lower_level_bottom_level: level + 1 == snapshot.levels.len(),
+    }))
+}
+
+/// The task that merges the tiers from the first to the last one with SSTs in the key range. Tiers are always
+/// compacted as a whole.
+fn tiered_task(
+    snapshot: &LsmStorageState,
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+) -> Option<CompactionTask> {
+    let overlapping_tiers = snapshot
+        .levels
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, ssts))| {
+            ssts.iter()
+                .any(|id| sst_overlaps(snapshot, *id, lower, upper))
+        })
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    let (first, last) = (*overlapping_tiers.first()?, *overlapping_tiers.last()?);
+    Some(CompactionTask::Tiered(TieredCompactionTask {
+        tiers: snapshot.levels[first..=last].to_vec(),
+        bottom_tier_included: last == snapshot.levels.len() - 1,
+    }))
+}
+
+/// The task that merges the SSTs in the key range, together with the ones that overlap with them, into new SSTs in L0.
+fn fifo_task(
+    snapshot: &LsmStorageState,
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+) -> Option<CompactionTask> {
+    let sst_ids = overlapping_l0_ssts(snapshot, lower, upper);
+    if sst_ids.is_empty() {
+        return None;
+    }
+    Some(CompactionTask::Fifo(FifoCompactionTask::Merge { sst_ids }))
+}
+
+impl LsmStorageInner {
+    /// Compact the SSTs that overlap with the key range down to `target_level`, which is the bottom level if `None`.
+    /// The memtables are flushed first, so that all data in the range is compacted. In tiered and hybrid compaction, the
+    /// tiers that overlap with the range are merged into one, and in FIFO compaction, the L0 SSTs that overlap with the
+    /// range are merged in place. `target_level` is ignored in these.
+    pub fn compact_range(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        target_level: Option<usize>,
+    ) -> Result<()> {
+        {
+            let state_lock = self.state_lock.lock();
+            if !self.state.read().memtable.is_empty() {
+                self.force_freeze_memtable(&state_lock)?;
+            }
+        }
+        while !self.state.read().imm_memtables.is_empty() {
+            self.force_flush_next_imm_memtable()?;
+        }
+
+        if let CompactionOptions::Tiered(_) | CompactionOptions::Hybrid(_) =
+            self.options.compaction_options
+        {
+            return self
+                .run_manual_compaction(|snapshot| tiered_task(snapshot, lower, upper), false);
+        }
+        if let CompactionOptions::Fifo(_) = self.options.compaction_options {
+            return self.run_manual_compaction(|snapshot| fifo_task(snapshot, lower, upper), false);
+        }
+        let max_level = self.state.read().levels.len();
+        let target_level = target_level.unwrap_or(max_level);
+        if target_level == 0 || target_level > max_level {
+            bail!(
+                "target level {} is out of range, the database has levels 1 to {}",
+                target_level,
+                max_level
+            );
+        }
+        for level in 0..target_level {
+            // SSTs can be moved through the levels in between, but the target level is rewritten to drop the deleted
+            // keys and old versions.
+            let is_target_level = level + 1 == target_level;
+            self.run_manual_compaction(
+                |snapshot| leveled_task(snapshot, level, lower, upper, is_target_level),
+                !is_target_level,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Run the task generated from the latest state, after the running compactions that conflict with it finish.
+    fn run_manual_compaction(
+        &self,
+        generate_task: impl Fn(&LsmStorageState) -> Option<CompactionTask>,
+        allow_trivial_move: bool,
+    ) -> Result<()> {
+        let mut running = self.running_compactions.lock();
+        loop {
+            let snapshot = self.state.read().clone();
+            let Some(task) = generate_task(&snapshot) else {
+                return Ok(());
+            };
+            if let Some(mut job) = self.register_compaction_job(&mut running, &snapshot, task) {
+                drop(running);
+                job.allow_trivial_move = allow_trivial_move;
+                println!("manual compaction: {:?}", job.task);
+                return self.run_compaction_job(job);
+            }
+            self.compaction_job_done.wait(&mut running);
+        }
+    }
+}
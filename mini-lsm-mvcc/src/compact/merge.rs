@@ -0,0 +1,72 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bytes::Bytes;
+
+use crate::merge_operator::{self, MergeOperator};
+use crate::value;
+
+/// Merge the versions of `key` below the watermark, newest first, that start with a merge operand and end with the
+/// first value or delete, if there is one. Returns the versions to write to the compaction output.
+///
+/// The operands are merged into the value when no reader can tell the difference, i.e., the value is a delete, a
+/// value without TTL, or an expired value, or there is no value in the levels below. Otherwise, they are combined by a
+/// partial merge if the merge operator supports it, and kept as they are if it does not.
+pub(super) fn merge_operands(
+    merge_operator: Option<&dyn MergeOperator>,
+    key: &[u8],
+    versions: Vec<(u64, Bytes)>,
+    compact_to_bottom_level: bool,
+    now: u64,
+) -> Vec<(u64, Bytes)> {
+    let Some(merge_operator) = merge_operator else {
+        // Keep the operands for a merge operator that is set when the database is opened again.
+        return versions;
+    };
+    let ts = versions[0].0;
+    let base = versions
+        .last()
+        .filter(|(_, raw)| !value::is_merge_operand(raw))
+        .cloned();
+    let operands = versions[..versions.len() - base.is_some() as usize]
+        .iter()
+        .rev()
+        .map(|(_, raw)| value::decode(raw))
+        .collect::<Vec<_>>();
+    // Reads merge into a value with TTL until it expires, and into nothing after.
+    let can_full_merge = match &base {
+        Some((_, raw)) => value::expire_at(raw).is_none() || value::is_expired(raw, now),
+        None => compact_to_bottom_level,
+    };
+    if can_full_merge {
+        let existing = base
+            .as_ref()
+            .map(|(_, raw)| raw)
+            .filter(|raw| value::is_live(raw, now))
+            .map(|raw| value::decode(raw));
+        let merged = merge_operator::full_merge(merge_operator, key, existing, &operands);
+        if merged.is_empty() && compact_to_bottom_level {
+            return Vec::new();
+        }
+        return vec![(ts, merged)];
+    }
+    if operands.len() > 1
+        && let Some(operand) = merge_operator.partial_merge(key, &operands)
+    {
+        return std::iter::once((ts, value::encode_merge_operand(&operand)))
+            .chain(base)
+            .collect();
+    }
+    versions
+}
@@ -0,0 +1,265 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use super::{CompactionOptions, CompactionTask};
+use crate::lsm_storage::{LsmStorageInner, LsmStorageState};
+
+/// The events that make the compaction scheduler look for new compaction tasks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CompactionEvent {
+    /// The storage engine is opened, and the recovered SSTs might need compaction.
+    Opened,
+    /// A memtable is flushed to a new SST.
+    FlushCompleted,
+    /// A compaction job finished or failed, so that its output and the SSTs it blocked can be compacted.
+    CompactionCompleted,
+    /// Time passed, and SSTs might be old enough for the TTL or the periodic compaction.
+    Tick,
+}
+
+/// How often the scheduler looks for compaction tasks when the age of SSTs can trigger compactions.
+const COMPACTION_TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A compaction task picked by the scheduler and the user key range of its input SSTs.
+struct RunningCompaction {
+    task: Arc<CompactionTask>,
+    key_range: Option<(Bytes, Bytes)>,
+}
+
+/// The compaction jobs that are running.
+#[derive(Default)]
+pub(crate) struct RunningCompactions {
+    next_job_id: usize,
+    jobs: HashMap<usize, RunningCompaction>,
+}
+
+impl RunningCompactions {
+    /// The SSTs read by the running compactions, which cannot be picked by other compactions.
+    pub(crate) fn in_progress_ssts(&self) -> HashSet<usize> {
+        self.jobs
+            .values()
+            .flat_map(|x| x.task.input_sst_ids())
+            .collect()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.jobs.len()
+    }
+}
+
+/// A compaction task that is registered as running. It is removed from the running compactions when dropped, which must
+/// be after its result is applied to the LSM state or it fails.
+pub(crate) struct CompactionJob<'a> {
+    storage: &'a LsmStorageInner,
+    job_id: usize,
+    pub(crate) task: Arc<CompactionTask>,
+    /// Whether SSTs can be moved to the lower level instead of rewriting them.
+    pub(crate) allow_trivial_move: bool,
+}
+
+impl Drop for CompactionJob<'_> {
+    fn drop(&mut self) {
+        self.storage
+            .running_compactions
+            .lock()
+            .jobs
+            .remove(&self.job_id);
+        self.storage.compaction_job_done.notify_all();
+    }
+}
+
+fn key_range(snapshot: &LsmStorageState, sst_ids: &[usize]) -> Option<(Bytes, Bytes)> {
+    let first_key = sst_ids
+        .iter()
+        .map(|id| snapshot.sstables[id].first_key().key_ref())
+        .min()?;
+    let last_key = sst_ids
+        .iter()
+        .map(|id| snapshot.sstables[id].last_key().key_ref())
+        .max()?;
+    Some((
+        Bytes::copy_from_slice(first_key),
+        Bytes::copy_from_slice(last_key),
+    ))
+}
+
+/// Check if two compactions cannot run at the same time, i.e., they read the same SSTs, or their results cannot both be
+/// applied to the LSM state.
+fn conflicts(x: &RunningCompaction, y: &RunningCompaction) -> bool {
+    let y_ssts = y.task.input_sst_ids();
+    if x.task.input_sst_ids().iter().any(|id| y_ssts.contains(id)) {
+        return true;
+    }
+    match (x.task.as_ref(), y.task.as_ref()) {
+        // A simple leveled compaction replaces both levels, even if they are empty.
+        (CompactionTask::Simple(x), CompactionTask::Simple(y)) => {
+            let x_levels = [x.upper_level.unwrap_or(0), x.lower_level];
+            let y_levels = [y.upper_level.unwrap_or(0), y.lower_level];
+            x_levels.iter().any(|level| y_levels.contains(level))
+        }
+        // The outputs of two compactions must not overlap in the same level.
+        (CompactionTask::Leveled(x_task), CompactionTask::Leveled(y_task)) => {
+            x_task.lower_level == y_task.lower_level
+                && match (&x.key_range, &y.key_range) {
+                    (Some((x_first, x_last)), Some((y_first, y_last))) => {
+                        x_first <= y_last && y_first <= x_last
+                    }
+                    _ => false,
+                }
+        }
+        (CompactionTask::Tiered(_), CompactionTask::Tiered(_)) => false,
+        // FIFO compactions only remove their own SSTs from L0.
+        (CompactionTask::Fifo(_), CompactionTask::Fifo(_)) => false,
+        _ => true,
+    }
+}
+
+impl LsmStorageInner {
+    /// Pick a compaction task that does not conflict with the running compactions, and register it as running.
+    pub(crate) fn pick_compaction_job(&self) -> Option<CompactionJob<'_>> {
+        let mut running = self.running_compactions.lock();
+        let snapshot = self.state.read().clone();
+        let task = self.compaction_controller.generate_compaction_task(
+            &snapshot,
+            &running.in_progress_ssts(),
+            self.mvcc().watermark(),
+        )?;
+        let job = self.register_compaction_job(&mut running, &snapshot, task);
+        if job.is_none() {
+            println!("compaction task conflicts with a running compaction");
+        }
+        job
+    }
+
+    /// Register a task generated from `snapshot` as running, unless it conflicts with a running compaction. The lock of
+    /// the running compactions must be held since the snapshot is taken.
+    pub(crate) fn register_compaction_job(
+        &self,
+        running: &mut RunningCompactions,
+        snapshot: &LsmStorageState,
+        task: CompactionTask,
+    ) -> Option<CompactionJob<'_>> {
+        let key_range = key_range(snapshot, &task.input_sst_ids());
+        let compaction = RunningCompaction {
+            task: Arc::new(task),
+            key_range,
+        };
+        if running.jobs.values().any(|x| conflicts(x, &compaction)) {
+            return None;
+        }
+        let job_id = running.next_job_id;
+        running.next_job_id += 1;
+        let task = compaction.task.clone();
+        running.jobs.insert(job_id, compaction);
+        Some(CompactionJob {
+            storage: self,
+            job_id,
+            task,
+            allow_trivial_move: true,
+        })
+    }
+
+    /// Run a compaction job, and notify the scheduler once its SSTs can be compacted again, even if the job fails.
+    pub(crate) fn run_compaction_job(&self, job: CompactionJob<'_>) -> Result<()> {
+        let result = self.run_compaction(&job.task, job.allow_trivial_move);
+        drop(job);
+        self.notify_compaction_scheduler(CompactionEvent::CompactionCompleted);
+        result
+    }
+
+    /// Whether the age of SSTs can trigger compactions, which happens without any flush or compaction.
+    fn has_age_triggers(&self) -> bool {
+        match &self.options.compaction_options {
+            CompactionOptions::Fifo(options) => options.ttl.is_some(),
+            CompactionOptions::Leveled(_) | CompactionOptions::Tiered(_) => self
+                .options
+                .compaction_triggers
+                .periodic_compaction_age
+                .is_some(),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn notify_compaction_scheduler(&self, event: CompactionEvent) {
+        // The scheduler looks at the latest state on every event, so a pending event covers new ones.
+        self.compaction_events.0.try_send(event).ok();
+    }
+
+    /// Start as many compaction jobs as allowed by `max_background_compactions`, each on a thread of `scope`. No job is
+    /// started after a manifest or SST write has failed.
+    fn schedule_compactions<'scope>(
+        &'scope self,
+        scope: &'scope std::thread::Scope<'scope, '_>,
+        event: CompactionEvent,
+    ) {
+        while self.check_background_error().is_ok()
+            && self.running_compactions.lock().len() < self.options.max_background_compactions
+        {
+            let Some(job) = self.pick_compaction_job() else {
+                return;
+            };
+            println!("scheduled compaction job on {:?}: {:?}", event, job.task);
+            scope.spawn(move || {
+                if let Err(e) = self.run_compaction_job(job) {
+                    eprintln!("compaction failed: {}", e);
+                }
+            });
+        }
+    }
+
+    /// Spawn the compaction scheduler, which runs compaction jobs in a pool of up to `max_background_compactions`
+    /// threads whenever a flush or a compaction finishes, and every `COMPACTION_TICK_INTERVAL` if the age of SSTs can
+    /// trigger compactions. Running jobs are waited for on shutdown.
+    pub(crate) fn spawn_compaction_thread(
+        self: &Arc<Self>,
+        rx: crossbeam_channel::Receiver<()>,
+    ) -> Result<Option<std::thread::JoinHandle<()>>> {
+        if let CompactionOptions::Leveled(_)
+        | CompactionOptions::Simple(_)
+        | CompactionOptions::Tiered(_)
+        | CompactionOptions::Fifo(_)
+        | CompactionOptions::Hybrid(_) = self.options.compaction_options
+        {
+            let this = self.clone();
+            let events = self.compaction_events.1.clone();
+            let ticker = if self.has_age_triggers() {
+                crossbeam_channel::tick(COMPACTION_TICK_INTERVAL)
+            } else {
+                crossbeam_channel::never()
+            };
+            let handle = std::thread::spawn(move || {
+                std::thread::scope(|scope| {
+                    loop {
+                        crossbeam_channel::select! {
+                            recv(events) -> event => if let Ok(event) = event {
+                                this.schedule_compactions(scope, event);
+                            },
+                            recv(ticker) -> _ => this.schedule_compactions(scope, CompactionEvent::Tick),
+                            recv(rx) -> _ => return
+                        }
+                    }
+                })
+            });
+            self.notify_compaction_scheduler(CompactionEvent::Opened);
+            return Ok(Some(handle));
+        }
+        Ok(None)
+    }
+}
@@ -16,9 +16,10 @@ use std::collections::HashSet;
 
 use serde::{Deserialize, Serialize};
 
+use super::trivially_movable_ssts;
 use crate::lsm_storage::LsmStorageState;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SimpleLeveledCompactionOptions {
     pub size_ratio_percent: usize,
     pub level0_file_num_compaction_trigger: usize,
@@ -35,6 +36,17 @@ pub struct SimpleLeveledCompactionTask {
     pub is_lower_level_bottom_level: bool,
 }
 
+impl SimpleLeveledCompactionTask {
+    /// If the lower level has no SSTs in the key range of the upper level SSTs, the upper level SSTs can be moved to the
+    /// lower level without rewriting them. Returns the SSTs to move in key order.
+    pub fn trivial_move(&self, snapshot: &LsmStorageState) -> Option<Vec<usize>> {
+        if !self.lower_level_sst_ids.is_empty() {
+            return None;
+        }
+        trivially_movable_ssts(snapshot, self.upper_level, &self.upper_level_sst_ids)
+    }
+}
+
 pub struct SimpleLeveledCompactionController {
     options: SimpleLeveledCompactionOptions,
 }
@@ -47,18 +59,37 @@ impl SimpleLeveledCompactionController {
     /// Generates a compaction task.
     ///
     /// Returns `None` if no compaction needs to be scheduled. The order of SSTs in the compaction task id vector matters.
+    /// Levels with SSTs in `in_progress`, which are being compacted by other tasks, are skipped. The size ratio is
+    /// computed from the bytes of the SSTs in each level.
     pub fn generate_compaction_task(
         &self,
         snapshot: &LsmStorageState,
+        in_progress: &HashSet<usize>,
     ) -> Option<SimpleLeveledCompactionTask> {
+        let level_size = |ssts: &[usize]| {
+            ssts.iter()
+                .map(|x| snapshot.sstables[x].table_size())
+                .sum::<u64>()
+        };
         let mut level_sizes = Vec::new();
-        level_sizes.push(snapshot.l0_sstables.len());
+        level_sizes.push(level_size(&snapshot.l0_sstables));
         for (_, files) in &snapshot.levels {
-            level_sizes.push(files.len());
+            level_sizes.push(level_size(files));
         }
+        let level_in_progress = |level: usize| {
+            let ssts = if level == 0 {
+                &snapshot.l0_sstables
+            } else {
+                &snapshot.levels[level - 1].1
+            };
+            ssts.iter().any(|x| in_progress.contains(x))
+        };
 
         // check level0_file_num_compaction_trigger for compaction of L0 to L1
-        if snapshot.l0_sstables.len() >= self.options.level0_file_num_compaction_trigger {
+        if snapshot.l0_sstables.len() >= self.options.level0_file_num_compaction_trigger
+            && !level_in_progress(0)
+            && !level_in_progress(1)
+        {
             println!(
                 "compaction triggered at level 0 because L0 has {} SSTs >= {}",
                 snapshot.l0_sstables.len(),
@@ -81,6 +112,9 @@ impl SimpleLeveledCompactionController {
             }
 
             let lower_level = i + 1;
+            if level_in_progress(i) || level_in_progress(lower_level) {
+                continue;
+            }
             let size_ratio = level_sizes[lower_level] as f64 / level_sizes[i] as f64;
             if size_ratio < self.options.size_ratio_percent as f64 / 100.0 {
                 println!(
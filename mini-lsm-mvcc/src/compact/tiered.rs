@@ -12,10 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 
+use super::{CompactionMark, CompactionTriggers};
+use crate::clock::Clock;
 use crate::lsm_storage::LsmStorageState;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -24,7 +27,7 @@ pub struct TieredCompactionTask {
     pub bottom_tier_included: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TieredCompactionOptions {
     pub num_tiers: usize,
     pub max_size_amplification_percent: usize,
@@ -35,16 +38,42 @@ pub struct TieredCompactionOptions {
 
 pub struct TieredCompactionController {
     options: TieredCompactionOptions,
+    triggers: CompactionTriggers,
+    clock: Arc<dyn Clock>,
 }
 
 impl TieredCompactionController {
-    pub fn new(options: TieredCompactionOptions) -> Self {
-        Self { options }
+    pub fn new(
+        options: TieredCompactionOptions,
+        triggers: CompactionTriggers,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            options,
+            triggers,
+            clock,
+        }
     }
 
+    /// Generates a compaction task that does not read any SST in `in_progress`, which are being compacted by other
+    /// tasks. If the tiers are within the size limits, the tiers with SSTs with too many deletes or too old are
+    /// compacted.
     pub fn generate_compaction_task(
         &self,
         snapshot: &LsmStorageState,
+        in_progress: &HashSet<usize>,
+        watermark: u64,
+    ) -> Option<TieredCompactionTask> {
+        self.generate_size_compaction_task(snapshot, in_progress)
+            .or_else(|| self.generate_marked_compaction_task(snapshot, in_progress, watermark))
+    }
+
+    /// Only the tiers newer than all tiers being compacted can be picked, so that the output tier can take their place.
+    /// The space amplification and size ratios are computed from the bytes of the SSTs in each tier.
+    fn generate_size_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+        in_progress: &HashSet<usize>,
     ) -> Option<TieredCompactionTask> {
         assert!(
             snapshot.l0_sstables.is_empty(),
@@ -53,14 +82,26 @@ impl TieredCompactionController {
         if snapshot.levels.len() < self.options.num_tiers {
             return None;
         }
+        let num_free_tiers = snapshot
+            .levels
+            .iter()
+            .take_while(|(_, ssts)| ssts.iter().all(|x| !in_progress.contains(x)))
+            .count();
+        let tier_sizes = snapshot
+            .levels
+            .iter()
+            .map(|(_, ssts)| {
+                ssts.iter()
+                    .map(|x| snapshot.sstables[x].table_size())
+                    .sum::<u64>()
+            })
+            .collect::<Vec<_>>();
         // compaction triggered by space amplification ratio
-        let mut size = 0;
-        for id in 0..(snapshot.levels.len() - 1) {
-            size += snapshot.levels[id].1.len();
-        }
-        let space_amp_ratio =
-            (size as f64) / (snapshot.levels.last().unwrap().1.len() as f64) * 100.0;
-        if space_amp_ratio >= self.options.max_size_amplification_percent as f64 {
+        let size = tier_sizes[..tier_sizes.len() - 1].iter().sum::<u64>();
+        let space_amp_ratio = (size as f64) / (*tier_sizes.last().unwrap() as f64) * 100.0;
+        if space_amp_ratio >= self.options.max_size_amplification_percent as f64
+            && num_free_tiers == snapshot.levels.len()
+        {
             println!(
                 "compaction triggered by space amplification ratio: {}",
                 space_amp_ratio
@@ -73,9 +114,9 @@ impl TieredCompactionController {
         let size_ratio_trigger = (100.0 + self.options.size_ratio as f64) / 100.0;
         // compaction triggered by size ratio
         let mut size = 0;
-        for id in 0..(snapshot.levels.len() - 1) {
-            size += snapshot.levels[id].1.len();
-            let next_level_size = snapshot.levels[id + 1].1.len();
+        for id in 0..(snapshot.levels.len() - 1).min(num_free_tiers) {
+            size += tier_sizes[id];
+            let next_level_size = tier_sizes[id + 1];
             let current_size_ratio = next_level_size as f64 / size as f64;
             if current_size_ratio > size_ratio_trigger && id + 1 >= self.options.min_merge_width {
                 println!(
@@ -99,7 +140,11 @@ impl TieredCompactionController {
         let num_tiers_to_take = snapshot
             .levels
             .len()
-            .min(self.options.max_merge_width.unwrap_or(usize::MAX));
+            .min(self.options.max_merge_width.unwrap_or(usize::MAX))
+            .min(num_free_tiers);
+        if num_tiers_to_take < 2 && num_free_tiers < snapshot.levels.len() {
+            return None;
+        }
         println!("compaction triggered by reducing sorted runs");
         Some(TieredCompactionTask {
             tiers: snapshot
@@ -108,48 +153,106 @@ impl TieredCompactionController {
                 .take(num_tiers_to_take)
                 .cloned()
                 .collect::<Vec<_>>(),
-            bottom_tier_included: snapshot.levels.len() >= num_tiers_to_take,
+            bottom_tier_included: snapshot.levels.len() == num_tiers_to_take,
         })
     }
 
+    /// Merges the oldest tier with an SST with too many deletes or too old with all tiers below it, so that the deletes
+    /// reach the bottom tier and are dropped. An SST with too many deletes waits until all of its versions are below
+    /// `watermark`, so that compacting it drops the deletes instead of rewriting them. The output tier takes the place
+    /// of the bottom tier, which works along with compactions of the newer tiers.
+    fn generate_marked_compaction_task(
+        &self,
+        snapshot: &LsmStorageState,
+        in_progress: &HashSet<usize>,
+        watermark: u64,
+    ) -> Option<TieredCompactionTask> {
+        if !self.triggers.is_enabled() {
+            return None;
+        }
+        let now = self.clock.now_millis();
+        // The tiers below the picked one must not be being compacted.
+        let num_free_bottom_tiers = snapshot
+            .levels
+            .iter()
+            .rev()
+            .take_while(|(_, ssts)| ssts.iter().all(|x| !in_progress.contains(x)))
+            .count();
+        let first_free_tier = snapshot.levels.len() - num_free_bottom_tiers;
+        for (idx, (tier_id, ssts)) in snapshot
+            .levels
+            .iter()
+            .enumerate()
+            .skip(first_free_tier)
+            .rev()
+        {
+            let Some(mark) = ssts.iter().find_map(|id| {
+                let sst = &snapshot.sstables[id];
+                self.triggers
+                    .mark(sst, now)
+                    .filter(|mark| *mark != CompactionMark::Tombstones || sst.max_ts() <= watermark)
+            }) else {
+                continue;
+            };
+            println!("compaction triggered by {mark:?} of an SST in tier {tier_id}");
+            return Some(TieredCompactionTask {
+                tiers: snapshot.levels[idx..].to_vec(),
+                bottom_tier_included: true,
+            });
+        }
+        None
+    }
+
     pub fn apply_compaction_result(
         &self,
         snapshot: &LsmStorageState,
         task: &TieredCompactionTask,
         output: &[usize],
     ) -> (LsmStorageState, Vec<usize>) {
-        assert!(
-            snapshot.l0_sstables.is_empty(),
-            "should not add l0 ssts in tiered compaction"
-        );
-        let mut snapshot = snapshot.clone();
-        let mut tier_to_remove = task
-            .tiers
-            .iter()
-            .map(|(x, y)| (*x, y))
-            .collect::<HashMap<_, _>>();
-        let mut levels = Vec::new();
-        let mut new_tier_added = false;
-        let mut files_to_remove = Vec::new();
-        for (tier_id, files) in &snapshot.levels {
-            if let Some(ffiles) = tier_to_remove.remove(tier_id) {
-                // the tier should be removed
-                assert_eq!(ffiles, files, "file changed after issuing compaction task");
-                files_to_remove.extend(ffiles.iter().copied());
-            } else {
-                // retain the tier
-                levels.push((*tier_id, files.clone()));
-            }
-            if tier_to_remove.is_empty() && !new_tier_added {
-                // add the compacted tier to the LSM tree
-                new_tier_added = true;
+        apply_tiered_compaction_result(snapshot, task, output)
+    }
+}
+
+/// Replace the tiers of the task with the output tier, which takes the place of the oldest of them. It is shared by
+/// tiered and hybrid compaction, which keep their sorted runs in the same layout.
+pub(crate) fn apply_tiered_compaction_result(
+    snapshot: &LsmStorageState,
+    task: &TieredCompactionTask,
+    output: &[usize],
+) -> (LsmStorageState, Vec<usize>) {
+    assert!(
+        snapshot.l0_sstables.is_empty(),
+        "should not add l0 ssts in tiered compaction"
+    );
+    let mut snapshot = snapshot.clone();
+    let mut tier_to_remove = task
+        .tiers
+        .iter()
+        .map(|(x, y)| (*x, y))
+        .collect::<HashMap<_, _>>();
+    let mut levels = Vec::new();
+    let mut new_tier_added = false;
+    let mut files_to_remove = Vec::new();
+    for (tier_id, files) in &snapshot.levels {
+        if let Some(ffiles) = tier_to_remove.remove(tier_id) {
+            // the tier should be removed
+            assert_eq!(ffiles, files, "file changed after issuing compaction task");
+            files_to_remove.extend(ffiles.iter().copied());
+        } else {
+            // retain the tier
+            levels.push((*tier_id, files.clone()));
+        }
+        if tier_to_remove.is_empty() && !new_tier_added {
+            // add the compacted tier to the LSM tree, unless all keys are deleted
+            new_tier_added = true;
+            if !output.is_empty() {
                 levels.push((output[0], output.to_vec()));
             }
         }
-        if !tier_to_remove.is_empty() {
-            unreachable!("some tiers not found??");
-        }
-        snapshot.levels = levels;
-        (snapshot, files_to_remove)
     }
+    if !tier_to_remove.is_empty() {
+        unreachable!("some tiers not found??");
+    }
+    snapshot.levels = levels;
+    (snapshot, files_to_remove)
 }
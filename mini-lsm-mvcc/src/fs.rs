@@ -0,0 +1,498 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod fault_injection;
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use parking_lot::{Mutex, RwLock};
+
+pub use fault_injection::{Fault, FaultInjectionFileSystem};
+
+/// The file operations used by the storage engine. All files of a database, including the SSTs, the WALs, the
+/// manifest and the options, are accessed through this trait, so that the engine can run on storage other than the
+/// local disk.
+pub trait FileSystem: Send + Sync {
+    /// Create a new file for writing. Fails if the file already exists.
+    fn create(&self, path: &Path) -> Result<Box<dyn WritableFile>>;
+
+    /// Open an existing file for writing, starting at offset 0.
+    fn open_writable(&self, path: &Path) -> Result<Box<dyn WritableFile>>;
+
+    /// Open an existing file for random reads.
+    fn open(&self, path: &Path) -> Result<Box<dyn RandomAccessFile>>;
+
+    /// Read the whole file.
+    fn read(&self, path: &Path) -> Result<Vec<u8>>;
+
+    /// Create or truncate the file, write `data` and sync it.
+    fn write(&self, path: &Path, data: &[u8]) -> Result<()>;
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Create `dst` as another name of the file at `src`.
+    fn hard_link(&self, src: &Path, dst: &Path) -> Result<()>;
+
+    fn remove_file(&self, path: &Path) -> Result<()>;
+
+    fn remove_dir_all(&self, path: &Path) -> Result<()>;
+
+    fn create_dir_all(&self, path: &Path) -> Result<()>;
+
+    /// List the paths of the files and directories in a directory.
+    fn list_dir(&self, path: &Path) -> Result<Vec<PathBuf>>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    fn file_size(&self, path: &Path) -> Result<u64>;
+
+    fn modified(&self, path: &Path) -> Result<SystemTime>;
+
+    /// Persist the creation, removal and renaming of the files in a directory.
+    fn sync_dir(&self, path: &Path) -> Result<()>;
+}
+
+impl std::fmt::Debug for dyn FileSystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FileSystem")
+    }
+}
+
+/// A file opened for writing. Writes go to the current position, which starts at 0 and can be moved with `seek`.
+pub trait WritableFile: Write + Send {
+    fn seek(&mut self, offset: u64) -> Result<()>;
+
+    /// Allocate space for the file up to `len` bytes. The allocated space reads as zeroes.
+    fn preallocate(&mut self, len: u64) -> Result<()>;
+
+    /// Persist the data and the file size, but not necessarily other metadata.
+    fn sync_data(&mut self) -> Result<()>;
+
+    fn sync_all(&mut self) -> Result<()>;
+}
+
+/// A file opened for random reads.
+pub trait RandomAccessFile: Send + Sync {
+    /// Fill `buf` with the data at `offset`. Fails if the file is shorter.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()>;
+
+    /// The size of the file when it was opened.
+    fn size(&self) -> u64;
+}
+
+/// Copy a file, possibly between two file systems, and sync the copy.
+pub fn copy_file(
+    src_fs: &dyn FileSystem,
+    src: &Path,
+    dst_fs: &dyn FileSystem,
+    dst: &Path,
+) -> Result<()> {
+    dst_fs.write(dst, &src_fs.read(src)?)
+}
+
+/// The file system used when none is specified. Set the environment variable `MINI_LSM_FS=memory` to keep all
+/// databases of the process in memory, e.g., to run the test suite in RAM.
+pub fn default_fs() -> Arc<dyn FileSystem> {
+    static DEFAULT_FS: OnceLock<Arc<dyn FileSystem>> = OnceLock::new();
+    DEFAULT_FS
+        .get_or_init(|| match std::env::var("MINI_LSM_FS").as_deref() {
+            Ok("memory") => Arc::new(MemoryFileSystem::new()),
+            _ => Arc::new(DiskFileSystem),
+        })
+        .clone()
+}
+
+/// The local file system.
+pub struct DiskFileSystem;
+
+struct DiskWritableFile(File);
+
+impl Write for DiskWritableFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl WritableFile for DiskWritableFile {
+    fn seek(&mut self, offset: u64) -> Result<()> {
+        self.0.seek(SeekFrom::Start(offset))?;
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn preallocate(&mut self, len: u64) -> Result<()> {
+        use std::os::fd::AsRawFd;
+        let ret = unsafe { libc::fallocate(self.0.as_raw_fd(), 0, 0, len as libc::off_t) };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error()).context("failed to preallocate file");
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn preallocate(&mut self, len: u64) -> Result<()> {
+        if self.0.metadata()?.len() < len {
+            self.0.set_len(len)?;
+        }
+        Ok(())
+    }
+
+    fn sync_data(&mut self) -> Result<()> {
+        self.0.sync_data()?;
+        Ok(())
+    }
+
+    fn sync_all(&mut self) -> Result<()> {
+        self.0.sync_all()?;
+        Ok(())
+    }
+}
+
+struct DiskRandomAccessFile(File, u64);
+
+impl RandomAccessFile for DiskRandomAccessFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        use std::os::unix::fs::FileExt;
+        self.0.read_exact_at(buf, offset)?;
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.1
+    }
+}
+
+impl FileSystem for DiskFileSystem {
+    fn create(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        Ok(Box::new(DiskWritableFile(file)))
+    }
+
+    fn open_writable(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Box::new(DiskWritableFile(file)))
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn RandomAccessFile>> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Box::new(DiskRandomAccessFile(file, size)))
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        File::open(path)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> Result<()> {
+        let mut file = File::create(path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        std::fs::rename(from, to)?;
+        Ok(())
+    }
+
+    fn hard_link(&self, src: &Path, dst: &Path) -> Result<()> {
+        std::fs::hard_link(src, dst)?;
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<()> {
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        std::fs::create_dir_all(path)?;
+        Ok(())
+    }
+
+    fn list_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(path)? {
+            paths.push(entry?.path());
+        }
+        Ok(paths)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn file_size(&self, path: &Path) -> Result<u64> {
+        Ok(std::fs::metadata(path)?.len())
+    }
+
+    fn modified(&self, path: &Path) -> Result<SystemTime> {
+        Ok(std::fs::metadata(path)?.modified()?)
+    }
+
+    fn sync_dir(&self, path: &Path) -> Result<()> {
+        File::open(path)?.sync_all()?;
+        Ok(())
+    }
+}
+
+struct MemoryFile {
+    data: RwLock<Vec<u8>>,
+    modified: Mutex<SystemTime>,
+}
+
+impl MemoryFile {
+    fn new(data: Vec<u8>) -> Arc<Self> {
+        Arc::new(Self {
+            data: RwLock::new(data),
+            modified: Mutex::new(SystemTime::now()),
+        })
+    }
+}
+
+#[derive(Default)]
+struct MemoryFileSystemInner {
+    files: HashMap<PathBuf, Arc<MemoryFile>>,
+    dirs: HashSet<PathBuf>,
+}
+
+/// A file system that keeps all files in memory. Directories exist implicitly for the files in them, and everything
+/// is durable as soon as it is written.
+#[derive(Default)]
+pub struct MemoryFileSystem {
+    inner: Mutex<MemoryFileSystemInner>,
+}
+
+fn not_found(path: &Path) -> anyhow::Error {
+    anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::NotFound))
+        .context(format!("{} not found", path.display()))
+}
+
+fn already_exists(path: &Path) -> anyhow::Error {
+    anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::AlreadyExists))
+        .context(format!("{} already exists", path.display()))
+}
+
+struct MemoryWritableFile {
+    file: Arc<MemoryFile>,
+    pos: usize,
+}
+
+impl Write for MemoryWritableFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut data = self.file.data.write();
+        let end = self.pos + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[self.pos..end].copy_from_slice(buf);
+        self.pos = end;
+        *self.file.modified.lock() = SystemTime::now();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WritableFile for MemoryWritableFile {
+    fn seek(&mut self, offset: u64) -> Result<()> {
+        self.pos = offset as usize;
+        Ok(())
+    }
+
+    fn preallocate(&mut self, len: u64) -> Result<()> {
+        let mut data = self.file.data.write();
+        if data.len() < len as usize {
+            data.resize(len as usize, 0);
+        }
+        Ok(())
+    }
+
+    fn sync_data(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync_all(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct MemoryRandomAccessFile(Arc<MemoryFile>, u64);
+
+impl RandomAccessFile for MemoryRandomAccessFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let data = self.0.data.read();
+        let offset = offset as usize;
+        let Some(src) = data.get(offset..offset + buf.len()) else {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        };
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.1
+    }
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn get(&self, path: &Path) -> Result<Arc<MemoryFile>> {
+        let inner = self.inner.lock();
+        inner
+            .files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| not_found(path))
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn create(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let mut inner = self.inner.lock();
+        if inner.files.contains_key(path) {
+            return Err(already_exists(path));
+        }
+        let file = MemoryFile::new(Vec::new());
+        inner.files.insert(path.to_path_buf(), file.clone());
+        Ok(Box::new(MemoryWritableFile { file, pos: 0 }))
+    }
+
+    fn open_writable(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        Ok(Box::new(MemoryWritableFile {
+            file: self.get(path)?,
+            pos: 0,
+        }))
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn RandomAccessFile>> {
+        let file = self.get(path)?;
+        let size = file.data.read().len() as u64;
+        Ok(Box::new(MemoryRandomAccessFile(file, size)))
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        Ok(self.get(path)?.data.read().clone())
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> Result<()> {
+        let mut inner = self.inner.lock();
+        inner
+            .files
+            .insert(path.to_path_buf(), MemoryFile::new(data.to_vec()));
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let file = inner.files.remove(from).ok_or_else(|| not_found(from))?;
+        inner.files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn hard_link(&self, src: &Path, dst: &Path) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let file = inner
+            .files
+            .get(src)
+            .cloned()
+            .ok_or_else(|| not_found(src))?;
+        if inner.files.contains_key(dst) {
+            return Err(already_exists(dst));
+        }
+        inner.files.insert(dst.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        let mut inner = self.inner.lock();
+        inner.files.remove(path).ok_or_else(|| not_found(path))?;
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<()> {
+        let mut inner = self.inner.lock();
+        inner.files.retain(|x, _| !x.starts_with(path));
+        inner.dirs.retain(|x| !x.starts_with(path));
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        let mut inner = self.inner.lock();
+        inner.dirs.extend(path.ancestors().map(|x| x.to_path_buf()));
+        Ok(())
+    }
+
+    fn list_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let inner = self.inner.lock();
+        let mut children = HashSet::new();
+        for child in inner.files.keys().chain(inner.dirs.iter()) {
+            // The direct child of `path` on the way to a file is an implicit directory.
+            if let Ok(relative) = child.strip_prefix(path)
+                && let Some(name) = relative.components().next()
+            {
+                children.insert(path.join(name));
+            }
+        }
+        Ok(children.into_iter().collect())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let inner = self.inner.lock();
+        inner.files.contains_key(path)
+            || inner
+                .files
+                .keys()
+                .chain(inner.dirs.iter())
+                .any(|x| x.starts_with(path))
+    }
+
+    fn file_size(&self, path: &Path) -> Result<u64> {
+        Ok(self.get(path)?.data.read().len() as u64)
+    }
+
+    fn modified(&self, path: &Path) -> Result<SystemTime> {
+        Ok(*self.get(path)?.modified.lock())
+    }
+
+    fn sync_dir(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+}
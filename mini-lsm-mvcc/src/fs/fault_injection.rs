@@ -0,0 +1,451 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{Result, bail};
+use parking_lot::Mutex;
+
+use super::{FileSystem, RandomAccessFile, WritableFile, already_exists, not_found};
+
+/// A fault to inject into an operation of a [`FaultInjectionFileSystem`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The operation fails with an I/O error and has no effect.
+    Error,
+    /// The first half of the write reaches the disk together with everything written to the file before it, and then
+    /// the file system crashes. Other operations crash the file system without taking effect.
+    TornWrite,
+}
+
+struct FaultFile {
+    /// The content seen by reads.
+    data: Vec<u8>,
+    /// The content that survives a crash.
+    synced: Vec<u8>,
+    modified: SystemTime,
+}
+
+type FileRef = Arc<Mutex<FaultFile>>;
+
+impl FaultFile {
+    fn new(data: Vec<u8>, synced: Vec<u8>) -> FileRef {
+        Arc::new(Mutex::new(Self {
+            data,
+            synced,
+            modified: SystemTime::now(),
+        }))
+    }
+
+    fn write_at(&mut self, pos: usize, buf: &[u8]) {
+        let end = pos + buf.len();
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        self.data[pos..end].copy_from_slice(buf);
+        self.modified = SystemTime::now();
+    }
+}
+
+#[derive(Default)]
+struct FaultState {
+    /// The directory entries seen by the process.
+    files: HashMap<PathBuf, FileRef>,
+    /// The directory entries that survive a crash.
+    synced_files: HashMap<PathBuf, FileRef>,
+    dirs: HashSet<PathBuf>,
+    op_count: u64,
+    sync_count: u64,
+    faults: HashMap<u64, Fault>,
+    crash_after_syncs: Option<u64>,
+    crashed: bool,
+}
+
+fn crashed_error() -> anyhow::Error {
+    anyhow::Error::new(std::io::Error::other("the file system has crashed"))
+}
+
+impl FaultState {
+    fn check_alive(&self) -> Result<()> {
+        if self.crashed {
+            return Err(crashed_error());
+        }
+        Ok(())
+    }
+
+    /// Start a modifying operation, and return the fault to apply to it, if any.
+    fn begin_op(&mut self) -> Result<Option<Fault>> {
+        self.check_alive()?;
+        self.op_count += 1;
+        match self.faults.remove(&self.op_count) {
+            Some(Fault::Error) => Err(anyhow::Error::new(std::io::Error::other(format!(
+                "injected fault at operation {}",
+                self.op_count
+            )))),
+            fault => Ok(fault),
+        }
+    }
+
+    /// Start a modifying operation that is not a write, which a torn write fault crashes the file system at.
+    fn begin_non_write_op(&mut self) -> Result<()> {
+        if self.begin_op()?.is_some() {
+            self.crashed = true;
+            return Err(crashed_error());
+        }
+        Ok(())
+    }
+
+    fn end_sync(&mut self) {
+        self.sync_count += 1;
+        if self
+            .crash_after_syncs
+            .is_some_and(|syncs| self.sync_count >= syncs)
+        {
+            self.crashed = true;
+        }
+    }
+
+    fn get(&self, path: &Path) -> Result<FileRef> {
+        self.files.get(path).cloned().ok_or_else(|| not_found(path))
+    }
+}
+
+/// An in-memory file system for crash testing. It keeps track of the data and the directory entries that have not been
+/// synced, and [`crash`](Self::crash) drops all of them, like a power loss would. Directories themselves are created
+/// and removed durably.
+///
+/// The operations that modify the file system or a file are numbered from 1, and faults can be injected into any of
+/// them. The file system can also crash at a sync point, after which every operation fails.
+#[derive(Default)]
+pub struct FaultInjectionFileSystem {
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl FaultInjectionFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inject a fault into the operation with the given number.
+    pub fn inject_fault(&self, op: u64, fault: Fault) {
+        self.state.lock().faults.insert(op, fault);
+    }
+
+    /// Crash the file system once the given number of sync points have completed. A sync point is a `sync_data`,
+    /// `sync_all`, `sync_dir` or `write` call.
+    pub fn crash_after_syncs(&self, syncs: u64) {
+        let mut state = self.state.lock();
+        state.crash_after_syncs = Some(syncs);
+        if state.sync_count >= syncs {
+            state.crashed = true;
+        }
+    }
+
+    /// The number of modifying operations so far.
+    pub fn op_count(&self) -> u64 {
+        self.state.lock().op_count
+    }
+
+    /// The number of sync points so far.
+    pub fn sync_count(&self) -> u64 {
+        self.state.lock().sync_count
+    }
+
+    pub fn is_crashed(&self) -> bool {
+        self.state.lock().crashed
+    }
+
+    /// Crash the file system, and return a new file system with what survived: the synced data of the files under the
+    /// synced directory entries. The old file system stays crashed, so that the threads of the crashed process cannot
+    /// affect the new one.
+    pub fn crash(&self) -> Self {
+        let mut state = self.state.lock();
+        state.crashed = true;
+        // Hard links must still share the same file after the crash.
+        let mut copies: HashMap<*const Mutex<FaultFile>, FileRef> = HashMap::new();
+        let mut files = HashMap::new();
+        for (path, file) in &state.synced_files {
+            let copy = copies
+                .entry(Arc::as_ptr(file))
+                .or_insert_with(|| {
+                    let synced = file.lock().synced.clone();
+                    FaultFile::new(synced.clone(), synced)
+                })
+                .clone();
+            files.insert(path.clone(), copy);
+        }
+        Self {
+            state: Arc::new(Mutex::new(FaultState {
+                synced_files: files.clone(),
+                files,
+                dirs: state.dirs.clone(),
+                ..Default::default()
+            })),
+        }
+    }
+}
+
+struct FaultWritableFile {
+    state: Arc<Mutex<FaultState>>,
+    file: FileRef,
+    pos: usize,
+}
+
+impl Write for FaultWritableFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut state = self.state.lock();
+        let fault = state.begin_op().map_err(std::io::Error::other)?;
+        let mut file = self.file.lock();
+        if fault == Some(Fault::TornWrite) {
+            let torn = &buf[..buf.len() / 2];
+            file.write_at(self.pos, torn);
+            file.synced = file.data.clone();
+            state.crashed = true;
+            return Err(std::io::Error::other("torn write"));
+        }
+        file.write_at(self.pos, buf);
+        self.pos += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WritableFile for FaultWritableFile {
+    fn seek(&mut self, offset: u64) -> Result<()> {
+        self.state.lock().check_alive()?;
+        self.pos = offset as usize;
+        Ok(())
+    }
+
+    fn preallocate(&mut self, len: u64) -> Result<()> {
+        self.state.lock().begin_non_write_op()?;
+        let mut file = self.file.lock();
+        if file.data.len() < len as usize {
+            file.data.resize(len as usize, 0);
+        }
+        Ok(())
+    }
+
+    fn sync_data(&mut self) -> Result<()> {
+        let mut state = self.state.lock();
+        state.begin_non_write_op()?;
+        let mut file = self.file.lock();
+        file.synced = file.data.clone();
+        state.end_sync();
+        Ok(())
+    }
+
+    fn sync_all(&mut self) -> Result<()> {
+        self.sync_data()
+    }
+}
+
+struct FaultRandomAccessFile {
+    state: Arc<Mutex<FaultState>>,
+    file: FileRef,
+    size: u64,
+}
+
+impl RandomAccessFile for FaultRandomAccessFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        self.state.lock().check_alive()?;
+        let file = self.file.lock();
+        let offset = offset as usize;
+        let Some(src) = file.data.get(offset..offset + buf.len()) else {
+            bail!(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        };
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl FileSystem for FaultInjectionFileSystem {
+    fn create(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let mut state = self.state.lock();
+        state.begin_non_write_op()?;
+        if state.files.contains_key(path) {
+            return Err(already_exists(path));
+        }
+        let file = FaultFile::new(Vec::new(), Vec::new());
+        state.files.insert(path.to_path_buf(), file.clone());
+        Ok(Box::new(FaultWritableFile {
+            state: self.state.clone(),
+            file,
+            pos: 0,
+        }))
+    }
+
+    fn open_writable(&self, path: &Path) -> Result<Box<dyn WritableFile>> {
+        let state = self.state.lock();
+        state.check_alive()?;
+        Ok(Box::new(FaultWritableFile {
+            state: self.state.clone(),
+            file: state.get(path)?,
+            pos: 0,
+        }))
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn RandomAccessFile>> {
+        let state = self.state.lock();
+        state.check_alive()?;
+        let file = state.get(path)?;
+        let size = file.lock().data.len() as u64;
+        Ok(Box::new(FaultRandomAccessFile {
+            state: self.state.clone(),
+            file,
+            size,
+        }))
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        let state = self.state.lock();
+        state.check_alive()?;
+        Ok(state.get(path)?.lock().data.clone())
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> Result<()> {
+        let mut state = self.state.lock();
+        let fault = state.begin_op()?;
+        let data = if fault == Some(Fault::TornWrite) {
+            &data[..data.len() / 2]
+        } else {
+            data
+        };
+        // Like a file that is created or truncated, written and synced, the data is durable but a new directory entry
+        // is not.
+        if let Some(file) = state.files.get(path) {
+            let mut file = file.lock();
+            file.data = data.to_vec();
+            file.synced = data.to_vec();
+            file.modified = SystemTime::now();
+        } else {
+            state.files.insert(
+                path.to_path_buf(),
+                FaultFile::new(data.to_vec(), data.to_vec()),
+            );
+        }
+        if fault.is_some() {
+            state.crashed = true;
+            bail!(std::io::Error::other("torn write"));
+        }
+        state.end_sync();
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut state = self.state.lock();
+        state.begin_non_write_op()?;
+        let file = state.files.remove(from).ok_or_else(|| not_found(from))?;
+        state.files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn hard_link(&self, src: &Path, dst: &Path) -> Result<()> {
+        let mut state = self.state.lock();
+        state.begin_non_write_op()?;
+        let file = state.get(src)?;
+        if state.files.contains_key(dst) {
+            return Err(already_exists(dst));
+        }
+        state.files.insert(dst.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        let mut state = self.state.lock();
+        state.begin_non_write_op()?;
+        state.files.remove(path).ok_or_else(|| not_found(path))?;
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<()> {
+        let mut state = self.state.lock();
+        state.begin_non_write_op()?;
+        state.files.retain(|x, _| !x.starts_with(path));
+        state.synced_files.retain(|x, _| !x.starts_with(path));
+        state.dirs.retain(|x| !x.starts_with(path));
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        let mut state = self.state.lock();
+        state.begin_non_write_op()?;
+        state.dirs.extend(path.ancestors().map(|x| x.to_path_buf()));
+        Ok(())
+    }
+
+    fn list_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let state = self.state.lock();
+        state.check_alive()?;
+        let mut children = HashSet::new();
+        for child in state.files.keys().chain(state.dirs.iter()) {
+            if let Ok(relative) = child.strip_prefix(path)
+                && let Some(name) = relative.components().next()
+            {
+                children.insert(path.join(name));
+            }
+        }
+        Ok(children.into_iter().collect())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let state = self.state.lock();
+        state
+            .files
+            .keys()
+            .chain(state.dirs.iter())
+            .any(|x| x.starts_with(path))
+    }
+
+    fn file_size(&self, path: &Path) -> Result<u64> {
+        let state = self.state.lock();
+        state.check_alive()?;
+        Ok(state.get(path)?.lock().data.len() as u64)
+    }
+
+    fn modified(&self, path: &Path) -> Result<SystemTime> {
+        let state = self.state.lock();
+        state.check_alive()?;
+        Ok(state.get(path)?.lock().modified)
+    }
+
+    fn sync_dir(&self, path: &Path) -> Result<()> {
+        let mut state = self.state.lock();
+        state.begin_non_write_op()?;
+        let FaultState {
+            files,
+            synced_files,
+            ..
+        } = &mut *state;
+        synced_files.retain(|x, _| x.parent() != Some(path) || files.contains_key(x));
+        for (child, file) in files.iter() {
+            if child.parent() == Some(path) {
+                synced_files.insert(child.clone(), file.clone());
+            }
+        }
+        state.end_sync();
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use anyhow::Result;
+
+use crate::fs::FileSystem;
+use crate::lsm_storage::LsmStorageInner;
+
+/// Protects the SSTs created by a job from the garbage collector until they are added to the LSM state. All SSTs
+/// created by the job have ids no smaller than the lower bound.
+pub(crate) struct PendingOutputs<'a> {
+    storage: &'a LsmStorageInner,
+    lower_bound: usize,
+}
+
+impl Drop for PendingOutputs<'_> {
+    fn drop(&mut self) {
+        let mut pending_outputs = self.storage.pending_outputs.lock();
+        let idx = pending_outputs
+            .iter()
+            .position(|x| *x == self.lower_bound)
+            .unwrap();
+        pending_outputs.swap_remove(idx);
+    }
+}
+
+fn remove_file_if_exists(fs: &dyn FileSystem, path: &Path) -> Result<()> {
+    match fs.remove_file(path) {
+        Err(e)
+            if e.downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) =>
+        {
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+/// Pauses the deletion of obsolete files until dropped, so that files referenced by an older state stay accessible.
+/// Files that become obsolete in the meantime are deleted by the garbage collector later.
+pub(crate) struct FileDeletionPause<'a> {
+    storage: &'a LsmStorageInner,
+}
+
+impl Drop for FileDeletionPause<'_> {
+    fn drop(&mut self) {
+        self.storage
+            .file_deletion_pause
+            .fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl LsmStorageInner {
+    pub(crate) fn pause_file_deletion(&self) -> FileDeletionPause<'_> {
+        self.file_deletion_pause.fetch_add(1, Ordering::SeqCst);
+        FileDeletionPause { storage: self }
+    }
+
+    fn file_deletion_paused(&self) -> bool {
+        self.file_deletion_pause.load(Ordering::SeqCst) > 0
+    }
+
+    /// Remove a file that is no longer referenced. The file might have been removed by the garbage collector already,
+    /// and is left to the garbage collector if file deletion is paused.
+    pub(crate) fn remove_obsolete_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        if self.file_deletion_paused() {
+            return Ok(());
+        }
+        remove_file_if_exists(self.fs(), path.as_ref())
+    }
+
+    pub(crate) fn register_pending_outputs(&self) -> PendingOutputs<'_> {
+        let mut pending_outputs = self.pending_outputs.lock();
+        let lower_bound = self.next_sst_id.load(Ordering::SeqCst);
+        pending_outputs.push(lower_bound);
+        PendingOutputs {
+            storage: self,
+            lower_bound,
+        }
+    }
+
+    /// List the SST and WAL files in the directory that are not referenced by the LSM state, without deleting them.
+    pub fn list_obsolete_files(&self) -> Result<Vec<PathBuf>> {
+        let _state_lock = self.state_lock.lock();
+        self.find_obsolete_files(true)
+    }
+
+    /// Delete the SST and WAL files in the directory that are not referenced by the LSM state, e.g., the outputs of a
+    /// compaction that crashed before it was recorded in the manifest. Returns the deleted files.
+    pub fn delete_obsolete_files(&self) -> Result<Vec<PathBuf>> {
+        self.delete_obsolete_files_inner(true)
+    }
+
+    /// Delete obsolete files when opening the database. No job is running, so files with ids beyond the recovered
+    /// state are left over from a crash, and are deleted as well.
+    pub(crate) fn delete_obsolete_files_on_open(&self) -> Result<Vec<PathBuf>> {
+        self.delete_obsolete_files_inner(false)
+    }
+
+    fn delete_obsolete_files_inner(&self, protect_new_files: bool) -> Result<Vec<PathBuf>> {
+        // Flushes and new memtables are done with the state lock held, so that their files are always in the state
+        // when we see them.
+        let _state_lock = self.state_lock.lock();
+        if self.file_deletion_paused() {
+            return Ok(Vec::new());
+        }
+        let files = self.find_obsolete_files(protect_new_files)?;
+        for file in &files {
+            // The file might be removed by the compaction that made it obsolete at the same time.
+            remove_file_if_exists(self.fs(), file)?;
+        }
+        if !files.is_empty() {
+            println!("removed {} obsolete files: {:?}", files.len(), files);
+            self.sync_dir()?;
+        }
+        Ok(files)
+    }
+
+    fn find_obsolete_files(&self, protect_new_files: bool) -> Result<Vec<PathBuf>> {
+        // Compactions allocate ids for their outputs after registering, so files with larger ids may be in progress.
+        let protected_lower_bound = if protect_new_files {
+            let pending_outputs = self.pending_outputs.lock();
+            pending_outputs
+                .iter()
+                .copied()
+                .chain(std::iter::once(self.next_sst_id.load(Ordering::SeqCst)))
+                .min()
+                .unwrap()
+        } else {
+            usize::MAX
+        };
+        let snapshot = self.state.read().clone();
+        let live_ssts = snapshot.sstables.keys().copied().collect::<HashSet<_>>();
+        let live_wals = if self.options.enable_wal {
+            std::iter::once(snapshot.memtable.id())
+                .chain(snapshot.imm_memtables.iter().map(|x| x.id()))
+                .collect::<HashSet<_>>()
+        } else {
+            HashSet::new()
+        };
+
+        let mut files = Vec::new();
+        for path in self.fs().list_dir(&self.path)? {
+            let (Some(stem), Some(ext)) = (path.file_stem(), path.extension()) else {
+                continue;
+            };
+            let Some(id) = stem.to_str().and_then(|x| x.parse::<usize>().ok()) else {
+                continue;
+            };
+            let obsolete = match ext.to_str() {
+                Some("sst") => !live_ssts.contains(&id) && id < protected_lower_bound,
+                Some("wal") => !live_wals.contains(&id),
+                _ => false,
+            };
+            if obsolete {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    pub(crate) fn spawn_gc_thread(
+        self: &Arc<Self>,
+        rx: crossbeam_channel::Receiver<()>,
+    ) -> Result<Option<std::thread::JoinHandle<()>>> {
+        let Some(interval) = self.options.obsolete_file_gc_interval else {
+            return Ok(None);
+        };
+        let this = self.clone();
+        let handle = std::thread::spawn(move || {
+            let ticker = crossbeam_channel::tick(interval);
+            loop {
+                crossbeam_channel::select! {
+                    recv(ticker) -> _ => if let Err(e) = this.delete_obsolete_files() {
+                        eprintln!("obsolete file gc failed: {}", e);
+                    },
+                    recv(rx) -> _ => return
+                }
+            }
+        });
+        Ok(Some(handle))
+    }
+}
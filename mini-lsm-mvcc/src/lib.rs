@@ -12,17 +12,26 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod backup;
 pub mod block;
+pub mod checkpoint;
+pub mod clock;
 pub mod compact;
 pub mod debug;
+pub mod fs;
+pub mod gc;
 pub mod iterators;
 pub mod key;
 pub mod lsm_iterator;
 pub mod lsm_storage;
 pub mod manifest;
 pub mod mem_table;
+pub mod merge_operator;
 pub mod mvcc;
+pub mod rate_limiter;
+pub mod repair;
 pub mod table;
+mod value;
 pub mod wal;
 
 #[cfg(test)]
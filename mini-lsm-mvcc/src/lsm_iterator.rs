@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::ops::Bound;
+use std::sync::Arc;
 
 use anyhow::{Result, bail};
 use bytes::Bytes;
@@ -22,7 +23,9 @@ use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
 use crate::mem_table::MemTableIterator;
+use crate::merge_operator::{self, MergeOperator};
 use crate::table::SsTableIterator;
+use crate::value;
 
 /// Represents the internal type for an LSM iterator. This type will be changed across the course for multiple times.
 type LsmIteratorInner = TwoMergeIterator<
@@ -35,7 +38,13 @@ pub struct LsmIterator {
     end_bound: Bound<Bytes>,
     is_valid: bool,
     read_ts: u64,
+    /// The current time of the storage clock. Values that have expired by then are skipped like deletes.
+    now: u64,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
     prev_key: Vec<u8>,
+    /// The merged value of `prev_key` if its newest version is a merge operand. `inner` is then already past the
+    /// versions that were merged.
+    merged: Option<Bytes>,
 }
 
 impl LsmIterator {
@@ -43,13 +52,18 @@ impl LsmIterator {
         iter: LsmIteratorInner,
         end_bound: Bound<Bytes>,
         read_ts: u64,
+        now: u64,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
     ) -> Result<Self> {
         let mut iter = Self {
             is_valid: iter.is_valid(),
             inner: iter,
             end_bound,
             read_ts,
+            now,
+            merge_operator,
             prev_key: Vec::new(),
+            merged: None,
         };
         iter.move_to_key()?;
         Ok(iter)
@@ -57,16 +71,20 @@ impl LsmIterator {
 
     fn next_inner(&mut self) -> Result<()> {
         self.inner.next()?;
+        self.check_end_bound();
+        Ok(())
+    }
+
+    fn check_end_bound(&mut self) {
         if !self.inner.is_valid() {
             self.is_valid = false;
-            return Ok(());
+            return;
         }
         match self.end_bound.as_ref() {
             Bound::Unbounded => {}
             Bound::Included(key) => self.is_valid = self.inner.key().key_ref() <= key.as_ref(),
             Bound::Excluded(key) => self.is_valid = self.inner.key().key_ref() < key.as_ref(),
         }
-        Ok(())
     }
 
     fn move_to_key(&mut self) -> Result<()> {
@@ -91,12 +109,50 @@ impl LsmIterator {
             if self.inner.key().key_ref() != self.prev_key {
                 continue;
             }
-            if !self.inner.value().is_empty() {
+            if value::is_merge_operand(self.inner.value()) {
+                let merged = self.merge_operands()?;
+                if merged.is_empty() {
+                    // The merge deleted the key.
+                    self.check_end_bound();
+                    continue;
+                }
+                self.merged = Some(merged);
+                break;
+            }
+            if value::is_live(self.inner.value(), self.now) {
                 break;
             }
         }
         Ok(())
     }
+
+    /// Fold the merge operands of `prev_key`, starting from the current one, into the newest older value, and move
+    /// `inner` past them.
+    fn merge_operands(&mut self) -> Result<Bytes> {
+        let Some(merge_operator) = self.merge_operator.clone() else {
+            bail!("found a merge operand but no merge operator is set");
+        };
+        let mut operands = Vec::new();
+        let mut existing = None;
+        while self.inner.is_valid() && self.inner.key().key_ref() == self.prev_key {
+            let raw = self.inner.value();
+            if !value::is_merge_operand(raw) {
+                if value::is_live(raw, self.now) {
+                    existing = Some(Bytes::copy_from_slice(value::decode(raw)));
+                }
+                break;
+            }
+            operands.push(Bytes::copy_from_slice(value::decode(raw)));
+            self.inner.next()?;
+        }
+        let operands = operands.iter().rev().map(|x| &x[..]).collect::<Vec<_>>();
+        Ok(merge_operator::full_merge(
+            merge_operator.as_ref(),
+            &self.prev_key,
+            existing.as_deref(),
+            &operands,
+        ))
+    }
 }
 
 impl StorageIterator for LsmIterator {
@@ -107,15 +163,24 @@ impl StorageIterator for LsmIterator {
     }
 
     fn key(&self) -> &[u8] {
-        self.inner.key().key_ref()
+        &self.prev_key
     }
 
+    /// The encoded value. `TxnIterator` decodes it after merging with the writes of the transaction.
     fn value(&self) -> &[u8] {
-        self.inner.value()
+        match &self.merged {
+            Some(merged) => merged,
+            None => self.inner.value(),
+        }
     }
 
     fn next(&mut self) -> Result<()> {
-        self.next_inner()?;
+        if self.merged.take().is_some() {
+            // `inner` is already past the merged versions.
+            self.check_end_bound();
+        } else {
+            self.next_inner()?;
+        }
         self.move_to_key()?;
         Ok(())
     }
@@ -12,33 +12,43 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::borrow::Cow;
 use std::collections::{BTreeSet, HashMap};
-use std::fs::File;
 use std::ops::Bound;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::AtomicUsize;
+use std::time::{Duration, SystemTime};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail, ensure};
 use bytes::Bytes;
-use parking_lot::{Mutex, MutexGuard, RwLock};
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
+use serde::{Deserialize, Serialize};
 
 use crate::block::Block;
+use crate::clock::{Clock, default_clock};
 use crate::compact::{
-    CompactionController, CompactionOptions, LeveledCompactionController, LeveledCompactionOptions,
+    CompactionController, CompactionEvent, CompactionFilter, CompactionOptions, CompactionStats,
+    CompactionTriggers, FifoCompactionController, HybridCompactionController,
+    LeveledCompactionController, LeveledCompactionOptions, RunningCompactions,
     SimpleLeveledCompactionController, SimpleLeveledCompactionOptions, TieredCompactionController,
 };
+use crate::fs::{FileSystem, default_fs};
 use crate::iterators::StorageIterator;
 use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
 use crate::key::{self, KeySlice};
 use crate::lsm_iterator::{FusedIterator, LsmIterator};
-use crate::manifest::{Manifest, ManifestRecord};
+use crate::manifest::{LegacyManifestRecord, Manifest, ManifestRecord, VersionEdit};
 use crate::mem_table::{MemTable, map_bound, map_key_bound_plus_ts};
+use crate::merge_operator::MergeOperator;
 use crate::mvcc::LsmMvccInner;
 use crate::mvcc::txn::{Transaction, TxnIterator};
+use crate::rate_limiter::{IoPriority, RateLimiter};
 use crate::table::{FileObject, SsTable, SsTableBuilder, SsTableIterator};
+use crate::value;
+use crate::wal::Wal;
 
 pub type BlockCache = moka::sync::Cache<(usize, usize), Arc<Block>>;
 
@@ -58,20 +68,28 @@ pub struct LsmStorageState {
     pub sstables: HashMap<usize, Arc<SsTable>>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum WriteBatchRecord<T: AsRef<[u8]>> {
     Put(T, T),
     Del(T),
+    /// A put that is no longer visible from the given time, in milliseconds since the UNIX epoch on the clock of the
+    /// storage.
+    PutExpiring(T, T, u64),
+    /// A merge operand for the key, see `MiniLsm::merge`.
+    Merge(T, T),
 }
 
 impl LsmStorageState {
-    fn create(options: &LsmStorageOptions) -> Self {
+    pub(crate) fn create(options: &LsmStorageOptions) -> Self {
         let levels = match &options.compaction_options {
             CompactionOptions::Leveled(LeveledCompactionOptions { max_levels, .. })
             | CompactionOptions::Simple(SimpleLeveledCompactionOptions { max_levels, .. }) => (1
                 ..=*max_levels)
                 .map(|level| (level, Vec::new()))
                 .collect::<Vec<_>>(),
-            CompactionOptions::Tiered(_) => Vec::new(),
+            CompactionOptions::Tiered(_)
+            | CompactionOptions::Fifo(_)
+            | CompactionOptions::Hybrid(_) => Vec::new(),
             CompactionOptions::NoCompaction => vec![(1, Vec::new())],
         };
         Self {
@@ -84,7 +102,9 @@ impl LsmStorageState {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Options missing from an `OPTIONS` file written by an older version take their default values.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct LsmStorageOptions {
     // Block size in bytes
     pub block_size: usize,
@@ -93,8 +113,43 @@ pub struct LsmStorageOptions {
     // Maximum number of memtables in memory, flush to L0 when exceeding this limit
     pub num_memtable_limit: usize,
     pub compaction_options: CompactionOptions,
+    // Compact SSTs with too many deletes or too old in leveled and tiered compaction, even if the levels are within the
+    // size limits.
+    pub compaction_triggers: CompactionTriggers,
     pub enable_wal: bool,
     pub serializable: bool,
+    // Keep flushed WALs in the archive directory for this long instead of deleting them, so that
+    // `changes_since` can read them. `None` deletes WALs right after flush.
+    pub wal_archive_retention: Option<Duration>,
+    // Preallocate new WAL files to this size in bytes, so that appends do not change the file size. 0 disables it.
+    pub wal_preallocate_size: usize,
+    // Keep up to this many obsolete WAL files for reuse instead of deleting them. 0 disables it.
+    pub wal_recycle_pool_size: usize,
+    // Start a new manifest with a snapshot of the LSM structure once the manifest grows beyond this size in bytes.
+    // 0 disables it.
+    pub max_manifest_file_size: usize,
+    // Delete unreferenced SST and WAL files in the background at this interval. `None` only deletes them on open.
+    pub obsolete_file_gc_interval: Option<Duration>,
+    // Limit the bytes written by flushes and read and written by compactions per second. 0 disables it.
+    pub rate_limit_bytes_per_second: usize,
+    // Split a large compaction into up to this many key ranges that are compacted in parallel. 1 disables it.
+    pub max_subcompactions: usize,
+    // Cut a compaction output SST once it overlaps more than this many bytes of the level below its output level,
+    // which bounds the size of the next compaction of the SST (= LevelDB's `max_grandparent_overlap_bytes`). 0
+    // disables it.
+    pub max_grandparent_overlap_bytes: usize,
+    // Run up to this many compaction jobs at the same time.
+    pub max_background_compactions: usize,
+    // The file system that stores the database files. It is not persisted in the `OPTIONS` file.
+    #[serde(skip, default = "default_fs")]
+    pub fs: Arc<dyn FileSystem>,
+    // The clock that decides when keys written with a TTL expire. It is not persisted in the `OPTIONS` file.
+    #[serde(skip, default = "default_clock")]
+    pub clock: Arc<dyn Clock>,
+    // Combines the operands written with `merge`. It is not persisted in the `OPTIONS` file, and must be set again
+    // when opening a database that contains merge operands.
+    #[serde(skip)]
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
 }
 
 impl LsmStorageOptions {
@@ -103,9 +158,22 @@ impl LsmStorageOptions {
             block_size: 4096,
             target_sst_size: 2 << 20,
             compaction_options: CompactionOptions::NoCompaction,
+            compaction_triggers: CompactionTriggers::default(),
             enable_wal: false,
             num_memtable_limit: 50,
             serializable: false,
+            wal_archive_retention: None,
+            wal_preallocate_size: 0,
+            wal_recycle_pool_size: 0,
+            max_manifest_file_size: 1 << 20,
+            obsolete_file_gc_interval: Some(Duration::from_secs(60)),
+            rate_limit_bytes_per_second: 0,
+            max_subcompactions: 1,
+            max_grandparent_overlap_bytes: 0,
+            max_background_compactions: 1,
+            fs: default_fs(),
+            clock: default_clock(),
+            merge_operator: None,
         }
     }
 
@@ -114,9 +182,22 @@ impl LsmStorageOptions {
             block_size: 4096,
             target_sst_size: 2 << 20,
             compaction_options: CompactionOptions::NoCompaction,
+            compaction_triggers: CompactionTriggers::default(),
             enable_wal: false,
             num_memtable_limit: 2,
             serializable: false,
+            wal_archive_retention: None,
+            wal_preallocate_size: 0,
+            wal_recycle_pool_size: 0,
+            max_manifest_file_size: 1 << 20,
+            obsolete_file_gc_interval: Some(Duration::from_secs(60)),
+            rate_limit_bytes_per_second: 0,
+            max_subcompactions: 1,
+            max_grandparent_overlap_bytes: 0,
+            max_background_compactions: 1,
+            fs: default_fs(),
+            clock: default_clock(),
+            merge_operator: None,
         }
     }
 
@@ -125,14 +206,66 @@ impl LsmStorageOptions {
             block_size: 4096,
             target_sst_size: 1 << 20, // 1MB
             compaction_options,
+            compaction_triggers: CompactionTriggers::default(),
             enable_wal: false,
             num_memtable_limit: 2,
             serializable: false,
+            wal_archive_retention: None,
+            wal_preallocate_size: 0,
+            wal_recycle_pool_size: 0,
+            max_manifest_file_size: 1 << 20,
+            obsolete_file_gc_interval: Some(Duration::from_secs(60)),
+            rate_limit_bytes_per_second: 0,
+            max_subcompactions: 1,
+            max_grandparent_overlap_bytes: 0,
+            max_background_compactions: 1,
+            fs: default_fs(),
+            clock: default_clock(),
+            merge_operator: None,
+        }
+    }
+
+    /// Load the options persisted in the `OPTIONS` file of a database, if there is one.
+    pub fn load(fs: &dyn FileSystem, path: impl AsRef<Path>) -> Result<Option<Self>> {
+        let options_path = LsmStorageInner::path_of_options_static(path);
+        if !fs.exists(&options_path) {
+            return Ok(None);
+        }
+        let options = fs.read(&options_path)?;
+        Ok(Some(
+            serde_json::from_slice(&options).context("failed to parse OPTIONS")?,
+        ))
+    }
+}
+
+impl Default for LsmStorageOptions {
+    /// The options of the CLI, without compaction or WAL. The optional features are disabled.
+    fn default() -> Self {
+        Self {
+            block_size: 4096,
+            target_sst_size: 2 << 20, // 2MB
+            num_memtable_limit: 3,
+            compaction_options: CompactionOptions::NoCompaction,
+            compaction_triggers: CompactionTriggers::default(),
+            enable_wal: false,
+            serializable: false,
+            wal_archive_retention: None,
+            wal_preallocate_size: 0,
+            wal_recycle_pool_size: 0,
+            max_manifest_file_size: 1 << 20,
+            obsolete_file_gc_interval: Some(Duration::from_secs(60)),
+            rate_limit_bytes_per_second: 0,
+            max_subcompactions: 1,
+            max_grandparent_overlap_bytes: 0,
+            max_background_compactions: 1,
+            fs: default_fs(),
+            clock: default_clock(),
+            merge_operator: None,
         }
     }
 }
 
-fn range_overlap(
+pub(crate) fn range_overlap(
     user_begin: Bound<&[u8]>,
     user_end: Bound<&[u8]>,
     table_begin: KeySlice,
@@ -159,27 +292,103 @@ fn range_overlap(
     true
 }
 
-fn key_within(user_key: &[u8], table_begin: KeySlice, table_end: KeySlice) -> bool {
-    table_begin.key_ref() <= user_key && user_key <= table_end.key_ref()
+impl LsmStorageOptions {
+    /// Check if a database with these options, read back from its `OPTIONS` file, can be opened with `options`. Only
+    /// the compaction strategy and the WAL determine the layout of the database files, and the other options can be
+    /// changed freely between opens.
+    fn check_compatible(&self, options: &LsmStorageOptions) -> Result<()> {
+        match (&self.compaction_options, &options.compaction_options) {
+            (CompactionOptions::Leveled(persisted), CompactionOptions::Leveled(options))
+                if persisted.max_levels != options.max_levels =>
+            {
+                bail!(
+                    "the database has {} levels and cannot be opened with max_levels={}",
+                    persisted.max_levels,
+                    options.max_levels
+                );
+            }
+            (CompactionOptions::Simple(persisted), CompactionOptions::Simple(options))
+                if persisted.max_levels != options.max_levels =>
+            {
+                bail!(
+                    "the database has {} levels and cannot be opened with max_levels={}",
+                    persisted.max_levels,
+                    options.max_levels
+                );
+            }
+            (CompactionOptions::Leveled(_), CompactionOptions::Leveled(_))
+            | (CompactionOptions::Simple(_), CompactionOptions::Simple(_))
+            | (CompactionOptions::Tiered(_), CompactionOptions::Tiered(_))
+            | (CompactionOptions::Fifo(_), CompactionOptions::Fifo(_))
+            | (CompactionOptions::Hybrid(_), CompactionOptions::Hybrid(_))
+            | (CompactionOptions::NoCompaction, CompactionOptions::NoCompaction) => {}
+            (persisted, options) => {
+                bail!(
+                    "the database uses {} compaction and cannot be opened with {} compaction, use \
+                     `MiniLsm::migrate_compaction_strategy` to switch the compaction strategy",
+                    compaction_strategy_name(persisted),
+                    compaction_strategy_name(options)
+                );
+            }
+        }
+        if self.enable_wal && !options.enable_wal {
+            bail!(
+                "the database is written with WAL enabled and cannot be opened without WAL, as the unflushed \
+                 memtables would be lost"
+            );
+        }
+        Ok(())
+    }
+}
+
+fn compaction_strategy_name(options: &CompactionOptions) -> &'static str {
+    match options {
+        CompactionOptions::Leveled(_) => "leveled",
+        CompactionOptions::Tiered(_) => "tiered",
+        CompactionOptions::Simple(_) => "simple leveled",
+        CompactionOptions::Fifo(_) => "FIFO",
+        CompactionOptions::Hybrid(_) => "hybrid",
+        CompactionOptions::NoCompaction => "no",
+    }
 }
 
-#[derive(Clone, Debug)]
-pub enum CompactionFilter {
-    Prefix(Bytes),
+fn key_within(user_key: &[u8], table_begin: KeySlice, table_end: KeySlice) -> bool {
+    table_begin.key_ref() <= user_key && user_key <= table_end.key_ref()
 }
 
 /// The storage interface of the LSM tree.
 pub(crate) struct LsmStorageInner {
     pub(crate) state: Arc<RwLock<Arc<LsmStorageState>>>,
     pub(crate) state_lock: Mutex<()>,
-    path: PathBuf,
+    pub(crate) path: PathBuf,
     pub(crate) block_cache: Arc<BlockCache>,
-    next_sst_id: AtomicUsize,
+    pub(crate) next_sst_id: AtomicUsize,
     pub(crate) options: Arc<LsmStorageOptions>,
     pub(crate) compaction_controller: CompactionController,
     pub(crate) manifest: Option<Manifest>,
     pub(crate) mvcc: Option<LsmMvccInner>,
-    pub(crate) compaction_filters: Arc<Mutex<Vec<CompactionFilter>>>,
+    pub(crate) compaction_filters: Arc<Mutex<Vec<Arc<dyn CompactionFilter>>>>,
+    /// Obsolete WAL files that can be reused for new memtables.
+    wal_recycle_pool: Mutex<Vec<PathBuf>>,
+    /// The lower bounds of the SST ids that running compactions may create.
+    pub(crate) pending_outputs: Mutex<Vec<usize>>,
+    /// The number of ongoing operations that need obsolete files to be kept, e.g., checkpoints.
+    pub(crate) file_deletion_pause: AtomicUsize,
+    /// Throttles the I/O of flushes and compactions.
+    pub(crate) rate_limiter: RateLimiter,
+    pub(crate) compaction_stats: Mutex<CompactionStats>,
+    /// The compaction jobs that are running, whose SSTs cannot be picked by other compactions.
+    pub(crate) running_compactions: Mutex<RunningCompactions>,
+    /// Notified when a compaction job is removed from the running compactions.
+    pub(crate) compaction_job_done: Condvar,
+    /// Wakes up the compaction scheduler. The channel holds at most one pending event.
+    pub(crate) compaction_events: (
+        crossbeam_channel::Sender<CompactionEvent>,
+        crossbeam_channel::Receiver<CompactionEvent>,
+    ),
+    /// The first manifest or SST write that failed. The files on disk might no longer match the in-memory state, so
+    /// writes, flushes and compactions are rejected once it is set.
+    background_error: Mutex<Option<String>>,
 }
 
 /// A thin wrapper for `LsmStorageInner` and the user interface for MiniLSM.
@@ -193,12 +402,17 @@ pub struct MiniLsm {
     compaction_notifier: crossbeam_channel::Sender<()>,
     /// The handle for the compaction thread. (In week 2)
     compaction_thread: Mutex<Option<std::thread::JoinHandle<()>>>,
+    /// Notifies the obsolete file GC thread to stop working.
+    gc_notifier: crossbeam_channel::Sender<()>,
+    /// The handle for the obsolete file GC thread.
+    gc_thread: Mutex<Option<std::thread::JoinHandle<()>>>,
 }
 
 impl Drop for MiniLsm {
     fn drop(&mut self) {
         self.compaction_notifier.send(()).ok();
         self.flush_notifier.send(()).ok();
+        self.gc_notifier.send(()).ok();
     }
 }
 
@@ -207,6 +421,7 @@ impl MiniLsm {
         self.inner.sync_dir()?;
         self.compaction_notifier.send(()).ok();
         self.flush_notifier.send(()).ok();
+        self.gc_notifier.send(()).ok();
 
         let mut compaction_thread = self.compaction_thread.lock();
         if let Some(compaction_thread) = compaction_thread.take() {
@@ -220,6 +435,10 @@ impl MiniLsm {
                 .join()
                 .map_err(|e| anyhow::anyhow!("{:?}", e))?;
         }
+        let mut gc_thread = self.gc_thread.lock();
+        if let Some(gc_thread) = gc_thread.take() {
+            gc_thread.join().map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        }
 
         if self.inner.options.enable_wal {
             self.inner.sync()?;
@@ -254,19 +473,71 @@ impl MiniLsm {
         let compaction_thread = inner.spawn_compaction_thread(rx)?;
         let (tx2, rx) = crossbeam_channel::unbounded();
         let flush_thread = inner.spawn_flush_thread(rx)?;
+        let (tx3, rx) = crossbeam_channel::unbounded();
+        let gc_thread = inner.spawn_gc_thread(rx)?;
         Ok(Arc::new(Self {
             inner,
             flush_notifier: tx2,
             flush_thread: Mutex::new(flush_thread),
             compaction_notifier: tx1,
             compaction_thread: Mutex::new(compaction_thread),
+            gc_notifier: tx3,
+            gc_thread: Mutex::new(gc_thread),
         }))
     }
 
-    pub fn add_compaction_filter(&self, compaction_filter: CompactionFilter) {
+    /// Rewrite an existing database for the compaction strategy in `options`. All data is flushed and compacted into
+    /// a single sorted run in the layout of the new strategy. The database must not be open while migrating.
+    pub fn migrate_compaction_strategy(
+        path: impl AsRef<Path>,
+        options: LsmStorageOptions,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let Some(persisted) = LsmStorageOptions::load(options.fs.as_ref(), path)? else {
+            bail!("no OPTIONS file in the database, open the database once to record its options");
+        };
+        let mut current_options = options.clone();
+        current_options.compaction_options = persisted.compaction_options;
+        current_options.enable_wal = persisted.enable_wal;
+        let inner = LsmStorageInner::open(path, current_options)?;
+        inner.migrate_compaction_strategy(&options)?;
+        drop(inner);
+        LsmStorageInner::write_options(options.fs.as_ref(), path, &options)
+    }
+
+    /// Add a filter that decides what compactions do with each key. Filters run in the order they are added.
+    pub fn add_compaction_filter(&self, compaction_filter: impl CompactionFilter + 'static) {
         self.inner.add_compaction_filter(compaction_filter)
     }
 
+    pub fn create_checkpoint(&self, dir: impl AsRef<Path>) -> Result<u64> {
+        self.inner.create_checkpoint(dir)
+    }
+
+    /// Change the I/O rate limit of flushes and compactions. 0 disables it.
+    pub fn set_rate_limit(&self, bytes_per_second: usize) {
+        self.inner
+            .rate_limiter
+            .set_bytes_per_second(bytes_per_second)
+    }
+
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.inner.rate_limiter
+    }
+
+    pub fn compaction_stats(&self) -> CompactionStats {
+        self.inner.compaction_stats.lock().clone()
+    }
+
+    /// List the files that would be deleted by `delete_obsolete_files`.
+    pub fn list_obsolete_files(&self) -> Result<Vec<PathBuf>> {
+        self.inner.list_obsolete_files()
+    }
+
+    pub fn delete_obsolete_files(&self) -> Result<Vec<PathBuf>> {
+        self.inner.delete_obsolete_files()
+    }
+
     pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
         self.inner.get(key)
     }
@@ -279,6 +550,17 @@ impl MiniLsm {
         self.inner.put(key, value)
     }
 
+    /// Put a key-value pair that expires after `ttl`. Expired keys are invisible to reads and removed by compaction.
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.inner.put_with_ttl(key, value, ttl)
+    }
+
+    /// Write a merge operand for a key. It is combined with the value of the key by the merge operator of the
+    /// options when the key is read or compacted.
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.inner.merge(key, operand)
+    }
+
     pub fn delete(&self, key: &[u8]) -> Result<()> {
         self.inner.delete(key)
     }
@@ -310,6 +592,25 @@ impl MiniLsm {
     pub fn force_full_compaction(&self) -> Result<()> {
         self.inner.force_full_compaction()
     }
+
+    /// Compact the data in the key range down to `target_level`, or the bottom level if `None`, e.g., to reclaim the
+    /// space of deleted keys. It runs alongside the background compactions.
+    pub fn compact_range(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        target_level: Option<usize>,
+    ) -> Result<()> {
+        self.inner.compact_range(lower, upper, target_level)
+    }
+
+    /// Returns all committed write batches with a commit timestamp larger than `ts`, in commit order.
+    pub fn changes_since(
+        &self,
+        ts: u64,
+    ) -> Result<impl Iterator<Item = (u64, Vec<WriteBatchRecord<Bytes>>)> + use<>> {
+        self.inner.changes_since(ts)
+    }
 }
 
 impl LsmStorageInner {
@@ -336,40 +637,83 @@ impl LsmStorageInner {
         let manifest;
 
         let compaction_controller = match &options.compaction_options {
-            CompactionOptions::Leveled(options) => {
-                CompactionController::Leveled(LeveledCompactionController::new(options.clone()))
+            CompactionOptions::Leveled(leveled_options) => {
+                CompactionController::Leveled(LeveledCompactionController::new(
+                    leveled_options.clone(),
+                    options.compaction_triggers.clone(),
+                    options.clock.clone(),
+                ))
             }
-            CompactionOptions::Tiered(options) => {
-                CompactionController::Tiered(TieredCompactionController::new(options.clone()))
+            CompactionOptions::Tiered(tiered_options) => {
+                CompactionController::Tiered(TieredCompactionController::new(
+                    tiered_options.clone(),
+                    options.compaction_triggers.clone(),
+                    options.clock.clone(),
+                ))
             }
             CompactionOptions::Simple(options) => CompactionController::Simple(
                 SimpleLeveledCompactionController::new(options.clone()),
             ),
+            CompactionOptions::Fifo(fifo_options) => CompactionController::Fifo(
+                FifoCompactionController::new(fifo_options.clone(), options.clock.clone()),
+            ),
+            CompactionOptions::Hybrid(hybrid_options) => CompactionController::Hybrid(
+                HybridCompactionController::new(hybrid_options.clone()),
+            ),
             CompactionOptions::NoCompaction => CompactionController::NoCompaction,
         };
 
-        if !path.exists() {
-            std::fs::create_dir_all(path).context("failed to create DB dir")?;
+        let fs = options.fs.clone();
+        if !fs.exists(path) {
+            fs.create_dir_all(path).context("failed to create DB dir")?;
+        }
+        if options.wal_archive_retention.is_some() {
+            fs.create_dir_all(&Self::path_of_wal_archive_static(path))
+                .context("failed to create WAL archive dir")?;
+        }
+        if Manifest::exists(fs.as_ref(), path)
+            && let Some(persisted) = LsmStorageOptions::load(fs.as_ref(), path)?
+        {
+            persisted.check_compatible(&options)?;
         }
-        let manifest_path = path.join("MANIFEST");
         let mut last_commit_ts = 0;
-        if !manifest_path.exists() {
+        let mut wal_recycle_pool = Vec::new();
+        for entry_path in fs.list_dir(path)? {
+            if entry_path.extension().is_some_and(|ext| ext == "recycle") {
+                wal_recycle_pool.push(entry_path);
+            }
+        }
+        if !Manifest::exists(fs.as_ref(), path) {
             if options.enable_wal {
                 state.memtable = Arc::new(MemTable::create_with_wal(
                     state.memtable.id(),
-                    Self::path_of_wal_static(path, state.memtable.id()),
-                )?);
+                    Self::create_wal_static(
+                        path,
+                        state.memtable.id(),
+                        &options,
+                        &mut wal_recycle_pool,
+                    )?,
+                ));
             }
-            manifest = Manifest::create(&manifest_path).context("failed to create manifest")?;
-            manifest.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
+            manifest = Manifest::create(fs.clone(), path, options.max_manifest_file_size)
+                .context("failed to create manifest")?;
+            manifest.add_record_when_init(VersionEdit::new_memtable(state.memtable.id()))?;
         } else {
-            let (m, records) = Manifest::recover(&manifest_path)?;
+            let (m, records) = Manifest::recover(fs.clone(), path, options.max_manifest_file_size)?;
             let mut memtables = BTreeSet::new();
             for record in records {
                 match record {
-                    ManifestRecord::Flush(sst_id) => {
-                        let res = memtables.remove(&sst_id);
-                        assert!(res, "memtable not exist?");
+                    ManifestRecord::Edit(edit) => {
+                        edit.apply(&mut state, &mut memtables)?;
+                        next_sst_id = next_sst_id.max(edit.max_id());
+                    }
+                    // JSON manifests can only be replayed with the compaction controller.
+                    ManifestRecord::Legacy(LegacyManifestRecord::Flush(sst_id)) => {
+                        ensure!(
+                            memtables.remove(&sst_id),
+                            "flushed memtable {} not found",
+                            sst_id
+                        );
                         if compaction_controller.flush_to_l0() {
                             state.l0_sstables.insert(0, sst_id);
                         } else {
@@ -377,14 +721,14 @@ impl LsmStorageInner {
                         }
                         next_sst_id = next_sst_id.max(sst_id);
                     }
-                    ManifestRecord::NewMemtable(x) => {
+                    ManifestRecord::Legacy(LegacyManifestRecord::NewMemtable(x)) => {
                         next_sst_id = next_sst_id.max(x);
                         memtables.insert(x);
                     }
-                    ManifestRecord::Compaction(task, output) => {
+                    ManifestRecord::Legacy(LegacyManifestRecord::Compaction(task, output)) => {
                         let (new_state, _) = compaction_controller
                             .apply_compaction_result(&state, &task, &output, true);
-                        // TODO: apply remove again
+                        // The removed SSTs are deleted by the obsolete file GC after recovery.
                         state = new_state;
                         next_sst_id =
                             next_sst_id.max(output.iter().max().copied().unwrap_or_default());
@@ -403,8 +747,11 @@ impl LsmStorageInner {
                 let sst = SsTable::open(
                     table_id,
                     Some(block_cache.clone()),
-                    FileObject::open(&Self::path_of_sst_static(path, table_id))
-                        .context("failed to open SST")?,
+                    FileObject::open_with_fs(
+                        fs.as_ref(),
+                        &Self::path_of_sst_static(path, table_id),
+                    )
+                    .context("failed to open SST")?,
                 )?;
                 last_commit_ts = last_commit_ts.max(sst.max_ts());
                 state.sstables.insert(table_id, Arc::new(sst));
@@ -429,11 +776,15 @@ impl LsmStorageInner {
             }
 
             // recover memtables
+            let mut dropped_memtables = Vec::new();
             if options.enable_wal {
                 let mut wal_cnt = 0;
                 for id in memtables.iter() {
-                    let memtable =
-                        MemTable::recover_from_wal(*id, Self::path_of_wal_static(path, *id))?;
+                    let memtable = MemTable::recover_from_wal(
+                        fs.as_ref(),
+                        *id,
+                        Self::path_of_wal_static(path, *id),
+                    )?;
                     let max_ts = memtable
                         .map
                         .iter()
@@ -444,17 +795,29 @@ impl LsmStorageInner {
                     if !memtable.is_empty() {
                         state.imm_memtables.insert(0, Arc::new(memtable));
                         wal_cnt += 1;
+                    } else {
+                        dropped_memtables.push(*id);
                     }
                 }
                 println!("{} WALs recovered", wal_cnt);
                 state.memtable = Arc::new(MemTable::create_with_wal(
                     next_sst_id,
-                    Self::path_of_wal_static(path, next_sst_id),
-                )?);
+                    Self::create_wal_static(path, next_sst_id, &options, &mut wal_recycle_pool)?,
+                ));
             } else {
+                dropped_memtables.extend(memtables.iter().copied());
                 state.memtable = Arc::new(MemTable::create(next_sst_id));
             }
-            m.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
+            if m.needs_roll_over() {
+                // Migrate a JSON manifest or drop a torn record by starting a new manifest with the recovered
+                // structure, which also records the new memtable.
+                m.roll_over_when_init(VersionEdit::snapshot(&state))?;
+            } else {
+                // Memtables that are not recovered no longer need their WALs.
+                let mut edit = VersionEdit::new_memtable(state.memtable.id());
+                edit.flushed_memtables = dropped_memtables;
+                m.add_record_when_init(edit)?;
+            }
             next_sst_id += 1;
             manifest = m;
         };
@@ -467,18 +830,31 @@ impl LsmStorageInner {
             next_sst_id: AtomicUsize::new(next_sst_id),
             compaction_controller,
             manifest: Some(manifest),
+            rate_limiter: RateLimiter::new(options.rate_limit_bytes_per_second),
+            compaction_stats: Mutex::new(CompactionStats::default()),
+            running_compactions: Mutex::new(RunningCompactions::default()),
+            compaction_job_done: Condvar::new(),
+            compaction_events: crossbeam_channel::bounded(1),
+            background_error: Mutex::new(None),
             options: options.into(),
             mvcc: Some(LsmMvccInner::new(last_commit_ts)),
             compaction_filters: Arc::new(Mutex::new(Vec::new())),
+            wal_recycle_pool: Mutex::new(wal_recycle_pool),
+            pending_outputs: Mutex::new(Vec::new()),
+            file_deletion_pause: AtomicUsize::new(0),
         };
+        storage.purge_archived_wals()?;
+        storage.delete_obsolete_files_on_open()?;
+        Self::write_options(storage.fs(), path, &storage.options)?;
         storage.sync_dir()?;
+        storage.maybe_roll_over_manifest(&storage.state_lock.lock())?;
 
         Ok(storage)
     }
 
-    pub fn add_compaction_filter(&self, compaction_filter: CompactionFilter) {
+    pub fn add_compaction_filter(&self, compaction_filter: impl CompactionFilter + 'static) {
         let mut compaction_filters = self.compaction_filters.lock();
-        compaction_filters.push(compaction_filter);
+        compaction_filters.push(Arc::new(compaction_filter));
     }
 
     pub fn sync(&self) -> Result<()> {
@@ -562,15 +938,19 @@ impl LsmStorageInner {
             )?,
             Bound::Unbounded,
             read_ts,
+            self.options.clock.now_millis(),
+            self.options.merge_operator.clone(),
         )?;
 
         if iter.is_valid() && iter.key() == key && !iter.value().is_empty() {
-            return Ok(Some(Bytes::copy_from_slice(iter.value())));
+            return Ok(Some(Bytes::copy_from_slice(value::decode(iter.value()))));
         }
         Ok(None)
     }
 
+    /// Write a batch at a new commit timestamp. The values must already be encoded, see `crate::value`.
     pub fn write_batch_inner<T: AsRef<[u8]>>(&self, batch: &[WriteBatchRecord<T>]) -> Result<u64> {
+        self.check_background_error()?;
         let _lck = self.mvcc().write_lock.lock();
         let ts = self.mvcc().latest_commit_ts() + 1;
         let mut batch_datas: Vec<(key::Key<&[u8]>, &[u8])> = vec![];
@@ -589,6 +969,9 @@ impl LsmStorageInner {
                     assert!(!value.is_empty(), "value cannot be empty");
                     batch_datas.push((KeySlice::from_slice(key, ts), value));
                 }
+                WriteBatchRecord::PutExpiring(..) | WriteBatchRecord::Merge(..) => {
+                    bail!("expiring puts and merge operands must be encoded as puts")
+                }
             }
         }
         {
@@ -606,8 +989,34 @@ impl LsmStorageInner {
         self: &Arc<Self>,
         batch: &[WriteBatchRecord<T>],
     ) -> Result<()> {
+        if batch
+            .iter()
+            .any(|record| matches!(record, WriteBatchRecord::Merge(..)))
+        {
+            self.merge_operator()?;
+        }
         if !self.options.serializable {
-            self.write_batch_inner(batch)?;
+            let batch = batch
+                .iter()
+                .map(|record| match record {
+                    WriteBatchRecord::Del(key) => {
+                        WriteBatchRecord::Del(Cow::Borrowed(key.as_ref()))
+                    }
+                    WriteBatchRecord::Put(key, value) => WriteBatchRecord::Put(
+                        Cow::Borrowed(key.as_ref()),
+                        value::encode_plain(value.as_ref()),
+                    ),
+                    WriteBatchRecord::PutExpiring(key, value, expire_at) => WriteBatchRecord::Put(
+                        Cow::Borrowed(key.as_ref()),
+                        Cow::Owned(value::encode_expiring(value.as_ref(), *expire_at).into()),
+                    ),
+                    WriteBatchRecord::Merge(key, operand) => WriteBatchRecord::Put(
+                        Cow::Borrowed(key.as_ref()),
+                        Cow::Owned(value::encode_merge_operand(operand.as_ref()).into()),
+                    ),
+                })
+                .collect::<Vec<_>>();
+            self.write_batch_inner(&batch)?;
         } else {
             let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
             for record in batch {
@@ -618,6 +1027,12 @@ impl LsmStorageInner {
                     WriteBatchRecord::Put(key, value) => {
                         txn.put(key.as_ref(), value.as_ref());
                     }
+                    WriteBatchRecord::PutExpiring(key, value, expire_at) => {
+                        txn.put_expiring(key.as_ref(), value.as_ref(), *expire_at);
+                    }
+                    WriteBatchRecord::Merge(key, operand) => {
+                        txn.merge(key.as_ref(), operand.as_ref())?;
+                    }
                 }
             }
             txn.commit()?;
@@ -628,7 +1043,8 @@ impl LsmStorageInner {
     /// Put a key-value pair into the storage by writing into the current memtable.
     pub fn put(self: &Arc<Self>, key: &[u8], value: &[u8]) -> Result<()> {
         if !self.options.serializable {
-            self.write_batch_inner(&[WriteBatchRecord::Put(key, value)])?;
+            let value = value::encode_plain(value);
+            self.write_batch_inner(&[WriteBatchRecord::Put(key, &value[..])])?;
         } else {
             let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
             txn.put(key, value);
@@ -637,6 +1053,47 @@ impl LsmStorageInner {
         Ok(())
     }
 
+    /// Put a key-value pair that is no longer visible once `ttl` has passed on the clock of the storage.
+    pub fn put_with_ttl(self: &Arc<Self>, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        if !self.options.serializable {
+            let value = value::encode_expiring(value, self.expire_at(ttl));
+            self.write_batch_inner(&[WriteBatchRecord::Put(key, &value[..])])?;
+        } else {
+            let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
+            txn.put_with_ttl(key, value, ttl);
+            txn.commit()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn expire_at(&self, ttl: Duration) -> u64 {
+        self.options
+            .clock
+            .now_millis()
+            .saturating_add(ttl.as_millis() as u64)
+    }
+
+    /// Write a merge operand for a key without reading the key, so that concurrent merges never conflict.
+    pub fn merge(self: &Arc<Self>, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.merge_operator()?;
+        if !self.options.serializable {
+            let operand = value::encode_merge_operand(operand);
+            self.write_batch_inner(&[WriteBatchRecord::Put(key, &operand[..])])?;
+        } else {
+            let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
+            txn.merge(key, operand)?;
+            txn.commit()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn merge_operator(&self) -> Result<&Arc<dyn MergeOperator>> {
+        match &self.options.merge_operator {
+            Some(merge_operator) => Ok(merge_operator),
+            None => bail!("no merge operator is set"),
+        }
+    }
+
     /// Remove a key from the storage by writing an empty value.
     pub fn delete(self: &Arc<Self>, key: &[u8]) -> Result<()> {
         if !self.options.serializable {
@@ -678,11 +1135,42 @@ impl LsmStorageInner {
         Self::path_of_wal_static(&self.path, id)
     }
 
-    pub(super) fn sync_dir(&self) -> Result<()> {
-        File::open(&self.path)?.sync_all()?;
+    pub(crate) fn path_of_recycled_wal(&self, id: usize) -> PathBuf {
+        self.path.join(format!("{:05}.recycle", id))
+    }
+
+    fn path_of_options_static(path: impl AsRef<Path>) -> PathBuf {
+        path.as_ref().join("OPTIONS")
+    }
+
+    /// Atomically replace the `OPTIONS` file with the given options.
+    pub(crate) fn write_options(
+        fs: &dyn FileSystem,
+        path: &Path,
+        options: &LsmStorageOptions,
+    ) -> Result<()> {
+        let tmp_path = path.join("OPTIONS.tmp");
+        fs.write(&tmp_path, &serde_json::to_vec_pretty(options)?)?;
+        fs.rename(&tmp_path, &Self::path_of_options_static(path))?;
         Ok(())
     }
 
+    pub(crate) fn path_of_wal_archive_static(path: impl AsRef<Path>) -> PathBuf {
+        path.as_ref().join("archive")
+    }
+
+    pub(crate) fn path_of_wal_archive(&self) -> PathBuf {
+        Self::path_of_wal_archive_static(&self.path)
+    }
+
+    pub(crate) fn fs(&self) -> &dyn FileSystem {
+        self.options.fs.as_ref()
+    }
+
+    pub(super) fn sync_dir(&self) -> Result<()> {
+        self.fs().sync_dir(&self.path)
+    }
+
     fn freeze_memtable_with_memtable(&self, memtable: Arc<MemTable>) -> Result<()> {
         let mut guard = self.state.write();
         // Swap the current memtable with a new one.
@@ -701,84 +1189,250 @@ impl LsmStorageInner {
 
     /// Force freeze the current memtable to an immutable memtable
     pub fn force_freeze_memtable(&self, state_lock_observer: &MutexGuard<'_, ()>) -> Result<()> {
+        self.check_background_error()?;
         let memtable_id = self.next_sst_id();
         let memtable = if self.options.enable_wal {
             Arc::new(MemTable::create_with_wal(
                 memtable_id,
-                self.path_of_wal(memtable_id),
-            )?)
+                self.create_wal(memtable_id)?,
+            ))
         } else {
             Arc::new(MemTable::create(memtable_id))
         };
 
-        self.freeze_memtable_with_memtable(memtable)?;
-
-        self.manifest().add_record(
-            state_lock_observer,
-            ManifestRecord::NewMemtable(memtable_id),
+        // The manifest must refer to the new WAL before any write goes to it, but the snapshot of a new manifest
+        // must include the new memtable.
+        self.latch_background_error(
+            self.manifest()
+                .add_record(state_lock_observer, VersionEdit::new_memtable(memtable_id)),
         )?;
-        self.sync_dir()?;
+        self.freeze_memtable_with_memtable(memtable)?;
+        self.maybe_roll_over_manifest(state_lock_observer)?;
 
         Ok(())
     }
 
     /// Force flush the earliest-created immutable memtable to disk
     pub fn force_flush_next_imm_memtable(&self) -> Result<()> {
+        self.check_background_error()?;
         let state_lock = self.state_lock.lock();
 
-        let flush_memtable;
-
-        {
-            let guard = self.state.read();
-            flush_memtable = guard
-                .imm_memtables
-                .last()
-                .expect("no imm memtables!")
-                .clone();
-        }
+        // Another thread might have flushed the memtable since the caller checked.
+        let Some(flush_memtable) = self.state.read().imm_memtables.last().cloned() else {
+            return Ok(());
+        };
 
-        let mut builder = SsTableBuilder::new(self.options.block_size);
+        let mut builder = self.new_sst_builder();
         flush_memtable.flush(&mut builder)?;
         let sst_id = flush_memtable.id();
-        let sst = Arc::new(builder.build(
+        let sst = Arc::new(self.latch_background_error(builder.build_with_fs(
+            self.fs(),
             sst_id,
             Some(self.block_cache.clone()),
             self.path_of_sst(sst_id),
-        )?);
+        ))?);
+        // Charge the SST after writing it, which delays the next flush.
+        self.rate_limiter
+            .request(sst.table_size() as usize, IoPriority::High);
 
         // Add the flushed L0 table to the list.
-        {
-            let mut guard = self.state.write();
-            let mut snapshot = guard.as_ref().clone();
-            // Remove the memtable from the immutable memtables.
-            let mem = snapshot.imm_memtables.pop().unwrap();
-            assert_eq!(mem.id(), sst_id);
-            // Add L0 table
-            if self.compaction_controller.flush_to_l0() {
-                // In leveled compaction or no compaction, simply flush to L0
-                snapshot.l0_sstables.insert(0, sst_id);
+        let old_state = self.state.read().clone();
+        let mut snapshot = old_state.as_ref().clone();
+        // Remove the memtable from the immutable memtables.
+        let mem = snapshot.imm_memtables.pop().unwrap();
+        assert_eq!(mem.id(), sst_id);
+        // Add L0 table
+        if self.compaction_controller.flush_to_l0() {
+            // In leveled compaction or no compaction, simply flush to L0
+            snapshot.l0_sstables.insert(0, sst_id);
+        } else {
+            // In tiered compaction, create a new tier
+            snapshot.levels.insert(0, (sst_id, vec![sst_id]));
+        }
+        println!("flushed {}.sst with size={}", sst_id, sst.table_size());
+        snapshot.sstables.insert(sst_id, sst);
+        let mut edit = VersionEdit::diff(&old_state, &snapshot);
+        edit.flushed_memtables.push(sst_id);
+        // The WAL can only be removed after the manifest refers to the SST.
+        self.install_state(&state_lock, snapshot, edit)?;
+
+        if self.options.enable_wal {
+            if self.options.wal_archive_retention.is_some() {
+                self.fs().rename(
+                    &self.path_of_wal(sst_id),
+                    &Self::path_of_wal_static(self.path_of_wal_archive(), sst_id),
+                )?;
+                self.purge_archived_wals()?;
             } else {
-                // In tiered compaction, create a new tier
-                snapshot.levels.insert(0, (sst_id, vec![sst_id]));
+                self.recycle_or_remove_wal(sst_id)?;
             }
-            println!("flushed {}.sst with size={}", sst_id, sst.table_size());
-            snapshot.sstables.insert(sst_id, sst);
-            // Update the snapshot.
-            *guard = Arc::new(snapshot);
         }
 
-        if self.options.enable_wal {
-            std::fs::remove_file(self.path_of_wal(sst_id))?;
+        self.sync_dir()?;
+        self.notify_compaction_scheduler(CompactionEvent::FlushCompleted);
+
+        Ok(())
+    }
+
+    /// A builder for a new SST, which records the time of `options.clock` as its creation time.
+    pub(crate) fn new_sst_builder(&self) -> SsTableBuilder {
+        let mut builder = SsTableBuilder::new(self.options.block_size);
+        builder.set_created_at(self.options.clock.now_millis());
+        builder
+    }
+
+    /// Write `edit` to the manifest, and then publish `new_state`, which is the current state with `edit` applied.
+    /// Readers never see a state that recovery cannot rebuild, as the state is only published once the edit is durable.
+    pub(crate) fn install_state(
+        &self,
+        state_lock_observer: &MutexGuard<'_, ()>,
+        new_state: LsmStorageState,
+        edit: VersionEdit,
+    ) -> Result<()> {
+        // A concurrent flush or compaction might have failed after this one started. Its manifest record might be torn,
+        // and must not be followed by more records.
+        self.check_background_error()?;
+        // The new SSTs must be durable before the manifest refers to them.
+        self.latch_background_error(self.sync_dir())?;
+        self.latch_background_error(self.manifest().add_record(state_lock_observer, edit))?;
+        *self.state.write() = Arc::new(new_state);
+        self.maybe_roll_over_manifest(state_lock_observer)
+    }
+
+    fn maybe_roll_over_manifest(&self, state_lock_observer: &MutexGuard<'_, ()>) -> Result<()> {
+        if !self.manifest().should_roll_over() {
+            return Ok(());
         }
+        let snapshot = VersionEdit::snapshot(&self.state.read());
+        self.latch_background_error(self.manifest().roll_over(state_lock_observer, snapshot))
+    }
 
-        self.manifest()
-            .add_record(&state_lock, ManifestRecord::Flush(sst_id))?;
+    /// Return an error if a manifest or SST write has failed before.
+    pub(crate) fn check_background_error(&self) -> Result<()> {
+        if let Some(e) = self.background_error.lock().as_ref() {
+            bail!("a previous manifest or SST write failed: {}", e);
+        }
+        Ok(())
+    }
 
-        self.sync_dir()?;
+    /// Remember the error of a failed manifest or SST write, so that later writes, flushes and compactions fail.
+    pub(crate) fn latch_background_error<T>(&self, result: Result<T>) -> Result<T> {
+        if let Err(e) = &result {
+            self.background_error
+                .lock()
+                .get_or_insert_with(|| format!("{:#}", e));
+        }
+        result
+    }
 
+    /// Create the WAL for a new memtable, reusing a file from the recycle pool if there is one.
+    fn create_wal_static(
+        path: &Path,
+        id: usize,
+        options: &LsmStorageOptions,
+        recycle_pool: &mut Vec<PathBuf>,
+    ) -> Result<Wal> {
+        let wal_path = Self::path_of_wal_static(path, id);
+        let fs = options.fs.as_ref();
+        let wal = if let Some(recycled_path) = recycle_pool.pop() {
+            Wal::create_recycled(fs, recycled_path, wal_path)?
+        } else {
+            // The manifest does not refer to a WAL with a new id, so a file left behind by a crash holds no
+            // acknowledged write.
+            if fs.exists(&wal_path) {
+                fs.remove_file(&wal_path)?;
+            }
+            Wal::create(fs, wal_path, options.wal_preallocate_size)?
+        };
+        // The WAL must be durable before the manifest refers to it.
+        fs.sync_dir(path)?;
+        Ok(wal)
+    }
+
+    fn create_wal(&self, id: usize) -> Result<Wal> {
+        Self::create_wal_static(
+            &self.path,
+            id,
+            &self.options,
+            &mut self.wal_recycle_pool.lock(),
+        )
+    }
+
+    /// Put the WAL of a flushed memtable into the recycle pool, or remove it if the pool is full.
+    fn recycle_or_remove_wal(&self, id: usize) -> Result<()> {
+        let mut recycle_pool = self.wal_recycle_pool.lock();
+        if recycle_pool.len() < self.options.wal_recycle_pool_size {
+            let recycled_path = self.path_of_recycled_wal(id);
+            self.fs().rename(&self.path_of_wal(id), &recycled_path)?;
+            recycle_pool.push(recycled_path);
+        } else {
+            self.fs().remove_file(&self.path_of_wal(id))?;
+        }
         Ok(())
     }
 
+    /// Remove archived WALs that have been kept for longer than the retention period.
+    fn purge_archived_wals(&self) -> Result<()> {
+        let Some(retention) = self.options.wal_archive_retention else {
+            return Ok(());
+        };
+        let now = SystemTime::now();
+        for path in self.fs().list_dir(&self.path_of_wal_archive())? {
+            let modified = self.fs().modified(&path)?;
+            if now.duration_since(modified).unwrap_or_default() >= retention {
+                self.fs().remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read all committed write batches after `ts` from the live and archived WALs. Puts with a TTL are returned as
+    /// `PutExpiring`, even if they have already expired, and merge operands as `Merge`.
+    pub fn changes_since(
+        &self,
+        ts: u64,
+    ) -> Result<impl Iterator<Item = (u64, Vec<WriteBatchRecord<Bytes>>)> + use<>> {
+        if !self.options.enable_wal {
+            anyhow::bail!("changes_since requires WAL to be enabled");
+        }
+        // Only return batches that have been fully committed when the call starts.
+        let latest_commit_ts = self.mvcc().latest_commit_ts();
+        // Make sure everything buffered in the current WAL is visible to the reader.
+        self.sync()?;
+
+        let mut wal_paths = Vec::new();
+        let mut dirs = vec![self.path.clone()];
+        if self.options.wal_archive_retention.is_some() {
+            dirs.push(self.path_of_wal_archive());
+        }
+        for dir in dirs {
+            for path in self.fs().list_dir(&dir)? {
+                if path.extension().is_some_and(|ext| ext == "wal") {
+                    let id = path
+                        .file_stem()
+                        .and_then(|stem| stem.to_str())
+                        .and_then(|stem| stem.parse::<usize>().ok())
+                        .with_context(|| format!("invalid WAL file name: {}", path.display()))?;
+                    wal_paths.push((id, path));
+                }
+            }
+        }
+        wal_paths.sort();
+
+        let mut batches = Vec::new();
+        for (_, path) in wal_paths {
+            for (commit_ts, records) in Wal::read_batches(self.fs(), &path)? {
+                if commit_ts > ts && commit_ts <= latest_commit_ts {
+                    batches.push((commit_ts, records));
+                }
+            }
+        }
+        // A batch may land in a memtable that is being frozen concurrently, so WAL order is not
+        // always commit order.
+        batches.sort_by_key(|(commit_ts, _)| *commit_ts);
+        Ok(batches.into_iter())
+    }
+
     pub fn new_txn(self: &Arc<Self>) -> Result<Arc<Transaction>> {
         Ok(self.mvcc().new_txn(self.clone(), self.options.serializable))
     }
@@ -888,6 +1542,8 @@ impl LsmStorageInner {
             iter,
             map_bound(upper),
             read_ts,
+            self.options.clock.now_millis(),
+            self.options.merge_operator.clone(),
         )?))
     }
 }
@@ -12,88 +12,627 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
-use std::path::Path;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use anyhow::{Context, Result, bail};
-use bytes::{Buf, BufMut};
+use anyhow::{Context, Result, bail, ensure};
+use bytes::{Buf, BufMut, Bytes};
 use parking_lot::{Mutex, MutexGuard};
 use serde::{Deserialize, Serialize};
 
 use crate::compact::CompactionTask;
+use crate::fs::{FileSystem, WritableFile};
+use crate::key::KeyBytes;
+use crate::lsm_storage::LsmStorageState;
 
+/// The manifest is stored in a sequence of `MANIFEST-<n>` files. Only the file named in `CURRENT` is valid. When the
+/// current manifest grows beyond `max_file_size`, a new manifest is started with a snapshot of the LSM structure, so
+/// that recovery does not need to replay the full history of the database.
+///
+/// A manifest file starts with a magic number, followed by a sequence of encoded version edits:
+///
+/// ```text
+/// | magic (u32) | edit_len (u32) | version edit | checksum (u32) | ... |
+/// ```
+///
+/// The `MANIFEST` file written before manifest rollover was supported is a sequence of JSON records without the magic
+/// number. It is still readable, and is replaced by a new manifest when the database is opened.
 pub struct Manifest {
-    file: Arc<Mutex<File>>,
+    fs: Arc<dyn FileSystem>,
+    dir: PathBuf,
+    max_file_size: usize,
+    file: Arc<Mutex<ManifestFile>>,
+}
+
+struct ManifestFile {
+    file: Box<dyn WritableFile>,
+    file_number: u64,
+    size: usize,
+    /// New records cannot be appended to the file, see `Manifest::needs_roll_over`.
+    needs_roll_over: bool,
 }
 
-#[derive(Serialize, Deserialize)]
 pub enum ManifestRecord {
+    Edit(VersionEdit),
+    Legacy(LegacyManifestRecord),
+}
+
+/// A record of a JSON manifest. Recovering from these records requires replaying the compaction tasks with the
+/// compaction controller.
+#[derive(Serialize, Deserialize)]
+pub enum LegacyManifestRecord {
     Flush(usize),
     NewMemtable(usize),
     Compaction(CompactionTask, Vec<usize>),
 }
 
+/// The metadata of an SST recorded in the manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SstMeta {
+    pub id: usize,
+    pub first_key: KeyBytes,
+    pub last_key: KeyBytes,
+    pub size: u64,
+    pub max_ts: u64,
+}
+
+/// A change to the LSM structure. Level `None` refers to `l0_sstables`, and other level ids refer to the ids in
+/// `LsmStorageState::levels`, i.e., the level number for leveled compaction and the tier id for tiered compaction.
+/// Tier ids are SST ids, so a tier may have id 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VersionEdit {
+    /// The edit contains the full LSM structure, and replaces everything recovered before it.
+    pub is_snapshot: bool,
+    pub new_memtables: Vec<usize>,
+    /// Memtables that no longer need to be recovered from the WAL, because they are flushed or empty.
+    pub flushed_memtables: Vec<usize>,
+    /// `(level, sst_id)` of the removed SSTs.
+    pub removed_ssts: Vec<(Option<usize>, usize)>,
+    /// `(level, sst)` of the added SSTs. L0 SSTs are listed from latest to earliest.
+    pub added_ssts: Vec<(Option<usize>, SstMeta)>,
+    /// The ids of the levels (excluding L0) after this edit. Only set when levels are added, removed or reordered.
+    pub level_order: Option<Vec<usize>>,
+    /// The ids of the L0 SSTs after this edit. Only set when the added L0 SSTs are not all newer than the remaining
+    /// ones, e.g., when the output of an intra-L0 merge is placed behind an SST flushed during the merge.
+    pub l0_order: Option<Vec<usize>>,
+}
+
+const MANIFEST_MAGIC: u32 = 0x4d4c_5645; // "MLVE"
+
+/// The manifest file used before manifest rollover was supported.
+const LEGACY_MANIFEST: &str = "MANIFEST";
+const CURRENT: &str = "CURRENT";
+
+fn manifest_file_name(file_number: u64) -> String {
+    format!("MANIFEST-{:06}", file_number)
+}
+
+fn parse_manifest_file_name(name: &str) -> Option<u64> {
+    name.strip_prefix("MANIFEST-")?.parse().ok()
+}
+
+/// The SST ids of each level in the state, with L0 as level `None`.
+fn levels_of(state: &LsmStorageState) -> impl Iterator<Item = (Option<usize>, &Vec<usize>)> {
+    std::iter::once((None, &state.l0_sstables))
+        .chain(state.levels.iter().map(|(id, ssts)| (Some(*id), ssts)))
+}
+
+/// Encode a level as 0 for L0, or the level id plus 1.
+fn encode_level(level: Option<usize>, buf: &mut Vec<u8>) {
+    buf.put_u64(level.map_or(0, |x| x as u64 + 1));
+}
+
+fn decode_level(buf: &mut &[u8]) -> Result<Option<usize>> {
+    Ok(get_u64(buf)?.checked_sub(1).map(|x| x as usize))
+}
+
+/// Check that a record has `len` more bytes, so that a truncated record is reported instead of read past its end.
+fn ensure_remaining(buf: &[u8], len: usize) -> Result<()> {
+    ensure!(buf.remaining() >= len, "manifest record is truncated");
+    Ok(())
+}
+
+fn get_u32(buf: &mut &[u8]) -> Result<u32> {
+    ensure_remaining(buf, std::mem::size_of::<u32>())?;
+    Ok(buf.get_u32())
+}
+
+fn get_u64(buf: &mut &[u8]) -> Result<u64> {
+    ensure_remaining(buf, std::mem::size_of::<u64>())?;
+    Ok(buf.get_u64())
+}
+
+impl SstMeta {
+    fn from_state(state: &LsmStorageState, id: usize) -> Self {
+        let sst = &state.sstables[&id];
+        Self {
+            id,
+            first_key: sst.first_key().clone(),
+            last_key: sst.last_key().clone(),
+            size: sst.table_size(),
+            max_ts: sst.max_ts(),
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u64(self.id as u64);
+        encode_key(&self.first_key, buf);
+        encode_key(&self.last_key, buf);
+        buf.put_u64(self.size);
+        buf.put_u64(self.max_ts);
+    }
+
+    fn decode(buf: &mut &[u8]) -> Result<Self> {
+        Ok(Self {
+            id: get_u64(buf)? as usize,
+            first_key: decode_key(buf)?,
+            last_key: decode_key(buf)?,
+            size: get_u64(buf)?,
+            max_ts: get_u64(buf)?,
+        })
+    }
+}
+
+fn encode_key(key: &KeyBytes, buf: &mut Vec<u8>) {
+    buf.put_u16(key.key_len() as u16);
+    buf.put_slice(key.key_ref());
+    buf.put_u64(key.ts());
+}
+
+fn decode_key(buf: &mut &[u8]) -> Result<KeyBytes> {
+    ensure_remaining(buf, std::mem::size_of::<u16>())?;
+    let key_len = buf.get_u16() as usize;
+    ensure_remaining(buf, key_len)?;
+    let key = Bytes::copy_from_slice(&buf[..key_len]);
+    buf.advance(key_len);
+    Ok(KeyBytes::from_bytes_with_ts(key, get_u64(buf)?))
+}
+
+fn encode_ids(ids: &[usize], buf: &mut Vec<u8>) {
+    buf.put_u32(ids.len() as u32);
+    for id in ids {
+        buf.put_u64(*id as u64);
+    }
+}
+
+fn decode_ids(buf: &mut &[u8]) -> Result<Vec<usize>> {
+    let len = get_u32(buf)? as usize;
+    (0..len).map(|_| Ok(get_u64(buf)? as usize)).collect()
+}
+
+impl VersionEdit {
+    pub fn new_memtable(id: usize) -> Self {
+        Self {
+            new_memtables: vec![id],
+            ..Default::default()
+        }
+    }
+
+    /// Record the changes of SSTs from `old` to `new`.
+    pub fn diff(old: &LsmStorageState, new: &LsmStorageState) -> Self {
+        let old_ssts = levels_of(old)
+            .flat_map(|(level, ssts)| ssts.iter().map(move |id| (level, *id)))
+            .collect::<HashSet<_>>();
+        let new_ssts = levels_of(new)
+            .flat_map(|(level, ssts)| ssts.iter().map(move |id| (level, *id)))
+            .collect::<HashSet<_>>();
+        let removed_ssts = levels_of(old)
+            .flat_map(|(level, ssts)| ssts.iter().map(move |id| (level, *id)))
+            .filter(|x| !new_ssts.contains(x))
+            .collect();
+        let added_ssts = levels_of(new)
+            .flat_map(|(level, ssts)| ssts.iter().map(move |id| (level, *id)))
+            .filter(|x| !old_ssts.contains(x))
+            .map(|(level, id)| (level, SstMeta::from_state(new, id)))
+            .collect();
+        let old_level_order = old.levels.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        let new_level_order = new.levels.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        // `apply` puts the added L0 SSTs before the remaining ones.
+        let applied_l0_order = new
+            .l0_sstables
+            .iter()
+            .filter(|id| !old.l0_sstables.contains(id))
+            .chain(
+                old.l0_sstables
+                    .iter()
+                    .filter(|id| new.l0_sstables.contains(id)),
+            )
+            .copied()
+            .collect::<Vec<_>>();
+        Self {
+            removed_ssts,
+            added_ssts,
+            level_order: (old_level_order != new_level_order).then_some(new_level_order),
+            l0_order: (applied_l0_order != new.l0_sstables).then(|| new.l0_sstables.clone()),
+            ..Default::default()
+        }
+    }
+
+    /// Record the full LSM structure.
+    pub fn snapshot(state: &LsmStorageState) -> Self {
+        Self {
+            is_snapshot: true,
+            new_memtables: std::iter::once(state.memtable.id())
+                .chain(state.imm_memtables.iter().map(|x| x.id()))
+                .collect(),
+            added_ssts: levels_of(state)
+                .flat_map(|(level, ssts)| {
+                    ssts.iter()
+                        .map(move |id| (level, SstMeta::from_state(state, *id)))
+                })
+                .collect(),
+            level_order: Some(state.levels.iter().map(|(id, _)| *id).collect()),
+            ..Default::default()
+        }
+    }
+
+    /// The largest SST or memtable id in this edit.
+    pub fn max_id(&self) -> usize {
+        self.new_memtables
+            .iter()
+            .chain(self.added_ssts.iter().map(|(_, sst)| &sst.id))
+            .max()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Apply the edit to the SST ids in `state` during recovery. `memtables` holds the ids of the memtables that are
+    /// not flushed yet. An edit that does not match the state means that the manifest is inconsistent.
+    pub fn apply(
+        &self,
+        state: &mut LsmStorageState,
+        memtables: &mut BTreeSet<usize>,
+    ) -> Result<()> {
+        if self.is_snapshot {
+            state.l0_sstables.clear();
+            state.levels.clear();
+            memtables.clear();
+        }
+        for id in &self.new_memtables {
+            memtables.insert(*id);
+        }
+        for id in &self.flushed_memtables {
+            ensure!(memtables.remove(id), "flushed memtable {} not found", id);
+        }
+        for (level, id) in &self.removed_ssts {
+            let ssts = match level {
+                None => &mut state.l0_sstables,
+                Some(level) => Self::level_mut(state, *level)?,
+            };
+            let len = ssts.len();
+            ssts.retain(|x| x != id);
+            ensure!(
+                ssts.len() + 1 == len,
+                "{}.sst not found in level {:?}",
+                id,
+                level
+            );
+        }
+        if let Some(level_order) = &self.level_order {
+            let mut levels = std::mem::take(&mut state.levels)
+                .into_iter()
+                .collect::<HashMap<_, _>>();
+            state.levels = level_order
+                .iter()
+                .map(|id| (*id, levels.remove(id).unwrap_or_default()))
+                .collect();
+            ensure!(
+                levels.values().all(|ssts| ssts.is_empty()),
+                "non-empty level removed"
+            );
+        }
+        let mut new_l0_sstables = Vec::new();
+        for (level, sst) in &self.added_ssts {
+            match level {
+                None => new_l0_sstables.push(sst.id),
+                Some(level) => Self::level_mut(state, *level)?.push(sst.id),
+            }
+        }
+        new_l0_sstables.append(&mut state.l0_sstables);
+        state.l0_sstables = new_l0_sstables;
+        if let Some(l0_order) = &self.l0_order {
+            let mut ssts = state.l0_sstables.clone();
+            let mut order = l0_order.clone();
+            ssts.sort_unstable();
+            order.sort_unstable();
+            ensure!(ssts == order, "L0 order does not match the L0 SSTs");
+            state.l0_sstables = l0_order.clone();
+        }
+        Ok(())
+    }
+
+    fn level_mut(state: &mut LsmStorageState, level: usize) -> Result<&mut Vec<usize>> {
+        match state.levels.iter_mut().find(|(id, _)| *id == level) {
+            Some((_, ssts)) => Ok(ssts),
+            None => bail!("level {} not found", level),
+        }
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u8(self.is_snapshot as u8);
+        encode_ids(&self.new_memtables, buf);
+        encode_ids(&self.flushed_memtables, buf);
+        buf.put_u32(self.removed_ssts.len() as u32);
+        for (level, id) in &self.removed_ssts {
+            encode_level(*level, buf);
+            buf.put_u64(*id as u64);
+        }
+        buf.put_u32(self.added_ssts.len() as u32);
+        for (level, sst) in &self.added_ssts {
+            encode_level(*level, buf);
+            sst.encode(buf);
+        }
+        match &self.level_order {
+            Some(level_order) => {
+                buf.put_u8(1);
+                encode_ids(level_order, buf);
+            }
+            None => buf.put_u8(0),
+        }
+        match &self.l0_order {
+            Some(l0_order) => {
+                buf.put_u8(1);
+                encode_ids(l0_order, buf);
+            }
+            None => buf.put_u8(0),
+        }
+    }
+
+    pub fn decode(mut buf: &[u8]) -> Result<Self> {
+        ensure_remaining(buf, 1)?;
+        let is_snapshot = buf.get_u8() != 0;
+        let new_memtables = decode_ids(&mut buf)?;
+        let flushed_memtables = decode_ids(&mut buf)?;
+        let removed_ssts = (0..get_u32(&mut buf)?)
+            .map(|_| Ok((decode_level(&mut buf)?, get_u64(&mut buf)? as usize)))
+            .collect::<Result<_>>()?;
+        let added_ssts = (0..get_u32(&mut buf)?)
+            .map(|_| Ok((decode_level(&mut buf)?, SstMeta::decode(&mut buf)?)))
+            .collect::<Result<_>>()?;
+        ensure_remaining(buf, 1)?;
+        let level_order = if buf.get_u8() != 0 {
+            Some(decode_ids(&mut buf)?)
+        } else {
+            None
+        };
+        // Edits written before the L0 order was recorded end here.
+        let l0_order = if buf.has_remaining() && buf.get_u8() != 0 {
+            Some(decode_ids(&mut buf)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            is_snapshot,
+            new_memtables,
+            flushed_memtables,
+            removed_ssts,
+            added_ssts,
+            level_order,
+            l0_order,
+        })
+    }
+}
+
 impl Manifest {
-    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+    /// Check if there is a manifest in the directory.
+    pub fn exists(fs: &dyn FileSystem, dir: impl AsRef<Path>) -> bool {
+        let dir = dir.as_ref();
+        fs.exists(&dir.join(CURRENT)) || fs.exists(&dir.join(LEGACY_MANIFEST))
+    }
+
+    pub fn create(
+        fs: Arc<dyn FileSystem>,
+        dir: impl AsRef<Path>,
+        max_file_size: usize,
+    ) -> Result<Self> {
+        let dir = dir.as_ref();
+        let file_number = 1;
+        let file = Self::create_file(fs.as_ref(), dir, file_number)?;
+        Self::set_current(fs.as_ref(), dir, file_number)?;
         Ok(Self {
-            file: Arc::new(Mutex::new(
-                OpenOptions::new()
-                    .read(true)
-                    .create_new(true)
-                    .write(true)
-                    .open(path)
-                    .context("failed to create manifest")?,
-            )),
+            fs,
+            dir: dir.to_path_buf(),
+            max_file_size,
+            file: Arc::new(Mutex::new(file)),
         })
     }
 
-    pub fn recover(path: impl AsRef<Path>) -> Result<(Self, Vec<ManifestRecord>)> {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .open(path)
-            .context("failed to recover manifest")?;
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf)?;
+    pub fn recover(
+        fs: Arc<dyn FileSystem>,
+        dir: impl AsRef<Path>,
+        max_file_size: usize,
+    ) -> Result<(Self, Vec<ManifestRecord>)> {
+        let dir = dir.as_ref();
+        let (path, file_number) = if fs.exists(&dir.join(CURRENT)) {
+            let current = String::from_utf8(fs.read(&dir.join(CURRENT))?)?;
+            let name = current.trim();
+            let Some(file_number) = parse_manifest_file_name(name) else {
+                bail!("invalid CURRENT file: {:?}", current);
+            };
+            (dir.join(name), file_number)
+        } else {
+            (dir.join(LEGACY_MANIFEST), 0)
+        };
+        let buf = fs.read(&path).context("failed to recover manifest")?;
         let mut buf_ptr = buf.as_slice();
         let mut records = Vec::new();
-        while buf_ptr.has_remaining() {
-            let len = buf_ptr.get_u64();
-            let slice = &buf_ptr[..len as usize];
-            let json = serde_json::from_slice::<ManifestRecord>(slice)?;
-            buf_ptr.advance(len as usize);
-            let checksum = buf_ptr.get_u32();
-            if checksum != crc32fast::hash(slice) {
-                bail!("checksum mismatched!");
+        let is_legacy = file_number == 0;
+        if is_legacy {
+            while buf_ptr.has_remaining() {
+                let len = get_u64(&mut buf_ptr)? as usize;
+                ensure_remaining(buf_ptr, len + std::mem::size_of::<u32>())?;
+                let slice = &buf_ptr[..len];
+                let json = serde_json::from_slice::<LegacyManifestRecord>(slice)?;
+                buf_ptr.advance(len);
+                let checksum = buf_ptr.get_u32();
+                if checksum != crc32fast::hash(slice) {
+                    bail!("checksum mismatched!");
+                }
+                records.push(ManifestRecord::Legacy(json));
+            }
+        } else {
+            ensure!(
+                buf_ptr.remaining() >= 4 && buf_ptr.get_u32() == MANIFEST_MAGIC,
+                "invalid manifest {}",
+                path.display()
+            );
+            while buf_ptr.has_remaining() {
+                // A record that was torn by a crash was never acknowledged, so it is dropped.
+                if buf_ptr.remaining() < std::mem::size_of::<u32>() {
+                    break;
+                }
+                let len = (&buf_ptr[..]).get_u32() as usize;
+                if buf_ptr.remaining() < len + 2 * std::mem::size_of::<u32>() {
+                    break;
+                }
+                buf_ptr.advance(std::mem::size_of::<u32>());
+                let slice = &buf_ptr[..len];
+                buf_ptr.advance(len);
+                let checksum = buf_ptr.get_u32();
+                if checksum != crc32fast::hash(slice) {
+                    bail!("checksum mismatched!");
+                }
+                records.push(ManifestRecord::Edit(VersionEdit::decode(slice)?));
             }
-            records.push(json);
         }
+        let size = buf.len() - buf_ptr.remaining();
+        let needs_roll_over = is_legacy || size < buf.len();
+        let mut file = fs
+            .open_writable(&path)
+            .context("failed to recover manifest")?;
+        file.seek(size as u64)?;
+        // A crash during rollover may leave behind a manifest that is no longer (or not yet) current.
+        Self::remove_obsolete_manifests(fs.as_ref(), dir, file_number)?;
         Ok((
             Self {
-                file: Arc::new(Mutex::new(file)),
+                fs,
+                dir: dir.to_path_buf(),
+                max_file_size,
+                file: Arc::new(Mutex::new(ManifestFile {
+                    file,
+                    file_number,
+                    size,
+                    needs_roll_over,
+                })),
             },
             records,
         ))
     }
 
+    /// Check if the recovered manifest must be rolled over to a new manifest before adding records. This is the case for
+    /// a manifest in the legacy JSON format, and for a manifest that ends with a record torn by a crash, as a shorter
+    /// record written over it would leave the rest of the torn record behind.
+    pub fn needs_roll_over(&self) -> bool {
+        self.file.lock().needs_roll_over
+    }
+
     pub fn add_record(
         &self,
         _state_lock_observer: &MutexGuard<()>,
-        record: ManifestRecord,
+        edit: VersionEdit,
     ) -> Result<()> {
-        self.add_record_when_init(record)
+        self.add_record_when_init(edit)
     }
 
-    pub fn add_record_when_init(&self, record: ManifestRecord) -> Result<()> {
+    pub fn add_record_when_init(&self, edit: VersionEdit) -> Result<()> {
         let mut file = self.file.lock();
-        let mut buf = serde_json::to_vec(&record)?;
+        assert!(
+            !file.needs_roll_over,
+            "cannot add records to a legacy or torn manifest"
+        );
+        Self::write_record(&mut file, &edit)
+    }
+
+    fn write_record(file: &mut ManifestFile, edit: &VersionEdit) -> Result<()> {
+        let mut buf = Vec::new();
+        edit.encode(&mut buf);
         let hash = crc32fast::hash(&buf);
-        file.write_all(&(buf.len() as u64).to_be_bytes())?;
+        file.file.write_all(&(buf.len() as u32).to_be_bytes())?;
         buf.put_u32(hash);
-        file.write_all(&buf)?;
+        file.file.write_all(&buf)?;
+        file.file.sync_all()?;
+        file.size += std::mem::size_of::<u32>() + buf.len();
+        Ok(())
+    }
+
+    /// Check if the current manifest has grown beyond the size limit and should be rolled over.
+    pub fn should_roll_over(&self) -> bool {
+        self.max_file_size > 0 && self.file.lock().size >= self.max_file_size
+    }
+
+    /// Start a new manifest with the snapshot edit, point `CURRENT` to it, and remove the old manifest. The caller
+    /// must hold the state lock so that no record is added between taking the snapshot and writing it.
+    pub fn roll_over(
+        &self,
+        _state_lock_observer: &MutexGuard<()>,
+        snapshot: VersionEdit,
+    ) -> Result<()> {
+        self.roll_over_when_init(snapshot)
+    }
+
+    pub fn roll_over_when_init(&self, snapshot: VersionEdit) -> Result<()> {
+        assert!(
+            snapshot.is_snapshot,
+            "a new manifest must start with a snapshot"
+        );
+        let mut current = self.file.lock();
+        let file_number = current.file_number + 1;
+        let mut new_file = Self::create_file(self.fs.as_ref(), &self.dir, file_number)?;
+        Self::write_record(&mut new_file, &snapshot)?;
+        Self::set_current(self.fs.as_ref(), &self.dir, file_number)?;
+        *current = new_file;
+        Self::remove_obsolete_manifests(self.fs.as_ref(), &self.dir, file_number)?;
+        Ok(())
+    }
+
+    fn create_file(fs: &dyn FileSystem, dir: &Path, file_number: u64) -> Result<ManifestFile> {
+        let mut file = fs
+            .create(&dir.join(manifest_file_name(file_number)))
+            .context("failed to create manifest")?;
+        file.write_all(&MANIFEST_MAGIC.to_be_bytes())?;
         file.sync_all()?;
+        Ok(ManifestFile {
+            file,
+            file_number,
+            size: std::mem::size_of::<u32>(),
+            needs_roll_over: false,
+        })
+    }
+
+    /// Atomically point `CURRENT` to the manifest with the given file number.
+    fn set_current(fs: &dyn FileSystem, dir: &Path, file_number: u64) -> Result<()> {
+        let tmp_path = dir.join(format!("{}.tmp", CURRENT));
+        fs.write(
+            &tmp_path,
+            format!("{}\n", manifest_file_name(file_number)).as_bytes(),
+        )?;
+        fs.rename(&tmp_path, &dir.join(CURRENT))?;
+        fs.sync_dir(dir)?;
+        Ok(())
+    }
+
+    fn remove_obsolete_manifests(
+        fs: &dyn FileSystem,
+        dir: &Path,
+        current_file_number: u64,
+    ) -> Result<()> {
+        for path in fs.list_dir(dir)? {
+            let Some(name) = path.file_name().and_then(|x| x.to_str()) else {
+                continue;
+            };
+            let obsolete = if name == LEGACY_MANIFEST {
+                current_file_number != 0
+            } else if let Some(file_number) = parse_manifest_file_name(name) {
+                file_number != current_file_number
+            } else {
+                false
+            };
+            if obsolete {
+                fs.remove_file(&path)?;
+            }
+        }
         Ok(())
     }
 }
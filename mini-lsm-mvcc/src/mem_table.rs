@@ -23,6 +23,7 @@ use crossbeam_skiplist::SkipMap;
 use crossbeam_skiplist::map::Entry;
 use ouroboros::self_referencing;
 
+use crate::fs::FileSystem;
 use crate::iterators::StorageIterator;
 use crate::key::{KeyBytes, KeySlice, TS_DEFAULT, TS_RANGE_BEGIN, TS_RANGE_END};
 use crate::table::SsTableBuilder;
@@ -100,26 +101,50 @@ impl MemTable {
     }
 
     /// Create a new mem-table with WAL
-    pub fn create_with_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
-        Ok(Self {
+    pub fn create_with_wal(id: usize, wal: Wal) -> Self {
+        Self {
             id,
             map: Arc::new(SkipMap::new()),
-            wal: Some(Wal::create(path.as_ref())?),
+            wal: Some(wal),
             approximate_size: Arc::new(AtomicUsize::new(0)),
-        })
+        }
     }
 
     /// Create a memtable from WAL
-    pub fn recover_from_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
+    pub fn recover_from_wal(
+        fs: &dyn FileSystem,
+        id: usize,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
         let map = Arc::new(SkipMap::new());
         Ok(Self {
             id,
-            wal: Some(Wal::recover(path.as_ref(), &map)?),
+            wal: Some(Wal::recover(fs, path.as_ref(), &map)?),
             map,
             approximate_size: Arc::new(AtomicUsize::new(0)),
         })
     }
 
+    /// Create a memtable without WAL from the readable records of a damaged WAL. Returns the memtable and whether the
+    /// whole WAL is readable.
+    pub fn salvage_from_wal(
+        fs: &dyn FileSystem,
+        id: usize,
+        path: impl AsRef<Path>,
+    ) -> Result<(Self, bool)> {
+        let map = Arc::new(SkipMap::new());
+        let is_complete = Wal::salvage(fs, path, &map)?;
+        Ok((
+            Self {
+                id,
+                wal: None,
+                map,
+                approximate_size: Arc::new(AtomicUsize::new(0)),
+            },
+            is_complete,
+        ))
+    }
+
     /// Get a value by key. Should not be used in week 3.
     pub fn get(&self, key: KeySlice) -> Option<Bytes> {
         let key_bytes = KeyBytes::from_bytes_with_ts(
@@ -0,0 +1,51 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use bytes::Bytes;
+
+use crate::value;
+
+/// Combines the operands written with `MiniLsm::merge` with the value of a key, so that read-modify-write updates
+/// such as counters do not need to read the key first.
+pub trait MergeOperator: Send + Sync {
+    /// Apply `operands`, oldest first, to the value of `key`, which is `None` if the key does not exist or is
+    /// deleted. An empty result deletes the key.
+    fn full_merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Bytes;
+
+    /// Combine `operands`, oldest first, into a single operand, or return `None` if they cannot be combined without
+    /// the value of the key. Compactions use it when the value is in a lower level.
+    fn partial_merge(&self, _key: &[u8], _operands: &[&[u8]]) -> Option<Bytes> {
+        None
+    }
+}
+
+impl std::fmt::Debug for dyn MergeOperator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MergeOperator")
+    }
+}
+
+/// Apply the decoded `operands`, oldest first, to the decoded value `existing` and encode the result as a value
+/// written with `put`. The result is empty, i.e., a tombstone, if the merge operator deletes the key.
+pub(crate) fn full_merge(
+    merge_operator: &dyn MergeOperator,
+    key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &[&[u8]],
+) -> Bytes {
+    let merged = merge_operator.full_merge(key, existing, operands);
+    if merged.is_empty() {
+        return merged;
+    }
+    Bytes::from(value::encode_plain(&merged).into_owned())
+}
@@ -19,6 +19,7 @@ use std::{
         Arc,
         atomic::{AtomicBool, Ordering},
     },
+    time::Duration,
 };
 
 use anyhow::{Result, bail};
@@ -32,7 +33,9 @@ use crate::{
     lsm_iterator::{FusedIterator, LsmIterator},
     lsm_storage::{LsmStorageInner, WriteBatchRecord},
     mem_table::map_bound,
+    merge_operator,
     mvcc::CommittedTxnData,
+    value,
 };
 
 pub struct Transaction {
@@ -55,10 +58,14 @@ impl Transaction {
             read_set.insert(farmhash::hash32(key));
         }
         if let Some(entry) = self.local_storage.get(key) {
-            if entry.value().is_empty() {
+            if value::is_merge_operand(entry.value()) {
+                let merged = self.merge_with_storage(key, &[value::decode(entry.value())])?;
+                return Ok((!merged.is_empty()).then(|| value::decode_bytes(&merged)));
+            }
+            if !value::is_live(entry.value(), self.inner.options.clock.now_millis()) {
                 return Ok(None);
             } else {
-                return Ok(Some(entry.value().clone()));
+                return Ok(Some(value::decode_bytes(entry.value())));
             }
         }
         self.inner.get_with_ts(key, self.read_ts)
@@ -87,11 +94,72 @@ impl Transaction {
     }
 
     pub fn put(&self, key: &[u8], value: &[u8]) {
+        self.put_encoded(key, Bytes::from(value::encode_plain(value).into_owned()));
+    }
+
+    /// Put a key-value pair that expires `ttl` after this call.
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) {
+        self.put_expiring(key, value, self.inner.expire_at(ttl));
+    }
+
+    /// Put a key-value pair that expires at `expire_at` milliseconds on the clock of the storage.
+    pub(crate) fn put_expiring(&self, key: &[u8], value: &[u8], expire_at: u64) {
+        self.put_encoded(key, value::encode_expiring(value, expire_at));
+    }
+
+    /// Write a merge operand for a key. Unlike `get` followed by `put`, it does not add the key to the read set.
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        if self.committed.load(Ordering::SeqCst) {
+            panic!("cannot operate on committed txn!");
+        }
+        let merge_operator = self.inner.merge_operator()?;
+        // The local storage holds one value per key, so the operand is combined with an earlier write to the key.
+        let value = match self.local_storage.get(key) {
+            None => value::encode_merge_operand(operand),
+            Some(entry) if value::is_merge_operand(entry.value()) => {
+                let operands = [value::decode(entry.value()), operand];
+                match merge_operator.partial_merge(key, &operands) {
+                    Some(operand) => value::encode_merge_operand(&operand),
+                    None => {
+                        if let Some(guard) = &self.key_hashes {
+                            let mut guard = guard.lock();
+                            let (_, read_set) = &mut *guard;
+                            read_set.insert(farmhash::hash32(key));
+                        }
+                        self.merge_with_storage(key, &operands)?
+                    }
+                }
+            }
+            Some(entry) => {
+                let now = self.inner.options.clock.now_millis();
+                let existing = Some(entry.value())
+                    .filter(|x| value::is_live(x, now))
+                    .map(|x| value::decode(x));
+                merge_operator::full_merge(merge_operator.as_ref(), key, existing, &[operand])
+            }
+        };
+        self.put_encoded(key, value);
+        Ok(())
+    }
+
+    /// Apply `operands`, oldest first, to the value of `key` at the read timestamp, and return the encoded result.
+    fn merge_with_storage(&self, key: &[u8], operands: &[&[u8]]) -> Result<Bytes> {
+        let merge_operator = self.inner.merge_operator()?;
+        let existing = self.inner.get_with_ts(key, self.read_ts)?;
+        Ok(merge_operator::full_merge(
+            merge_operator.as_ref(),
+            key,
+            existing.as_deref(),
+            operands,
+        ))
+    }
+
+    fn put_encoded(&self, key: &[u8], value: Bytes) {
         if self.committed.load(Ordering::SeqCst) {
             panic!("cannot operate on committed txn!");
         }
         self.local_storage
-            .insert(Bytes::copy_from_slice(key), Bytes::copy_from_slice(value));
+            .insert(Bytes::copy_from_slice(key), value);
         if let Some(key_hashes) = &self.key_hashes {
             let mut key_hashes = key_hashes.lock();
             let (write_hashes, _) = &mut *key_hashes;
@@ -234,6 +302,10 @@ impl StorageIterator for TxnLocalIterator {
 pub struct TxnIterator {
     txn: Arc<Transaction>,
     iter: TwoMergeIterator<TxnLocalIterator, FusedIterator<LsmIterator>>,
+    /// The current time of the storage clock when the iterator is created.
+    now: u64,
+    /// The encoded value of the current key if the transaction wrote a merge operand for it.
+    merged: Option<Bytes>,
 }
 
 impl TxnIterator {
@@ -241,7 +313,13 @@ impl TxnIterator {
         txn: Arc<Transaction>,
         iter: TwoMergeIterator<TxnLocalIterator, FusedIterator<LsmIterator>>,
     ) -> Result<Self> {
-        let mut iter = Self { txn, iter };
+        let now = txn.inner.options.clock.now_millis();
+        let mut iter = Self {
+            txn,
+            iter,
+            now,
+            merged: None,
+        };
         iter.skip_deletes()?;
         if iter.is_valid() {
             iter.add_to_read_set(iter.key());
@@ -250,7 +328,19 @@ impl TxnIterator {
     }
 
     fn skip_deletes(&mut self) -> Result<()> {
-        while self.iter.is_valid() && self.iter.value().is_empty() {
+        while self.iter.is_valid() {
+            // `LsmIterator` merges the operands in the storage, so an operand here is written by the transaction.
+            if value::is_merge_operand(self.iter.value()) {
+                let merged = self
+                    .txn
+                    .merge_with_storage(self.iter.key(), &[value::decode(self.iter.value())])?;
+                if !merged.is_empty() {
+                    self.merged = Some(merged);
+                    break;
+                }
+            } else if value::is_live(self.iter.value(), self.now) {
+                break;
+            }
             self.iter.next()?;
         }
         Ok(())
@@ -272,7 +362,10 @@ impl StorageIterator for TxnIterator {
         Self: 'a;
 
     fn value(&self) -> &[u8] {
-        self.iter.value()
+        match &self.merged {
+            Some(merged) => value::decode(merged),
+            None => value::decode(self.iter.value()),
+        }
     }
 
     fn key(&self) -> Self::KeyType<'_> {
@@ -284,6 +377,7 @@ impl StorageIterator for TxnIterator {
     }
 
     fn next(&mut self) -> Result<()> {
+        self.merged = None;
         self.iter.next()?;
         self.skip_deletes()?;
         if self.is_valid() {
@@ -0,0 +1,132 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+/// The priority of an I/O request. Pending high priority requests are served before any low priority request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Flushes, which block writes once there are too many immutable memtables.
+    High,
+    /// Compactions.
+    Low,
+}
+
+/// Tokens are refilled continuously, and at most this period's worth of tokens can be saved up for a burst.
+const REFILL_PERIOD: Duration = Duration::from_millis(100);
+
+struct RateLimiterState {
+    bytes_per_second: usize,
+    tokens: usize,
+    last_refill: Instant,
+    /// The number of high priority requests waiting for tokens.
+    high_priority_waiters: usize,
+    total_bytes: [u64; 2],
+}
+
+impl RateLimiterState {
+    fn burst_size(&self) -> usize {
+        ((self.bytes_per_second as f64 * REFILL_PERIOD.as_secs_f64()) as usize).max(1)
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill);
+        let new_tokens = (elapsed.as_secs_f64() * self.bytes_per_second as f64) as usize;
+        if new_tokens > 0 {
+            self.tokens = (self.tokens + new_tokens).min(self.burst_size());
+            self.last_refill = now;
+        }
+    }
+}
+
+/// A token bucket that limits the I/O throughput of the background jobs.
+pub struct RateLimiter {
+    state: Mutex<RateLimiterState>,
+    cv: Condvar,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter that allows `bytes_per_second` bytes of I/O per second. 0 disables the limit.
+    pub fn new(bytes_per_second: usize) -> Self {
+        Self {
+            state: Mutex::new(RateLimiterState {
+                bytes_per_second,
+                tokens: 0,
+                last_refill: Instant::now(),
+                high_priority_waiters: 0,
+                total_bytes: [0; 2],
+            }),
+            cv: Condvar::new(),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> usize {
+        self.state.lock().bytes_per_second
+    }
+
+    /// Change the rate. Pending requests continue at the new rate.
+    pub fn set_bytes_per_second(&self, bytes_per_second: usize) {
+        let mut state = self.state.lock();
+        state.refill();
+        state.bytes_per_second = bytes_per_second;
+        state.tokens = state.tokens.min(state.burst_size());
+        self.cv.notify_all();
+    }
+
+    /// The total number of bytes requested with the given priority.
+    pub fn total_bytes(&self, priority: IoPriority) -> u64 {
+        self.state.lock().total_bytes[priority as usize]
+    }
+
+    /// Block until `bytes` bytes of I/O are allowed. A large request is served in bursts, so that it does not hold
+    /// back the other requests for long.
+    pub fn request(&self, bytes: usize, priority: IoPriority) {
+        let mut state = self.state.lock();
+        state.total_bytes[priority as usize] += bytes as u64;
+        let mut remaining = bytes;
+        if priority == IoPriority::High {
+            state.high_priority_waiters += 1;
+        }
+        while remaining > 0 {
+            if state.bytes_per_second == 0 {
+                break;
+            }
+            state.refill();
+            let chunk = remaining.min(state.burst_size());
+            let yield_to_high_priority =
+                priority == IoPriority::Low && state.high_priority_waiters > 0;
+            if !yield_to_high_priority && state.tokens >= chunk {
+                state.tokens -= chunk;
+                remaining -= chunk;
+                // Tokens are not consumed in order, so the other waiters must check again.
+                self.cv.notify_all();
+                continue;
+            }
+            let wait = if yield_to_high_priority {
+                REFILL_PERIOD
+            } else {
+                let missing = chunk - state.tokens;
+                Duration::from_secs_f64(missing as f64 / state.bytes_per_second as f64)
+            };
+            self.cv.wait_for(&mut state, wait);
+        }
+        if priority == IoPriority::High {
+            state.high_priority_waiters -= 1;
+            self.cv.notify_all();
+        }
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::compact::CompactionOptions;
+use crate::fs::FileSystem;
+use crate::lsm_storage::{LsmStorageInner, LsmStorageOptions, LsmStorageState};
+use crate::manifest::{Manifest, VersionEdit};
+use crate::mem_table::MemTable;
+use crate::table::{FileObject, SsTable, SsTableBuilder};
+
+/// The result of `repair_db`.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// The SSTs found in the directory that passed validation.
+    pub recovered_ssts: Vec<usize>,
+    /// The SSTs built from WALs.
+    pub wal_ssts: Vec<usize>,
+    /// The files moved into the `lost/` directory.
+    pub lost_files: Vec<PathBuf>,
+    /// The largest commit timestamp in the repaired database.
+    pub max_ts: u64,
+}
+
+fn parse_file_id(path: &Path, extension: &str) -> Option<usize> {
+    if path.extension()? != extension {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Open an SST and read all of its blocks, so that every checksum is validated.
+fn open_and_validate_sst(fs: &dyn FileSystem, id: usize, path: &Path) -> Result<SsTable> {
+    let sst = SsTable::open(id, None, FileObject::open_with_fs(fs, path)?)?;
+    for block_idx in 0..sst.num_of_blocks() {
+        sst.read_block(block_idx)?;
+    }
+    Ok(sst)
+}
+
+/// Move a file into the `lost/` directory, without overwriting the files quarantined by a previous repair.
+fn quarantine(fs: &dyn FileSystem, path: &Path, lost_dir: &Path) -> Result<PathBuf> {
+    fs.create_dir_all(lost_dir)?;
+    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+    let mut dst = lost_dir.join(&name);
+    let mut suffix = 1;
+    while fs.exists(&dst) {
+        dst = lost_dir.join(format!("{}.{}", name, suffix));
+        suffix += 1;
+    }
+    fs.rename(path, &dst)?;
+    Ok(dst)
+}
+
+/// Rebuild the manifest of a database from the files in the directory, e.g., when the manifest is lost or corrupted.
+/// The database must not be open.
+///
+/// All valid SSTs are put into L0, or into one tier each for tiered compaction, and the WALs are replayed into new
+/// SSTs. SSTs that fail validation, damaged WALs and the old manifest are moved into the `lost/` directory.
+pub fn repair_db(path: impl AsRef<Path>, options: &LsmStorageOptions) -> Result<RepairReport> {
+    let path = path.as_ref();
+    let fs = options.fs.as_ref();
+    let lost_dir = path.join("lost");
+    let mut report = RepairReport::default();
+    let mut ssts = Vec::new();
+    let mut wals = Vec::new();
+    let mut manifest_files = Vec::new();
+    for entry_path in fs.list_dir(path)? {
+        let name = entry_path.file_name().unwrap().to_string_lossy();
+        if let Some(id) = parse_file_id(&entry_path, "sst") {
+            match open_and_validate_sst(fs, id, &entry_path) {
+                Ok(sst) => ssts.push(sst),
+                Err(e) => {
+                    println!("SST {} is corrupted: {}", entry_path.display(), e);
+                    report
+                        .lost_files
+                        .push(quarantine(fs, &entry_path, &lost_dir)?);
+                }
+            }
+        } else if let Some(id) = parse_file_id(&entry_path, "wal") {
+            wals.push((id, entry_path));
+        } else if name == "CURRENT" || name.starts_with("MANIFEST") {
+            manifest_files.push(entry_path);
+        }
+    }
+    wals.sort_by_key(|(id, _)| *id);
+
+    // A WAL whose SST already exists has been flushed, and was not deleted before the crash.
+    let mut replayed_wals = Vec::new();
+    let mut damaged_wals = Vec::new();
+    for (id, wal_path) in wals {
+        if ssts.iter().any(|sst| sst.sst_id() == id) {
+            replayed_wals.push(wal_path);
+            continue;
+        }
+        let (memtable, is_complete) = match MemTable::salvage_from_wal(fs, id, &wal_path) {
+            Ok(res) => res,
+            Err(e) => {
+                println!("WAL {} is unreadable: {}", wal_path.display(), e);
+                damaged_wals.push(wal_path);
+                continue;
+            }
+        };
+        if is_complete {
+            replayed_wals.push(wal_path);
+        } else {
+            println!(
+                "WAL {} is damaged, keeping the readable records",
+                wal_path.display()
+            );
+            damaged_wals.push(wal_path);
+        }
+        if memtable.is_empty() {
+            continue;
+        }
+        let mut builder = SsTableBuilder::new(options.block_size);
+        builder.set_created_at(options.clock.now_millis());
+        memtable.flush(&mut builder)?;
+        let sst_path = LsmStorageInner::path_of_sst_static(path, id);
+        let sst = builder.build_with_fs(fs, id, None, &sst_path)?;
+        report.wal_ssts.push(id);
+        ssts.push(sst);
+    }
+
+    // With MVCC, every key carries its commit timestamp, so the SSTs do not need to be ordered to produce correct
+    // reads. They are still ordered from the latest to the oldest to keep the structure close to the original one.
+    ssts.sort_by_key(|sst| std::cmp::Reverse((sst.max_ts(), sst.sst_id())));
+    let mut state = LsmStorageState::create(options);
+    for sst in ssts {
+        let id = sst.sst_id();
+        report.max_ts = report.max_ts.max(sst.max_ts());
+        if !report.wal_ssts.contains(&id) {
+            report.recovered_ssts.push(id);
+        }
+        if let CompactionOptions::Tiered(_) | CompactionOptions::Hybrid(_) =
+            options.compaction_options
+        {
+            state.levels.push((id, vec![id]));
+        } else {
+            state.l0_sstables.push(id);
+        }
+        state.sstables.insert(id, Arc::new(sst));
+    }
+    report.recovered_ssts.sort();
+
+    for manifest_path in manifest_files {
+        report
+            .lost_files
+            .push(quarantine(fs, &manifest_path, &lost_dir)?);
+    }
+    let manifest = Manifest::create(options.fs.clone(), path, options.max_manifest_file_size)?;
+    let mut edit = VersionEdit::snapshot(&state);
+    // All WALs are replayed into SSTs, so there are no memtables to recover.
+    edit.new_memtables.clear();
+    manifest.add_record_when_init(edit)?;
+    LsmStorageInner::write_options(fs, path, options)?;
+    fs.sync_dir(path)?;
+
+    // The WALs can only be removed after the new manifest is durable.
+    for wal_path in replayed_wals {
+        fs.remove_file(&wal_path)?;
+    }
+    for wal_path in damaged_wals {
+        report
+            .lost_files
+            .push(quarantine(fs, &wal_path, &lost_dir)?);
+    }
+    fs.sync_dir(path)?;
+    println!(
+        "repaired {}: {} SSTs recovered, {} SSTs built from WALs, {} files moved to {}",
+        path.display(),
+        report.recovered_ssts.len(),
+        report.wal_ssts.len(),
+        report.lost_files.len(),
+        lost_dir.display()
+    );
+    Ok(report)
+}
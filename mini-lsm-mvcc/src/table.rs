@@ -16,18 +16,21 @@ pub(crate) mod bloom;
 mod builder;
 mod iterator;
 
-use std::fs::File;
+use std::borrow::Cow;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use anyhow::{Result, anyhow, bail};
+use anyhow::{Result, anyhow, bail, ensure};
 pub use builder::SsTableBuilder;
 use bytes::{Buf, BufMut};
 pub use iterator::SsTableIterator;
 
-use crate::block::Block;
+use crate::block::{Block, BlockBuilder, BlockIterator};
+use crate::fs::{FileSystem, RandomAccessFile, default_fs};
 use crate::key::{KeyBytes, KeySlice};
 use crate::lsm_storage::BlockCache;
+use crate::value;
 
 use self::bloom::Bloom;
 
@@ -41,9 +44,59 @@ pub struct BlockMeta {
     pub last_key: KeyBytes,
 }
 
+/// The version of the properties stored after the block meta, i.e., the stats. Version 1 has no `created_at`.
+///
+/// SSTs written before the version was added store no stats, only `num_entries` and `num_deletes`, or the first three
+/// stats, and are told apart by the size of the properties, which is a multiple of 8. The `u32` version makes the size
+/// of the properties 4 more than a multiple of 8, so they are never mistaken for these layouts.
+const PROPERTIES_VERSION: u32 = 2;
+
+/// The number of entries and deletes in an SST, the timestamp of its oldest version, and the time it was written,
+/// stored after the block meta.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TableStats {
+    pub num_entries: u64,
+    pub num_deletes: u64,
+    pub min_ts: u64,
+    /// The time the SST was written in milliseconds since the UNIX epoch, or 0 for SSTs written before it was
+    /// recorded. Unlike the file modification time, it is kept when the file is copied.
+    pub created_at: u64,
+}
+
+impl TableStats {
+    /// The share of deletes in the entries, from 0 to 1.
+    pub fn tombstone_ratio(&self) -> f64 {
+        if self.num_entries == 0 {
+            return 0.0;
+        }
+        self.num_deletes as f64 / self.num_entries as f64
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u64(self.num_entries);
+        buf.put_u64(self.num_deletes);
+        buf.put_u64(self.min_ts);
+        buf.put_u64(self.created_at);
+    }
+
+    fn decode(buf: &mut &[u8], version: u32) -> Self {
+        Self {
+            num_entries: buf.get_u64(),
+            num_deletes: buf.get_u64(),
+            min_ts: buf.get_u64(),
+            created_at: if version >= 2 { buf.get_u64() } else { 0 },
+        }
+    }
+}
+
 impl BlockMeta {
     /// Encode block meta to a buffer.
-    pub fn encode_block_meta(block_meta: &[BlockMeta], max_ts: u64, buf: &mut Vec<u8>) {
+    pub fn encode_block_meta(
+        block_meta: &[BlockMeta],
+        max_ts: u64,
+        stats: &TableStats,
+        buf: &mut Vec<u8>,
+    ) {
         let mut estimated_size = std::mem::size_of::<u32>(); // number of blocks
         for meta in block_meta {
             // The size of offset
@@ -58,6 +111,8 @@ impl BlockMeta {
             estimated_size += meta.last_key.raw_len();
         }
         estimated_size += std::mem::size_of::<u64>(); // max timestamp
+        estimated_size += std::mem::size_of::<u32>(); // properties version
+        estimated_size += std::mem::size_of::<u64>() * 4; // stats
         estimated_size += std::mem::size_of::<u32>(); // checksum
 
         // Reserve the space to improve performance, especially when the size of incoming data is
@@ -75,12 +130,14 @@ impl BlockMeta {
             buf.put_u64(meta.last_key.ts());
         }
         buf.put_u64(max_ts);
+        buf.put_u32(PROPERTIES_VERSION);
+        stats.encode(buf);
         buf.put_u32(crc32fast::hash(&buf[original_len + 4..]));
         assert_eq!(estimated_size, buf.len() - original_len);
     }
 
-    /// Decode block meta from a buffer.
-    pub fn decode_block_meta(mut buf: &[u8]) -> Result<(Vec<BlockMeta>, u64)> {
+    /// Decode block meta from a buffer. The stats are `None` for SSTs written before they were added.
+    pub fn decode_block_meta(mut buf: &[u8]) -> Result<(Vec<BlockMeta>, u64, Option<TableStats>)> {
         let mut block_meta = Vec::new();
         let num = buf.get_u32() as usize;
         let checksum = crc32fast::hash(&buf[..buf.remaining() - 4]);
@@ -99,25 +156,47 @@ impl BlockMeta {
             });
         }
         let max_ts = buf.get_u64();
+        let properties_size = buf.remaining() - 4;
+        let stats = match properties_size {
+            0 => None,
+            // The oldest version is unknown, so the SST is assumed to hold the oldest data.
+            16 => Some(TableStats {
+                num_entries: buf.get_u64(),
+                num_deletes: buf.get_u64(),
+                min_ts: 0,
+                created_at: 0,
+            }),
+            24 => Some(TableStats::decode(&mut buf, 1)),
+            _ => {
+                ensure!(properties_size >= 4, "invalid SST properties");
+                let version = buf.get_u32();
+                let expected_remaining = match version {
+                    1 => 28,
+                    PROPERTIES_VERSION => 36,
+                    _ => bail!("unsupported SST properties version {}", version),
+                };
+                ensure!(
+                    buf.remaining() == expected_remaining,
+                    "invalid SST properties"
+                );
+                Some(TableStats::decode(&mut buf, version))
+            }
+        };
         if buf.get_u32() != checksum {
             bail!("meta checksum mismatched");
         }
 
-        Ok((block_meta, max_ts))
+        Ok((block_meta, max_ts, stats))
     }
 }
 
-/// A file object.
-pub struct FileObject(Option<File>, u64);
+/// A file object, with its size and the time it was written.
+pub struct FileObject(Option<Box<dyn RandomAccessFile>>, u64, SystemTime);
 
 impl FileObject {
     pub fn read(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
-        use std::os::unix::fs::FileExt;
         let mut data = vec![0; len as usize];
-        self.0
-            .as_ref()
-            .unwrap()
-            .read_exact_at(&mut data[..], offset)?;
+        self.0.as_ref().unwrap().read_at(&mut data[..], offset)?;
         Ok(data)
     }
 
@@ -125,20 +204,34 @@ impl FileObject {
         self.1
     }
 
+    /// The time the file was last written. SSTs are never modified, so it is the time the SST was created.
+    pub fn modified(&self) -> SystemTime {
+        self.2
+    }
+
     /// Create a new file object (day 2) and write the file to the disk (day 4).
     pub fn create(path: &Path, data: Vec<u8>) -> Result<Self> {
-        std::fs::write(path, &data)?;
-        File::open(path)?.sync_all()?;
+        Self::create_with_fs(default_fs().as_ref(), path, data)
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::open_with_fs(default_fs().as_ref(), path)
+    }
+
+    /// Write the file to the given file system and open it.
+    pub fn create_with_fs(fs: &dyn FileSystem, path: &Path, data: Vec<u8>) -> Result<Self> {
+        fs.write(path, &data)?;
         Ok(FileObject(
-            Some(File::options().read(true).write(false).open(path)?),
+            Some(fs.open(path)?),
             data.len() as u64,
+            fs.modified(path)?,
         ))
     }
 
-    pub fn open(path: &Path) -> Result<Self> {
-        let file = File::options().read(true).write(false).open(path)?;
-        let size = file.metadata()?.len();
-        Ok(FileObject(Some(file), size))
+    pub fn open_with_fs(fs: &dyn FileSystem, path: &Path) -> Result<Self> {
+        let file = fs.open(path)?;
+        let size = file.size();
+        Ok(FileObject(Some(file), size, fs.modified(path)?))
     }
 }
 
@@ -156,6 +249,9 @@ pub struct SsTable {
     last_key: KeyBytes,
     pub(crate) bloom: Option<Bloom>,
     max_ts: u64,
+    stats: Option<TableStats>,
+    /// The SST was written before values were encoded, see `crate::value`.
+    unencoded_values: bool,
 }
 impl SsTable {
     #[cfg(test)]
@@ -173,7 +269,7 @@ impl SsTable {
         let raw_meta_offset = file.read(bloom_offset - 4, 4)?;
         let block_meta_offset = (&raw_meta_offset[..]).get_u32() as u64;
         let raw_meta = file.read(block_meta_offset, bloom_offset - 4 - block_meta_offset)?;
-        let (block_meta, max_ts) = BlockMeta::decode_block_meta(&raw_meta[..])?;
+        let (block_meta, max_ts, stats) = BlockMeta::decode_block_meta(&raw_meta[..])?;
         Ok(Self {
             file,
             first_key: block_meta.first().unwrap().first_key.clone(),
@@ -184,6 +280,9 @@ impl SsTable {
             block_cache,
             bloom: Some(bloom_filter),
             max_ts,
+            // Values are encoded since before the stats were added.
+            unencoded_values: stats.is_none(),
+            stats,
         })
     }
 
@@ -195,7 +294,7 @@ impl SsTable {
         last_key: KeyBytes,
     ) -> Self {
         Self {
-            file: FileObject(None, file_size),
+            file: FileObject(None, file_size, UNIX_EPOCH),
             block_meta: vec![],
             block_meta_offset: 0,
             id,
@@ -204,6 +303,8 @@ impl SsTable {
             last_key,
             bloom: None,
             max_ts: 0,
+            stats: None,
+            unencoded_values: false,
         }
     }
 
@@ -223,7 +324,30 @@ impl SsTable {
         if checksum != crc32fast::hash(block_data) {
             bail!("block checksum mismatched");
         }
-        Ok(Arc::new(Block::decode(block_data)))
+        let block = Arc::new(Block::decode(block_data));
+        if self.unencoded_values {
+            return Ok(Self::escape_unencoded_values(block));
+        }
+        Ok(block)
+    }
+
+    /// Escape the values of a block that were written before values were encoded, so that a value starting with the
+    /// marker is not read as encoded metadata. The block is only rebuilt if it has such a value.
+    fn escape_unencoded_values(block: Arc<Block>) -> Arc<Block> {
+        let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+        let mut builder = BlockBuilder::new(usize::MAX);
+        let mut escaped = false;
+        while iter.is_valid() {
+            let value = value::encode_plain(iter.value());
+            escaped |= matches!(value, Cow::Owned(_));
+            assert!(builder.add(iter.key(), &value));
+            iter.next();
+        }
+        if escaped {
+            Arc::new(builder.build())
+        } else {
+            block
+        }
     }
 
     /// Read a block from disk, with block cache.
@@ -269,4 +393,9 @@ impl SsTable {
     pub fn max_ts(&self) -> u64 {
         self.max_ts
     }
+
+    /// The number of entries and deletes, or `None` if the SST was written without them.
+    pub fn stats(&self) -> Option<TableStats> {
+        self.stats
+    }
 }
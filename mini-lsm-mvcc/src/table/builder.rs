@@ -19,8 +19,10 @@ use anyhow::Result;
 use bytes::BufMut;
 
 use super::bloom::Bloom;
-use super::{BlockMeta, FileObject, SsTable};
+use super::{BlockMeta, FileObject, SsTable, TableStats};
 use crate::block::BlockBuilder;
+use crate::clock::{Clock, SystemClock};
+use crate::fs::{FileSystem, default_fs};
 use crate::key::{KeySlice, KeyVec};
 use crate::lsm_storage::BlockCache;
 
@@ -34,6 +36,7 @@ pub struct SsTableBuilder {
     block_size: usize,
     key_hashes: Vec<u32>,
     max_ts: u64,
+    stats: TableStats,
 }
 
 impl SsTableBuilder {
@@ -48,9 +51,19 @@ impl SsTableBuilder {
             builder: BlockBuilder::new(block_size),
             key_hashes: Vec::new(),
             max_ts: 0,
+            stats: TableStats {
+                min_ts: u64::MAX,
+                created_at: SystemClock.now_millis(),
+                ..Default::default()
+            },
         }
     }
 
+    /// Set the time the SST is written, in milliseconds since the UNIX epoch. It is the current system time by default.
+    pub fn set_created_at(&mut self, created_at: u64) {
+        self.stats.created_at = created_at;
+    }
+
     /// Adds a key-value pair to SSTable
     pub fn add(&mut self, key: KeySlice, value: &[u8]) {
         if self.first_key.is_empty() {
@@ -61,6 +74,11 @@ impl SsTableBuilder {
             self.max_ts = key.ts();
         }
         self.key_hashes.push(farmhash::fingerprint32(key.key_ref()));
+        self.stats.num_entries += 1;
+        self.stats.min_ts = self.stats.min_ts.min(key.ts());
+        if value.is_empty() {
+            self.stats.num_deletes += 1;
+        }
 
         if self.builder.add(key, value) {
             self.last_key.set_from_slice(key);
@@ -76,6 +94,11 @@ impl SsTableBuilder {
         self.last_key.set_from_slice(key);
     }
 
+    /// Check if no key-value pair has been added.
+    pub fn is_empty(&self) -> bool {
+        self.key_hashes.is_empty()
+    }
+
     /// Get the estimated size of the SSTable.
     pub fn estimated_size(&self) -> usize {
         self.data.len()
@@ -96,7 +119,18 @@ impl SsTableBuilder {
 
     /// Builds the SSTable and writes it to the given path. Use the `FileObject` structure to manipulate the disk objects.
     pub fn build(
+        self,
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        path: impl AsRef<Path>,
+    ) -> Result<SsTable> {
+        self.build_with_fs(default_fs().as_ref(), id, block_cache, path)
+    }
+
+    /// Builds the SSTable and writes it to the given path on the given file system.
+    pub fn build_with_fs(
         mut self,
+        fs: &dyn FileSystem,
         id: usize,
         block_cache: Option<Arc<BlockCache>>,
         path: impl AsRef<Path>,
@@ -104,7 +138,7 @@ impl SsTableBuilder {
         self.finish_block();
         let mut buf = self.data;
         let meta_offset = buf.len();
-        BlockMeta::encode_block_meta(&self.meta, self.max_ts, &mut buf);
+        BlockMeta::encode_block_meta(&self.meta, self.max_ts, &self.stats, &mut buf);
         buf.put_u32(meta_offset as u32);
         let bloom = Bloom::build_from_key_hashes(
             &self.key_hashes,
@@ -113,7 +147,7 @@ impl SsTableBuilder {
         let bloom_offset = buf.len();
         bloom.encode(&mut buf);
         buf.put_u32(bloom_offset as u32);
-        let file = FileObject::create(path.as_ref(), buf)?;
+        let file = FileObject::create_with_fs(fs, path.as_ref(), buf)?;
         Ok(SsTable {
             id,
             file,
@@ -124,6 +158,8 @@ impl SsTableBuilder {
             block_cache,
             bloom: Some(bloom),
             max_ts: self.max_ts,
+            stats: Some(self.stats),
+            unencoded_values: false,
         })
     }
 
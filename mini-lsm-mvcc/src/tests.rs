@@ -12,7 +12,31 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod backup;
+mod checkpoint;
+mod compact_range;
+mod compaction_filter;
+mod compaction_pri;
+mod compaction_scheduler;
+mod compaction_sizes;
+mod compaction_triggers;
+mod crash;
+mod fifo_compaction;
+mod fs;
+mod grandparent_overlap;
 mod harness;
+mod hybrid_compaction;
+mod manifest_rollover;
+mod merge_operator;
+mod obsolete_files;
+mod options;
+mod rate_limiter;
+mod repair;
+mod subcompaction;
+mod trivial_move;
+mod ttl;
+mod wal_recycle;
+mod wal_tailing;
 mod week1_day1;
 mod week1_day2;
 mod week1_day3;
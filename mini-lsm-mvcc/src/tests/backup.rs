@@ -0,0 +1,209 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    backup::{BackupEngine, BackupInfo},
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    fs::default_fs,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+fn backup_options() -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 10,
+            max_levels: 3,
+        },
+    ));
+    options.enable_wal = true;
+    options
+}
+
+fn num_shared_files(backup_dir: &std::path::Path) -> usize {
+    default_fs()
+        .list_dir(&backup_dir.join("shared"))
+        .unwrap()
+        .len()
+}
+
+#[test]
+fn test_incremental_backup_and_restore() {
+    let dir = tempdir().unwrap();
+    let backup_dir = tempdir().unwrap();
+    let restore_dir = tempdir().unwrap();
+    let options = backup_options();
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let engine = BackupEngine::open(backup_dir.path()).unwrap();
+    for i in 0..3 {
+        storage.put(format!("key{i}").as_bytes(), b"v1").unwrap();
+        storage.force_flush().unwrap();
+    }
+    let backup1 = engine.create_backup(&storage).unwrap();
+    assert_eq!(backup1.backup_id, 1);
+    assert_eq!(num_shared_files(backup_dir.path()), 3);
+
+    // only the new SSTs are copied into the second backup
+    storage.put(b"key3", b"v1").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"key0", b"v2").unwrap();
+    let backup2 = engine.create_backup(&storage).unwrap();
+    assert_eq!(backup2.backup_id, 2);
+    assert_eq!(backup2.commit_ts, storage.inner.mvcc().latest_commit_ts());
+    assert_eq!(num_shared_files(backup_dir.path()), 5);
+    assert_eq!(engine.list_backups().unwrap(), vec![backup1, backup2]);
+    engine.verify_backup(1).unwrap();
+    engine.verify_backup(2).unwrap();
+    storage.put(b"key1", b"after backup").unwrap();
+    storage.close().unwrap();
+
+    let restore_path = restore_dir.path().join("1");
+    engine.restore(1, &restore_path).unwrap();
+    let restored = MiniLsm::open(&restore_path, options.clone()).unwrap();
+    for i in 0..3 {
+        assert_eq!(
+            restored.get(format!("key{i}").as_bytes()).unwrap(),
+            Some(Bytes::from_static(b"v1"))
+        );
+    }
+    assert_eq!(restored.get(b"key3").unwrap(), None);
+    restored.close().unwrap();
+
+    let restore_path = restore_dir.path().join("2");
+    engine.restore(2, &restore_path).unwrap();
+    let restored = MiniLsm::open(&restore_path, options).unwrap();
+    assert_eq!(
+        restored.get(b"key0").unwrap(),
+        Some(Bytes::from_static(b"v2"))
+    );
+    assert_eq!(
+        restored.get(b"key1").unwrap(),
+        Some(Bytes::from_static(b"v1"))
+    );
+    assert_eq!(
+        restored.get(b"key3").unwrap(),
+        Some(Bytes::from_static(b"v1"))
+    );
+    assert!(engine.restore(2, &restore_path).is_err());
+}
+
+#[test]
+fn test_incremental_backup_does_not_read_backed_up_ssts() {
+    let dir = tempdir().unwrap();
+    let backup_dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, backup_options()).unwrap();
+    let engine = BackupEngine::open(backup_dir.path()).unwrap();
+    for i in 0..3 {
+        storage.put(format!("key{i}").as_bytes(), b"v1").unwrap();
+        storage.force_flush().unwrap();
+    }
+    let backup1 = engine.create_backup(&storage).unwrap();
+
+    // change the content of the backed up SSTs in the database, which the next backup does not notice
+    for path in default_fs().list_dir(dir.path()).unwrap() {
+        if path.extension().is_some_and(|ext| ext == "sst") {
+            let mut data = default_fs().read(&path).unwrap();
+            data[0] ^= 0xff;
+            default_fs().write(&path, &data).unwrap();
+        }
+    }
+    let backup2 = engine.create_backup(&storage).unwrap();
+    assert_eq!(num_shared_files(backup_dir.path()), 3);
+    let shared_files = |backup: &BackupInfo| {
+        backup
+            .files
+            .iter()
+            .filter(|x| x.path.starts_with("shared/"))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(shared_files(&backup1), shared_files(&backup2));
+    engine.verify_backup(2).unwrap();
+}
+
+#[test]
+fn test_verify_backup_corruption() {
+    let dir = tempdir().unwrap();
+    let backup_dir = tempdir().unwrap();
+    let restore_dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, backup_options()).unwrap();
+    let engine = BackupEngine::open(backup_dir.path()).unwrap();
+    storage.put(b"key", b"value").unwrap();
+    let backup = engine.create_backup(&storage).unwrap();
+    engine.verify_backup(backup.backup_id).unwrap();
+
+    let sst = backup
+        .files
+        .iter()
+        .find(|x| x.path.starts_with("shared/"))
+        .unwrap();
+    let path = backup_dir.path().join(&sst.path);
+    let mut data = default_fs().read(&path).unwrap();
+    data[0] ^= 0xff;
+    default_fs().write(&path, &data).unwrap();
+    assert!(engine.verify_backup(backup.backup_id).is_err());
+    assert!(
+        engine
+            .restore(backup.backup_id, restore_dir.path().join("db"))
+            .is_err()
+    );
+    assert!(engine.verify_backup(backup.backup_id + 1).is_err());
+}
+
+#[test]
+fn test_purge_old_backups() {
+    let dir = tempdir().unwrap();
+    let backup_dir = tempdir().unwrap();
+    let mut options = backup_options();
+    options.compaction_options = CompactionOptions::NoCompaction;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let engine = BackupEngine::open(backup_dir.path()).unwrap();
+    for i in 0..3 {
+        storage.put(format!("key{i}").as_bytes(), b"value").unwrap();
+        storage.force_flush().unwrap();
+        engine.create_backup(&storage).unwrap();
+    }
+    assert_eq!(num_shared_files(backup_dir.path()), 3);
+    // compact all SSTs into one, so that the next backup shares nothing with the older ones
+    storage.force_full_compaction().unwrap();
+    engine.create_backup(&storage).unwrap();
+    assert_eq!(num_shared_files(backup_dir.path()), 4);
+
+    engine.purge_old_backups(2).unwrap();
+    let backup_ids = engine
+        .list_backups()
+        .unwrap()
+        .iter()
+        .map(|x| x.backup_id)
+        .collect::<Vec<_>>();
+    assert_eq!(backup_ids, vec![3, 4]);
+    assert_eq!(num_shared_files(backup_dir.path()), 4);
+    assert!(!default_fs().exists(&backup_dir.path().join("private").join("1")));
+
+    engine.delete_backup(3).unwrap();
+    assert_eq!(num_shared_files(backup_dir.path()), 1);
+    engine.verify_backup(4).unwrap();
+
+    // an incomplete backup is cleaned up when the engine is opened
+    default_fs()
+        .create_dir_all(&backup_dir.path().join("private").join("5"))
+        .unwrap();
+    drop(engine);
+    let engine = BackupEngine::open(backup_dir.path()).unwrap();
+    assert!(!default_fs().exists(&backup_dir.path().join("private").join("5")));
+    assert_eq!(engine.list_backups().unwrap().len(), 1);
+}
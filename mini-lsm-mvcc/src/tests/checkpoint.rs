@@ -0,0 +1,93 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    fs::default_fs,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+#[test]
+fn test_checkpoint() {
+    let dir = tempdir().unwrap();
+    let checkpoint_dir = tempdir().unwrap();
+    let checkpoint_path = checkpoint_dir.path().join("checkpoint");
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+        },
+    ));
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    for i in 0..5 {
+        storage
+            .put(format!("key{i}").as_bytes(), b"flushed")
+            .unwrap();
+        storage.force_flush().unwrap();
+    }
+    storage.put(b"key0", b"in memtable").unwrap();
+    storage.delete(b"key1").unwrap();
+    let commit_ts = storage.create_checkpoint(&checkpoint_path).unwrap();
+    assert_eq!(commit_ts, storage.inner.mvcc().latest_commit_ts());
+
+    // changes after the checkpoint are not visible in the checkpoint
+    storage.put(b"key2", b"after checkpoint").unwrap();
+    storage.force_flush().unwrap();
+    // compacted SSTs are deleted from the database, but not from the checkpoint
+    storage.inner.trigger_compaction().unwrap();
+    storage.inner.trigger_compaction().unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let checkpoint = MiniLsm::open(&checkpoint_path, options.clone()).unwrap();
+    assert_eq!(checkpoint.inner.mvcc().latest_commit_ts(), commit_ts);
+    assert_eq!(
+        checkpoint.get(b"key0").unwrap(),
+        Some(Bytes::from_static(b"in memtable"))
+    );
+    assert_eq!(checkpoint.get(b"key1").unwrap(), None);
+    for i in 2..5 {
+        assert_eq!(
+            checkpoint.get(format!("key{i}").as_bytes()).unwrap(),
+            Some(Bytes::from_static(b"flushed"))
+        );
+    }
+    checkpoint.close().unwrap();
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(
+        storage.get(b"key2").unwrap(),
+        Some(Bytes::from_static(b"after checkpoint"))
+    );
+}
+
+#[test]
+fn test_checkpoint_dir_not_empty() {
+    let dir = tempdir().unwrap();
+    let checkpoint_dir = tempdir().unwrap();
+    default_fs()
+        .write(&checkpoint_dir.path().join("file"), b"")
+        .unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction),
+    )
+    .unwrap();
+    assert!(storage.create_checkpoint(&checkpoint_dir).is_err());
+}
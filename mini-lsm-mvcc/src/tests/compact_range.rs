@@ -0,0 +1,237 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::ops::Bound;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{
+        CompactionOptions, LeveledCompactionOptions, SimpleLeveledCompactionOptions,
+        TieredCompactionOptions,
+    },
+    iterators::StorageIterator,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    table::SsTableIterator,
+};
+
+fn key_of(i: usize) -> Vec<u8> {
+    format!("key_{i:04}").into_bytes()
+}
+
+/// Write one SST for each range of keys.
+fn put_and_flush(storage: &MiniLsm, ranges: &[std::ops::Range<usize>]) {
+    for range in ranges {
+        for i in range.clone() {
+            storage.put(&key_of(i), b"value").unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+}
+
+/// The number of entries of keys in `range` in all SSTs, including tombstones and old versions.
+fn num_entries_in_ssts(storage: &MiniLsm, range: std::ops::Range<usize>) -> usize {
+    let snapshot = storage.inner.state.read().clone();
+    let mut cnt = 0;
+    for sst in snapshot.sstables.values() {
+        let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+        while iter.is_valid() {
+            if (key_of(range.start).as_slice()..key_of(range.end).as_slice())
+                .contains(&iter.key().key_ref())
+            {
+                cnt += 1;
+            }
+            iter.next().unwrap();
+        }
+    }
+    cnt
+}
+
+fn key_range(lower: usize, upper: usize) -> (Vec<u8>, Vec<u8>) {
+    (key_of(lower), key_of(upper))
+}
+
+#[test]
+fn test_compact_range_leveled() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Leveled(
+        LeveledCompactionOptions {
+            level_size_multiplier: 2,
+            level0_file_num_compaction_trigger: 100,
+            max_levels: 3,
+            base_level_size_mb: 1,
+            ..Default::default()
+        },
+    ));
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    put_and_flush(&storage, &[0..100, 100..200, 200..300, 300..400]);
+    for i in 100..200 {
+        storage.delete(&key_of(i)).unwrap();
+    }
+    // a snapshot taken before the deletes keeps the deleted keys in the SSTs
+    let txn = storage.new_txn().unwrap();
+    for i in 200..300 {
+        storage.delete(&key_of(i)).unwrap();
+    }
+    drop(txn);
+    let txn = storage.new_txn().unwrap();
+    for i in 300..400 {
+        storage.delete(&key_of(i)).unwrap();
+    }
+
+    let (lower, upper) = key_range(100, 399);
+    storage
+        .compact_range(Bound::Included(&lower), Bound::Included(&upper), Some(3))
+        .unwrap();
+    {
+        let snapshot = storage.inner.state.read();
+        // the SST that does not overlap with the range is not compacted
+        assert_eq!(snapshot.l0_sstables.len(), 1);
+        assert!(snapshot.levels[0].1.is_empty());
+        assert!(snapshot.levels[1].1.is_empty());
+        assert!(!snapshot.levels[2].1.is_empty());
+    }
+    assert_eq!(num_entries_in_ssts(&storage, 0..100), 100);
+    assert_eq!(num_entries_in_ssts(&storage, 100..300), 0);
+    assert_eq!(num_entries_in_ssts(&storage, 300..400), 200);
+    assert_eq!(txn.get(&key_of(342)).unwrap(), Some(Bytes::from("value")));
+    assert_eq!(storage.get(&key_of(342)).unwrap(), None);
+    assert_eq!(
+        storage.get(&key_of(42)).unwrap(),
+        Some(Bytes::from("value"))
+    );
+
+    drop(txn);
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded, None)
+        .unwrap();
+    assert_eq!(num_entries_in_ssts(&storage, 100..400), 0);
+    assert_eq!(storage.inner.state.read().l0_sstables.len(), 0);
+    assert_eq!(
+        storage.get(&key_of(42)).unwrap(),
+        Some(Bytes::from("value"))
+    );
+}
+
+#[test]
+fn test_compact_range_simple() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 0,
+            level0_file_num_compaction_trigger: 100,
+            max_levels: 3,
+        },
+    ));
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    put_and_flush(&storage, &[0..100, 100..200, 50..150]);
+    let (lower, upper) = key_range(120, 130);
+    assert!(
+        storage
+            .compact_range(Bound::Included(&lower), Bound::Included(&upper), Some(4))
+            .is_err()
+    );
+    storage
+        .compact_range(Bound::Included(&lower), Bound::Included(&upper), Some(1))
+        .unwrap();
+    let snapshot = storage.inner.state.read().clone();
+    // the L0 SST with keys 0..100 overlaps with an SST in the range, and is compacted as well
+    assert!(snapshot.l0_sstables.is_empty());
+    assert!(!snapshot.levels[0].1.is_empty());
+    assert_eq!(num_entries_in_ssts(&storage, 0..200), 200);
+}
+
+#[test]
+fn test_compact_range_tiered() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Tiered(
+        TieredCompactionOptions {
+            num_tiers: 100,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+            max_merge_width: None,
+        },
+    ));
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    put_and_flush(&storage, &[0..100, 100..200, 200..300, 100..200, 400..500]);
+    for i in 100..200 {
+        storage.delete(&key_of(i)).unwrap();
+    }
+    let (lower, upper) = key_range(100, 199);
+    storage
+        .compact_range(Bound::Included(&lower), Bound::Included(&upper), None)
+        .unwrap();
+    // the tiers from the memtable flushed above to the second tier are merged, and the deletes are kept for the
+    // bottom tier
+    assert_eq!(storage.inner.state.read().levels.len(), 2);
+    assert_eq!(num_entries_in_ssts(&storage, 100..200), 100);
+    assert_eq!(num_entries_in_ssts(&storage, 0..500), 400);
+    assert_eq!(storage.get(&key_of(150)).unwrap(), None);
+    assert_eq!(
+        storage.get(&key_of(250)).unwrap(),
+        Some(Bytes::from("value"))
+    );
+
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded, None)
+        .unwrap();
+    assert_eq!(storage.inner.state.read().levels.len(), 1);
+    assert_eq!(num_entries_in_ssts(&storage, 0..500), 300);
+}
+
+#[test]
+fn test_compact_range_with_background_compactions() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Leveled(
+        LeveledCompactionOptions {
+            level_size_multiplier: 2,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+            base_level_size_mb: 1,
+            ..Default::default()
+        },
+    ));
+    options.target_sst_size = 1 << 14;
+    options.max_background_compactions = 2;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let writer = {
+        let storage = storage.clone();
+        std::thread::spawn(move || {
+            for round in 0..20 {
+                for i in 0..200 {
+                    storage
+                        .put(&key_of(i * 5), format!("value{round}").as_bytes())
+                        .unwrap();
+                }
+                storage.force_flush().unwrap();
+            }
+        })
+    };
+    for _ in 0..10 {
+        storage
+            .compact_range(Bound::Unbounded, Bound::Unbounded, None)
+            .unwrap();
+    }
+    writer.join().unwrap();
+    let storage = Arc::try_unwrap(storage).ok().unwrap();
+    storage.close().unwrap();
+    for i in 0..200 {
+        assert_eq!(
+            storage.get(&key_of(i * 5)).unwrap(),
+            Some(Bytes::from("value19"))
+        );
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::ops::Bound;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{
+        CompactionDecision, CompactionFilter, CompactionOptions, SimpleLeveledCompactionOptions,
+    },
+    iterators::StorageIterator,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    table::SsTableIterator,
+};
+
+/// Removes values starting with `expired`, rewrites values starting with `v1:` to `v2:`, and skips the keys from
+/// `orphan_a` to `orphan_z`. Records the calls.
+#[derive(Default)]
+struct TestFilter {
+    calls: Arc<Mutex<Vec<(Bytes, u64, usize)>>>,
+}
+
+impl CompactionFilter for TestFilter {
+    fn filter(&self, key: &[u8], ts: u64, value: &[u8], level: usize) -> CompactionDecision {
+        self.calls
+            .lock()
+            .push((Bytes::copy_from_slice(key), ts, level));
+        if key == b"orphan_a" {
+            CompactionDecision::RemoveAndSkipUntil(Bytes::from_static(b"orphan_z"))
+        } else if value.starts_with(b"expired") {
+            CompactionDecision::Remove
+        } else if let Some(value) = value.strip_prefix(b"v1:") {
+            CompactionDecision::ChangeValue(Bytes::from([b"v2:", value].concat()))
+        } else {
+            CompactionDecision::Keep
+        }
+    }
+}
+
+fn open_storage(dir: &tempfile::TempDir) -> Arc<MiniLsm> {
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 0,
+            level0_file_num_compaction_trigger: 100,
+            max_levels: 2,
+        },
+    ));
+    MiniLsm::open(dir, options).unwrap()
+}
+
+/// All versions of the keys in the SSTs.
+fn entries_in_ssts(storage: &MiniLsm) -> Vec<(Bytes, Bytes)> {
+    let snapshot = storage.inner.state.read().clone();
+    let mut entries = Vec::new();
+    for sst in snapshot.sstables.values() {
+        let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+        while iter.is_valid() {
+            entries.push((
+                Bytes::copy_from_slice(iter.key().key_ref()),
+                Bytes::copy_from_slice(iter.value()),
+            ));
+            iter.next().unwrap();
+        }
+    }
+    entries.sort();
+    entries
+}
+
+#[test]
+fn test_remove_hides_older_versions() {
+    let dir = tempdir().unwrap();
+    let storage = open_storage(&dir);
+    storage.add_compaction_filter(TestFilter::default());
+    storage.put(b"key", b"value").unwrap();
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded, None)
+        .unwrap();
+    storage.put(b"key", b"expired").unwrap();
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded, Some(1))
+        .unwrap();
+    // the removed version becomes a delete above the bottom level
+    assert_eq!(storage.get(b"key").unwrap(), None);
+    assert_eq!(
+        entries_in_ssts(&storage),
+        vec![
+            (Bytes::from("key"), Bytes::new()),
+            (Bytes::from("key"), Bytes::from("value"))
+        ]
+    );
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded, None)
+        .unwrap();
+    assert_eq!(storage.get(b"key").unwrap(), None);
+    assert!(entries_in_ssts(&storage).is_empty());
+}
+
+#[test]
+fn test_change_value_and_skip_range() {
+    let dir = tempdir().unwrap();
+    let storage = open_storage(&dir);
+    let filter = TestFilter::default();
+    let calls = filter.calls.clone();
+    storage.add_compaction_filter(filter);
+    for key in ["a", "orphan_a", "orphan_b", "orphan_c", "orphan_z"] {
+        storage.put(key.as_bytes(), b"v1:value").unwrap();
+    }
+    storage.delete(b"a_deleted").unwrap();
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded, None)
+        .unwrap();
+    assert_eq!(
+        entries_in_ssts(&storage),
+        vec![
+            (Bytes::from("a"), Bytes::from("v2:value")),
+            (Bytes::from("orphan_z"), Bytes::from("v2:value")),
+        ]
+    );
+    // the filter is not called on deletes or skipped keys
+    let called_keys = calls
+        .lock()
+        .iter()
+        .map(|(key, _, level)| {
+            assert_eq!(*level, 2);
+            key.clone()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(called_keys, vec!["a", "orphan_a", "orphan_z"]);
+}
+
+#[test]
+fn test_filter_below_watermark() {
+    let dir = tempdir().unwrap();
+    let storage = open_storage(&dir);
+    let filter = TestFilter::default();
+    let calls = filter.calls.clone();
+    storage.add_compaction_filter(filter);
+    storage.put(b"key", b"v1:old").unwrap();
+    let txn = storage.new_txn().unwrap();
+    storage.put(b"key", b"expired").unwrap();
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded, None)
+        .unwrap();
+    // the latest version is above the watermark, and only the version seen by the transaction is filtered
+    assert_eq!(txn.get(b"key").unwrap(), Some(Bytes::from("v2:old")));
+    assert_eq!(storage.get(b"key").unwrap(), Some(Bytes::from("expired")));
+    let watermark = storage.inner.mvcc().watermark();
+    assert!(calls.lock().iter().all(|(_, ts, _)| *ts <= watermark));
+    drop(txn);
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded, None)
+        .unwrap();
+    assert_eq!(storage.get(b"key").unwrap(), None);
+    assert!(entries_in_ssts(&storage).is_empty());
+}
@@ -0,0 +1,130 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::{
+    clock::default_clock,
+    compact::{
+        CompactionOptions, CompactionPri, CompactionTriggers, LeveledCompactionController,
+        LeveledCompactionOptions,
+    },
+    key::KeyBytes,
+    lsm_storage::{LsmStorageOptions, LsmStorageState},
+    table::SsTable,
+};
+
+fn key(key: &'static str) -> KeyBytes {
+    KeyBytes::for_testing_from_bytes_no_ts(Bytes::from_static(key.as_bytes()))
+}
+
+/// L1 is larger than its target size. SST 1 overlaps with 2 SSTs in L2, SST 2 with none, and SST 3 with one.
+fn snapshot() -> LsmStorageState {
+    let mut snapshot = LsmStorageState::create(&LsmStorageOptions::default_for_week2_test(
+        CompactionOptions::Leveled(leveled_options(CompactionPri::default())),
+    ));
+    let ssts = [
+        (1, "b", "c"),
+        (2, "h", "i"),
+        (3, "m", "n"),
+        (4, "a", "bb"),
+        (5, "bc", "d"),
+        (6, "k", "p"),
+        (7, "x", "z"),
+    ];
+    for (id, first_key, last_key) in ssts {
+        snapshot.sstables.insert(
+            id,
+            Arc::new(SsTable::create_meta_only(
+                id,
+                1024 * 1024,
+                key(first_key),
+                key(last_key),
+            )),
+        );
+    }
+    snapshot.levels[0].1 = vec![1, 2, 3];
+    snapshot.levels[1].1 = vec![4, 5, 6, 7];
+    snapshot
+}
+
+fn leveled_options(compaction_pri: CompactionPri) -> LeveledCompactionOptions {
+    LeveledCompactionOptions {
+        level_size_multiplier: 2,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 2,
+        base_level_size_mb: 1,
+        compaction_pri,
+    }
+}
+
+fn controller(compaction_pri: CompactionPri) -> LeveledCompactionController {
+    LeveledCompactionController::new(
+        leveled_options(compaction_pri),
+        CompactionTriggers::default(),
+        default_clock(),
+    )
+}
+
+/// The SSTs picked from L1 by the next task.
+fn pick(controller: &LeveledCompactionController, in_progress: &[usize]) -> (usize, Vec<usize>) {
+    let in_progress = in_progress.iter().copied().collect::<HashSet<_>>();
+    let task = controller
+        .generate_compaction_task(&snapshot(), &in_progress, u64::MAX)
+        .unwrap();
+    assert_eq!(task.upper_level, Some(1));
+    assert_eq!(task.upper_level_sst_ids.len(), 1);
+    (task.upper_level_sst_ids[0], task.lower_level_sst_ids)
+}
+
+/// The SST picked from L1 by the next task, after applying the result of the task.
+fn compact(controller: &LeveledCompactionController) -> usize {
+    let snapshot = snapshot();
+    let task = controller
+        .generate_compaction_task(&snapshot, &HashSet::new(), u64::MAX)
+        .unwrap();
+    controller.apply_compaction_result(&snapshot, &task, &[], false);
+    task.upper_level_sst_ids[0]
+}
+
+#[test]
+fn test_oldest_file() {
+    let controller = controller(CompactionPri::OldestFile);
+    assert_eq!(pick(&controller, &[]), (1, vec![4, 5]));
+    assert_eq!(pick(&controller, &[1]), (2, vec![]));
+}
+
+#[test]
+fn test_min_overlapping_ratio() {
+    let controller = controller(CompactionPri::MinOverlappingRatio);
+    assert_eq!(pick(&controller, &[]), (2, vec![]));
+    assert_eq!(pick(&controller, &[2]), (3, vec![6]));
+    // SST 3 cannot be picked while the lower level SST it overlaps with is being compacted
+    assert_eq!(pick(&controller, &[2, 6]), (1, vec![4, 5]));
+}
+
+#[test]
+fn test_round_robin() {
+    let controller = controller(CompactionPri::RoundRobin);
+    // the order only moves on once a task is applied, e.g., not if it conflicts with a running compaction
+    assert_eq!(pick(&controller, &[]).0, 1);
+    assert_eq!(compact(&controller), 1);
+    assert_eq!(pick(&controller, &[2]).0, 3);
+    assert_eq!(compact(&controller), 2);
+    assert_eq!(compact(&controller), 3);
+    assert_eq!(compact(&controller), 1);
+}
@@ -0,0 +1,184 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{
+        CompactionOptions, CompactionTask, LeveledCompactionOptions,
+        SimpleLeveledCompactionOptions, TieredCompactionOptions,
+    },
+    lsm_storage::{LsmStorageInner, LsmStorageOptions, MiniLsm},
+};
+
+/// Write a new SST with keys `key_{i}` of `prefix`, without any background thread.
+fn put_and_flush(storage: &Arc<LsmStorageInner>, prefix: &str, range: std::ops::Range<usize>) {
+    for i in range {
+        storage
+            .put(format!("key_{i:04}").as_bytes(), prefix.as_bytes())
+            .unwrap();
+    }
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+    storage.force_flush_next_imm_memtable().unwrap();
+}
+
+#[test]
+fn test_simple_leveled_skip_in_progress_ssts() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+        },
+    ));
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    put_and_flush(&storage, "a", 0..100);
+    put_and_flush(&storage, "b", 0..100);
+    let job = storage.pick_compaction_job().unwrap();
+    let in_progress = job.task.input_sst_ids();
+    assert_eq!(in_progress.len(), 2);
+
+    // the new L0 SSTs cannot be compacted until the running compaction replaces L1
+    put_and_flush(&storage, "c", 0..100);
+    put_and_flush(&storage, "d", 0..100);
+    assert!(storage.pick_compaction_job().is_none());
+
+    storage.run_compaction_job(job).unwrap();
+    let job = storage.pick_compaction_job().unwrap();
+    assert!(
+        job.task
+            .input_sst_ids()
+            .iter()
+            .all(|x| !in_progress.contains(x))
+    );
+    storage.run_compaction_job(job).unwrap();
+    assert_eq!(
+        storage.get(b"key_0042").unwrap(),
+        Some(Bytes::from_static(b"d"))
+    );
+}
+
+#[test]
+fn test_tiered_concurrent_compactions() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Tiered(
+        TieredCompactionOptions {
+            num_tiers: 3,
+            max_size_amplification_percent: 200,
+            size_ratio: 1000,
+            min_merge_width: 2,
+            max_merge_width: None,
+        },
+    ));
+    let storage = Arc::new(LsmStorageInner::open(&dir, options).unwrap());
+    put_and_flush(&storage, "a", 0..100);
+    put_and_flush(&storage, "b", 50..150);
+    put_and_flush(&storage, "c", 100..200);
+    let old_job = storage.pick_compaction_job().unwrap();
+    assert!(matches!(&*old_job.task, CompactionTask::Tiered(task) if task.tiers.len() == 3));
+
+    // only the tiers flushed after the running compaction can be picked
+    put_and_flush(&storage, "d", 0..50);
+    put_and_flush(&storage, "e", 150..200);
+    put_and_flush(&storage, "f", 180..250);
+    let new_job = storage.pick_compaction_job().unwrap();
+    let old_ssts = old_job
+        .task
+        .input_sst_ids()
+        .into_iter()
+        .collect::<HashSet<_>>();
+    let CompactionTask::Tiered(task) = &*new_job.task else {
+        unreachable!()
+    };
+    assert_eq!(task.tiers.len(), 3);
+    assert!(!task.bottom_tier_included);
+    assert!(
+        new_job
+            .task
+            .input_sst_ids()
+            .iter()
+            .all(|x| !old_ssts.contains(x))
+    );
+    assert!(storage.pick_compaction_job().is_none());
+
+    // the newer compaction finishes first
+    storage.run_compaction_job(new_job).unwrap();
+    storage.run_compaction_job(old_job).unwrap();
+    assert_eq!(storage.state.read().levels.len(), 2);
+    for (key, value) in [
+        ("key_0010", "d"),
+        ("key_0060", "b"),
+        ("key_0120", "c"),
+        ("key_0160", "e"),
+        ("key_0190", "f"),
+        ("key_0240", "f"),
+    ] {
+        assert_eq!(
+            storage.get(key.as_bytes()).unwrap(),
+            Some(Bytes::copy_from_slice(value.as_bytes())),
+            "{key}"
+        );
+    }
+}
+
+#[test]
+fn test_background_compactions_on_flush() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Leveled(
+        LeveledCompactionOptions {
+            level0_file_num_compaction_trigger: 2,
+            level_size_multiplier: 2,
+            base_level_size_mb: 1,
+            max_levels: 4,
+            ..Default::default()
+        },
+    ));
+    options.target_sst_size = 1 << 16;
+    options.max_background_compactions = 4;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for round in 0..20 {
+        for i in 0..1000 {
+            storage
+                .put(
+                    format!("key_{:05}", (i * 37 + round * 101) % 5000).as_bytes(),
+                    format!("value_{round}_{i:0128}").as_bytes(),
+                )
+                .unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+    // compactions are started by the flushes and by each other, without polling
+    let start = Instant::now();
+    while storage.inner.state.read().l0_sstables.len() >= 2 {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    storage.close().unwrap();
+    assert!(storage.compaction_stats().compactions + storage.compaction_stats().trivial_moves > 0);
+    assert_eq!(storage.inner.running_compactions.lock().len(), 0);
+    for i in 0..1000 {
+        let key = format!("key_{:05}", (i * 37 + 19 * 101) % 5000);
+        assert_eq!(
+            storage.get(key.as_bytes()).unwrap(),
+            Some(Bytes::from(format!("value_19_{i:0128}")))
+        );
+    }
+}
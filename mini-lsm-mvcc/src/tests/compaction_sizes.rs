@@ -0,0 +1,104 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::{
+    clock::default_clock,
+    compact::{
+        CompactionOptions, CompactionTriggers, SimpleLeveledCompactionController,
+        SimpleLeveledCompactionOptions, TieredCompactionController, TieredCompactionOptions,
+    },
+    key::KeyBytes,
+    lsm_storage::{LsmStorageOptions, LsmStorageState},
+    table::SsTable,
+};
+
+const MB: u64 = 1024 * 1024;
+
+fn add_sst(snapshot: &mut LsmStorageState, id: usize, size: u64) {
+    let key = |key: &'static str| {
+        KeyBytes::for_testing_from_bytes_no_ts(Bytes::from_static(key.as_bytes()))
+    };
+    snapshot.sstables.insert(
+        id,
+        Arc::new(SsTable::create_meta_only(id, size, key("a"), key("z"))),
+    );
+}
+
+#[test]
+fn test_simple_leveled_compares_level_bytes() {
+    let options = SimpleLeveledCompactionOptions {
+        size_ratio_percent: 200,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 2,
+    };
+    let mut snapshot = LsmStorageState::create(&LsmStorageOptions::default_for_week2_test(
+        CompactionOptions::Simple(options.clone()),
+    ));
+    let controller = SimpleLeveledCompactionController::new(options);
+    // L2 has twice as many SSTs as L1, but less than twice its bytes
+    add_sst(&mut snapshot, 1, 10 * MB);
+    add_sst(&mut snapshot, 2, 4 * MB);
+    add_sst(&mut snapshot, 3, 4 * MB);
+    snapshot.levels[0].1 = vec![1];
+    snapshot.levels[1].1 = vec![2, 3];
+    let task = controller
+        .generate_compaction_task(&snapshot, &HashSet::new())
+        .unwrap();
+    assert_eq!(task.upper_level, Some(1));
+    assert_eq!(task.lower_level_sst_ids, vec![2, 3]);
+
+    // L2 has as many SSTs as L1, but more than twice its bytes
+    add_sst(&mut snapshot, 4, 20 * MB);
+    snapshot.levels[1].1 = vec![4];
+    assert!(
+        controller
+            .generate_compaction_task(&snapshot, &HashSet::new())
+            .is_none()
+    );
+}
+
+#[test]
+fn test_tiered_compares_tier_bytes() {
+    let controller = TieredCompactionController::new(
+        TieredCompactionOptions {
+            num_tiers: 3,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+            max_merge_width: Some(2),
+        },
+        CompactionTriggers::default(),
+        default_clock(),
+    );
+    let mut snapshot = LsmStorageState::create(&LsmStorageOptions::default_for_week2_test(
+        CompactionOptions::NoCompaction,
+    ));
+    // The upper tiers have three SSTs for the one in the bottom tier, but only 25% of its bytes.
+    add_sst(&mut snapshot, 1, 10 * MB);
+    add_sst(&mut snapshot, 2, MB);
+    add_sst(&mut snapshot, 3, MB);
+    add_sst(&mut snapshot, 4, MB / 2);
+    snapshot.levels = vec![(4, vec![4]), (3, vec![3]), (2, vec![2]), (1, vec![1])];
+    let task = controller
+        .generate_compaction_task(&snapshot, &HashSet::new(), u64::MAX)
+        .unwrap();
+    // triggered by the size ratio of the bottom tier to the upper tiers instead of the space amplification
+    assert!(!task.bottom_tier_included);
+    assert_eq!(task.tiers, vec![(4, vec![4]), (3, vec![3]), (2, vec![2])]);
+}
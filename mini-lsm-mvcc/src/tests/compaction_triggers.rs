@@ -0,0 +1,304 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::{BufMut, Bytes};
+use tempfile::tempdir;
+
+use crate::{
+    clock::{Clock, MockClock, SystemClock},
+    compact::{
+        CompactionOptions, CompactionTriggers, LeveledCompactionOptions, TieredCompactionOptions,
+    },
+    key::KeyBytes,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    table::{BlockMeta, TableStats},
+};
+
+fn leveled_options(compaction_triggers: CompactionTriggers) -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Leveled(
+        LeveledCompactionOptions {
+            level_size_multiplier: 2,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 2,
+            base_level_size_mb: 1,
+            ..Default::default()
+        },
+    ));
+    options.compaction_triggers = compaction_triggers;
+    options
+}
+
+fn put_keys(storage: &MiniLsm, num_keys: usize) {
+    for i in 0..num_keys {
+        storage
+            .put(format!("key_{i:03}").as_bytes(), b"value")
+            .unwrap();
+    }
+}
+
+fn bottom_level_ssts(storage: &MiniLsm) -> Vec<usize> {
+    storage.inner.state.read().levels.last().unwrap().1.clone()
+}
+
+#[test]
+fn test_sst_stats_count_deletes() {
+    let dir = tempdir().unwrap();
+    let mut options = leveled_options(CompactionTriggers::default());
+    options.clock = Arc::new(MockClock::new(1000));
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    put_keys(&storage, 10);
+    for i in 0..4 {
+        storage.delete(format!("key_{i:03}").as_bytes()).unwrap();
+    }
+    storage.force_flush().unwrap();
+    let state = storage.inner.state.read();
+    let sst = &state.sstables[&state.l0_sstables[0]];
+    // both the values and the deletes of the deleted keys are flushed
+    assert_eq!(
+        sst.stats(),
+        Some(TableStats {
+            num_entries: 14,
+            num_deletes: 4,
+            min_ts: 1,
+            created_at: 1000,
+        })
+    );
+    assert_eq!(sst.stats().unwrap().tombstone_ratio(), 4.0 / 14.0);
+}
+
+#[test]
+fn test_decode_stats_of_older_layouts() {
+    let block_meta = vec![BlockMeta {
+        offset: 0,
+        first_key: KeyBytes::from_bytes_with_ts(Bytes::from_static(b"a"), 1),
+        last_key: KeyBytes::from_bytes_with_ts(Bytes::from_static(b"b"), 1),
+    }];
+    let stats = TableStats {
+        num_entries: 14,
+        num_deletes: 4,
+        min_ts: 1,
+        created_at: 1000,
+    };
+    let mut buf = Vec::new();
+    BlockMeta::encode_block_meta(&block_meta, 5, &stats, &mut buf);
+    assert_eq!(
+        BlockMeta::decode_block_meta(&buf).unwrap(),
+        (block_meta.clone(), 5, Some(stats))
+    );
+
+    // the layouts without a version store no stats, the counts, or all stats after the max timestamp
+    let encode_unversioned = |properties: &[u64]| {
+        let mut buf = Vec::new();
+        BlockMeta::encode_block_meta(&block_meta, 5, &stats, &mut buf);
+        // keep the block meta and the max timestamp, and replace the versioned stats and the checksum
+        buf.truncate(buf.len() - 4 - 4 - 32);
+        for x in properties {
+            buf.put_u64(*x);
+        }
+        buf.put_u32(crc32fast::hash(&buf[4..]));
+        buf
+    };
+    let decode_stats = |buf: &[u8]| BlockMeta::decode_block_meta(buf).unwrap().2;
+    assert_eq!(decode_stats(&encode_unversioned(&[])), None);
+    assert_eq!(
+        decode_stats(&encode_unversioned(&[14, 4])),
+        Some(TableStats {
+            num_entries: 14,
+            num_deletes: 4,
+            min_ts: 0,
+            created_at: 0,
+        })
+    );
+    let stats_without_creation_time = TableStats {
+        created_at: 0,
+        ..stats
+    };
+    assert_eq!(
+        decode_stats(&encode_unversioned(&[14, 4, 1])),
+        Some(stats_without_creation_time)
+    );
+
+    // version 1 has no creation time
+    let mut buf = Vec::new();
+    BlockMeta::encode_block_meta(&block_meta, 5, &stats, &mut buf);
+    buf.truncate(buf.len() - 4 - 4 - 32);
+    buf.put_u32(1);
+    for x in [14, 4, 1] {
+        buf.put_u64(x);
+    }
+    buf.put_u32(crc32fast::hash(&buf[4..]));
+    assert_eq!(decode_stats(&buf), Some(stats_without_creation_time));
+}
+
+#[test]
+fn test_leveled_compacts_ssts_with_many_deletes() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        leveled_options(CompactionTriggers {
+            tombstone_ratio_trigger_percent: Some(50),
+            periodic_compaction_age: None,
+        }),
+    )
+    .unwrap();
+    put_keys(&storage, 100);
+    storage.force_flush().unwrap();
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded, None)
+        .unwrap();
+
+    // The transaction keeps the values below the deletes in the bottom level, so the deletes cannot be dropped yet.
+    let txn = storage.new_txn().unwrap();
+    for i in 0..100 {
+        storage.delete(format!("key_{i:03}").as_bytes()).unwrap();
+    }
+    storage.force_flush().unwrap();
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded, None)
+        .unwrap();
+    let bottom_ssts = bottom_level_ssts(&storage);
+    assert!(!bottom_ssts.is_empty());
+    let compactions = storage.compaction_stats().compactions;
+    storage.inner.trigger_compaction().unwrap();
+    assert_eq!(storage.compaction_stats().compactions, compactions);
+    assert_eq!(bottom_level_ssts(&storage), bottom_ssts);
+    assert_eq!(
+        txn.get(b"key_000").unwrap(),
+        Some(Bytes::from_static(b"value"))
+    );
+
+    drop(txn);
+    while !bottom_level_ssts(&storage).is_empty() {
+        storage.inner.trigger_compaction().unwrap();
+    }
+    assert!(storage.inner.state.read().sstables.is_empty());
+    assert_eq!(storage.get(b"key_000").unwrap(), None);
+}
+
+#[test]
+fn test_leveled_compacts_old_ssts() {
+    let dir = tempdir().unwrap();
+    // The age of an SST is measured from the creation time recorded in it.
+    let clock = Arc::new(MockClock::new(SystemClock.now_millis()));
+    let mut options = leveled_options(CompactionTriggers {
+        tombstone_ratio_trigger_percent: None,
+        periodic_compaction_age: Some(Duration::from_secs(3600)),
+    });
+    options.clock = clock.clone();
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    put_keys(&storage, 100);
+    storage.force_flush().unwrap();
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded, None)
+        .unwrap();
+    let bottom_ssts = bottom_level_ssts(&storage);
+    let compactions = storage.compaction_stats().compactions;
+    storage.inner.trigger_compaction().unwrap();
+    assert_eq!(storage.compaction_stats().compactions, compactions);
+
+    clock.advance(Duration::from_secs(3660));
+    while bottom_level_ssts(&storage) == bottom_ssts {
+        storage.inner.trigger_compaction().unwrap();
+    }
+    // the rewritten SSTs are young again
+    clock.set(SystemClock.now_millis());
+    assert!(storage.compaction_stats().compactions > compactions);
+    assert_eq!(
+        storage.get(b"key_099").unwrap(),
+        Some(Bytes::from_static(b"value"))
+    );
+}
+
+#[test]
+fn test_leveled_background_compacts_old_ssts_without_writes() {
+    let dir = tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(SystemClock.now_millis()));
+    let mut options = leveled_options(CompactionTriggers {
+        tombstone_ratio_trigger_percent: None,
+        periodic_compaction_age: Some(Duration::from_secs(3600)),
+    });
+    options.clock = clock.clone();
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    put_keys(&storage, 100);
+    storage.force_flush().unwrap();
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded, None)
+        .unwrap();
+    let bottom_ssts = bottom_level_ssts(&storage);
+
+    // let the scheduler handle the events of the flushes while the SSTs are young
+    while !storage.inner.compaction_events.1.is_empty() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    std::thread::sleep(Duration::from_millis(100));
+    // no more writes, the compaction thread finds the old SSTs by itself
+    clock.advance(Duration::from_secs(3660));
+    let start = Instant::now();
+    while bottom_level_ssts(&storage) == bottom_ssts {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    clock.set(SystemClock.now_millis());
+    assert_eq!(
+        storage.get(b"key_099").unwrap(),
+        Some(Bytes::from_static(b"value"))
+    );
+}
+
+#[test]
+fn test_tiered_compacts_tiers_with_many_deletes() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Tiered(
+        TieredCompactionOptions {
+            num_tiers: 100,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+            max_merge_width: None,
+        },
+    ));
+    options.compaction_triggers.tombstone_ratio_trigger_percent = Some(50);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    put_keys(&storage, 100);
+    storage.force_flush().unwrap();
+    put_keys(&storage, 10);
+    storage.force_flush().unwrap();
+    let compactions = storage.compaction_stats().compactions;
+    storage.inner.trigger_compaction().unwrap();
+    assert_eq!(storage.compaction_stats().compactions, compactions);
+
+    for i in 0..80 {
+        storage.delete(format!("key_{i:03}").as_bytes()).unwrap();
+    }
+    storage.force_flush().unwrap();
+    assert_eq!(storage.inner.state.read().levels.len(), 3);
+    while storage.inner.state.read().levels.len() > 1 {
+        storage.inner.trigger_compaction().unwrap();
+    }
+    let state = storage.inner.state.read();
+    // the deletes are merged down to the bottom tier and dropped with the keys they delete
+    for sst_id in &state.levels[0].1 {
+        assert_eq!(state.sstables[sst_id].stats().unwrap().num_deletes, 0);
+    }
+    drop(state);
+    assert_eq!(storage.get(b"key_000").unwrap(), None);
+    assert_eq!(
+        storage.get(b"key_080").unwrap(),
+        Some(Bytes::from_static(b"value"))
+    );
+}
@@ -0,0 +1,252 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::{
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    fs::{Fault, FaultInjectionFileSystem, FileSystem},
+    iterators::StorageIterator,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+const DB_PATH: &str = "/crash-test";
+
+fn crash_test_options(fs: Arc<dyn FileSystem>) -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+        },
+    ));
+    options.target_sst_size = 4096;
+    options.num_memtable_limit = 4;
+    options.enable_wal = true;
+    options.max_manifest_file_size = 1024;
+    options.obsolete_file_gc_interval = None;
+    options.fs = fs;
+    options
+}
+
+/// A write of the workload. `None` is a delete.
+type Write = (Bytes, Option<Bytes>);
+
+/// The writes issued by a workload, and how many of them were acknowledged by a successful sync.
+struct WorkloadResult {
+    writes: Vec<Write>,
+    acknowledged: usize,
+}
+
+/// Run a random workload until it finishes or an operation fails.
+fn run_workload(storage: &MiniLsm, seed: u64) -> WorkloadResult {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut result = WorkloadResult {
+        writes: Vec::new(),
+        acknowledged: 0,
+    };
+    for i in 0..300 {
+        let key = Bytes::from(format!("key{:03}", rng.gen_range(0..60)));
+        let res = match rng.gen_range(0..100) {
+            0..70 => {
+                let value = Bytes::from(format!("value{:03}_{}", i, "x".repeat(64)));
+                result.writes.push((key.clone(), Some(value.clone())));
+                storage.put(&key, &value)
+            }
+            70..85 => {
+                result.writes.push((key.clone(), None));
+                storage.delete(&key)
+            }
+            85..95 => storage
+                .sync()
+                .map(|_| result.acknowledged = result.writes.len()),
+            95..98 => storage.force_flush(),
+            _ => storage.inner.trigger_compaction(),
+        };
+        if res.is_err() {
+            break;
+        }
+    }
+    result
+}
+
+fn scan_all(storage: &MiniLsm) -> BTreeMap<Bytes, Bytes> {
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let mut kvs = BTreeMap::new();
+    while iter.is_valid() {
+        kvs.insert(
+            Bytes::copy_from_slice(iter.key()),
+            Bytes::copy_from_slice(iter.value()),
+        );
+        iter.next().unwrap();
+    }
+    kvs
+}
+
+/// Check that the recovered data is the result of a prefix of the writes that contains all acknowledged writes.
+fn check_recovered(recovered: &BTreeMap<Bytes, Bytes>, result: &WorkloadResult, desc: &str) {
+    let mut expected = BTreeMap::new();
+    for (idx, (key, value)) in result.writes.iter().enumerate() {
+        if idx >= result.acknowledged && &expected == recovered {
+            return;
+        }
+        match value {
+            Some(value) => expected.insert(key.clone(), value.clone()),
+            None => expected.remove(key),
+        };
+    }
+    assert_eq!(
+        &expected,
+        recovered,
+        "{desc}: recovered data does not match any prefix of the {} writes with {} acknowledged",
+        result.writes.len(),
+        result.acknowledged
+    );
+}
+
+/// Run the workload on a fresh database, crash the file system, and check what survived after reopening it twice.
+fn crash_and_recover(fs: FaultInjectionFileSystem, seed: u64, desc: &str) {
+    let fs = Arc::new(fs);
+    // The crash might happen while the database is being created.
+    let result = match MiniLsm::open(Path::new(DB_PATH), crash_test_options(fs.clone())) {
+        Ok(storage) => run_workload(&storage, seed),
+        Err(_) => WorkloadResult {
+            writes: Vec::new(),
+            acknowledged: 0,
+        },
+    };
+
+    let fs: Arc<dyn FileSystem> = Arc::new(fs.crash());
+    for _ in 0..2 {
+        let storage = MiniLsm::open(Path::new(DB_PATH), crash_test_options(fs.clone()))
+            .unwrap_or_else(|e| panic!("{desc}: failed to reopen: {e:?}"));
+        check_recovered(&scan_all(&storage), &result, desc);
+        storage.close().unwrap();
+    }
+}
+
+#[test]
+fn test_fault_injection_file_system() {
+    let fs = FaultInjectionFileSystem::new();
+    let dir = Path::new("/db");
+    fs.create_dir_all(dir).unwrap();
+    let mut file = fs.create(&dir.join("synced")).unwrap();
+    file.write_all(b"durable").unwrap();
+    file.sync_data().unwrap();
+    file.write_all(b" lost").unwrap();
+    fs.sync_dir(dir).unwrap();
+    fs.write(&dir.join("unlinked"), b"no dir sync").unwrap();
+    fs.rename(&dir.join("synced"), &dir.join("renamed"))
+        .unwrap();
+    assert_eq!(fs.read(&dir.join("renamed")).unwrap(), b"durable lost");
+    assert_eq!(fs.sync_count(), 3);
+
+    let crashed = fs.crash();
+    assert!(fs.is_crashed());
+    assert!(fs.read(&dir.join("renamed")).is_err());
+    assert!(!crashed.exists(&dir.join("unlinked")));
+    assert!(!crashed.exists(&dir.join("renamed")));
+    assert_eq!(crashed.read(&dir.join("synced")).unwrap(), b"durable");
+
+    let op = crashed.op_count();
+    crashed.inject_fault(op + 1, Fault::Error);
+    assert!(crashed.remove_file(&dir.join("synced")).is_err());
+    assert!(crashed.exists(&dir.join("synced")));
+    crashed.inject_fault(op + 3, Fault::TornWrite);
+    let mut file = crashed.open_writable(&dir.join("synced")).unwrap();
+    file.write_all(b"12").unwrap();
+    assert!(file.write_all(b"3456").is_err());
+    assert!(crashed.is_crashed());
+    assert_eq!(
+        crashed.crash().read(&dir.join("synced")).unwrap(),
+        b"1234ble"
+    );
+}
+
+#[test]
+fn test_crash_at_every_sync_point() {
+    let seed = 0;
+    // Count the sync points of the workload without crashing.
+    let fs = FaultInjectionFileSystem::new();
+    let total_syncs = {
+        let fs = Arc::new(fs);
+        let storage = MiniLsm::open(Path::new(DB_PATH), crash_test_options(fs.clone())).unwrap();
+        let result = run_workload(&storage, seed);
+        assert!(result.acknowledged > 0);
+        drop(storage);
+        fs.sync_count()
+    };
+    for syncs in 1..=total_syncs {
+        let fs = FaultInjectionFileSystem::new();
+        fs.crash_after_syncs(syncs);
+        crash_and_recover(fs, seed, &format!("crash after {syncs} syncs"));
+    }
+}
+
+#[test]
+fn test_random_faults() {
+    let mut rng = StdRng::seed_from_u64(0);
+    for seed in 0..20 {
+        let fs = FaultInjectionFileSystem::new();
+        let op = rng.gen_range(1..300);
+        let fault = if rng.gen_bool(0.5) {
+            Fault::Error
+        } else {
+            Fault::TornWrite
+        };
+        fs.inject_fault(op, fault);
+        crash_and_recover(fs, seed, &format!("{fault:?} at operation {op}"));
+    }
+}
+
+#[test]
+fn test_background_error() {
+    // Fail each file operation of a flush in turn, until the flush succeeds.
+    for fault_op in 1.. {
+        let fs = Arc::new(FaultInjectionFileSystem::new());
+        let storage = MiniLsm::open(Path::new(DB_PATH), crash_test_options(fs.clone())).unwrap();
+        storage.put(b"key", b"value").unwrap();
+        storage
+            .inner
+            .force_freeze_memtable(&storage.inner.state_lock.lock())
+            .unwrap();
+        fs.inject_fault(fs.op_count() + fault_op, Fault::Error);
+        if storage.inner.force_flush_next_imm_memtable().is_ok() {
+            break;
+        }
+        let l0_sstables = storage.inner.state.read().l0_sstables.clone();
+        if l0_sstables.is_empty() {
+            // The SST or the manifest record was not written, so nothing can be written until the database is reopened.
+            assert!(storage.put(b"key", b"value2").is_err());
+            assert!(storage.inner.force_flush_next_imm_memtable().is_err());
+        }
+        drop(storage);
+
+        // A published SST is recovered. A manifest record that failed to be synced might be recovered as well.
+        let storage = MiniLsm::open(Path::new(DB_PATH), crash_test_options(fs.clone())).unwrap();
+        if !l0_sstables.is_empty() {
+            assert_eq!(storage.inner.state.read().l0_sstables, l0_sstables);
+        }
+        assert_eq!(
+            storage.get(b"key").unwrap(),
+            Some(Bytes::from_static(b"value"))
+        );
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    clock::{Clock, MockClock, SystemClock},
+    compact::{CompactionOptions, CompactionTask, FifoCompactionOptions, FifoCompactionTask},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    manifest::VersionEdit,
+};
+
+fn fifo_options(
+    max_table_files_size: u64,
+    ttl: Option<Duration>,
+    max_l0_files: Option<usize>,
+) -> LsmStorageOptions {
+    LsmStorageOptions::default_for_week2_test(CompactionOptions::Fifo(FifoCompactionOptions {
+        max_table_files_size,
+        ttl,
+        max_l0_files,
+    }))
+}
+
+/// Write 100 keys with prefix `key{i}_` and flush them to an SST.
+fn flush_keys(storage: &MiniLsm, i: usize) {
+    for j in 0..100 {
+        storage
+            .put(format!("key{i}_{j:03}").as_bytes(), b"value")
+            .unwrap();
+    }
+    storage.force_flush().unwrap();
+}
+
+#[test]
+fn test_fifo_deletes_oldest_ssts_over_size_limit() {
+    let sst_size = {
+        let dir = tempdir().unwrap();
+        let storage = MiniLsm::open(&dir, fifo_options(u64::MAX, None, None)).unwrap();
+        flush_keys(&storage, 0);
+        let state = storage.inner.state.read();
+        state.sstables[&state.l0_sstables[0]].table_size()
+    };
+    let dir = tempdir().unwrap();
+    let options = fifo_options(sst_size * 5 / 2, None, None);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    for i in 0..4 {
+        flush_keys(&storage, i);
+    }
+    while storage.inner.state.read().l0_sstables.len() > 2 {
+        storage.inner.trigger_compaction().unwrap();
+    }
+    let l0_sstables = storage.inner.state.read().l0_sstables.clone();
+    assert_eq!(l0_sstables.len(), 2);
+    assert_eq!(storage.get(b"key0_000").unwrap(), None);
+    assert_eq!(storage.get(b"key1_099").unwrap(), None);
+    assert_eq!(
+        storage.get(b"key2_000").unwrap(),
+        Some(Bytes::from_static(b"value"))
+    );
+    assert_eq!(storage.compaction_stats().bytes_written, 0);
+    storage.close().unwrap();
+    drop(storage);
+
+    // the deletes are recorded in the manifest
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.inner.state.read().l0_sstables, l0_sstables);
+    assert_eq!(storage.get(b"key0_000").unwrap(), None);
+}
+
+#[test]
+fn test_fifo_deletes_ssts_older_than_ttl() {
+    let dir = tempdir().unwrap();
+    // The age of an SST is measured from the creation time recorded in it.
+    let clock = Arc::new(MockClock::new(SystemClock.now_millis()));
+    let mut options = fifo_options(u64::MAX, Some(Duration::from_secs(3600)), None);
+    options.clock = clock.clone();
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    flush_keys(&storage, 0);
+    flush_keys(&storage, 1);
+    storage.inner.trigger_compaction().unwrap();
+    assert_eq!(storage.inner.state.read().l0_sstables.len(), 2);
+
+    clock.advance(Duration::from_secs(3660));
+    while !storage.inner.state.read().l0_sstables.is_empty() {
+        storage.inner.trigger_compaction().unwrap();
+    }
+    assert_eq!(storage.get(b"key0_000").unwrap(), None);
+    assert_eq!(storage.get(b"key1_000").unwrap(), None);
+}
+
+#[test]
+fn test_fifo_background_ttl_without_writes() {
+    let dir = tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(SystemClock.now_millis()));
+    let mut options = fifo_options(u64::MAX, Some(Duration::from_secs(3600)), None);
+    options.clock = clock.clone();
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    flush_keys(&storage, 0);
+    flush_keys(&storage, 1);
+    assert_eq!(storage.inner.state.read().l0_sstables.len(), 2);
+
+    // let the scheduler handle the events of the flushes while the SSTs are young
+    while !storage.inner.compaction_events.1.is_empty() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    std::thread::sleep(Duration::from_millis(100));
+    // no more flushes, the compaction thread finds the expired SSTs by itself
+    clock.advance(Duration::from_secs(3660));
+    let start = Instant::now();
+    while !storage.inner.state.read().l0_sstables.is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(storage.get(b"key0_000").unwrap(), None);
+}
+
+#[test]
+fn test_fifo_ttl_survives_copying_ssts() {
+    let dir = tempdir().unwrap();
+    let now = SystemClock.now_millis();
+    let mut options = fifo_options(u64::MAX, Some(Duration::from_secs(3600)), None);
+    options.clock = Arc::new(MockClock::new(now - 7_200_000));
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    flush_keys(&storage, 0);
+    flush_keys(&storage, 1);
+    storage.close().unwrap();
+    drop(storage);
+
+    // copying the database, e.g., to restore a backup, gives the files a new modification time
+    let copy_dir = tempdir().unwrap();
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        std::fs::copy(&path, copy_dir.path().join(path.file_name().unwrap())).unwrap();
+    }
+    options.clock = Arc::new(MockClock::new(now));
+    let storage = MiniLsm::open(&copy_dir, options).unwrap();
+    storage.inner.trigger_compaction().unwrap();
+    assert!(storage.inner.state.read().l0_sstables.is_empty());
+    assert_eq!(storage.get(b"key0_000").unwrap(), None);
+}
+
+#[test]
+fn test_fifo_merges_latest_ssts() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, fifo_options(u64::MAX, None, Some(2))).unwrap();
+    for i in 0..5 {
+        storage.put(b"key", format!("value{i}").as_bytes()).unwrap();
+        flush_keys(&storage, i);
+    }
+    while storage.inner.state.read().l0_sstables.len() > 2 {
+        storage.inner.trigger_compaction().unwrap();
+    }
+    assert_eq!(storage.get(b"key").unwrap(), Some(Bytes::from("value4")));
+    for i in 0..5 {
+        assert_eq!(
+            storage.get(format!("key{i}_050").as_bytes()).unwrap(),
+            Some(Bytes::from_static(b"value"))
+        );
+    }
+}
+
+#[test]
+fn test_fifo_compact_range() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, fifo_options(u64::MAX, None, None)).unwrap();
+    for i in 0..3 {
+        flush_keys(&storage, i);
+    }
+    storage.put(b"key0_000", b"new").unwrap();
+    storage.delete(b"key0_001").unwrap();
+    storage
+        .compact_range(
+            Bound::Included(b"key0_000"),
+            Bound::Included(b"key0_001"),
+            None,
+        )
+        .unwrap();
+    // the SST of `key0_` is merged with the flushed memtable, and the others are kept
+    assert_eq!(storage.inner.state.read().l0_sstables.len(), 3);
+    assert_eq!(storage.get(b"key0_000").unwrap(), Some(Bytes::from("new")));
+    assert_eq!(storage.get(b"key0_001").unwrap(), None);
+    assert_eq!(
+        storage.get(b"key1_000").unwrap(),
+        Some(Bytes::from_static(b"value"))
+    );
+}
+
+#[test]
+fn test_fifo_merge_output_order_recovered() {
+    let dir = tempdir().unwrap();
+    let options = fifo_options(u64::MAX, None, None);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    for i in 0..3 {
+        storage.put(b"key", format!("value{i}").as_bytes()).unwrap();
+        storage.force_flush().unwrap();
+    }
+    let [c, b, a] = storage.inner.state.read().l0_sstables[..] else {
+        panic!("expected 3 L0 SSTs");
+    };
+    // Replay an intra-L0 merge of `a` into `b` that finishes after `c` is flushed, so that the output is placed
+    // behind `c`.
+    let install = |l0_sstables: Vec<usize>| {
+        let state_lock = storage.inner.state_lock.lock();
+        let old_state = storage.inner.state.read().clone();
+        let mut new_state = old_state.as_ref().clone();
+        new_state.l0_sstables = l0_sstables;
+        let edit = VersionEdit::diff(&old_state, &new_state);
+        storage
+            .inner
+            .install_state(&state_lock, new_state, edit)
+            .unwrap();
+    };
+    install(vec![c, a]);
+    let (new_state, _) = storage.inner.compaction_controller.apply_compaction_result(
+        &storage.inner.state.read(),
+        &CompactionTask::Fifo(FifoCompactionTask::Merge { sst_ids: vec![a] }),
+        &[b],
+        false,
+    );
+    assert_eq!(new_state.l0_sstables, vec![c, b]);
+    install(new_state.l0_sstables);
+    assert_eq!(storage.get(b"key").unwrap(), Some(Bytes::from("value2")));
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.inner.state.read().l0_sstables, vec![c, b]);
+    assert_eq!(storage.get(b"key").unwrap(), Some(Bytes::from("value2")));
+}
@@ -0,0 +1,98 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    fs::{FileSystem, MemoryFileSystem},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+#[test]
+fn test_memory_file_system() {
+    let dir = tempdir().unwrap();
+    let fs = Arc::new(MemoryFileSystem::new());
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+        },
+    ));
+    options.enable_wal = true;
+    options.fs = fs.clone();
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    for i in 0..4 {
+        storage
+            .put(format!("key{i}").as_bytes(), b"flushed")
+            .unwrap();
+        storage.force_flush().unwrap();
+    }
+    storage.inner.trigger_compaction().unwrap();
+    storage.put(b"key0", b"in memtable").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    // nothing is written to the disk
+    assert_eq!(dir.path().read_dir().unwrap().count(), 0);
+    assert!(fs.exists(&dir.path().join("CURRENT")));
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(
+        storage.get(b"key0").unwrap(),
+        Some(Bytes::from_static(b"in memtable"))
+    );
+    for i in 1..4 {
+        assert_eq!(
+            storage.get(format!("key{i}").as_bytes()).unwrap(),
+            Some(Bytes::from_static(b"flushed"))
+        );
+    }
+}
+
+#[test]
+fn test_memory_file_system_operations() {
+    let fs = MemoryFileSystem::new();
+    let dir = std::path::Path::new("/db");
+    fs.create_dir_all(&dir.join("archive")).unwrap();
+    let mut file = fs.create(&dir.join("1.wal")).unwrap();
+    file.write_all(b"hello").unwrap();
+    file.preallocate(8).unwrap();
+    assert!(fs.create(&dir.join("1.wal")).is_err());
+    assert_eq!(fs.read(&dir.join("1.wal")).unwrap(), b"hello\0\0\0");
+
+    let mut buf = [0; 3];
+    fs.open(&dir.join("1.wal"))
+        .unwrap()
+        .read_at(&mut buf, 1)
+        .unwrap();
+    assert_eq!(&buf, b"ell");
+
+    fs.hard_link(&dir.join("1.wal"), &dir.join("2.wal"))
+        .unwrap();
+    fs.rename(&dir.join("1.wal"), &dir.join("archive").join("1.wal"))
+        .unwrap();
+    let mut files = fs.list_dir(dir).unwrap();
+    files.sort();
+    assert_eq!(files, vec![dir.join("2.wal"), dir.join("archive")]);
+    fs.remove_dir_all(&dir.join("archive")).unwrap();
+    assert!(!fs.exists(&dir.join("archive")));
+    assert_eq!(fs.file_size(&dir.join("2.wal")).unwrap(), 8);
+    fs.remove_file(&dir.join("2.wal")).unwrap();
+    assert!(fs.remove_file(&dir.join("2.wal")).is_err());
+}
@@ -0,0 +1,108 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::sync::Arc;
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+fn flush_all(storage: &MiniLsm) {
+    while {
+        let state = storage.inner.state.read();
+        !state.memtable.is_empty() || !state.imm_memtables.is_empty()
+    } {
+        storage.force_flush().unwrap();
+    }
+}
+
+/// Fill a grandparent level of small SSTs, compact a sparse range of keys over it into L1, and return the overlap of
+/// each L1 SST with the grandparent level in bytes and the size of the grandparent SSTs.
+fn compact_over_grandparents(max_grandparent_overlap_bytes: usize) -> (Vec<u64>, u64) {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.target_sst_size = 4096;
+    options.max_grandparent_overlap_bytes = max_grandparent_overlap_bytes;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for i in 0..5000 {
+        storage
+            .put(format!("key{:06}", i * 10).as_bytes(), b"value")
+            .unwrap();
+    }
+    flush_all(&storage);
+    storage.force_full_compaction().unwrap();
+    {
+        // Move the compacted SSTs down to L2, which is the grandparent level of the next full compaction.
+        let mut state = storage.inner.state.write();
+        let mut snapshot = state.as_ref().clone();
+        let ssts = std::mem::take(&mut snapshot.levels[0].1);
+        snapshot.levels.push((2, ssts));
+        *state = Arc::new(snapshot);
+    }
+    // Every 10th key of the grandparent level, so that an L1 SST covers a wide range of L2.
+    for i in 0..500 {
+        storage
+            .put(format!("key{:06}", i * 100 + 5).as_bytes(), b"value")
+            .unwrap();
+    }
+    flush_all(&storage);
+    storage.force_full_compaction().unwrap();
+
+    let state = storage.inner.state.read();
+    let grandparents = state.levels[1]
+        .1
+        .iter()
+        .map(|id| state.sstables[id].clone())
+        .collect::<Vec<_>>();
+    assert!(grandparents.len() > 10);
+    let overlaps = state.levels[0]
+        .1
+        .iter()
+        .map(|id| {
+            let sst = &state.sstables[id];
+            grandparents
+                .iter()
+                .filter(|gp| {
+                    gp.first_key().key_ref() <= sst.last_key().key_ref()
+                        && gp.last_key().key_ref() >= sst.first_key().key_ref()
+                })
+                .map(|gp| gp.table_size())
+                .sum()
+        })
+        .collect();
+    let max_grandparent_size = grandparents.iter().map(|gp| gp.table_size()).max().unwrap();
+    (overlaps, max_grandparent_size)
+}
+
+#[test]
+fn test_grandparent_overlap_disabled() {
+    let (overlaps, max_grandparent_size) = compact_over_grandparents(0);
+    assert!(overlaps.iter().any(|x| *x > 4 * max_grandparent_size));
+}
+
+#[test]
+fn test_cut_on_grandparent_overlap() {
+    let (overlaps, max_grandparent_size) = compact_over_grandparents(16384);
+    let (unlimited, _) = compact_over_grandparents(0);
+    assert!(overlaps.len() > unlimited.len());
+    // An SST is cut right after it passes the limit, and it also overlaps the grandparent SSTs at its two ends.
+    for overlap in overlaps {
+        assert!(
+            overlap <= 16384 + 3 * max_grandparent_size,
+            "overlap {overlap} is too large"
+        );
+    }
+}
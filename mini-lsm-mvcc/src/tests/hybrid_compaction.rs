@@ -0,0 +1,149 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, HybridCompactionController, HybridCompactionOptions},
+    key::KeyBytes,
+    lsm_storage::{LsmStorageOptions, LsmStorageState, MiniLsm},
+    table::SsTable,
+    tests::harness::check_compaction_ratio,
+};
+
+fn hybrid_options() -> HybridCompactionOptions {
+    HybridCompactionOptions {
+        num_levels: 3,
+        max_runs_per_level: 2,
+        base_run_size_mb: 1,
+    }
+}
+
+/// The runs from the latest to the oldest, each of which is one SST of the size in MB.
+fn snapshot(run_sizes_mb: &[u64]) -> LsmStorageState {
+    let mut snapshot = LsmStorageState::create(&LsmStorageOptions::default_for_week2_test(
+        CompactionOptions::Hybrid(hybrid_options()),
+    ));
+    for (idx, size_mb) in run_sizes_mb.iter().enumerate() {
+        let id = run_sizes_mb.len() - idx;
+        let key = KeyBytes::for_testing_from_bytes_no_ts(Bytes::from_static(b"key"));
+        snapshot.sstables.insert(
+            id,
+            Arc::new(SsTable::create_meta_only(
+                id,
+                size_mb << 20,
+                key.clone(),
+                key,
+            )),
+        );
+        snapshot.levels.push((id, vec![id]));
+    }
+    snapshot
+}
+
+#[test]
+fn test_hybrid_run_levels() {
+    let controller = HybridCompactionController::new(hybrid_options());
+    assert_eq!(controller.run_levels(&snapshot(&[])), Vec::<usize>::new());
+    assert_eq!(controller.run_levels(&snapshot(&[1])), vec![3]);
+    assert_eq!(
+        controller.run_levels(&snapshot(&[1, 1, 3, 1])),
+        vec![1, 1, 2, 3]
+    );
+    // A small run older than a large one is in the same level, so that the runs of a level are next to each other.
+    assert_eq!(
+        controller.run_levels(&snapshot(&[3, 1, 9, 1])),
+        vec![2, 2, 2, 3]
+    );
+}
+
+#[test]
+fn test_hybrid_merges_upper_level_into_next_level() {
+    let controller = HybridCompactionController::new(hybrid_options());
+    let snapshot = snapshot(&[1, 1, 1, 3, 20]);
+    let task = controller
+        .generate_compaction_task(&snapshot, &HashSet::new())
+        .unwrap();
+    assert_eq!(task.tiers, snapshot.levels[..3].to_vec());
+    assert!(!task.bottom_tier_included);
+
+    // The runs being compacted are skipped.
+    assert!(
+        controller
+            .generate_compaction_task(&snapshot, &HashSet::from([5]))
+            .is_none()
+    );
+
+    let (new_snapshot, removed) = controller.apply_compaction_result(&snapshot, &task, &[6]);
+    assert_eq!(removed, vec![5, 4, 3]);
+    assert_eq!(
+        new_snapshot.levels,
+        vec![(6, vec![6]), (2, vec![2]), (1, vec![1])]
+    );
+}
+
+#[test]
+fn test_hybrid_merges_last_upper_level_into_last_level() {
+    let controller = HybridCompactionController::new(hybrid_options());
+    let snapshot = snapshot(&[1, 3, 3, 3, 20]);
+    let task = controller
+        .generate_compaction_task(&snapshot, &HashSet::new())
+        .unwrap();
+    assert_eq!(task.tiers, snapshot.levels[1..].to_vec());
+    assert!(task.bottom_tier_included);
+}
+
+#[test]
+fn test_hybrid_compaction() {
+    let dir = tempdir().unwrap();
+    let options =
+        LsmStorageOptions::default_for_week2_test(CompactionOptions::Hybrid(hybrid_options()));
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let value = vec![b'v'; 4000];
+    for round in 0..10 {
+        // About 1MB in each flushed run, where the keys of each round overwrite half of the previous round.
+        for i in round * 125..round * 125 + 250 {
+            storage
+                .put(format!("key{i:05}").as_bytes(), &value)
+                .unwrap();
+        }
+        storage.force_flush().unwrap();
+        storage.inner.trigger_compaction().unwrap();
+    }
+    while storage
+        .inner
+        .compaction_controller
+        .generate_compaction_task(&storage.inner.state.read(), &HashSet::new(), u64::MAX)
+        .is_some()
+    {
+        storage.inner.trigger_compaction().unwrap();
+    }
+    check_compaction_ratio(storage.clone());
+    assert!(storage.compaction_stats().compactions > 0);
+    let levels = storage.inner.state.read().levels.clone();
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.inner.state.read().levels, levels);
+    for i in 0..1375 {
+        assert_eq!(
+            storage.get(format!("key{i:05}").as_bytes()).unwrap(),
+            Some(Bytes::from(value.clone())),
+        );
+    }
+}
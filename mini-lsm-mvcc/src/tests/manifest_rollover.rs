@@ -0,0 +1,218 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::Path;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions, TieredCompactionOptions},
+    fs::default_fs,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    manifest::{LegacyManifestRecord, Manifest, ManifestRecord, VersionEdit},
+};
+
+fn manifest_files(path: &Path) -> Vec<String> {
+    let mut files = default_fs()
+        .list_dir(path)
+        .unwrap()
+        .iter()
+        .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
+        .filter(|name| name.starts_with("MANIFEST"))
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+fn current_manifest(path: &Path) -> String {
+    String::from_utf8(default_fs().read(&path.join("CURRENT")).unwrap())
+        .unwrap()
+        .trim()
+        .to_string()
+}
+
+#[test]
+fn test_manifest_rollover() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+        },
+    ));
+    options.enable_wal = true;
+    options.max_manifest_file_size = 256;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert_eq!(manifest_files(dir.path()), vec!["MANIFEST-000001"]);
+    for i in 0..20 {
+        storage
+            .put(
+                format!("key{i:03}").as_bytes(),
+                format!("value{i}").as_bytes(),
+            )
+            .unwrap();
+        storage.force_flush().unwrap();
+    }
+    storage.put(b"key_in_wal", b"value").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    // only the current manifest is kept
+    let files = manifest_files(dir.path());
+    assert_eq!(files.len(), 1);
+    assert_ne!(files[0], "MANIFEST-000001");
+    assert_eq!(files[0], current_manifest(dir.path()));
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for i in 0..20 {
+        assert_eq!(
+            storage.get(format!("key{i:03}").as_bytes()).unwrap(),
+            Some(Bytes::from(format!("value{i}")))
+        );
+    }
+    assert_eq!(
+        storage.get(b"key_in_wal").unwrap(),
+        Some(Bytes::from_static(b"value"))
+    );
+}
+
+#[test]
+fn test_json_manifest_migration() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"b", b"2").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    // replace the manifest with the JSON records written by older versions
+    for name in manifest_files(dir.path()) {
+        default_fs().remove_file(&dir.path().join(name)).unwrap();
+    }
+    default_fs()
+        .remove_file(&dir.path().join("CURRENT"))
+        .unwrap();
+    let mut buf = Vec::new();
+    for record in [
+        LegacyManifestRecord::NewMemtable(0),
+        LegacyManifestRecord::NewMemtable(1),
+        LegacyManifestRecord::Flush(0),
+    ] {
+        let json = serde_json::to_vec(&record).unwrap();
+        buf.extend((json.len() as u64).to_be_bytes());
+        buf.extend(&json);
+        buf.extend(crc32fast::hash(&json).to_be_bytes());
+    }
+    default_fs()
+        .write(&dir.path().join("MANIFEST"), &buf)
+        .unwrap();
+
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert_eq!(manifest_files(dir.path()), vec!["MANIFEST-000001"]);
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from_static(b"1")));
+    assert_eq!(storage.get(b"b").unwrap(), Some(Bytes::from_static(b"2")));
+    storage.close().unwrap();
+    drop(storage);
+
+    let (_, records) =
+        Manifest::recover(default_fs(), dir.path(), options.max_manifest_file_size).unwrap();
+    assert!(
+        records
+            .iter()
+            .all(|record| matches!(record, ManifestRecord::Edit(_)))
+    );
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from_static(b"1")));
+    assert_eq!(storage.get(b"b").unwrap(), Some(Bytes::from_static(b"2")));
+}
+
+#[test]
+fn test_version_edit_recovery_tiered() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Tiered(
+        TieredCompactionOptions {
+            num_tiers: 3,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+            max_merge_width: None,
+        },
+    ));
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    // Write enough keys into each SST that the tier sizes follow the number of keys.
+    for i in 0..10 {
+        for j in 0..100 {
+            storage
+                .put(
+                    format!("key{i:03}_{j:03}").as_bytes(),
+                    format!("value{i}").as_bytes(),
+                )
+                .unwrap();
+        }
+        storage.force_flush().unwrap();
+        storage.inner.trigger_compaction().unwrap();
+    }
+    storage.close().unwrap();
+    let levels = storage.inner.state.read().levels.clone();
+    assert!(levels.len() > 1);
+    drop(storage);
+
+    // tiers are recovered in the same order without replaying the compaction tasks
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.inner.state.read().levels, levels);
+    for i in 0..10 {
+        assert_eq!(
+            storage.get(format!("key{i:03}_000").as_bytes()).unwrap(),
+            Some(Bytes::from(format!("value{i}")))
+        );
+    }
+}
+
+#[test]
+fn test_inconsistent_manifest() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.close().unwrap();
+    let snapshot = VersionEdit::snapshot(&storage.inner.state.read());
+    drop(storage);
+
+    // a truncated edit cannot be decoded
+    let mut buf = Vec::new();
+    snapshot.encode(&mut buf);
+    assert_eq!(VersionEdit::decode(&buf).unwrap(), snapshot);
+    // the last byte is the flag of the L0 order, which edits written before it was recorded do not have
+    for len in 0..buf.len() - 1 {
+        assert!(VersionEdit::decode(&buf[..len]).is_err());
+    }
+
+    // an edit that removes an SST which does not exist makes the database fail to open
+    let (manifest, _) =
+        Manifest::recover(default_fs(), dir.path(), options.max_manifest_file_size).unwrap();
+    manifest
+        .add_record_when_init(VersionEdit {
+            removed_ssts: vec![(None, 100)],
+            ..Default::default()
+        })
+        .unwrap();
+    drop(manifest);
+    assert!(MiniLsm::open(&dir, options).is_err());
+}
@@ -0,0 +1,232 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::ops::Bound;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    iterators::StorageIterator,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    merge_operator::MergeOperator,
+    table::SsTableIterator,
+};
+
+/// Adds up `u64` counters. An operand of `0` deletes the counter.
+struct CounterMergeOperator;
+
+impl CounterMergeOperator {
+    fn decode(value: &[u8]) -> u64 {
+        u64::from_be_bytes(value.try_into().unwrap())
+    }
+}
+
+impl MergeOperator for CounterMergeOperator {
+    fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Bytes {
+        let mut sum = existing.map(Self::decode).unwrap_or(0);
+        for operand in operands {
+            match Self::decode(operand) {
+                0 => sum = 0,
+                x => sum += x,
+            }
+        }
+        if sum == 0 {
+            return Bytes::new();
+        }
+        Bytes::copy_from_slice(&sum.to_be_bytes())
+    }
+
+    fn partial_merge(&self, _key: &[u8], operands: &[&[u8]]) -> Option<Bytes> {
+        if operands.iter().any(|x| Self::decode(x) == 0) {
+            return None;
+        }
+        let sum = operands.iter().map(|x| Self::decode(x)).sum::<u64>();
+        Some(Bytes::copy_from_slice(&sum.to_be_bytes()))
+    }
+}
+
+fn counter(x: u64) -> Bytes {
+    Bytes::copy_from_slice(&x.to_be_bytes())
+}
+
+fn open_storage(dir: &tempfile::TempDir, serializable: bool) -> Arc<MiniLsm> {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 0,
+            level0_file_num_compaction_trigger: 100,
+            max_levels: 2,
+        },
+    ));
+    options.serializable = serializable;
+    options.merge_operator = Some(Arc::new(CounterMergeOperator));
+    MiniLsm::open(dir, options).unwrap()
+}
+
+fn scan_all(storage: &MiniLsm) -> Vec<(Bytes, Bytes)> {
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let mut entries = Vec::new();
+    while iter.is_valid() {
+        entries.push((
+            Bytes::copy_from_slice(iter.key()),
+            Bytes::copy_from_slice(iter.value()),
+        ));
+        iter.next().unwrap();
+    }
+    entries
+}
+
+/// The number of versions of `key` in the SSTs.
+fn versions_in_ssts(storage: &MiniLsm, key: &[u8]) -> usize {
+    let snapshot = storage.inner.state.read().clone();
+    let mut versions = 0;
+    for sst in snapshot.sstables.values() {
+        let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+        while iter.is_valid() {
+            if iter.key().key_ref() == key {
+                versions += 1;
+            }
+            iter.next().unwrap();
+        }
+    }
+    versions
+}
+
+#[test]
+fn test_merge_on_read() {
+    let dir = tempdir().unwrap();
+    let storage = open_storage(&dir, false);
+    storage.merge(b"key1", &counter(1)).unwrap();
+    storage.merge(b"key1", &counter(2)).unwrap();
+    storage.put(b"key2", &counter(10)).unwrap();
+    storage.force_flush().unwrap();
+    storage.merge(b"key2", &counter(5)).unwrap();
+    storage.merge(b"key3", &counter(7)).unwrap();
+    storage.delete(b"key3").unwrap();
+    storage.merge(b"key3", &counter(1)).unwrap();
+    storage.merge(b"key4", &counter(3)).unwrap();
+    storage.merge(b"key4", &counter(0)).unwrap();
+
+    assert_eq!(storage.get(b"key1").unwrap(), Some(counter(3)));
+    assert_eq!(storage.get(b"key2").unwrap(), Some(counter(15)));
+    assert_eq!(storage.get(b"key3").unwrap(), Some(counter(1)));
+    assert_eq!(storage.get(b"key4").unwrap(), None);
+    assert_eq!(
+        scan_all(&storage),
+        vec![
+            (Bytes::from("key1"), counter(3)),
+            (Bytes::from("key2"), counter(15)),
+            (Bytes::from("key3"), counter(1)),
+        ]
+    );
+
+    // a snapshot only sees the operands written before it
+    let txn = storage.new_txn().unwrap();
+    storage.merge(b"key1", &counter(100)).unwrap();
+    assert_eq!(txn.get(b"key1").unwrap(), Some(counter(3)));
+    assert_eq!(storage.get(b"key1").unwrap(), Some(counter(103)));
+}
+
+#[test]
+fn test_concurrent_merges_do_not_conflict() {
+    let dir = tempdir().unwrap();
+    let storage = open_storage(&dir, true);
+    storage.put(b"key", &counter(1)).unwrap();
+    let txn1 = storage.new_txn().unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    txn1.merge(b"key", &counter(2)).unwrap();
+    txn1.merge(b"key", &counter(3)).unwrap();
+    txn2.merge(b"key", &counter(4)).unwrap();
+    txn1.commit().unwrap();
+    txn2.commit().unwrap();
+    storage.merge(b"key", &counter(5)).unwrap();
+    assert_eq!(storage.get(b"key").unwrap(), Some(counter(15)));
+}
+
+#[test]
+fn test_transaction_reads_its_own_merges() {
+    let dir = tempdir().unwrap();
+    let storage = open_storage(&dir, false);
+    storage.put(b"key1", &counter(1)).unwrap();
+    storage.merge(b"key2", &counter(2)).unwrap();
+    let txn = storage.new_txn().unwrap();
+    txn.merge(b"key1", &counter(10)).unwrap();
+    txn.merge(b"key2", &counter(20)).unwrap();
+    txn.merge(b"key3", &counter(30)).unwrap();
+    txn.merge(b"key2", &counter(0)).unwrap();
+    txn.put(b"key4", &counter(4));
+    txn.merge(b"key4", &counter(40)).unwrap();
+    assert_eq!(txn.get(b"key1").unwrap(), Some(counter(11)));
+    assert_eq!(txn.get(b"key2").unwrap(), None);
+    let mut iter = txn.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let mut entries = Vec::new();
+    while iter.is_valid() {
+        entries.push((
+            Bytes::copy_from_slice(iter.key()),
+            Bytes::copy_from_slice(iter.value()),
+        ));
+        iter.next().unwrap();
+    }
+    assert_eq!(
+        entries,
+        vec![
+            (Bytes::from("key1"), counter(11)),
+            (Bytes::from("key3"), counter(30)),
+            (Bytes::from("key4"), counter(44)),
+        ]
+    );
+    txn.commit().unwrap();
+    assert_eq!(scan_all(&storage), entries);
+}
+
+#[test]
+fn test_compaction_merges_operands() {
+    let dir = tempdir().unwrap();
+    let storage = open_storage(&dir, false);
+    storage.put(b"key1", &counter(1)).unwrap();
+    storage.force_flush().unwrap();
+    storage.merge(b"key1", &counter(2)).unwrap();
+    storage.merge(b"key2", &counter(3)).unwrap();
+    storage.force_flush().unwrap();
+    storage.merge(b"key1", &counter(4)).unwrap();
+    storage.merge(b"key2", &counter(5)).unwrap();
+    storage.merge(b"key3", &counter(6)).unwrap();
+    storage.merge(b"key3", &counter(0)).unwrap();
+    storage.force_flush().unwrap();
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded, None)
+        .unwrap();
+    assert_eq!(versions_in_ssts(&storage, b"key1"), 1);
+    assert_eq!(versions_in_ssts(&storage, b"key2"), 1);
+    assert_eq!(versions_in_ssts(&storage, b"key3"), 0);
+    assert_eq!(
+        scan_all(&storage),
+        vec![
+            (Bytes::from("key1"), counter(7)),
+            (Bytes::from("key2"), counter(8)),
+        ]
+    );
+}
+
+#[test]
+fn test_merge_without_merge_operator() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(
+        &dir,
+        LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction),
+    )
+    .unwrap();
+    assert!(storage.merge(b"key", &counter(1)).is_err());
+}
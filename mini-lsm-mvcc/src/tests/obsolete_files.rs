@@ -0,0 +1,89 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    fs::default_fs,
+    lsm_storage::{LsmStorageInner, LsmStorageOptions, MiniLsm},
+};
+
+#[test]
+fn test_delete_obsolete_files_on_open() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"b", b"2").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    // files left behind by a crash, e.g., compaction outputs that are not recorded in the manifest
+    let orphan_sst = LsmStorageInner::path_of_sst_static(&dir, 100);
+    let orphan_wal = LsmStorageInner::path_of_wal_static(&dir, 101);
+    let other_file = dir.path().join("notes.txt");
+    default_fs().write(&orphan_sst, b"orphan").unwrap();
+    default_fs().write(&orphan_wal, b"orphan").unwrap();
+    default_fs()
+        .write(&other_file, b"not managed by the engine")
+        .unwrap();
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert!(!default_fs().exists(&orphan_sst));
+    assert!(!default_fs().exists(&orphan_wal));
+    assert!(default_fs().exists(&other_file));
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from_static(b"1")));
+    assert_eq!(storage.get(b"b").unwrap(), Some(Bytes::from_static(b"2")));
+}
+
+#[test]
+fn test_list_and_delete_obsolete_files() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.obsolete_file_gc_interval = None;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"b", b"2").unwrap();
+    storage.force_flush().unwrap();
+    let compacted_sst = storage.inner.state.read().l0_sstables[0];
+    storage.force_full_compaction().unwrap();
+    assert!(storage.list_obsolete_files().unwrap().is_empty());
+
+    let orphan_sst = storage.inner.path_of_sst(compacted_sst);
+    default_fs().write(&orphan_sst, b"orphan").unwrap();
+    // a file with a larger id than any allocated one might be written by a running job
+    let new_sst = LsmStorageInner::path_of_sst_static(&dir, 99999);
+    default_fs().write(&new_sst, b"new").unwrap();
+
+    // dry run does not delete anything
+    assert_eq!(
+        storage.list_obsolete_files().unwrap(),
+        vec![orphan_sst.clone()]
+    );
+    assert!(default_fs().exists(&orphan_sst));
+    assert_eq!(
+        storage.delete_obsolete_files().unwrap(),
+        vec![orphan_sst.clone()]
+    );
+    assert!(!default_fs().exists(&orphan_sst));
+    assert!(default_fs().exists(&new_sst));
+    assert!(storage.list_obsolete_files().unwrap().is_empty());
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from_static(b"1")));
+    assert_eq!(storage.get(b"b").unwrap(), Some(Bytes::from_static(b"2")));
+}
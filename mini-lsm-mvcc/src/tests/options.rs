@@ -0,0 +1,194 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionPri,
+    compact::{
+        CompactionOptions, LeveledCompactionOptions, SimpleLeveledCompactionOptions,
+        TieredCompactionOptions,
+    },
+    fs::default_fs,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+fn tiered_options() -> LsmStorageOptions {
+    LsmStorageOptions::default_for_week2_test(CompactionOptions::Tiered(TieredCompactionOptions {
+        num_tiers: 3,
+        max_size_amplification_percent: 200,
+        size_ratio: 1,
+        min_merge_width: 2,
+        max_merge_width: None,
+    }))
+}
+
+fn leveled_options() -> LsmStorageOptions {
+    LsmStorageOptions::default_for_week2_test(CompactionOptions::Leveled(
+        LeveledCompactionOptions {
+            level_size_multiplier: 2,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 4,
+            base_level_size_mb: 1,
+            ..Default::default()
+        },
+    ))
+}
+
+fn simple_options(max_levels: usize) -> LsmStorageOptions {
+    LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels,
+        },
+    ))
+}
+
+#[test]
+fn test_incompatible_options() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, tiered_options()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+    assert!(default_fs().exists(&dir.path().join("OPTIONS")));
+
+    let err = MiniLsm::open(&dir, leveled_options()).err().unwrap();
+    assert!(err.to_string().contains("tiered"), "{}", err);
+    // the rejected open does not overwrite the persisted options
+    let storage = MiniLsm::open(&dir, tiered_options()).unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from_static(b"1")));
+    storage.close().unwrap();
+    drop(storage);
+
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, simple_options(3)).unwrap();
+    storage.close().unwrap();
+    drop(storage);
+    assert!(MiniLsm::open(&dir, simple_options(4)).is_err());
+
+    let dir = tempdir().unwrap();
+    let mut options = simple_options(3);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+    options.enable_wal = false;
+    assert!(MiniLsm::open(&dir, options).is_err());
+}
+
+#[test]
+fn test_migrate_compaction_strategy() {
+    let dir = tempdir().unwrap();
+    let mut options = tiered_options();
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for i in 0..10 {
+        storage
+            .put(
+                format!("key{i:03}").as_bytes(),
+                format!("value{i}").as_bytes(),
+            )
+            .unwrap();
+        if i % 2 == 0 {
+            storage.force_flush().unwrap();
+        }
+    }
+    storage.delete(b"key000").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let mut options = leveled_options();
+    options.enable_wal = true;
+    MiniLsm::migrate_compaction_strategy(&dir, options.clone()).unwrap();
+    for _ in 0..2 {
+        let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+        {
+            let state = storage.inner.state.read();
+            assert!(state.l0_sstables.is_empty());
+            assert_eq!(state.levels.len(), 4);
+            assert!(state.levels[..3].iter().all(|(_, ssts)| ssts.is_empty()));
+            assert!(!state.levels[3].1.is_empty());
+        }
+        assert_eq!(storage.get(b"key000").unwrap(), None);
+        for i in 1..10 {
+            assert_eq!(
+                storage.get(format!("key{i:03}").as_bytes()).unwrap(),
+                Some(Bytes::from(format!("value{i}")))
+            );
+        }
+        storage.close().unwrap();
+    }
+    let mut options = tiered_options();
+    options.enable_wal = true;
+    assert!(MiniLsm::open(&dir, options).is_err());
+}
+
+#[test]
+fn test_load_options_without_newer_fields() {
+    let dir = tempdir().unwrap();
+    let storage = MiniLsm::open(&dir, simple_options(3)).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    // the OPTIONS file written when it was introduced
+    let options = r#"{
+        "block_size": 4096,
+        "target_sst_size": 2097152,
+        "num_memtable_limit": 2,
+        "compaction_options": {
+            "Leveled": {
+                "level_size_multiplier": 2,
+                "level0_file_num_compaction_trigger": 2,
+                "max_levels": 3,
+                "base_level_size_mb": 1
+            }
+        },
+        "enable_wal": false,
+        "serializable": false,
+        "wal_archive_retention": null,
+        "wal_preallocate_size": 0,
+        "wal_recycle_pool_size": 0,
+        "max_manifest_file_size": 1048576
+    }"#;
+    default_fs()
+        .write(&dir.path().join("OPTIONS"), options.as_bytes())
+        .unwrap();
+    let options = LsmStorageOptions::load(default_fs().as_ref(), &dir)
+        .unwrap()
+        .unwrap();
+    assert_eq!(options.num_memtable_limit, 2);
+    assert_eq!(options.max_background_compactions, 1);
+    assert_eq!(options.max_subcompactions, 1);
+    assert_eq!(options.rate_limit_bytes_per_second, 0);
+    assert_eq!(options.max_grandparent_overlap_bytes, 0);
+    let CompactionOptions::Leveled(leveled_options) = &options.compaction_options else {
+        panic!("expected leveled compaction");
+    };
+    assert_eq!(leveled_options.compaction_pri, CompactionPri::default());
+    assert!(
+        options
+            .compaction_triggers
+            .tombstone_ratio_trigger_percent
+            .is_none()
+    );
+    assert!(options.merge_operator.is_none());
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.get(b"a").unwrap(), Some(Bytes::from_static(b"1")));
+}
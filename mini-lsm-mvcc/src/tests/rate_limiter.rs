@@ -0,0 +1,104 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    rate_limiter::{IoPriority, RateLimiter},
+};
+
+#[test]
+fn test_rate_limit() {
+    let limiter = RateLimiter::new(1 << 20);
+    let start = Instant::now();
+    limiter.request(256 << 10, IoPriority::Low);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert_eq!(limiter.total_bytes(IoPriority::Low), 256 << 10);
+
+    let limiter = RateLimiter::new(0);
+    let start = Instant::now();
+    limiter.request(1 << 30, IoPriority::Low);
+    assert!(start.elapsed() < Duration::from_millis(100));
+}
+
+#[test]
+fn test_rate_limit_priority() {
+    let limiter = Arc::new(RateLimiter::new(100 << 10));
+    let low = {
+        let limiter = limiter.clone();
+        std::thread::spawn(move || {
+            limiter.request(100 << 10, IoPriority::Low);
+            Instant::now()
+        })
+    };
+    std::thread::sleep(Duration::from_millis(100));
+    limiter.request(20 << 10, IoPriority::High);
+    let high_done = Instant::now();
+    assert!(high_done < low.join().unwrap());
+}
+
+#[test]
+fn test_rate_limit_adjustment() {
+    let limiter = Arc::new(RateLimiter::new(1 << 10));
+    let start = Instant::now();
+    let handle = {
+        let limiter = limiter.clone();
+        std::thread::spawn(move || limiter.request(1 << 20, IoPriority::Low))
+    };
+    std::thread::sleep(Duration::from_millis(100));
+    limiter.set_bytes_per_second(0);
+    handle.join().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert_eq!(limiter.bytes_per_second(), 0);
+}
+
+#[test]
+fn test_rate_limited_flush_and_compaction() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+        },
+    ));
+    options.rate_limit_bytes_per_second = 1 << 20;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for i in 0..4 {
+        for j in 0..100 {
+            storage
+                .put(
+                    format!("key{j:03}").as_bytes(),
+                    format!("value{i}").as_bytes(),
+                )
+                .unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+    storage.inner.trigger_compaction().unwrap();
+    let limiter = storage.rate_limiter();
+    assert!(limiter.total_bytes(IoPriority::High) > 0);
+    assert!(limiter.total_bytes(IoPriority::Low) > 0);
+    storage.set_rate_limit(2 << 20);
+    assert_eq!(limiter.bytes_per_second(), 2 << 20);
+    assert_eq!(
+        storage.get(b"key000").unwrap().as_deref(),
+        Some(b"value3".as_slice())
+    );
+}
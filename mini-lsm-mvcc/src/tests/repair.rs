@@ -0,0 +1,142 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, TieredCompactionOptions},
+    fs::default_fs,
+    lsm_storage::{LsmStorageInner, LsmStorageOptions, MiniLsm},
+    repair::repair_db,
+};
+
+fn corrupt_manifest(path: &std::path::Path) {
+    let current = String::from_utf8(default_fs().read(&path.join("CURRENT")).unwrap()).unwrap();
+    let manifest_path = path.join(current.trim());
+    let mut data = default_fs().read(&manifest_path).unwrap();
+    let len = data.len();
+    data[len - 1] ^= 0xff;
+    default_fs().write(&manifest_path, &data).unwrap();
+}
+
+#[test]
+fn test_repair_corrupted_manifest() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    for i in 0..3 {
+        storage
+            .put(format!("key{i}").as_bytes(), b"flushed")
+            .unwrap();
+        storage.force_flush().unwrap();
+    }
+    storage.put(b"key0", b"in memtable").unwrap();
+    storage.delete(b"key1").unwrap();
+    let latest_commit_ts = storage.inner.mvcc().latest_commit_ts();
+    storage.close().unwrap();
+    drop(storage);
+
+    corrupt_manifest(dir.path());
+    assert!(MiniLsm::open(&dir, options.clone()).is_err());
+
+    let report = repair_db(&dir, &options).unwrap();
+    assert_eq!(report.recovered_ssts.len(), 3);
+    assert_eq!(report.wal_ssts.len(), 1);
+    assert_eq!(report.max_ts, latest_commit_ts);
+    assert!(default_fs().exists(&dir.path().join("lost")));
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.inner.mvcc().latest_commit_ts(), latest_commit_ts);
+    assert_eq!(
+        storage.get(b"key0").unwrap(),
+        Some(Bytes::from_static(b"in memtable"))
+    );
+    assert_eq!(storage.get(b"key1").unwrap(), None);
+    assert_eq!(
+        storage.get(b"key2").unwrap(),
+        Some(Bytes::from_static(b"flushed"))
+    );
+    storage.put(b"key3", b"after repair").unwrap();
+    assert_eq!(
+        storage.get(b"key3").unwrap(),
+        Some(Bytes::from_static(b"after repair"))
+    );
+}
+
+#[test]
+fn test_repair_quarantine_corrupted_sst() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Tiered(
+        TieredCompactionOptions {
+            num_tiers: 10,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+            max_merge_width: None,
+        },
+    ));
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let mut sst_ids = Vec::new();
+    for i in 0..3 {
+        storage.put(format!("key{i}").as_bytes(), b"value").unwrap();
+        storage.force_flush().unwrap();
+        sst_ids.push(storage.inner.state.read().levels[0].0);
+    }
+    storage.close().unwrap();
+    drop(storage);
+
+    let corrupted_path = LsmStorageInner::path_of_sst_static(dir.path(), sst_ids[1]);
+    let mut data = default_fs().read(&corrupted_path).unwrap();
+    data[0] ^= 0xff;
+    default_fs().write(&corrupted_path, &data).unwrap();
+
+    let report = repair_db(&dir, &options).unwrap();
+    assert_eq!(report.recovered_ssts, vec![sst_ids[0], sst_ids[2]]);
+    assert!(!default_fs().exists(&corrupted_path));
+    assert!(
+        report.lost_files.contains(
+            &dir.path()
+                .join("lost")
+                .join(corrupted_path.file_name().unwrap())
+        )
+    );
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let tiers = storage
+        .inner
+        .state
+        .read()
+        .levels
+        .iter()
+        .map(|(id, ssts)| (*id, ssts.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        tiers,
+        vec![
+            (sst_ids[2], vec![sst_ids[2]]),
+            (sst_ids[0], vec![sst_ids[0]])
+        ]
+    );
+    assert_eq!(
+        storage.get(b"key0").unwrap(),
+        Some(Bytes::from_static(b"value"))
+    );
+    assert_eq!(storage.get(b"key1").unwrap(), None);
+    assert_eq!(
+        storage.get(b"key2").unwrap(),
+        Some(Bytes::from_static(b"value"))
+    );
+}
@@ -0,0 +1,87 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::ops::Bound;
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    iterators::StorageIterator,
+    key::KeySlice,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+type KeyPairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Write overlapping SSTs, fully compact them, and return all key-value pairs, the SST key ranges of L1, and the
+/// number of subcompactions.
+fn compact_with_subcompactions(max_subcompactions: usize) -> (KeyPairs, KeyPairs, u64) {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.target_sst_size = 4096;
+    options.max_subcompactions = max_subcompactions;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for round in 0..4 {
+        for i in (round..1000).step_by(3) {
+            let key = format!("key{:04}", i);
+            if i % 7 == round {
+                storage.delete(key.as_bytes()).unwrap();
+            } else {
+                storage
+                    .put(key.as_bytes(), format!("value{i}@{round}").as_bytes())
+                    .unwrap();
+            }
+        }
+        storage.force_flush().unwrap();
+    }
+    storage.force_full_compaction().unwrap();
+
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let mut kvs = Vec::new();
+    while iter.is_valid() {
+        kvs.push((iter.key().to_vec(), iter.value().to_vec()));
+        iter.next().unwrap();
+    }
+    let state = storage.inner.state.read();
+    assert!(state.l0_sstables.is_empty());
+    let ranges = state.levels[0]
+        .1
+        .iter()
+        .map(|id| {
+            let sst = &state.sstables[id];
+            (
+                sst.first_key().key_ref().to_vec(),
+                sst.last_key().key_ref().to_vec(),
+            )
+        })
+        .collect();
+    (kvs, ranges, storage.compaction_stats().subcompactions)
+}
+
+#[test]
+fn test_subcompactions() {
+    let (expected, _, subcompactions) = compact_with_subcompactions(1);
+    assert_eq!(subcompactions, 0);
+    let (kvs, ranges, subcompactions) = compact_with_subcompactions(4);
+    assert_eq!(subcompactions, 4);
+    assert_eq!(kvs, expected);
+    // The outputs of the subcompactions form a single sorted run.
+    for window in ranges.windows(2) {
+        assert!(
+            KeySlice::for_testing_from_slice_no_ts(&window[0].1)
+                < KeySlice::for_testing_from_slice_no_ts(&window[1].0)
+        );
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashSet;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    fs::default_fs,
+    lsm_storage::{LsmStorageInner, LsmStorageOptions, MiniLsm},
+};
+
+#[test]
+fn test_trivial_move() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+        },
+    ));
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    // SSTs with disjoint key ranges
+    for i in 0..2 {
+        for j in 0..100 {
+            storage
+                .put(format!("key{i}_{j:03}").as_bytes(), b"value")
+                .unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+    let flushed_ssts = storage.inner.state.read().l0_sstables.clone();
+    assert_eq!(flushed_ssts.len(), 2);
+    while storage.inner.state.read().levels[2].1.is_empty() {
+        storage.inner.trigger_compaction().unwrap();
+    }
+
+    let stats = storage.compaction_stats();
+    assert_eq!(stats.compactions, 0);
+    assert!(stats.trivial_moves >= 3);
+    assert_eq!(stats.bytes_written, 0);
+    let bottom_level = storage.inner.state.read().levels[2].1.clone();
+    assert_eq!(
+        bottom_level.iter().collect::<HashSet<_>>(),
+        flushed_ssts.iter().collect::<HashSet<_>>()
+    );
+    for id in &flushed_ssts {
+        assert!(default_fs().exists(&LsmStorageInner::path_of_sst_static(&dir, *id)));
+    }
+    storage.close().unwrap();
+    drop(storage);
+
+    // the moves are recorded in the manifest
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.inner.state.read().levels[2].1, bottom_level);
+    assert_eq!(
+        storage.get(b"key1_042").unwrap(),
+        Some(Bytes::from_static(b"value"))
+    );
+}
@@ -0,0 +1,263 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes};
+use tempfile::tempdir;
+
+use crate::{
+    clock::MockClock,
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions},
+    fs::default_fs,
+    iterators::StorageIterator,
+    key::KeySlice,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    table::{BlockMeta, SsTableBuilder, SsTableIterator, TableStats},
+};
+
+fn open_storage(dir: &tempfile::TempDir, clock: &Arc<MockClock>) -> Arc<MiniLsm> {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::Simple(
+        SimpleLeveledCompactionOptions {
+            size_ratio_percent: 0,
+            level0_file_num_compaction_trigger: 100,
+            max_levels: 2,
+        },
+    ));
+    options.clock = clock.clone();
+    MiniLsm::open(dir, options).unwrap()
+}
+
+fn scan_all(storage: &MiniLsm) -> Vec<(Bytes, Bytes)> {
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let mut entries = Vec::new();
+    while iter.is_valid() {
+        entries.push((
+            Bytes::copy_from_slice(iter.key()),
+            Bytes::copy_from_slice(iter.value()),
+        ));
+        iter.next().unwrap();
+    }
+    entries
+}
+
+/// The keys of all versions in the SSTs.
+fn keys_in_ssts(storage: &MiniLsm) -> Vec<Bytes> {
+    let snapshot = storage.inner.state.read().clone();
+    let mut keys = Vec::new();
+    for sst in snapshot.sstables.values() {
+        let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+        while iter.is_valid() {
+            keys.push(Bytes::copy_from_slice(iter.key().key_ref()));
+            iter.next().unwrap();
+        }
+    }
+    keys.sort();
+    keys
+}
+
+#[test]
+fn test_expired_keys_are_invisible() {
+    let dir = tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let storage = open_storage(&dir, &clock);
+    storage.put(b"key1", b"value1").unwrap();
+    storage
+        .put_with_ttl(b"key2", b"value2", Duration::from_secs(10))
+        .unwrap();
+    storage
+        .put_with_ttl(b"key3", b"value3", Duration::from_secs(20))
+        .unwrap();
+    assert_eq!(storage.get(b"key2").unwrap(), Some(Bytes::from("value2")));
+    assert_eq!(scan_all(&storage).len(), 3);
+
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(storage.get(b"key2").unwrap(), None);
+    assert_eq!(
+        scan_all(&storage),
+        vec![
+            (Bytes::from("key1"), Bytes::from("value1")),
+            (Bytes::from("key3"), Bytes::from("value3")),
+        ]
+    );
+    let txn = storage.new_txn().unwrap();
+    assert_eq!(txn.get(b"key2").unwrap(), None);
+    assert_eq!(txn.get(b"key3").unwrap(), Some(Bytes::from("value3")));
+
+    // the expiry is checked in the SSTs as well
+    storage.force_flush().unwrap();
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(storage.get(b"key3").unwrap(), None);
+    assert_eq!(
+        scan_all(&storage),
+        vec![(Bytes::from("key1"), Bytes::from("value1"))]
+    );
+}
+
+#[test]
+fn test_expired_version_hides_older_versions() {
+    let dir = tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let storage = open_storage(&dir, &clock);
+    storage.put(b"key", b"old").unwrap();
+    storage
+        .put_with_ttl(b"key", b"new", Duration::from_secs(1))
+        .unwrap();
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(storage.get(b"key").unwrap(), None);
+    assert!(scan_all(&storage).is_empty());
+    storage.put(b"key", b"newer").unwrap();
+    assert_eq!(storage.get(b"key").unwrap(), Some(Bytes::from("newer")));
+}
+
+#[test]
+fn test_transaction_put_with_ttl() {
+    let dir = tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let storage = open_storage(&dir, &clock);
+    let txn = storage.new_txn().unwrap();
+    txn.put_with_ttl(b"key", b"value", Duration::from_secs(5));
+    assert_eq!(txn.get(b"key").unwrap(), Some(Bytes::from("value")));
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(txn.get(b"key").unwrap(), None);
+    let iter = txn.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    assert!(!iter.is_valid());
+    txn.commit().unwrap();
+    assert_eq!(storage.get(b"key").unwrap(), None);
+}
+
+#[test]
+fn test_compaction_drops_expired_keys() {
+    let dir = tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let storage = open_storage(&dir, &clock);
+    storage.put(b"key1", b"value1").unwrap();
+    storage
+        .put_with_ttl(b"key1", b"value2", Duration::from_secs(10))
+        .unwrap();
+    storage
+        .put_with_ttl(b"key2", b"value", Duration::from_secs(100))
+        .unwrap();
+    storage.put(b"key3", b"value").unwrap();
+    clock.advance(Duration::from_secs(10));
+    storage
+        .compact_range(Bound::Unbounded, Bound::Unbounded, None)
+        .unwrap();
+    assert_eq!(
+        keys_in_ssts(&storage),
+        vec![Bytes::from("key2"), Bytes::from("key3")]
+    );
+    assert_eq!(storage.get(b"key2").unwrap(), Some(Bytes::from("value")));
+    assert_eq!(storage.get(b"key1").unwrap(), None);
+}
+
+#[test]
+fn test_values_starting_with_marker_byte() {
+    let dir = tempdir().unwrap();
+    let clock = Arc::new(MockClock::new(1_000_000));
+    let storage = open_storage(&dir, &clock);
+    let value = [0xff, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, b'x'];
+    storage.put(b"key", &value).unwrap();
+    storage
+        .put_with_ttl(b"key_ttl", &value, Duration::from_secs(1))
+        .unwrap();
+    storage.force_flush().unwrap();
+    assert_eq!(
+        storage.get(b"key").unwrap(),
+        Some(Bytes::copy_from_slice(&value))
+    );
+    assert_eq!(
+        storage.get(b"key_ttl").unwrap(),
+        Some(Bytes::copy_from_slice(&value))
+    );
+    assert_eq!(
+        scan_all(&storage),
+        vec![
+            (Bytes::from("key"), Bytes::copy_from_slice(&value)),
+            (Bytes::from("key_ttl"), Bytes::copy_from_slice(&value)),
+        ]
+    );
+}
+
+/// Write an SST in the layout without properties, whose values are not encoded.
+fn write_unencoded_sst(path: &Path, key: &[u8], ts: u64, value: &[u8]) {
+    let mut builder = SsTableBuilder::new(4096);
+    builder.add(KeySlice::for_testing_from_slice_with_ts(key, ts), value);
+    let sst = builder.build_for_test(path).unwrap();
+    let mut buf = default_fs().read(path).unwrap();
+    buf.truncate(sst.block_meta_offset);
+    let meta_offset = buf.len();
+    BlockMeta::encode_block_meta(&sst.block_meta, ts, &TableStats::default(), &mut buf);
+    // drop the properties and the checksum
+    buf.truncate(buf.len() - 4 - 4 - 32);
+    buf.put_u32(crc32fast::hash(&buf[meta_offset + 4..]));
+    buf.put_u32(meta_offset as u32);
+    let bloom_offset = buf.len();
+    sst.bloom.as_ref().unwrap().encode(&mut buf);
+    buf.put_u32(bloom_offset as u32);
+    default_fs().write(path, &buf).unwrap();
+}
+
+/// Write a WAL without the header, whose values are not encoded.
+fn write_unencoded_wal(path: &Path, key: &[u8], ts: u64, value: &[u8]) {
+    let mut batch = Vec::new();
+    batch.put_u16(key.len() as u16);
+    batch.put_slice(key);
+    batch.put_u64(ts);
+    batch.put_u16(value.len() as u16);
+    batch.put_slice(value);
+    let mut buf = Vec::new();
+    buf.put_u32(batch.len() as u32);
+    buf.put_slice(&batch);
+    buf.put_u32(crc32fast::hash(&batch));
+    default_fs().write(path, &buf).unwrap();
+}
+
+#[test]
+fn test_values_written_before_encoding() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key_sst", b"value").unwrap();
+    storage.force_flush().unwrap();
+    storage.close().unwrap();
+    let (sst_path, wal_path) = {
+        let state = storage.inner.state.read();
+        (
+            storage.inner.path_of_sst(state.l0_sstables[0]),
+            storage.inner.path_of_wal(state.memtable.id()),
+        )
+    };
+    drop(storage);
+
+    // values that look like encoded ones in files written before values were encoded
+    let value = [0xff, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, b'x'];
+    write_unencoded_sst(&sst_path, b"key_sst", 1, &value);
+    write_unencoded_wal(&wal_path, b"key_wal", 2, &value);
+    for _ in 0..2 {
+        let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+        assert_eq!(
+            storage.get(b"key_sst").unwrap(),
+            Some(Bytes::copy_from_slice(&value))
+        );
+        assert_eq!(
+            storage.get(b"key_wal").unwrap(),
+            Some(Bytes::copy_from_slice(&value))
+        );
+        storage.close().unwrap();
+    }
+}
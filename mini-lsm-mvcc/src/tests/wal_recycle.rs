@@ -0,0 +1,173 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bytes::{BufMut, Bytes};
+use crossbeam_skiplist::SkipMap;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    fs::default_fs,
+    key::{KeyBytes, KeySlice},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    wal::Wal,
+};
+
+#[test]
+fn test_recycled_wal_ignores_stale_records() {
+    let dir = tempdir().unwrap();
+    let fs = default_fs();
+    let path = dir.path().join("00001.wal");
+    {
+        let wal = Wal::create(fs.as_ref(), &path, 4096).unwrap();
+        wal.put(KeySlice::for_testing_from_slice_with_ts(b"a", 1), b"1")
+            .unwrap();
+        wal.put(KeySlice::for_testing_from_slice_with_ts(b"b", 2), b"2")
+            .unwrap();
+        wal.sync().unwrap();
+    }
+    // preallocated space does not change the file size
+    assert_eq!(fs.file_size(&path).unwrap(), 4096);
+    let recycled_path = dir.path().join("00002.wal");
+    {
+        let wal = Wal::create_recycled(fs.as_ref(), &path, &recycled_path).unwrap();
+        wal.put(KeySlice::for_testing_from_slice_with_ts(b"c", 3), b"3")
+            .unwrap();
+        wal.sync().unwrap();
+    }
+    let skiplist = SkipMap::new();
+    let wal = Wal::recover(fs.as_ref(), &recycled_path, &skiplist).unwrap();
+    let entries = skiplist
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        entries,
+        vec![(
+            KeyBytes::from_bytes_with_ts(Bytes::from_static(b"c"), 3),
+            Bytes::from_static(b"3")
+        )]
+    );
+
+    // appending after recovery continues from the last valid record
+    wal.put(KeySlice::for_testing_from_slice_with_ts(b"d", 4), b"4")
+        .unwrap();
+    wal.sync().unwrap();
+    drop(wal);
+    let skiplist = SkipMap::new();
+    Wal::recover(fs.as_ref(), &recycled_path, &skiplist).unwrap();
+    assert_eq!(skiplist.len(), 2);
+}
+
+#[test]
+fn test_wal_checksum_covers_record_header() {
+    let dir = tempdir().unwrap();
+    let fs = default_fs();
+    let path = dir.path().join("00001.wal");
+    {
+        let wal = Wal::create(fs.as_ref(), &path, 0).unwrap();
+        wal.put(KeySlice::for_testing_from_slice_with_ts(b"a", 1), b"1")
+            .unwrap();
+        wal.sync().unwrap();
+    }
+    let mut buf = fs.read(&path).unwrap();
+    // | generation | batch_size | generation | batch | checksum |
+    let checksum_offset = buf.len() - 4;
+    assert_eq!(
+        buf[checksum_offset..],
+        crc32fast::hash(&buf[8..checksum_offset]).to_be_bytes()
+    );
+
+    // a corrupted batch size is detected by the checksum
+    buf[11] -= 1;
+    fs.write(&path, &buf).unwrap();
+    assert!(Wal::recover(fs.as_ref(), &path, &SkipMap::new()).is_err());
+}
+
+#[test]
+fn test_wal_recycle_pool() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.wal_preallocate_size = 4096;
+    options.wal_recycle_pool_size = 1;
+    let count_files = |ext: &str| {
+        default_fs()
+            .list_dir(dir.path())
+            .unwrap()
+            .iter()
+            .filter(|path| path.extension().is_some_and(|x| x == ext))
+            .count()
+    };
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"0", b"v0").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"1", b"v1").unwrap();
+    storage.force_flush().unwrap();
+    // one flushed WAL is kept for reuse, the other one is removed
+    assert_eq!(count_files("recycle"), 1);
+    assert_eq!(count_files("wal"), 1);
+
+    storage.put(b"2", b"v2").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"3", b"v3").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for i in 0..4 {
+        assert_eq!(
+            storage.get(format!("{i}").as_bytes()).unwrap(),
+            Some(Bytes::from(format!("v{i}")))
+        );
+    }
+    storage.put(b"4", b"v4").unwrap();
+    storage.force_flush().unwrap();
+    assert_eq!(count_files("recycle"), 1);
+}
+
+#[test]
+fn test_recover_wal_without_header() {
+    let dir = tempdir().unwrap();
+    let fs = default_fs();
+    let path = dir.path().join("00001.wal");
+    // A WAL written before the header was added: `| batch_size | batch | checksum |` for each batch.
+    let mut buf = Vec::new();
+    for (key, ts, value) in [(b"a", 1, b"1"), (b"b", 2, b"2")] {
+        let mut batch = Vec::new();
+        batch.put_u16(key.len() as u16);
+        batch.put_slice(key);
+        batch.put_u64(ts);
+        batch.put_u16(value.len() as u16);
+        batch.put_slice(value);
+        buf.put_u32(batch.len() as u32);
+        buf.put_slice(&batch);
+        buf.put_u32(crc32fast::hash(&batch));
+    }
+    fs.write(&path, &buf).unwrap();
+
+    let skiplist = SkipMap::new();
+    let wal = Wal::recover(fs.as_ref(), &path, &skiplist).unwrap();
+    assert_eq!(skiplist.len(), 2);
+    wal.put(KeySlice::for_testing_from_slice_with_ts(b"c", 3), b"3")
+        .unwrap();
+    wal.sync().unwrap();
+    drop(wal);
+
+    // the WAL is rewritten with the header, so that the new record is in the same format
+    assert_eq!(fs.read(&path).unwrap()[..8], 1u64.to_be_bytes());
+    let skiplist = SkipMap::new();
+    Wal::recover(fs.as_ref(), &path, &skiplist).unwrap();
+    assert_eq!(skiplist.len(), 3);
+}
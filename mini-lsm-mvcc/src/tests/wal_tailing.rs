@@ -0,0 +1,189 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    clock::MockClock,
+    compact::CompactionOptions,
+    fs::default_fs,
+    lsm_storage::{LsmStorageOptions, MiniLsm, WriteBatchRecord},
+    merge_operator::MergeOperator,
+};
+
+/// Joins the value and the operands with commas.
+struct AppendMergeOperator;
+
+impl MergeOperator for AppendMergeOperator {
+    fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Bytes {
+        let parts = existing.into_iter().chain(operands.iter().copied());
+        Bytes::from(parts.collect::<Vec<_>>().join(&b","[..]))
+    }
+}
+
+fn put(key: &str, value: &str) -> WriteBatchRecord<Bytes> {
+    WriteBatchRecord::Put(
+        Bytes::copy_from_slice(key.as_bytes()),
+        Bytes::copy_from_slice(value.as_bytes()),
+    )
+}
+
+fn del(key: &str) -> WriteBatchRecord<Bytes> {
+    WriteBatchRecord::Del(Bytes::copy_from_slice(key.as_bytes()))
+}
+
+#[test]
+fn test_changes_since_live_and_archived_wal() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.wal_archive_retention = Some(Duration::from_secs(3600));
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage
+        .write_batch(&[WriteBatchRecord::Put("b", "2"), WriteBatchRecord::Del("a")])
+        .unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"c", b"3").unwrap();
+
+    assert!(
+        !default_fs()
+            .list_dir(&dir.path().join("archive"))
+            .unwrap()
+            .is_empty()
+    );
+    let changes = storage.changes_since(0).unwrap().collect::<Vec<_>>();
+    assert_eq!(
+        changes,
+        vec![
+            (1, vec![put("a", "1")]),
+            (2, vec![put("b", "2"), del("a")]),
+            (3, vec![put("c", "3")]),
+        ]
+    );
+    let changes = storage.changes_since(1).unwrap().collect::<Vec<_>>();
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[0].0, 2);
+
+    // archived WALs are still readable after reopening the database
+    storage.close().unwrap();
+    drop(storage);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"d", b"4").unwrap();
+    let changes = storage.changes_since(2).unwrap().collect::<Vec<_>>();
+    assert_eq!(
+        changes,
+        vec![(3, vec![put("c", "3")]), (4, vec![put("d", "4")])]
+    );
+}
+
+#[test]
+fn test_archived_wal_retention() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.wal_archive_retention = Some(Duration::ZERO);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"a", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.put(b"b", b"2").unwrap();
+    assert_eq!(
+        default_fs()
+            .list_dir(&dir.path().join("archive"))
+            .unwrap()
+            .len(),
+        0
+    );
+    let changes = storage.changes_since(0).unwrap().collect::<Vec<_>>();
+    assert_eq!(changes, vec![(2, vec![put("b", "2")])]);
+}
+
+#[test]
+fn test_changes_since_decodes_values() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.clock = Arc::new(MockClock::new(1_000_000));
+    options.merge_operator = Some(Arc::new(AppendMergeOperator));
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let value = [0xff, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, b'x'];
+    storage.put(b"a", &value).unwrap();
+    storage
+        .put_with_ttl(b"b", b"2", Duration::from_secs(1))
+        .unwrap();
+    storage
+        .write_batch(&[WriteBatchRecord::PutExpiring("c", "3", 2_000_000)])
+        .unwrap();
+    storage.merge(b"d", b"4").unwrap();
+    storage
+        .write_batch(&[WriteBatchRecord::Merge("d", "5")])
+        .unwrap();
+
+    let changes = storage.changes_since(0).unwrap().collect::<Vec<_>>();
+    assert_eq!(
+        changes,
+        vec![
+            (
+                1,
+                vec![WriteBatchRecord::Put(
+                    Bytes::from_static(b"a"),
+                    Bytes::copy_from_slice(&value)
+                )]
+            ),
+            (
+                2,
+                vec![WriteBatchRecord::PutExpiring(
+                    Bytes::from_static(b"b"),
+                    Bytes::from_static(b"2"),
+                    1_001_000
+                )]
+            ),
+            (
+                3,
+                vec![WriteBatchRecord::PutExpiring(
+                    Bytes::from_static(b"c"),
+                    Bytes::from_static(b"3"),
+                    2_000_000
+                )]
+            ),
+            (
+                4,
+                vec![WriteBatchRecord::Merge(
+                    Bytes::from_static(b"d"),
+                    Bytes::from_static(b"4")
+                )]
+            ),
+            (
+                5,
+                vec![WriteBatchRecord::Merge(
+                    Bytes::from_static(b"d"),
+                    Bytes::from_static(b"5")
+                )]
+            ),
+        ]
+    );
+    assert_eq!(storage.get(b"c").unwrap(), Some(Bytes::from_static(b"3")));
+    assert_eq!(storage.get(b"d").unwrap(), Some(Bytes::from_static(b"4,5")));
+    // The inner write path only takes encoded values.
+    assert!(
+        storage
+            .inner
+            .write_batch_inner(&[WriteBatchRecord::Merge("e", "6")])
+            .is_err()
+    );
+}
@@ -0,0 +1,122 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+// The encoding of the values stored in memtables, WALs and SSTs.
+//
+// Most values are stored as they are. A value that carries metadata starts with `VALUE_MARKER` followed by a kind
+// byte. A plain value that happens to start with `VALUE_MARKER` is escaped with the `ESCAPED` kind. The empty value
+// is a tombstone.
+//
+// SSTs without properties and WALs without a header were written before values were encoded, and store all values as
+// they are. Their values are escaped with `escape_unencoded` when they are read.
+
+use std::borrow::Cow;
+
+use bytes::{BufMut, Bytes};
+
+const VALUE_MARKER: u8 = 0xff;
+const ESCAPED: u8 = 0;
+/// Followed by the `u64` expiry time in milliseconds and the value.
+const EXPIRING: u8 = 1;
+/// Followed by an operand written with `merge`.
+const MERGE_OPERAND: u8 = 2;
+
+/// Encode a value written with `put`. Only values starting with `VALUE_MARKER` are copied.
+pub(crate) fn encode_plain(value: &[u8]) -> Cow<'_, [u8]> {
+    if value.first() == Some(&VALUE_MARKER) {
+        let mut buf = Vec::with_capacity(value.len() + 2);
+        buf.put_u8(VALUE_MARKER);
+        buf.put_u8(ESCAPED);
+        buf.put_slice(value);
+        Cow::Owned(buf)
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// Encode a value read from a file that was written before values were encoded. Only values starting with
+/// `VALUE_MARKER` are copied.
+pub(crate) fn escape_unencoded(value: Bytes) -> Bytes {
+    if value.first() == Some(&VALUE_MARKER) {
+        Bytes::from(encode_plain(&value).into_owned())
+    } else {
+        value
+    }
+}
+
+/// Encode a value that becomes invisible at `expire_at` milliseconds.
+pub(crate) fn encode_expiring(value: &[u8], expire_at: u64) -> Bytes {
+    let mut buf = Vec::with_capacity(value.len() + 10);
+    buf.put_u8(VALUE_MARKER);
+    buf.put_u8(EXPIRING);
+    buf.put_u64(expire_at);
+    buf.put_slice(value);
+    buf.into()
+}
+
+/// Encode an operand written with `merge`.
+pub(crate) fn encode_merge_operand(operand: &[u8]) -> Bytes {
+    let mut buf = Vec::with_capacity(operand.len() + 2);
+    buf.put_u8(VALUE_MARKER);
+    buf.put_u8(MERGE_OPERAND);
+    buf.put_slice(operand);
+    buf.into()
+}
+
+/// Whether an encoded value is a merge operand rather than a value or a tombstone.
+pub(crate) fn is_merge_operand(raw: &[u8]) -> bool {
+    matches!(raw, [VALUE_MARKER, MERGE_OPERAND, ..])
+}
+
+/// Encode `value` with the same metadata as the encoded value `like`.
+pub(crate) fn encode_like(like: &[u8], value: &[u8]) -> Bytes {
+    match expire_at(like) {
+        Some(expire_at) => encode_expiring(value, expire_at),
+        None => Bytes::from(encode_plain(value).into_owned()),
+    }
+}
+
+/// The expiry time of an encoded value, if it has one.
+pub(crate) fn expire_at(raw: &[u8]) -> Option<u64> {
+    match raw {
+        [VALUE_MARKER, EXPIRING, rest @ ..] if rest.len() >= 8 => {
+            Some(u64::from_be_bytes(rest[..8].try_into().unwrap()))
+        }
+        _ => None,
+    }
+}
+
+/// Whether an encoded value has expired at `now` milliseconds.
+pub(crate) fn is_expired(raw: &[u8], now: u64) -> bool {
+    expire_at(raw).is_some_and(|expire_at| expire_at <= now)
+}
+
+/// Whether an encoded value is visible to reads at `now` milliseconds, i.e., it is neither a tombstone nor expired.
+pub(crate) fn is_live(raw: &[u8], now: u64) -> bool {
+    !raw.is_empty() && !is_expired(raw, now)
+}
+
+/// The user value of an encoded value.
+pub(crate) fn decode(raw: &[u8]) -> &[u8] {
+    match raw {
+        [VALUE_MARKER, ESCAPED | MERGE_OPERAND, rest @ ..] => rest,
+        [VALUE_MARKER, EXPIRING, rest @ ..] if rest.len() >= 8 => &rest[8..],
+        _ => raw,
+    }
+}
+
+/// The user value of an encoded value, sharing the buffer.
+pub(crate) fn decode_bytes(raw: &Bytes) -> Bytes {
+    let offset = raw.len() - decode(raw).len();
+    raw.slice(offset..)
+}
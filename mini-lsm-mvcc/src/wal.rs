@@ -12,9 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::fs::{File, OpenOptions};
 use std::hash::Hasher;
-use std::io::{BufWriter, Read, Write};
+use std::io::{BufWriter, Write};
 use std::path::Path;
 use std::sync::Arc;
 
@@ -23,95 +22,369 @@ use bytes::{Buf, BufMut, Bytes};
 use crossbeam_skiplist::SkipMap;
 use parking_lot::Mutex;
 
+use crate::fs::{FileSystem, WritableFile};
 use crate::key::{KeyBytes, KeySlice};
+use crate::lsm_storage::WriteBatchRecord;
+use crate::value;
 
+/// The size of the WAL file header, which stores the generation of the file.
+const WAL_HEADER_SIZE: usize = std::mem::size_of::<u64>();
+
+/// The WAL file starts with the generation number of the file. Every record also carries the generation, so that
+/// records left over from a previous use of a recycled file (or the zeroes of a preallocated file) can be told apart
+/// from the records written in this generation.
+///
+/// ```text
+/// | generation (u64) | batch_size (u32) | generation (u64) | batch | checksum (u32) | ... |
+/// ```
+///
+/// The checksum of a record covers its `batch_size` and generation as well as the batch, so that a corrupted size or
+/// generation is detected instead of misreading the rest of the log.
+///
+/// WALs written before the header was added start with the size of the first batch, and their records have no
+/// generation. The generation is always below 2^32, so the first 4 bytes of the header are zero, while the size of a
+/// batch is not. Such a WAL is rewritten in the current format when it is recovered. Its values are not encoded (see
+/// `crate::value`), and are escaped when it is read.
 pub struct Wal {
-    file: Arc<Mutex<BufWriter<File>>>,
+    file: Arc<Mutex<BufWriter<Box<dyn WritableFile>>>>,
+    generation: u64,
+}
+
+/// The records of the current generation in a WAL file.
+struct DecodedWal {
+    generation: u64,
+    batches: Vec<Vec<(Bytes, u64, Bytes)>>,
+    /// The offset right after the last valid record.
+    end_offset: usize,
+    is_corrupted: bool,
+    /// The file is in the format without the header and generations.
+    is_legacy: bool,
 }
 
 impl Wal {
-    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+    /// Create a new WAL file. If `preallocate_size` is not zero, the file is preallocated so that appending to the
+    /// WAL does not change the file size, and `sync` only needs to flush the data.
+    pub fn create(
+        fs: &dyn FileSystem,
+        path: impl AsRef<Path>,
+        preallocate_size: usize,
+    ) -> Result<Self> {
+        let mut file = fs.create(path.as_ref()).context("failed to create WAL")?;
+        if preallocate_size > 0 {
+            file.preallocate(preallocate_size as u64)
+                .context("failed to preallocate WAL")?;
+        }
+        let generation: u64 = 1;
+        file.write_all(&generation.to_be_bytes())?;
+        // The file size and allocated blocks changed, so the metadata must be synced once.
+        file.sync_all()?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(BufWriter::new(file))),
+            generation,
+        })
+    }
+
+    /// Reuse the obsolete WAL file at `from` as the WAL at `path`. The generation of the file is bumped and synced
+    /// before the file is renamed, so that the file never has the name of a live WAL while the records from its
+    /// previous use are still valid.
+    pub fn create_recycled(
+        fs: &dyn FileSystem,
+        from: impl AsRef<Path>,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let (from, path) = (from.as_ref(), path.as_ref());
+        let mut header = [0; WAL_HEADER_SIZE];
+        fs.open(from)?.read_at(&mut header, 0)?;
+        let generation = u64::from_be_bytes(header) + 1;
+        {
+            let mut file = fs
+                .open_writable(from)
+                .context("failed to open recycled WAL")?;
+            file.write_all(&generation.to_be_bytes())?;
+            file.sync_data()?;
+        }
+        fs.rename(from, path)?;
+        let mut file = fs
+            .open_writable(path)
+            .context("failed to open recycled WAL")?;
+        file.seek(WAL_HEADER_SIZE as u64)?;
         Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(
-                OpenOptions::new()
-                    .read(true)
-                    .create_new(true)
-                    .write(true)
-                    .open(path)
-                    .context("failed to create WAL")?,
-            ))),
+            file: Arc::new(Mutex::new(BufWriter::new(file))),
+            generation,
         })
     }
 
-    pub fn recover(path: impl AsRef<Path>, skiplist: &SkipMap<KeyBytes, Bytes>) -> Result<Self> {
+    pub fn recover(
+        fs: &dyn FileSystem,
+        path: impl AsRef<Path>,
+        skiplist: &SkipMap<KeyBytes, Bytes>,
+    ) -> Result<Self> {
         let path = path.as_ref();
-        let mut file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .open(path)
+        let buf = fs.read(path).context("failed to recover from WAL")?;
+        let DecodedWal {
+            generation,
+            batches,
+            end_offset,
+            is_legacy,
+            ..
+        } = Self::decode(&buf, false)?;
+        if is_legacy {
+            Self::migrate(fs, path, &batches)?;
+            return Self::recover(fs, path, skiplist);
+        }
+        let mut file = fs
+            .open_writable(path)
             .context("failed to recover from WAL")?;
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf)?;
-        let mut rbuf: &[u8] = buf.as_slice();
-        while rbuf.has_remaining() {
+        for batch in batches {
+            for (key, ts, value) in batch {
+                skiplist.insert(KeyBytes::from_bytes_with_ts(key, ts), value);
+            }
+        }
+        // Continue writing after the last valid record, overwriting preallocated or stale space.
+        file.seek(end_offset as u64)?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(BufWriter::new(file))),
+            generation,
+        })
+    }
+
+    /// Rewrite a WAL without the header in the current format. The new file is written next to the old one and then
+    /// replaces it, so that a crash leaves either of them.
+    fn migrate(
+        fs: &dyn FileSystem,
+        path: &Path,
+        batches: &[Vec<(Bytes, u64, Bytes)>],
+    ) -> Result<()> {
+        let generation: u64 = 1;
+        let mut buf = generation.to_be_bytes().to_vec();
+        for batch in batches {
+            let data = batch
+                .iter()
+                .map(|(key, ts, value)| (KeySlice::from_slice(key, *ts), value.as_ref()))
+                .collect::<Vec<_>>();
+            Self::encode_record(generation, &data, &mut buf);
+        }
+        let tmp_path = path.with_extension("wal.tmp");
+        fs.write(&tmp_path, &buf)?;
+        fs.rename(&tmp_path, path)?;
+        if let Some(dir) = path.parent() {
+            fs.sync_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Read all batches in a WAL file without building a memtable. Each batch is written by a single commit, so all
+    /// keys in a batch share the same commit timestamp. The values are decoded, see `crate::value`.
+    pub fn read_batches(
+        fs: &dyn FileSystem,
+        path: impl AsRef<Path>,
+    ) -> Result<Vec<(u64, Vec<WriteBatchRecord<Bytes>>)>> {
+        let buf = fs.read(path.as_ref()).context("failed to read WAL")?;
+        let decoded = Self::decode(&buf, false)?;
+        let mut batches = Vec::new();
+        for batch in decoded.batches {
+            let Some((_, commit_ts, _)) = batch.first() else {
+                continue;
+            };
+            let commit_ts = *commit_ts;
+            let records = batch
+                .into_iter()
+                .map(|(key, _, raw)| {
+                    if raw.is_empty() {
+                        WriteBatchRecord::Del(key)
+                    } else if value::is_merge_operand(&raw) {
+                        WriteBatchRecord::Merge(key, value::decode_bytes(&raw))
+                    } else if let Some(expire_at) = value::expire_at(&raw) {
+                        WriteBatchRecord::PutExpiring(key, value::decode_bytes(&raw), expire_at)
+                    } else {
+                        WriteBatchRecord::Put(key, value::decode_bytes(&raw))
+                    }
+                })
+                .collect();
+            batches.push((commit_ts, records));
+        }
+        Ok(batches)
+    }
+
+    /// Recover as many records as possible from a damaged WAL file, stopping at the first incomplete or corrupted
+    /// record instead of failing. Returns whether the whole file is readable.
+    pub fn salvage(
+        fs: &dyn FileSystem,
+        path: impl AsRef<Path>,
+        skiplist: &SkipMap<KeyBytes, Bytes>,
+    ) -> Result<bool> {
+        let buf = fs.read(path.as_ref()).context("failed to read WAL")?;
+        let decoded = Self::decode(&buf, true)?;
+        for batch in decoded.batches {
+            for (key, ts, value) in batch {
+                skiplist.insert(KeyBytes::from_bytes_with_ts(key, ts), value);
+            }
+        }
+        Ok(!decoded.is_corrupted)
+    }
+
+    /// Decode the records of the current generation. A record that is cut off by the end of the file was torn by a
+    /// crash before it was synced, and ends the log. If `salvage` is set, decoding stops at the first corrupted record
+    /// and reports it in `is_corrupted` as well as a torn record; otherwise a corrupted record is an error.
+    fn decode(buf: &[u8], salvage: bool) -> Result<DecodedWal> {
+        let mut decoded = DecodedWal {
+            generation: 0,
+            batches: Vec::new(),
+            end_offset: WAL_HEADER_SIZE,
+            is_corrupted: false,
+            is_legacy: false,
+        };
+        if buf.is_empty() || (buf.len() >= 4 && buf[..4] != [0; 4]) {
+            return Self::decode_legacy(buf, salvage);
+        }
+        if buf.len() < WAL_HEADER_SIZE {
+            if salvage {
+                decoded.is_corrupted = true;
+                return Ok(decoded);
+            }
+            bail!("incomplete WAL header");
+        }
+        let mut rbuf: &[u8] = buf;
+        let generation = rbuf.get_u64();
+        decoded.generation = generation;
+        const RECORD_HEADER_SIZE: usize = std::mem::size_of::<u32>() + std::mem::size_of::<u64>();
+        while rbuf.remaining() >= RECORD_HEADER_SIZE {
+            let record_header = &rbuf[..RECORD_HEADER_SIZE];
             let batch_size = rbuf.get_u32() as usize;
-            if rbuf.remaining() < batch_size {
-                bail!("incomplete WAL");
+            let record_generation = rbuf.get_u64();
+            // An empty record is never written, so this is the zeroed tail of a preallocated file. A record with
+            // another generation is left over from a previous use of a recycled file.
+            if batch_size == 0 || record_generation != generation {
+                break;
             }
-            let mut batch_buf = &rbuf[..batch_size];
-            let mut kv_pairs = Vec::new();
-            let mut hasher = crc32fast::Hasher::new();
+            if rbuf.remaining() < batch_size + std::mem::size_of::<u32>() {
+                decoded.is_corrupted = salvage;
+                break;
+            }
+            let batch_buf = &rbuf[..batch_size];
+            let expected_checksum = (&rbuf[batch_size..]).get_u32();
             // The checksum computed from the individual components should be the same as a direct checksum on the buffer.
             // Students' implementation only needs to do a single checksum on the buffer. We compute both for verification purpose.
-            let single_checksum = crc32fast::hash(batch_buf);
-            while batch_buf.has_remaining() {
-                let key_len = batch_buf.get_u16() as usize;
-                hasher.write(&(key_len as u16).to_be_bytes());
-                let key = Bytes::copy_from_slice(&batch_buf[..key_len]);
-                hasher.write(&key);
-                batch_buf.advance(key_len);
-                let ts = batch_buf.get_u64();
-                hasher.write(&ts.to_be_bytes());
-                let value_len = batch_buf.get_u16() as usize;
-                hasher.write(&(value_len as u16).to_be_bytes());
-                let value = Bytes::copy_from_slice(&batch_buf[..value_len]);
-                hasher.write(&value);
-                kv_pairs.push((key, ts, value));
-                batch_buf.advance(value_len);
-            }
-            rbuf.advance(batch_size);
-            let expected_checksum = rbuf.get_u32();
-            let component_checksum = hasher.finalize();
-            assert_eq!(component_checksum, single_checksum);
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(record_header);
+            let header_hasher = hasher.clone();
+            hasher.update(batch_buf);
+            let single_checksum = hasher.finalize();
+            // Check the checksum before decoding, so that a corrupted record is never parsed.
             if single_checksum != expected_checksum {
+                if salvage {
+                    decoded.is_corrupted = true;
+                    break;
+                }
                 bail!("checksum mismatch");
             }
-            for (key, ts, value) in kv_pairs {
-                skiplist.insert(KeyBytes::from_bytes_with_ts(key, ts), value);
+            decoded.batches.push(Self::decode_batch(
+                batch_buf,
+                header_hasher,
+                single_checksum,
+            ));
+            rbuf.advance(batch_size + std::mem::size_of::<u32>());
+            decoded.end_offset = buf.len() - rbuf.remaining();
+        }
+        Ok(decoded)
+    }
+
+    /// Decode a WAL without the header, whose records are `| batch_size (u32) | batch | checksum (u32) |`.
+    fn decode_legacy(buf: &[u8], salvage: bool) -> Result<DecodedWal> {
+        let mut decoded = DecodedWal {
+            generation: 0,
+            batches: Vec::new(),
+            end_offset: 0,
+            is_corrupted: false,
+            is_legacy: true,
+        };
+        let mut rbuf: &[u8] = buf;
+        while rbuf.remaining() >= std::mem::size_of::<u32>() {
+            let batch_size = rbuf.get_u32() as usize;
+            if rbuf.remaining() < batch_size + std::mem::size_of::<u32>() {
+                decoded.is_corrupted = salvage;
+                break;
+            }
+            let batch_buf = &rbuf[..batch_size];
+            let expected_checksum = (&rbuf[batch_size..]).get_u32();
+            let single_checksum = crc32fast::hash(batch_buf);
+            if single_checksum != expected_checksum {
+                if salvage {
+                    decoded.is_corrupted = true;
+                    break;
+                }
+                bail!("checksum mismatch");
             }
+            let batch = Self::decode_batch(batch_buf, crc32fast::Hasher::new(), single_checksum)
+                .into_iter()
+                .map(|(key, ts, value)| (key, ts, value::escape_unencoded(value)))
+                .collect();
+            decoded.batches.push(batch);
+            rbuf.advance(batch_size + std::mem::size_of::<u32>());
+            decoded.end_offset = buf.len() - rbuf.remaining();
         }
-        Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(file))),
-        })
+        Ok(decoded)
+    }
+
+    /// Decode the key-value pairs of a batch whose checksum is verified. `hasher` has already hashed the bytes of the
+    /// record before the batch.
+    fn decode_batch(
+        mut batch_buf: &[u8],
+        mut hasher: crc32fast::Hasher,
+        single_checksum: u32,
+    ) -> Vec<(Bytes, u64, Bytes)> {
+        let mut kv_pairs = Vec::new();
+        while batch_buf.has_remaining() {
+            let key_len = batch_buf.get_u16() as usize;
+            hasher.write(&(key_len as u16).to_be_bytes());
+            let key = Bytes::copy_from_slice(&batch_buf[..key_len]);
+            hasher.write(&key);
+            batch_buf.advance(key_len);
+            let ts = batch_buf.get_u64();
+            hasher.write(&ts.to_be_bytes());
+            let value_len = batch_buf.get_u16() as usize;
+            hasher.write(&(value_len as u16).to_be_bytes());
+            let value = Bytes::copy_from_slice(&batch_buf[..value_len]);
+            hasher.write(&value);
+            kv_pairs.push((key, ts, value));
+            batch_buf.advance(value_len);
+        }
+        let component_checksum = hasher.finalize();
+        assert_eq!(component_checksum, single_checksum);
+        kv_pairs
+    }
+
+    /// Append a record of the generation with the key-value pairs to `buf`.
+    fn encode_record(generation: u64, data: &[(KeySlice, &[u8])], buf: &mut Vec<u8>) {
+        let mut batch = Vec::<u8>::new();
+        for (key, value) in data {
+            batch.put_u16(key.key_len() as u16);
+            batch.put_slice(key.key_ref());
+            batch.put_u64(key.ts());
+            batch.put_u16(value.len() as u16);
+            batch.put_slice(value);
+        }
+        let record_start = buf.len();
+        // batch_size header (u32)
+        buf.put_u32(batch.len() as u32);
+        // generation (u64)
+        buf.put_u64(generation);
+        // key-value pairs body
+        buf.put_slice(&batch);
+        // checksum (u32) of the header and the body
+        let checksum = crc32fast::hash(&buf[record_start..]);
+        buf.put_u32(checksum);
     }
 
     /// Implement this in week 3, day 5.
     pub fn put_batch(&self, data: &[(KeySlice, &[u8])]) -> Result<()> {
-        let mut file = self.file.lock();
-        let mut buf = Vec::<u8>::new();
-        for (key, value) in data {
-            buf.put_u16(key.key_len() as u16);
-            buf.put_slice(key.key_ref());
-            buf.put_u64(key.ts());
-            buf.put_u16(value.len() as u16);
-            buf.put_slice(value);
+        // An empty record marks the end of the log, so empty batches must not be written.
+        if data.is_empty() {
+            return Ok(());
         }
-        // write batch_size header (u32)
-        file.write_all(&(buf.len() as u32).to_be_bytes())?;
-        // write key-value pairs body
-        file.write_all(&buf)?;
-        // write checksum (u32)
-        file.write_all(&crc32fast::hash(&buf).to_be_bytes())?;
+        let mut buf = Vec::new();
+        Self::encode_record(self.generation, data, &mut buf);
+        self.file.lock().write_all(&buf)?;
         Ok(())
     }
 
@@ -122,7 +395,9 @@ impl Wal {
     pub fn sync(&self) -> Result<()> {
         let mut file = self.file.lock();
         file.flush()?;
-        file.get_mut().sync_all()?;
+        // The file is either preallocated or grows with the appended data. In both cases, `fdatasync` persists the
+        // file size together with the data, and skips the unrelated metadata like mtime.
+        file.get_mut().sync_data()?;
         Ok(())
     }
 }
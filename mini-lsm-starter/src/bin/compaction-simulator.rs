@@ -15,14 +15,16 @@
 mod wrapper;
 use wrapper::mini_lsm_wrapper;
 
+#[path = "compaction-simulator/controllers.rs"]
+mod controllers;
+
 use std::collections::HashMap;
 use std::sync::Arc;
 
 use bytes::{Buf, BufMut, BytesMut};
 use clap::Parser;
 use mini_lsm_wrapper::compact::{
-    LeveledCompactionController, LeveledCompactionOptions, SimpleLeveledCompactionController,
-    SimpleLeveledCompactionOptions, TieredCompactionController, TieredCompactionOptions,
+    SimpleLeveledCompactionController, SimpleLeveledCompactionOptions, TieredCompactionOptions,
 };
 use mini_lsm_wrapper::key::KeyBytes;
 use mini_lsm_wrapper::lsm_storage::LsmStorageState;
@@ -52,6 +54,8 @@ enum Args {
         size_ratio_percent: usize,
         #[clap(long, default_value = "50")]
         iterations: usize,
+        #[clap(long, default_value = "32")]
+        sst_size_mb: usize,
     },
     Tiered {
         /// Dump the generated ID instead of where the original data comes from.
@@ -77,6 +81,8 @@ enum Args {
         max_merge_width: Option<usize>,
         #[clap(long, default_value = "50")]
         iterations: usize,
+        #[clap(long, default_value = "32")]
+        sst_size_mb: usize,
     },
     Leveled {
         /// Dump the generated ID instead of where the original data comes from.
@@ -102,7 +108,12 @@ enum Args {
         iterations: usize,
         #[clap(long, default_value = "32")]
         sst_size_mb: usize,
+        #[clap(flatten)]
+        leveled_args: controllers::LeveledArgs,
     },
+    /// Strategies only implemented by this crate.
+    #[clap(flatten)]
+    Other(controllers::Command),
 }
 
 pub struct MockStorage {
@@ -112,16 +123,18 @@ pub struct MockStorage {
     file_list: HashMap<usize, usize>,
     total_flushes: usize,
     total_writes: usize,
-}
-
-impl Default for MockStorage {
-    fn default() -> Self {
-        Self::new()
-    }
+    /// Compactions that moved SSTs to the lower level without writing new SSTs
+    total_trivial_moves: usize,
+    /// The size of the SSTs written by compactions. Flushes write SSTs of 50% to 100% of this size.
+    sst_size: u64,
+    /// Bytes written by flushes
+    flushed_bytes: u64,
+    /// Bytes written by flushes and compactions
+    written_bytes: u64,
 }
 
 impl MockStorage {
-    pub fn new() -> Self {
+    pub fn new(sst_size_mb: usize) -> Self {
         let snapshot = LsmStorageState {
             memtable: Arc::new(MemTable::create(0)),
             imm_memtables: Vec::new(),
@@ -135,6 +148,10 @@ impl MockStorage {
             file_list: Default::default(),
             total_flushes: 0,
             total_writes: 0,
+            total_trivial_moves: 0,
+            sst_size: sst_size_mb as u64 * 1024 * 1024,
+            flushed_bytes: 0,
+            written_bytes: 0,
         }
     }
 
@@ -144,25 +161,111 @@ impl MockStorage {
         id
     }
 
-    pub fn flush_sst_to_l0(&mut self) -> usize {
+    fn add_sst(&mut self, id: usize, size: u64, first_key: KeyBytes, last_key: KeyBytes) {
+        self.snapshot.sstables.insert(
+            id,
+            Arc::new(SsTable::create_meta_only(id, size, first_key, last_key)),
+        );
+        self.total_writes += 1;
+        self.written_bytes += size;
+    }
+
+    /// Write an SST of a random key range, with a size between 50% and 100% of the SST size as memtables are not
+    /// always full when they are flushed.
+    fn flush_sst(&mut self) -> usize {
+        use rand::Rng;
         let id = self.generate_sst_id();
-        self.snapshot.l0_sstables.push(id);
+        let size = rand::thread_rng().gen_range(self.sst_size / 2..=self.sst_size);
+        let (first_key, last_key) = generate_random_key_range();
+        self.add_sst(id, size, first_key, last_key);
         self.file_list.insert(id, id);
         self.total_flushes += 1;
-        self.total_writes += 1;
+        self.flushed_bytes += size;
+        id
+    }
+
+    pub fn flush_sst_to_l0(&mut self) -> usize {
+        let id = self.flush_sst();
+        self.snapshot.l0_sstables.push(id);
         id
     }
 
     pub fn flush_sst_to_new_tier(&mut self) {
-        let id = self.generate_sst_id();
+        let id = self.flush_sst();
         self.snapshot.levels.insert(0, (id, vec![id]));
-        self.file_list.insert(id, id);
-        self.total_flushes += 1;
-        self.total_writes += 1;
     }
 
-    pub fn remove(&mut self, files_to_remove: &[usize]) {
-        for file_id in files_to_remove {
+    /// Write the output of a compaction that merges the SSTs, as full SSTs and a last SST with the remaining bytes. The
+    /// key range of the input is randomly split among the output.
+    pub fn write_compaction_output(&mut self, input: &[usize]) -> Vec<usize> {
+        let input_ssts = input
+            .iter()
+            .map(|id| self.snapshot.sstables[id].clone())
+            .collect::<Vec<_>>();
+        let total_size = input_ssts.iter().map(|x| x.table_size()).sum::<u64>();
+        let begin = input_ssts.iter().map(|x| x.first_key()).min().unwrap();
+        let end = input_ssts.iter().map(|x| x.last_key()).max().unwrap();
+        let num_ssts = total_size.div_ceil(self.sst_size).max(1) as usize;
+        let splits = generate_random_split(begin.clone(), end.clone(), num_ssts);
+        let mut sst_ids = Vec::new();
+        for (idx, (first_key, last_key)) in splits.into_iter().enumerate() {
+            let new_sst_id = self.generate_sst_id();
+            let size = if idx + 1 == num_ssts {
+                total_size - self.sst_size * (num_ssts as u64 - 1)
+            } else {
+                self.sst_size
+            };
+            self.add_sst(new_sst_id, size, first_key, last_key);
+            self.file_list
+                .insert(new_sst_id, input[idx.min(input.len() - 1)]);
+            sst_ids.push(new_sst_id);
+        }
+        sst_ids
+    }
+
+    /// The bytes of the SSTs that are not removed yet.
+    fn space_used(&self) -> u64 {
+        self.file_list
+            .keys()
+            .map(|id| self.snapshot.sstables[id].table_size())
+            .sum()
+    }
+
+    fn dump_statistics(&self, max_space: u64) {
+        println!("--- Statistics ---");
+        println!(
+            "Write Amplification: {:.3}MB/{:.3}MB={:.3}x",
+            self.written_bytes as f64 / 1024.0 / 1024.0,
+            self.flushed_bytes as f64 / 1024.0 / 1024.0,
+            self.written_bytes as f64 / self.flushed_bytes as f64
+        );
+        println!(
+            "SSTs Written: {}, Flushed: {}",
+            self.total_writes, self.total_flushes
+        );
+        println!("Trivial Moves: {}", self.total_trivial_moves);
+        println!(
+            "Maximum Space Usage: {:.3}MB/{:.3}MB={:.3}x",
+            max_space as f64 / 1024.0 / 1024.0,
+            self.flushed_bytes as f64 / 1024.0 / 1024.0,
+            max_space as f64 / self.flushed_bytes as f64
+        );
+        println!(
+            "Read Amplification: {}x",
+            self.snapshot.l0_sstables.len()
+                + self
+                    .snapshot
+                    .levels
+                    .iter()
+                    .filter(|(_, f)| !f.is_empty())
+                    .count()
+        );
+        println!();
+    }
+
+    pub fn remove(&mut self, files_to_remove: &[usize], output: &[usize]) {
+        // SSTs that are moved are both removed from the upper level and added to the lower level.
+        for file_id in files_to_remove.iter().filter(|x| !output.contains(x)) {
             let ret = self.file_list.remove(file_id);
             assert!(ret.is_some(), "failed to remove file {}", file_id);
         }
@@ -287,6 +390,7 @@ fn main() {
             iterations,
             level0_file_num_compaction_trigger,
             max_levels,
+            sst_size_mb,
         } => {
             // TODO(chi): use unified logic for all 3 compactions...
             let controller =
@@ -295,7 +399,7 @@ fn main() {
                     level0_file_num_compaction_trigger,
                     max_levels,
                 });
-            let mut storage = MockStorage::new();
+            let mut storage = MockStorage::new(sst_size_mb);
             for i in 0..max_levels {
                 storage.snapshot.levels.push((i + 1, Vec::new()));
             }
@@ -316,19 +420,23 @@ fn main() {
                     if !size_only {
                         println!("--- Compaction Task ---");
                     }
-                    controller.generate_compaction_task(&storage.snapshot)
+                    controllers::simple_task(&controller, &storage.snapshot)
                 } {
-                    let mut sst_ids = Vec::new();
-                    for file in task
-                        .upper_level_sst_ids
-                        .iter()
-                        .chain(task.lower_level_sst_ids.iter())
+                    let sst_ids = if let Some(moved_ssts) =
+                        controllers::simple_trivial_move(&task, &storage.snapshot)
                     {
-                        let new_sst_id = storage.generate_sst_id();
-                        sst_ids.push(new_sst_id);
-                        storage.file_list.insert(new_sst_id, *file);
-                        storage.total_writes += 1;
-                    }
+                        storage.total_trivial_moves += 1;
+                        print!("Trivial Move ");
+                        moved_ssts
+                    } else {
+                        let input = task
+                            .upper_level_sst_ids
+                            .iter()
+                            .chain(task.lower_level_sst_ids.iter())
+                            .copied()
+                            .collect::<Vec<_>>();
+                        storage.write_compaction_output(&input)
+                    };
                     print!(
                         "Upper L{} {:?} ",
                         task.upper_level.unwrap_or_default(),
@@ -339,11 +447,11 @@ fn main() {
                         task.lower_level, task.lower_level_sst_ids
                     );
                     println!("-> {:?}", sst_ids);
-                    max_space = max_space.max(storage.file_list.len());
+                    max_space = max_space.max(storage.space_used());
                     let (snapshot, del) =
                         controller.apply_compaction_result(&storage.snapshot, &task, &sst_ids);
                     storage.snapshot = snapshot;
-                    storage.remove(&del);
+                    storage.remove(&del, &sst_ids);
                     println!("--- After Compaction ---");
                     if size_only {
                         storage.dump_size_only();
@@ -362,31 +470,8 @@ fn main() {
                 } else {
                     println!("{num_compactions} compaction triggered in this iteration");
                 }
-                max_space = max_space.max(storage.file_list.len());
-                println!("--- Statistics ---");
-                println!(
-                    "Write Amplification: {}/{}={:.3}x",
-                    storage.total_writes,
-                    storage.total_flushes,
-                    storage.total_writes as f64 / storage.total_flushes as f64
-                );
-                println!(
-                    "Maximum Space Usage: {}/{}={:.3}x",
-                    max_space,
-                    storage.total_flushes,
-                    max_space as f64 / storage.total_flushes as f64
-                );
-                println!(
-                    "Read Amplification: {}x",
-                    storage.snapshot.l0_sstables.len()
-                        + storage
-                            .snapshot
-                            .levels
-                            .iter()
-                            .filter(|(_, f)| !f.is_empty())
-                            .count()
-                );
-                println!();
+                max_space = max_space.max(storage.space_used());
+                storage.dump_statistics(max_space);
             }
         }
         Args::Tiered {
@@ -398,15 +483,16 @@ fn main() {
             min_merge_width,
             max_merge_width,
             iterations,
+            sst_size_mb,
         } => {
-            let controller = TieredCompactionController::new(TieredCompactionOptions {
+            let controller = controllers::tiered_controller(TieredCompactionOptions {
                 num_tiers: level0_file_num_compaction_trigger,
                 max_size_amplification_percent,
                 size_ratio,
                 min_merge_width,
                 max_merge_width,
             });
-            let mut storage = MockStorage::new();
+            let mut storage = MockStorage::new(sst_size_mb);
             let mut max_space = 0;
             for i in 0..iterations {
                 println!("=== Iteration {i} ===");
@@ -427,24 +513,20 @@ fn main() {
                     if !size_only {
                         println!("--- Compaction Task ---");
                     }
-                    controller.generate_compaction_task(&storage.snapshot)
+                    controllers::tiered_task(&controller, &storage.snapshot)
                 } {
-                    let mut sst_ids = Vec::new();
+                    let mut input = Vec::new();
                     for (tier_id, files) in &task.tiers {
-                        for file in files {
-                            let new_sst_id = storage.generate_sst_id();
-                            sst_ids.push(new_sst_id);
-                            storage.file_list.insert(new_sst_id, *file);
-                            storage.total_writes += 1;
-                        }
+                        input.extend(files);
                         print!("L{} {:?} ", tier_id, files);
                     }
+                    let sst_ids = storage.write_compaction_output(&input);
                     println!("-> {:?}", sst_ids);
-                    max_space = max_space.max(storage.file_list.len());
+                    max_space = max_space.max(storage.space_used());
                     let (snapshot, del) =
                         controller.apply_compaction_result(&storage.snapshot, &task, &sst_ids);
                     storage.snapshot = snapshot;
-                    storage.remove(&del);
+                    storage.remove(&del, &sst_ids);
                     println!("--- After Compaction ---");
                     if size_only {
                         storage.dump_size_only();
@@ -463,31 +545,8 @@ fn main() {
                 } else {
                     println!("{num_compactions} compaction triggered in this iteration");
                 }
-                max_space = max_space.max(storage.file_list.len());
-                println!("--- Statistics ---");
-                println!(
-                    "Write Amplification: {}/{}={:.3}x",
-                    storage.total_writes,
-                    storage.total_flushes,
-                    storage.total_writes as f64 / storage.total_flushes as f64
-                );
-                println!(
-                    "Maximum Space Usage: {}/{}={:.3}x",
-                    max_space,
-                    storage.total_flushes,
-                    max_space as f64 / storage.total_flushes as f64
-                );
-                println!(
-                    "Read Amplification: {}x",
-                    storage.snapshot.l0_sstables.len()
-                        + storage
-                            .snapshot
-                            .levels
-                            .iter()
-                            .filter(|(_, f)| !f.is_empty())
-                            .count()
-                );
-                println!();
+                max_space = max_space.max(storage.space_used());
+                storage.dump_statistics(max_space);
             }
         }
         Args::Leveled {
@@ -499,32 +558,24 @@ fn main() {
             base_level_size_mb,
             iterations,
             sst_size_mb,
+            leveled_args,
         } => {
-            let controller = LeveledCompactionController::new(LeveledCompactionOptions {
+            let controller = controllers::leveled_controller(
                 level0_file_num_compaction_trigger,
                 level_size_multiplier,
                 max_levels,
                 base_level_size_mb,
-            });
+                leveled_args,
+            );
 
-            let mut storage = MockStorage::new();
+            let mut storage = MockStorage::new(sst_size_mb);
             for i in 0..max_levels {
                 storage.snapshot.levels.push((i + 1, Vec::new()));
             }
             let mut max_space = 0;
             for i in 0..iterations {
                 println!("=== Iteration {i} ===");
-                let id = storage.flush_sst_to_l0();
-                let (first_key, last_key) = generate_random_key_range();
-                storage.snapshot.sstables.insert(
-                    id,
-                    Arc::new(SsTable::create_meta_only(
-                        id,
-                        sst_size_mb as u64 * 1024 * 1024,
-                        first_key,
-                        last_key,
-                    )),
-                );
+                storage.flush_sst_to_l0();
                 println!("--- After Flush ---");
                 if size_only {
                     storage.dump_size_only();
@@ -538,43 +589,23 @@ fn main() {
                     if !size_only {
                         println!("--- Compaction Task ---");
                     }
-                    controller.generate_compaction_task(&storage.snapshot)
+                    controllers::leveled_task(&controller, &storage.snapshot)
                 } {
-                    let mut sst_ids = Vec::new();
-                    let split_num = task.upper_level_sst_ids.len() + task.lower_level_sst_ids.len();
-                    let mut first_keys = Vec::new();
-                    let mut last_keys = Vec::new();
-                    for file in task
-                        .upper_level_sst_ids
-                        .iter()
-                        .chain(task.lower_level_sst_ids.iter())
-                    {
-                        first_keys.push(storage.snapshot.sstables[file].first_key().clone());
-                        last_keys.push(storage.snapshot.sstables[file].last_key().clone());
-                    }
-                    let begin = first_keys.into_iter().min().unwrap();
-                    let end = last_keys.into_iter().max().unwrap();
-                    let splits = generate_random_split(begin, end, split_num);
-                    for (id, file) in task
-                        .upper_level_sst_ids
-                        .iter()
-                        .chain(task.lower_level_sst_ids.iter())
-                        .enumerate()
+                    let sst_ids = if let Some(moved_ssts) =
+                        controllers::leveled_trivial_move(&task, &storage.snapshot)
                     {
-                        let new_sst_id = storage.generate_sst_id();
-                        sst_ids.push(new_sst_id);
-                        storage.file_list.insert(new_sst_id, *file);
-                        storage.total_writes += 1;
-                        storage.snapshot.sstables.insert(
-                            new_sst_id,
-                            Arc::new(SsTable::create_meta_only(
-                                new_sst_id,
-                                sst_size_mb as u64 * 1024 * 1024,
-                                splits[id].0.clone(),
-                                splits[id].1.clone(),
-                            )),
-                        );
-                    }
+                        storage.total_trivial_moves += 1;
+                        print!("Trivial Move ");
+                        moved_ssts
+                    } else {
+                        let input = task
+                            .upper_level_sst_ids
+                            .iter()
+                            .chain(task.lower_level_sst_ids.iter())
+                            .copied()
+                            .collect::<Vec<_>>();
+                        storage.write_compaction_output(&input)
+                    };
                     print!(
                         "Upper L{} [{}] ",
                         task.upper_level.unwrap_or_default(),
@@ -634,7 +665,7 @@ fn main() {
                             .collect::<Vec<_>>()
                             .join(", ")
                     );
-                    max_space = max_space.max(storage.file_list.len());
+                    max_space = max_space.max(storage.space_used());
                     let (snapshot, del) = controller.apply_compaction_result(
                         &storage.snapshot,
                         &task,
@@ -642,7 +673,7 @@ fn main() {
                         false,
                     );
                     storage.snapshot = snapshot;
-                    storage.remove(&del);
+                    storage.remove(&del, &sst_ids);
                     println!("--- After Compaction ---");
                     if size_only {
                         storage.dump_size_only();
@@ -661,32 +692,10 @@ fn main() {
                 } else {
                     println!("{num_compactions} compaction triggered in this iteration");
                 }
-                max_space = max_space.max(storage.file_list.len());
-                println!("--- Statistics ---");
-                println!(
-                    "Write Amplification: {}/{}={:.3}x",
-                    storage.total_writes,
-                    storage.total_flushes,
-                    storage.total_writes as f64 / storage.total_flushes as f64
-                );
-                println!(
-                    "Maximum Space Usage: {}/{}={:.3}x",
-                    max_space,
-                    storage.total_flushes,
-                    max_space as f64 / storage.total_flushes as f64
-                );
-                println!(
-                    "Read Amplification: {}x",
-                    storage.snapshot.l0_sstables.len()
-                        + storage
-                            .snapshot
-                            .levels
-                            .iter()
-                            .filter(|(_, f)| !f.is_empty())
-                            .count()
-                );
-                println!();
+                max_space = max_space.max(storage.space_used());
+                storage.dump_statistics(max_space);
             }
         }
+        Args::Other(command) => controllers::run(command),
     }
 }
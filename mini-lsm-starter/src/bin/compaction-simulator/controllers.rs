@@ -0,0 +1,90 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The parts of the compaction simulator that depend on the compaction controllers of the crate it is built with.
+
+use clap::Subcommand;
+
+use crate::mini_lsm_wrapper::compact::{
+    LeveledCompactionController, LeveledCompactionOptions, LeveledCompactionTask,
+    SimpleLeveledCompactionController, SimpleLeveledCompactionTask, TieredCompactionController,
+    TieredCompactionOptions, TieredCompactionTask,
+};
+use crate::mini_lsm_wrapper::lsm_storage::LsmStorageState;
+
+#[derive(clap::Args, Debug)]
+pub struct LeveledArgs {}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {}
+
+pub fn run(command: Command) {
+    match command {}
+}
+
+pub fn simple_task(
+    controller: &SimpleLeveledCompactionController,
+    snapshot: &LsmStorageState,
+) -> Option<SimpleLeveledCompactionTask> {
+    controller.generate_compaction_task(snapshot)
+}
+
+/// Compactions always rewrite the SSTs.
+pub fn simple_trivial_move(
+    _task: &SimpleLeveledCompactionTask,
+    _snapshot: &LsmStorageState,
+) -> Option<Vec<usize>> {
+    None
+}
+
+pub fn tiered_controller(options: TieredCompactionOptions) -> TieredCompactionController {
+    TieredCompactionController::new(options)
+}
+
+pub fn tiered_task(
+    controller: &TieredCompactionController,
+    snapshot: &LsmStorageState,
+) -> Option<TieredCompactionTask> {
+    controller.generate_compaction_task(snapshot)
+}
+
+pub fn leveled_controller(
+    level0_file_num_compaction_trigger: usize,
+    level_size_multiplier: usize,
+    max_levels: usize,
+    base_level_size_mb: usize,
+    _args: LeveledArgs,
+) -> LeveledCompactionController {
+    LeveledCompactionController::new(LeveledCompactionOptions {
+        level0_file_num_compaction_trigger,
+        level_size_multiplier,
+        max_levels,
+        base_level_size_mb,
+    })
+}
+
+pub fn leveled_task(
+    controller: &LeveledCompactionController,
+    snapshot: &LsmStorageState,
+) -> Option<LeveledCompactionTask> {
+    controller.generate_compaction_task(snapshot)
+}
+
+/// Compactions always rewrite the SSTs.
+pub fn leveled_trivial_move(
+    _task: &LeveledCompactionTask,
+    _snapshot: &LsmStorageState,
+) -> Option<Vec<usize>> {
+    None
+}
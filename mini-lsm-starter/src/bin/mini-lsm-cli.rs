@@ -331,9 +331,6 @@ fn main() -> Result<()> {
     let lsm = MiniLsm::open(
         args.path,
         LsmStorageOptions {
-            block_size: 4096,
-            target_sst_size: 2 << 20, // 2MB
-            num_memtable_limit: 3,
             compaction_options: match args.compaction {
                 CompactionStrategy::None => CompactionOptions::NoCompaction,
                 CompactionStrategy::Simple => {
@@ -351,16 +348,12 @@ fn main() -> Result<()> {
                     max_merge_width: None,
                 }),
                 CompactionStrategy::Leveled => {
-                    CompactionOptions::Leveled(LeveledCompactionOptions {
-                        level0_file_num_compaction_trigger: 2,
-                        max_levels: 4,
-                        base_level_size_mb: 128,
-                        level_size_multiplier: 2,
-                    })
+                    CompactionOptions::Leveled(LeveledCompactionOptions::default())
                 }
             },
             enable_wal: args.enable_wal,
             serializable: args.serializable,
+            ..Default::default()
         },
     )?;
 
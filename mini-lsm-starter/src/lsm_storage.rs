@@ -126,6 +126,20 @@ impl LsmStorageOptions {
     }
 }
 
+impl Default for LsmStorageOptions {
+    /// The options of the CLI, without compaction or WAL.
+    fn default() -> Self {
+        Self {
+            block_size: 4096,
+            target_sst_size: 2 << 20, // 2MB
+            num_memtable_limit: 3,
+            compaction_options: CompactionOptions::NoCompaction,
+            enable_wal: false,
+            serializable: false,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum CompactionFilter {
     Prefix(Bytes),
@@ -36,6 +36,18 @@ pub struct LeveledCompactionOptions {
     pub base_level_size_mb: usize,
 }
 
+impl Default for LeveledCompactionOptions {
+    /// The options of the CLI.
+    fn default() -> Self {
+        Self {
+            level_size_multiplier: 2,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 4,
+            base_level_size_mb: 128,
+        }
+    }
+}
+
 pub struct LeveledCompactionController {
     options: LeveledCompactionOptions,
 }
@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#![allow(clippy::needless_update)] // `LeveledCompactionOptions` has more fields in mini-lsm-mvcc
+
 use tempfile::tempdir;
 
 use crate::{
@@ -32,6 +34,7 @@ fn test_integration() {
                 level_size_multiplier: 2,
                 base_level_size_mb: 1,
                 max_levels: 4,
+                ..Default::default()
             },
         )),
     )
@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#![allow(clippy::needless_update)] // `LeveledCompactionOptions` has more fields in mini-lsm-mvcc
+
 use std::time::Duration;
 
 use bytes::BufMut;
@@ -33,6 +35,7 @@ fn test_integration_leveled() {
         level0_file_num_compaction_trigger: 2,
         max_levels: 3,
         base_level_size_mb: 1,
+        ..Default::default()
     }))
 }
 
@@ -68,6 +71,7 @@ fn test_multiple_compacted_ssts_leveled() {
         level0_file_num_compaction_trigger: 2,
         max_levels: 2,
         base_level_size_mb: 2,
+        ..Default::default()
     });
 
     let lsm_storage_options = LsmStorageOptions::default_for_week2_test(compaction_options.clone());
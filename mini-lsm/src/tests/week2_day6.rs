@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#![allow(clippy::needless_update)] // `LeveledCompactionOptions` has more fields in mini-lsm-mvcc
+
 use tempfile::tempdir;
 
 use crate::{
@@ -30,6 +32,7 @@ fn test_integration_leveled() {
         level0_file_num_compaction_trigger: 2,
         max_levels: 3,
         base_level_size_mb: 1,
+        ..Default::default()
     }))
 }
 